@@ -0,0 +1,202 @@
+/*!
+ * Client for the rustic_db server. A `Client` is one session on the server: statements run
+ * in their own transaction unless a transaction was started with `begin`, and the session's
+ * open transaction is rolled back if the connection closes.
+ */
+
+pub mod protocol;
+
+use protocol::{Column, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+// A connection to the server over TCP or a Unix socket
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn try_clone(&self) -> std::io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+pub struct Client {
+    reader: BufReader<Stream>,
+    writer: Stream,
+}
+
+impl Client {
+    // Connects to a server listening on a TCP address such as 127.0.0.1:5454
+    pub fn connect_tcp(address: impl ToSocketAddrs) -> Result<Self, String> {
+        let stream = TcpStream::connect(address).map_err(|e| format!("Cannot connect: {}", e))?;
+        Client::start(Stream::Tcp(stream))
+    }
+
+    // Connects to a server listening on a Unix socket
+    pub fn connect_unix(path: impl AsRef<Path>) -> Result<Self, String> {
+        let stream = UnixStream::connect(path).map_err(|e| format!("Cannot connect: {}", e))?;
+        Client::start(Stream::Unix(stream))
+    }
+
+    // Waits for the greeting of the server, which refuses connections above its limit
+    fn start(stream: Stream) -> Result<Self, String> {
+        let writer = stream
+            .try_clone()
+            .map_err(|e| format!("Cannot connect: {}", e))?;
+        let mut client = Client {
+            reader: BufReader::new(stream),
+            writer,
+        };
+        let line = client.read_line()?;
+        match protocol::split_line(&line) {
+            (protocol::READY, _) => Ok(client),
+            (protocol::ERROR, message) => Err(protocol::unescape(message)?),
+            _ => Err(format!("Unexpected greeting {}", line)),
+        }
+    }
+
+    // Runs a statement that returns no rows, such as INSERT or COMMIT, and returns the
+    // summary of the server, such as "INSERT 1"
+    pub fn execute(&mut self, statement: &str) -> Result<String, String> {
+        self.send(statement)?;
+        let line = self.read_line()?;
+        match protocol::split_line(&line) {
+            (protocol::OK, summary) => Ok(summary.to_string()),
+            (protocol::ERROR, message) => Err(protocol::unescape(message)?),
+            (protocol::COLUMNS, _) => {
+                // drain the rows so the session stays in step
+                let mut rows = Rows {
+                    client: self,
+                    columns: vec![],
+                    done: false,
+                };
+                rows.by_ref().for_each(drop);
+                Err("The statement returned rows, use query".to_string())
+            }
+            _ => Err(format!("Unexpected response {}", line)),
+        }
+    }
+
+    // Runs a query and returns its rows, which are read from the connection as they are
+    // iterated. The rows must be read to the end before the next statement is sent
+    pub fn query(&mut self, statement: &str) -> Result<Rows<'_>, String> {
+        self.send(statement)?;
+        let line = self.read_line()?;
+        match protocol::split_line(&line) {
+            (protocol::COLUMNS, columns) => Ok(Rows {
+                columns: protocol::decode_columns(columns)?,
+                client: self,
+                done: false,
+            }),
+            (protocol::ERROR, message) => Err(protocol::unescape(message)?),
+            _ => Err(format!("Unexpected response {}", line)),
+        }
+    }
+
+    // Starts a transaction that the following statements of the session run in
+    pub fn begin(&mut self) -> Result<(), String> {
+        self.execute("BEGIN").map(drop)
+    }
+
+    pub fn commit(&mut self) -> Result<(), String> {
+        self.execute("COMMIT").map(drop)
+    }
+
+    pub fn rollback(&mut self) -> Result<(), String> {
+        self.execute("ROLLBACK").map(drop)
+    }
+
+    fn send(&mut self, statement: &str) -> Result<(), String> {
+        let line = format!("{}\n", protocol::escape(statement));
+        self.writer
+            .write_all(line.as_bytes())
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| format!("Cannot send statement: {}", e))
+    }
+
+    fn read_line(&mut self) -> Result<String, String> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => Err("The server closed the connection".to_string()),
+            Ok(_) => Ok(line),
+            Err(e) => Err(format!("Cannot read response: {}", e)),
+        }
+    }
+}
+
+// Rows of a query result, read from the server one at a time
+pub struct Rows<'a> {
+    client: &'a mut Client,
+    columns: Vec<Column>,
+    done: bool,
+}
+
+impl Rows<'_> {
+    pub fn get_columns(&self) -> &[Column] {
+        &self.columns
+    }
+}
+
+impl Iterator for Rows<'_> {
+    type Item = Result<Vec<Value>, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let line = match self.client.read_line() {
+            Ok(line) => line,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+        match protocol::split_line(&line) {
+            (protocol::ROW, values) => Some(protocol::decode_row(values, &self.columns)),
+            (protocol::OK, _) => {
+                self.done = true;
+                None
+            }
+            (protocol::ERROR, message) => {
+                self.done = true;
+                Some(Err(protocol::unescape(message).unwrap_or_else(|e| e)))
+            }
+            _ => {
+                self.done = true;
+                Some(Err(format!("Unexpected response {}", line)))
+            }
+        }
+    }
+}
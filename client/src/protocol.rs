@@ -0,0 +1,204 @@
+/*!
+ * The wire protocol between the rustic_db server and its clients. Every message is one
+ * line of text; line breaks, tabs and backslashes inside a message are escaped.
+ *
+ * The server greets every connection with `READY`, or with `ERROR` and a reason before
+ * closing it. Clients then send one statement per line and the server answers each with
+ * `OK <summary>` or `ERROR <message>`. A query is answered with a `COLUMNS` line naming the
+ * columns and their types, then a `ROW` line per row, and finally `OK SELECT <count>`.
+ * Columns and the values of a row are separated by tabs.
+ */
+
+// Greeting sent to a connection the server accepted
+pub const READY: &str = "READY";
+// Prefixes of the lines the server answers with
+pub const OK: &str = "OK";
+pub const ERROR: &str = "ERROR";
+pub const COLUMNS: &str = "COLUMNS";
+pub const ROW: &str = "ROW";
+
+// Escapes backslashes, line breaks and tabs so the text fits on one line and one field
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Reverses escape, failing on an escape sequence it does not write
+pub fn unescape(text: &str) -> Result<String, String> {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => unescaped.push('\\'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('t') => unescaped.push('\t'),
+            _ => return Err(format!("Invalid escape in {}", text)),
+        }
+    }
+    Ok(unescaped)
+}
+
+// Splits a line into its kind, such as OK or ROW, and the rest of the line
+pub fn split_line(line: &str) -> (&str, &str) {
+    let line = line.trim_end_matches(['\r', '\n']);
+    line.split_once(' ').unwrap_or((line, ""))
+}
+
+// A value of a row, typed like the columns of the tables
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i32),
+    String(String),
+}
+
+impl Value {
+    // Encodes the value as a field of a ROW line
+    pub fn encode(&self) -> String {
+        match self {
+            Value::Int(value) => value.to_string(),
+            Value::String(value) => escape(value),
+        }
+    }
+
+    // Decodes a field of a ROW line holding a value of the named type
+    pub fn decode(field: &str, type_name: &str) -> Result<Self, String> {
+        match type_name {
+            "Int" => field
+                .parse()
+                .map(Value::Int)
+                .map_err(|_| format!("Invalid Int {}", field)),
+            "String" => unescape(field).map(Value::String),
+            _ => Err(format!("Unknown type {}", type_name)),
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Value::Int(value) => write!(f, "{}", value),
+            Value::String(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+// A column of a query result
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Column {
+    name: String,
+    type_name: String,
+}
+
+impl Column {
+    pub fn new(name: &str, type_name: &str) -> Self {
+        Column {
+            name: name.to_string(),
+            type_name: type_name.to_string(),
+        }
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    // Retrieves the type of the column, Int or String
+    pub fn get_type_name(&self) -> &str {
+        &self.type_name
+    }
+}
+
+// Encodes the columns as the rest of a COLUMNS line
+pub fn encode_columns(columns: &[Column]) -> String {
+    let columns: Vec<String> = columns
+        .iter()
+        .map(|column| format!("{}:{}", escape(&column.name), column.type_name))
+        .collect();
+    columns.join("\t")
+}
+
+// Decodes the rest of a COLUMNS line
+pub fn decode_columns(text: &str) -> Result<Vec<Column>, String> {
+    if text.is_empty() {
+        return Ok(vec![]);
+    }
+    text.split('\t')
+        .map(|column| {
+            let (name, type_name) = column
+                .rsplit_once(':')
+                .ok_or(format!("Invalid column {}", column))?;
+            Ok(Column::new(&unescape(name)?, type_name))
+        })
+        .collect()
+}
+
+// Encodes the values as the rest of a ROW line
+pub fn encode_row(values: &[Value]) -> String {
+    let values: Vec<String> = values.iter().map(Value::encode).collect();
+    values.join("\t")
+}
+
+// Decodes the rest of a ROW line of a result with the columns
+pub fn decode_row(text: &str, columns: &[Column]) -> Result<Vec<Value>, String> {
+    let fields: Vec<&str> = if columns.is_empty() {
+        vec![]
+    } else {
+        text.split('\t').collect()
+    };
+    if fields.len() != columns.len() {
+        return Err(format!(
+            "Expected {} values but found {}",
+            columns.len(),
+            fields.len()
+        ));
+    }
+    fields
+        .iter()
+        .zip(columns)
+        .map(|(field, column)| Value::decode(field, &column.type_name))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        let text = "a\tb\\n\nc\r";
+        assert_eq!(escape(text), "a\\tb\\\\n\\nc\\r");
+        assert_eq!(unescape(&escape(text)).unwrap(), text);
+        assert!(unescape("a\\x").is_err());
+        assert!(unescape("a\\").is_err());
+    }
+
+    #[test]
+    fn test_rows() {
+        let columns = vec![Column::new("id", "Int"), Column::new("na\tme", "String")];
+        let line = encode_columns(&columns);
+        assert_eq!(line, "id:Int\tna\\tme:String");
+        assert_eq!(decode_columns(&line).unwrap(), columns);
+
+        let row = vec![Value::Int(-4), Value::String("two\tparts\n".to_string())];
+        let line = encode_row(&row);
+        assert_eq!(line, "-4\ttwo\\tparts\\n");
+        assert_eq!(decode_row(&line, &columns).unwrap(), row);
+        assert!(decode_row("x\ty", &columns).is_err());
+        assert!(decode_row("1", &columns).is_err());
+        assert_eq!(split_line("OK SELECT 3\n"), ("OK", "SELECT 3"));
+        assert_eq!(split_line("READY"), ("READY", ""));
+    }
+}
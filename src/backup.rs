@@ -0,0 +1,157 @@
+use crate::catalog;
+use crate::check::{self, CheckReport};
+use crate::database::{Database, DatabaseConfig, CATALOG_FILE, PAGE_SIZE_FILE};
+use crate::heap_file;
+use crate::mvcc::EPOCH_FILE;
+use crate::table::Table;
+use crate::transaction::Transaction;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+// Outcome of backing up a database
+#[derive(Debug, Clone)]
+pub struct BackupReport {
+    // config of the database with the backup directory as its data directory
+    config: DatabaseConfig,
+    num_tables: usize,
+    num_pages: usize,
+}
+
+impl BackupReport {
+    pub fn get_config(&self) -> &DatabaseConfig {
+        &self.config
+    }
+
+    #[allow(dead_code)]
+    pub fn get_num_tables(&self) -> usize {
+        self.num_tables
+    }
+
+    #[allow(dead_code)]
+    pub fn get_num_pages(&self) -> usize {
+        self.num_pages
+    }
+}
+
+impl fmt::Display for BackupReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Backed up {} tables with {} pages to {}",
+            self.num_tables,
+            self.num_pages,
+            self.config.get_data_dir().display()
+        )
+    }
+}
+
+/**
+ * Copies the catalog and the heap files of the database to the backup directory, which
+ * must not exist or be empty, while the database stays open. Commits are paused while the
+ * files are copied, so the backup holds exactly the transactions committed before it
+ * started; other work goes on and commits continue once the copy is done. Pages appended
+ * by bulk loads that have not committed are left out. The epoch file is copied so that
+ * the versions in the backup count as committed when it is opened, and the page size
+ * file so that it is opened with the same page size. Free space maps are
+ * left out and rebuilt on open. There are no indexes yet, so there is nothing else to
+ * copy.
+ */
+pub fn backup(db: &Arc<Database>, backup_dir: impl AsRef<Path>) -> Result<BackupReport, String> {
+    let backup_dir = backup_dir.as_ref();
+    let config = db.get_config().clone().data_dir(backup_dir);
+    let error = |path: &Path, e: io::Error| format!("Cannot back up {}: {}", path.display(), e);
+    match fs::read_dir(backup_dir).map(|mut entries| entries.next().is_none()) {
+        Ok(true) => {}
+        Ok(false) => {
+            return Err(format!(
+                "Backup directory {} is not empty",
+                backup_dir.display()
+            ));
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            fs::create_dir_all(backup_dir).map_err(|e| error(backup_dir, e))?;
+        }
+        Err(e) => return Err(error(backup_dir, e)),
+    }
+
+    let mut report = BackupReport {
+        config,
+        num_tables: 0,
+        num_pages: 0,
+    };
+    let source = db.get_config();
+    let bp = db.get_buffer_pool();
+    let paused = bp.pause_commits();
+    let catalog_path = source.get_file_path(CATALOG_FILE);
+    let catalog_lines = match fs::read_to_string(&catalog_path) {
+        Ok(lines) => lines,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(error(&catalog_path, e)),
+    };
+    for line in catalog_lines.lines().filter(|line| !line.trim().is_empty()) {
+        let (table_name, _) = catalog::parse_schema_line(line)?;
+        let heap_file = db
+            .get_catalog()
+            .get_table_from_name(&table_name)
+            .ok_or(format!("Table {} is not open", table_name))?;
+        let (path, num_pages) = bp.get_committed_file(&heap_file);
+        copy_file(
+            &path,
+            &heap_file::table_file_path(&report.config, &table_name),
+            (num_pages * source.get_page_size()) as u64,
+        )?;
+        report.num_tables += 1;
+        report.num_pages += num_pages;
+    }
+    for file_name in [EPOCH_FILE, PAGE_SIZE_FILE] {
+        copy_file(
+            &source.get_file_path(file_name),
+            &report.config.get_file_path(file_name),
+            u64::MAX,
+        )?;
+    }
+    drop(paused);
+
+    let backup_catalog_path = report.config.get_file_path(CATALOG_FILE);
+    fs::write(&backup_catalog_path, catalog_lines)
+        .and_then(|_| File::open(&backup_catalog_path)?.sync_all())
+        .map_err(|e| error(&backup_catalog_path, e))?;
+    File::open(backup_dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|e| error(backup_dir, e))?;
+    Ok(report)
+}
+
+// Copies up to len bytes from the start of the file and syncs the copy
+fn copy_file(from: &Path, to: &Path, len: u64) -> Result<(), String> {
+    let mut source = File::open(from)
+        .map_err(|e| format!("Cannot back up {}: {}", from.display(), e))?
+        .take(len);
+    let error = |e: io::Error| format!("Cannot write {}: {}", to.display(), e);
+    let mut copy = File::create(to).map_err(error)?;
+    io::copy(&mut source, &mut copy).map_err(error)?;
+    copy.sync_all().map_err(error)
+}
+
+/**
+ * Verifies a backup by running the integrity checks on it and then opening it and reading
+ * every table. Fails if the backup cannot be opened or read; problems found by the checks
+ * are in the returned report.
+ */
+pub fn verify(config: &DatabaseConfig) -> Result<CheckReport, String> {
+    let report = check::check(config, false)?;
+    if !report.is_clean() {
+        return Ok(report);
+    }
+    let db = Database::open(config.clone())?;
+    let txn = Transaction::builder().database(&db).begin();
+    for heap_file in db.get_catalog().get_tables() {
+        let table = Table::open(&db, heap_file.get_name())?;
+        table.scan(usize::MAX, &txn).fetch()?;
+    }
+    txn.commit()?;
+    Ok(report)
+}
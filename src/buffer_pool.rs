@@ -1,114 +1,882 @@
-use crate::database;
+use crate::database::{Database, DatabaseConfig};
+use crate::group_commit::{GroupCommit, SyncMode};
+use crate::heap_file::{self, HeapFile};
 use crate::heap_page::{HeapPage, HeapPageId, Permission};
-use crate::lock_manager::LockManager;
-use crate::transaction::TransactionId;
-use crate::tuple::Tuple;
-use std::collections::HashMap;
+use crate::interrupt::Interrupts;
+use crate::lock_manager::{AbortReason, LockManager, LockMode, LockRequest, PredicateLock};
+use crate::mvcc::{Snapshot, TransactionTable, TupleVersion};
+use crate::table::Predicate;
+use crate::transaction::{IsolationLevel, TransactionId};
+use crate::tuple::{RecordId, Tuple};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Condvar, Mutex, RwLock, RwLockWriteGuard, Weak};
+use std::time::Duration;
 
-pub const PAGE_SIZE: usize = 4096;
+pub const DEFAULT_PAGE_SIZE: usize = 4096;
 pub const DEFAULT_PAGES: usize = 50;
 
+// Page access counters collected for a single transaction
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PageStats {
+    hits: usize,
+    reads: usize,
+    lock_waits: usize,
+}
+
+impl PageStats {
+    // Number of pages served from the buffer pool cache
+    pub fn get_hits(&self) -> usize {
+        self.hits
+    }
+
+    // Number of pages read from disk
+    pub fn get_reads(&self) -> usize {
+        self.reads
+    }
+
+    // Number of lock requests that had to wait for another transaction
+    pub fn get_lock_waits(&self) -> usize {
+        self.lock_waits
+    }
+
+    // Counters accumulated since the earlier snapshot was taken
+    pub fn since(&self, earlier: &PageStats) -> PageStats {
+        PageStats {
+            hits: self.hits - earlier.hits,
+            reads: self.reads - earlier.reads,
+            lock_waits: self.lock_waits - earlier.lock_waits,
+        }
+    }
+}
+
+// A running transaction, as reported by get_transaction_snapshot
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransactionInfo {
+    tid: TransactionId,
+    isolation: IsolationLevel,
+    age: Duration,
+    // lock request the transaction is blocked on, if any
+    waiting: Option<LockRequest>,
+    // why the transaction was chosen to abort, if it was
+    wounded: Option<AbortReason>,
+    num_locks: usize,
+}
+
+impl TransactionInfo {
+    pub fn get_tid(&self) -> TransactionId {
+        self.tid
+    }
+
+    pub fn get_isolation(&self) -> IsolationLevel {
+        self.isolation
+    }
+
+    // Time since the transaction began
+    pub fn get_age(&self) -> Duration {
+        self.age
+    }
+
+    pub fn get_waiting(&self) -> Option<&LockRequest> {
+        self.waiting.as_ref()
+    }
+
+    pub fn get_wounded(&self) -> Option<AbortReason> {
+        self.wounded
+    }
+
+    // Number of locks the transaction holds, including predicate locks
+    pub fn get_num_locks(&self) -> usize {
+        self.num_locks
+    }
+}
+
+// Contents of a slot before a transaction changed it
+struct UndoRecord {
+    pid: HeapPageId,
+    slot: usize,
+    before: Option<(TupleVersion, Tuple)>,
+}
+
+// Change a transaction made to a table's file directly instead of through cached pages
+enum FileChange {
+    // a bulk load appended pages from page number num_pages onwards
+    BulkLoad {
+        table_id: usize,
+        num_pages: usize,
+    },
+    // a truncate swapped the file, which had num_pages pages, for an empty one. The pages
+    // cached from the old file are kept aside with the number of undo records logged
+    // before, so that undoing the truncate brings them back
+    Truncate {
+        table_id: usize,
+        num_pages: usize,
+        undo_len: usize,
+        pages: Vec<(HeapPageId, Arc<RwLock<HeapPage>>)>,
+    },
+}
+
+// State of a transaction's changes at the time a savepoint was set
+struct Savepoint {
+    name: String,
+    // number of undo records the transaction had logged so far
+    undo_len: usize,
+    // number of file changes the transaction had made so far
+    num_file_changes: usize,
+}
+
 // Cache of pages kept in memory
 pub struct BufferPool {
+    db: Weak<Database>,
     id_to_page: RwLock<HashMap<HeapPageId, Arc<RwLock<HeapPage>>>>,
     lock_manager: LockManager,
     num_pages: usize,
+    page_stats: RwLock<HashMap<TransactionId, PageStats>>,
+    // bulk loads and truncates of each running transaction, oldest first
+    file_changes: RwLock<HashMap<TransactionId, Vec<FileChange>>>,
+    transactions: TransactionTable,
+    savepoints: RwLock<HashMap<TransactionId, Vec<Savepoint>>>,
+    // slot changes of each running transaction, oldest first
+    undo_logs: RwLock<HashMap<TransactionId, Vec<UndoRecord>>>,
+    interrupts: Interrupts,
+    sync_mode: RwLock<SyncMode>,
+    group_commit: GroupCommit,
+    // held shared while commits change the table files, and exclusively
+    // while a backup copies them
+    checkpoint: RwLock<()>,
+    // transactions being rolled back, so that one aborted from another thread is rolled
+    // back once while its own thread waits
+    aborting: Mutex<HashSet<TransactionId>>,
+    aborted: Condvar,
 }
 
 impl BufferPool {
-    pub fn new() -> Self {
+    pub fn new(db: Weak<Database>, config: &DatabaseConfig) -> Self {
+        let lock_manager = LockManager::new(db.clone());
+        lock_manager.set_deadlock_policy(config.get_deadlock_policy());
+        lock_manager.set_lock_timeout(config.get_lock_timeout());
         BufferPool {
+            db,
             id_to_page: RwLock::new(HashMap::new()),
-            num_pages: DEFAULT_PAGES,
-            lock_manager: LockManager::new(),
+            num_pages: config.get_pool_size(),
+            lock_manager,
+            page_stats: RwLock::new(HashMap::new()),
+            file_changes: RwLock::new(HashMap::new()),
+            transactions: TransactionTable::new(config.get_data_dir()),
+            savepoints: RwLock::new(HashMap::new()),
+            undo_logs: RwLock::new(HashMap::new()),
+            interrupts: Interrupts::new(),
+            sync_mode: RwLock::new(config.get_sync_mode()),
+            group_commit: GroupCommit::new(),
+            checkpoint: RwLock::new(()),
+            aborting: Mutex::new(HashSet::new()),
+            aborted: Condvar::new(),
         }
     }
 
-    // Retrieves the specified page from cache or disk
+    // Retrieves the database the buffer pool belongs to
+    fn get_db(&self) -> Arc<Database> {
+        self.db.upgrade().expect("Database was dropped")
+    }
+
+    // Retrieves the specified page from cache or disk. Writers take an intention exclusive
+    // lock on the table and lock the rows they change separately. Readers take an intention
+    // shared lock on the table, which only keeps out truncates, and filter the page's tuple
+    // versions through their snapshot, except under SERIALIZABLE where they also hold
+    // shared locks on the page's rows until the transaction ends. Fails if the page has to
+    // be read from disk and is corrupted
     pub fn get_page(
         &self,
         tid: TransactionId,
         pid: HeapPageId,
         perm: Permission,
-    ) -> Option<Arc<RwLock<HeapPage>>> {
-        let exclusive = perm == Permission::Write;
-        self.lock_manager.acquire_lock(tid, pid, exclusive);
+    ) -> Result<Arc<RwLock<HeapPage>>, String> {
+        let serializable = self.transactions.get_isolation(tid) == IsolationLevel::Serializable;
+        if perm == Permission::Write {
+            self.lock_manager
+                .lock_table(tid, pid.get_table_id(), LockMode::IntentionExclusive);
+        } else {
+            self.lock_manager
+                .lock_table(tid, pid.get_table_id(), LockMode::IntentionShared);
+        }
+        let page = self.fetch_page(tid, pid)?;
+        if perm == Permission::Read && serializable {
+            let slots: Vec<usize> = {
+                let page = page.read().unwrap();
+                (0..page.num_tuples())
+                    .filter(|slot| page.get_version(*slot).is_some())
+                    .collect()
+            };
+            for slot in slots {
+                self.lock_manager
+                    .lock_row(tid, RecordId::new(pid, slot), false);
+            }
+        }
+        Ok(page)
+    }
 
+    // Retrieves the specified page from cache or disk without locking it
+    fn fetch_page(
+        &self,
+        tid: TransactionId,
+        pid: HeapPageId,
+    ) -> Result<Arc<RwLock<HeapPage>>, String> {
         {
             let id_to_page = self.id_to_page.read().unwrap();
             if id_to_page.contains_key(&pid) {
-                return Some(Arc::clone(id_to_page.get(&pid).unwrap()));
+                self.page_stats
+                    .write()
+                    .unwrap()
+                    .entry(tid)
+                    .or_default()
+                    .hits += 1;
+                return Ok(Arc::clone(id_to_page.get(&pid).unwrap()));
             }
         }
+        self.page_stats
+            .write()
+            .unwrap()
+            .entry(tid)
+            .or_default()
+            .reads += 1;
         // read the page from disk and saves it to the buffer pool
-        let db = database::get_global_db();
+        let db = self.get_db();
         let catalog = db.get_catalog();
         let table = catalog.get_table_from_id(pid.get_table_id()).unwrap();
-        let page = table.read_page(&pid);
+        let page = table.read_page(&pid)?;
         let mut id_to_page = self.id_to_page.write().unwrap();
-        id_to_page.insert(pid, Arc::new(RwLock::new(page)));
-        Some(Arc::clone(id_to_page.get(&pid).unwrap()))
-    }
-
-    // Commits the specified transaction, writes all dirty pages to disk, and releases all locks
-    pub fn commit_transaction(&self, tid: TransactionId) {
-        let locked_pages = self.lock_manager.get_locked_pages(tid);
-        for pid in locked_pages {
-            if self.id_to_page.read().unwrap().contains_key(&pid) {
-                let id_to_page = self.id_to_page.read().unwrap();
-                let page = id_to_page.get(&pid).unwrap();
-                let mut page = page.write().unwrap();
-                if page.is_dirty() {
-                    let db = database::get_global_db();
-                    let catalog = db.get_catalog();
-                    let table = catalog.get_table_from_id(pid.get_table_id()).unwrap();
-                    table.write_page(&page);
-                    page.mark_dirty(false, tid);
-                    page.set_before_image();
+        if !id_to_page.contains_key(&pid) {
+            self.evict_pages(&mut id_to_page, 1);
+        }
+        Ok(Arc::clone(
+            id_to_page
+                .entry(pid)
+                .or_insert_with(|| Arc::new(RwLock::new(page))),
+        ))
+    }
+
+    // Makes room for num_new more pages within the num_pages pages of the pool. Only pages
+    // that no one holds and that no running transaction changed are dropped, since they
+    // match the committed data on disk. The pool grows past its size while every page is
+    // in use, and shrinks back as transactions end
+    fn evict_pages(
+        &self,
+        id_to_page: &mut HashMap<HeapPageId, Arc<RwLock<HeapPage>>>,
+        num_new: usize,
+    ) {
+        let num_evicted = (id_to_page.len() + num_new).saturating_sub(self.num_pages);
+        if num_evicted == 0 {
+            return;
+        }
+        let changed: HashSet<HeapPageId> = self
+            .undo_logs
+            .read()
+            .unwrap()
+            .values()
+            .flatten()
+            .map(|record| record.pid)
+            .collect();
+        let evicted: Vec<HeapPageId> = id_to_page
+            .iter()
+            .filter(|(pid, page)| Arc::strong_count(page) == 1 && !changed.contains(pid))
+            .map(|(pid, _)| *pid)
+            .take(num_evicted)
+            .collect();
+        for pid in evicted {
+            id_to_page.remove(&pid);
+        }
+    }
+
+    // Retrieves the number of pages currently cached
+    #[allow(dead_code)]
+    pub fn get_num_cached_pages(&self) -> usize {
+        self.id_to_page.read().unwrap().len()
+    }
+
+    // Commits the specified transaction, writes the pages it changed to disk, and releases
+    // all locks. Other running transactions may have changed the same pages, so their
+    // changes are undone in the written copy and reach the disk when they commit. The
+    // written files are synced according to the sync mode before the changes become
+    // visible. A transaction chosen to abort while it was not waiting, such as a deadlock
+    // victim or a killed transaction, is rolled back instead and the error describes why
+    pub fn commit_transaction(&self, tid: TransactionId) -> Result<(), String> {
+        if let Some(reason) = self.lock_manager.get_wound_reason(tid) {
+            self.lock_manager.record_abort(tid, reason, HashSet::new());
+            let error = self.interrupts.get_error(tid, reason);
+            self.abort_transaction(tid);
+            return Err(error);
+        }
+        let undo_log = self.undo_logs.write().unwrap().remove(&tid);
+        let changed_pages: HashSet<HeapPageId> = undo_log
+            .unwrap_or_default()
+            .iter()
+            .map(|record| record.pid)
+            .collect();
+        let db = self.get_db();
+        let catalog = db.get_catalog();
+        let mut written_tables = HashSet::new();
+        let lsn = self.transactions.stamp(tid);
+        let checkpoint = self.checkpoint.read().unwrap();
+        for pid in changed_pages {
+            let Some(page) = self.id_to_page.read().unwrap().get(&pid).cloned() else {
+                continue;
+            };
+            let Some(table) = catalog.get_table_from_id(pid.get_table_id()) else {
+                continue;
+            };
+            let mut page = page.write().unwrap();
+            let mut committed = page.clone();
+            let mut pending = false;
+            for records in self.undo_logs.read().unwrap().values() {
+                for record in records.iter().rev().filter(|record| record.pid == pid) {
+                    committed.set_slot_image(record.slot, record.before.clone());
+                    pending = true;
                 }
             }
+            committed.set_lsn(lsn);
+            page.set_lsn(lsn);
+            table.write_page(&committed);
+            table.record_free_space(&page);
+            if !pending {
+                page.mark_dirty(false, tid);
+            }
+            written_tables.insert(pid.get_table_id());
         }
+        // pages appended by bulk loads were written while loading
+        let loaded_tables = self.get_bulk_loaded_tables(tid);
+        written_tables.extend(loaded_tables.iter().copied());
+        self.flush_free_space_maps(written_tables.clone());
+        self.sync_tables(written_tables);
+        // the loaded pages count as committed once their markers are gone, and the truncates
+        // once the old files are
+        for table_id in loaded_tables {
+            if let Some(table) = catalog.get_table_from_id(table_id) {
+                table.clear_bulk_load();
+            }
+        }
+        for table_id in self.get_truncated_tables(tid) {
+            if let Some(table) = catalog.get_table_from_id(table_id) {
+                table.clear_truncated();
+            }
+        }
+        self.transactions.commit(tid);
+        self.page_stats.write().unwrap().remove(&tid);
+        self.file_changes.write().unwrap().remove(&tid);
+        drop(checkpoint);
+        self.savepoints.write().unwrap().remove(&tid);
         self.lock_manager.release_locks(tid);
+        self.evict_pages(&mut self.id_to_page.write().unwrap(), 0);
+        Ok(())
     }
 
-    // Aborts the specified transaction, reverting any changes made, and releases all locks
+    // Aborts the specified transaction, reverting any changes made, and releases all locks.
+    // If another thread is already rolling it back, waits for that rollback instead
     pub fn abort_transaction(&self, tid: TransactionId) {
-        let locked_pages = self.lock_manager.get_locked_pages(tid);
-        for pid in locked_pages {
-            if self.id_to_page.read().unwrap().contains_key(&pid) {
-                let id_to_page = self.id_to_page.read().unwrap();
-                let page = id_to_page.get(&pid).unwrap();
-                let mut page = page.write().unwrap();
-                if page.is_dirty() {
-                    // revert the page to its original state
-                    *page = page.get_before_image();
-                    page.mark_dirty(false, tid)
-                }
+        let mut aborting = self.aborting.lock().unwrap();
+        if !aborting.insert(tid) {
+            // another thread is rolling the transaction back
+            drop(
+                self.aborted
+                    .wait_while(aborting, |aborting| aborting.contains(&tid))
+                    .unwrap(),
+            );
+            return;
+        }
+        drop(aborting);
+        let loaded_tables = self.get_bulk_loaded_tables(tid);
+        let undo_log = self.undo_logs.write().unwrap().remove(&tid);
+        let file_changes = self.file_changes.write().unwrap().remove(&tid);
+        self.undo_changes(
+            undo_log.unwrap_or_default(),
+            0,
+            file_changes.unwrap_or_default(),
+        );
+        let db = self.get_db();
+        for table_id in loaded_tables {
+            if let Some(table) = db.get_catalog().get_table_from_id(table_id) {
+                table.clear_bulk_load();
             }
         }
+        self.savepoints.write().unwrap().remove(&tid);
+        self.transactions.abort(tid);
+        self.page_stats.write().unwrap().remove(&tid);
         self.lock_manager.release_locks(tid);
+        self.evict_pages(&mut self.id_to_page.write().unwrap(), 0);
+        self.aborting.lock().unwrap().remove(&tid);
+        self.aborted.notify_all();
+    }
+
+    // Records the contents of the slot before the transaction changes it. Callers must
+    // hold the page's write latch and a lock on the row
+    pub fn log_undo(&self, tid: TransactionId, page: &HeapPage, slot: usize) {
+        let mut undo_logs = self.undo_logs.write().unwrap();
+        undo_logs.entry(tid).or_default().push(UndoRecord {
+            pid: page.get_id(),
+            slot,
+            before: page.get_slot_image(slot),
+        });
+    }
+
+    // Restores the slots of the undo records, undoing the latest change first
+    fn undo(&self, records: Vec<UndoRecord>) {
+        let db = self.get_db();
+        let catalog = db.get_catalog();
+        let mut restored_tables = HashSet::new();
+        for record in records.into_iter().rev() {
+            let Some(page) = self.id_to_page.read().unwrap().get(&record.pid).cloned() else {
+                continue;
+            };
+            let mut page = page.write().unwrap();
+            page.set_slot_image(record.slot, record.before);
+            if let Some(table) = catalog.get_table_from_id(record.pid.get_table_id()) {
+                table.record_free_space(&page);
+                restored_tables.insert(record.pid.get_table_id());
+            }
+        }
+        self.flush_free_space_maps(restored_tables);
+    }
+
+    // Registers the isolation level of a transaction before it reads or writes
+    pub fn begin_transaction(&self, tid: TransactionId, isolation: IsolationLevel) {
+        self.transactions.begin(tid, isolation);
+    }
+
+    // Marks the start of a statement of the transaction. A transaction that was cancelled
+    // or sat idle for longer than its idle timeout is aborted instead
+    pub fn begin_statement(&self, tid: TransactionId) -> Result<(), String> {
+        if !self.interrupts.in_statement(tid) {
+            // a snapshot taken outside of any statement belongs to no statement
+            self.transactions.end_statement(tid);
+        }
+        self.interrupts
+            .begin_statement(tid)
+            .map_err(|reason| self.abort_interrupted(tid, reason))
+    }
+
+    // Marks the end of a statement of the transaction. Under READ COMMITTED the
+    // outermost statement's snapshot ends with it
+    pub fn end_statement(&self, tid: TransactionId) {
+        self.interrupts.end_statement(tid);
+        if !self.interrupts.in_statement(tid) {
+            self.transactions.end_statement(tid);
+        }
+    }
+
+    // Aborts the transaction if it was cancelled or its statement ran longer than its
+    // statement timeout. Long running operators call this between pages and tuples
+    pub fn check_interrupt(&self, tid: TransactionId) -> Result<(), String> {
+        match self.interrupts.check(tid) {
+            Some(reason) => Err(self.abort_interrupted(tid, reason)),
+            None => Ok(()),
+        }
+    }
+
+    // Aborts the interrupted transaction and describes why
+    fn abort_interrupted(&self, tid: TransactionId, reason: AbortReason) -> String {
+        let error = self.interrupts.get_error(tid, reason);
+        self.abort_transaction(tid);
+        error
+    }
+
+    // Retrieves the cancellation tokens and timeouts of running transactions
+    pub fn get_interrupts(&self) -> &Interrupts {
+        &self.interrupts
+    }
+
+    // Lists the tables the transaction bulk loaded into
+    fn get_bulk_loaded_tables(&self, tid: TransactionId) -> HashSet<usize> {
+        let file_changes = self.file_changes.read().unwrap();
+        let changes = file_changes.get(&tid).into_iter().flatten();
+        changes
+            .filter_map(|change| match change {
+                FileChange::BulkLoad { table_id, .. } => Some(*table_id),
+                FileChange::Truncate { .. } => None,
+            })
+            .collect()
+    }
+
+    // Lists the tables the transaction truncated
+    fn get_truncated_tables(&self, tid: TransactionId) -> HashSet<usize> {
+        let file_changes = self.file_changes.read().unwrap();
+        let changes = file_changes.get(&tid).into_iter().flatten();
+        changes
+            .filter_map(|change| match change {
+                FileChange::Truncate { table_id, .. } => Some(*table_id),
+                FileChange::BulkLoad { .. } => None,
+            })
+            .collect()
+    }
+
+    // Undoes the file changes and the changes in the undo records, which start at position
+    // undo_start of the transaction's undo log, latest first. Records logged after a
+    // truncate are undone before the truncate, since they refer to pages of the new file
+    fn undo_changes(
+        &self,
+        mut records: Vec<UndoRecord>,
+        undo_start: usize,
+        file_changes: Vec<FileChange>,
+    ) {
+        let db = self.get_db();
+        let catalog = db.get_catalog();
+        for change in file_changes.into_iter().rev() {
+            match change {
+                FileChange::BulkLoad {
+                    table_id,
+                    num_pages,
+                } => {
+                    let table = catalog.get_table_from_id(table_id).unwrap();
+                    let mut id_to_page = self.id_to_page.write().unwrap();
+                    id_to_page.retain(|pid, _| {
+                        pid.get_table_id() != table_id || pid.get_page_number() < num_pages
+                    });
+                    table.truncate_to(num_pages);
+                }
+                FileChange::Truncate {
+                    table_id,
+                    undo_len,
+                    pages,
+                    ..
+                } => {
+                    let later = records.split_off(undo_len.saturating_sub(undo_start));
+                    self.undo(later);
+                    let table = catalog.get_table_from_id(table_id).unwrap();
+                    let _checkpoint = self.checkpoint.read().unwrap();
+                    let mut id_to_page = self.id_to_page.write().unwrap();
+                    id_to_page.retain(|pid, _| pid.get_table_id() != table_id);
+                    id_to_page.extend(pages);
+                    table.restore_truncated();
+                }
+            }
+        }
+        self.undo(records);
+    }
+
+    // Marks the current state of the transaction's changes so that later changes can be
+    // undone without aborting the whole transaction
+    pub fn set_savepoint(&self, tid: TransactionId, name: &str) {
+        let undo_len = self
+            .undo_logs
+            .read()
+            .unwrap()
+            .get(&tid)
+            .map_or(0, |records| records.len());
+        let num_file_changes = self
+            .file_changes
+            .read()
+            .unwrap()
+            .get(&tid)
+            .map_or(0, |changes| changes.len());
+        let mut savepoints = self.savepoints.write().unwrap();
+        savepoints.entry(tid).or_default().push(Savepoint {
+            name: name.to_string(),
+            undo_len,
+            num_file_changes,
+        });
+    }
+
+    // Undoes every change the transaction made after the most recent savepoint with the
+    // name. The savepoint is kept while the savepoints set after it are removed
+    pub fn rollback_to_savepoint(&self, tid: TransactionId, name: &str) -> Result<(), String> {
+        let mut savepoints = self.savepoints.write().unwrap();
+        let stack = savepoints.entry(tid).or_default();
+        let i = stack
+            .iter()
+            .rposition(|savepoint| savepoint.name == name)
+            .ok_or(format!("No savepoint named {}", name))?;
+        stack.truncate(i + 1);
+        let savepoint = &stack[i];
+
+        let undo_len = savepoint.undo_len;
+        let later_records = match self.undo_logs.write().unwrap().get_mut(&tid) {
+            Some(records) if records.len() > undo_len => records.split_off(undo_len),
+            _ => vec![],
+        };
+        let later_changes = match self.file_changes.write().unwrap().get_mut(&tid) {
+            Some(changes) if changes.len() > savepoint.num_file_changes => {
+                changes.split_off(savepoint.num_file_changes)
+            }
+            _ => vec![],
+        };
+        drop(savepoints);
+        self.undo_changes(later_records, undo_len, later_changes);
+        Ok(())
+    }
+
+    // Forgets the most recent savepoint with the name and every savepoint set after it,
+    // keeping the changes made since
+    pub fn release_savepoint(&self, tid: TransactionId, name: &str) -> Result<(), String> {
+        let mut savepoints = self.savepoints.write().unwrap();
+        let stack = savepoints.entry(tid).or_default();
+        let i = stack
+            .iter()
+            .rposition(|savepoint| savepoint.name == name)
+            .ok_or(format!("No savepoint named {}", name))?;
+        stack.truncate(i);
+        Ok(())
+    }
+
+    // Retrieves the snapshot that determines which tuple versions the transaction sees
+    pub fn get_snapshot(&self, tid: TransactionId) -> Snapshot {
+        self.transactions.get_snapshot(tid)
+    }
+
+    // Retrieves the table of committed transactions and running snapshots
+    pub fn get_transaction_table(&self) -> &TransactionTable {
+        &self.transactions
+    }
+
+    // Waits until the files of the tables are on disk according to the sync mode. Files
+    // of transactions committing at the same time are synced together
+    fn sync_tables(&self, table_ids: HashSet<usize>) {
+        let mode = self.get_sync_mode();
+        if mode == SyncMode::Off || table_ids.is_empty() {
+            return;
+        }
+        self.group_commit.commit(table_ids, |table_ids| {
+            let db = self.get_db();
+            let catalog = db.get_catalog();
+            for table_id in table_ids {
+                if let Some(table) = catalog.get_table_from_id(*table_id) {
+                    table.sync(mode);
+                }
+            }
+        });
+    }
+
+    pub fn get_sync_mode(&self) -> SyncMode {
+        *self.sync_mode.read().unwrap()
+    }
+
+    // Changes how much of a commit reaches the disk before the commit returns
+    #[allow(dead_code)]
+    pub fn set_sync_mode(&self, mode: SyncMode) {
+        *self.sync_mode.write().unwrap() = mode;
+    }
+
+    // Retrieves the group commit, for example to count the syncs it saved
+    #[allow(dead_code)]
+    pub fn get_group_commit(&self) -> &GroupCommit {
+        &self.group_commit
+    }
+
+    // Persists the free space maps of the specified tables
+    fn flush_free_space_maps(&self, table_ids: HashSet<usize>) {
+        let db = self.get_db();
+        let catalog = db.get_catalog();
+        for table_id in table_ids {
+            if let Some(table) = catalog.get_table_from_id(table_id) {
+                table.flush_free_space_map();
+            }
+        }
     }
 
     // Adds the tuple to the specified table
-    pub fn insert_tuple(&self, tid: TransactionId, table_id: usize, tuple: Tuple) {
-        let db = database::get_global_db();
+    pub fn insert_tuple(
+        &self,
+        tid: TransactionId,
+        table_id: usize,
+        tuple: Tuple,
+    ) -> Result<(), String> {
+        let db = self.get_db();
         let catalog = db.get_catalog();
         let table = catalog.get_table_from_id(table_id).unwrap();
-        table.add_tuple(tid, tuple);
+        table.add_tuple(tid, tuple)
     }
 
-    // TODO: Deletes the tuple from the specified table
-    pub fn delete_tuple(&mut self, tid: TransactionId, table_id: usize, tuple: Tuple) {
-        let db = database::get_global_db();
+    // Deletes the tuple from the table and slot given by its record id
+    #[allow(dead_code)]
+    pub fn delete_tuple(&self, tid: TransactionId, tuple: Tuple) -> Result<(), String> {
+        let db = self.get_db();
         let catalog = db.get_catalog();
-        // TODO: get table by record id
-        let table = catalog.get_table_from_id(table_id).unwrap();
-        table.delete_tuple(tid, tuple);
+        let table_id = tuple.get_record_id().get_page_id().get_table_id();
+        let table = catalog
+            .get_table_from_id(table_id)
+            .ok_or("Tuple does not belong to a table".to_string())?;
+        table.delete_tuple(tid, tuple)
+    }
+
+    // Acquires an exclusive lock on the whole table for the specified transaction
+    pub fn lock_table(&self, tid: TransactionId, table_id: usize) {
+        self.lock_manager
+            .lock_table(tid, table_id, LockMode::Exclusive);
+    }
+
+    // Acquires a shared or exclusive lock on the row for the specified transaction
+    pub fn lock_row(&self, tid: TransactionId, rid: RecordId, exclusive: bool) {
+        self.lock_manager.lock_row(tid, rid, exclusive);
+    }
+
+    // Locks the rows of the table satisfying all of the filters for a SERIALIZABLE
+    // transaction, so that no other transaction can add rows its scans would return.
+    // Transactions at weaker isolation levels take no predicate locks
+    pub fn lock_predicate(
+        &self,
+        tid: TransactionId,
+        table_id: usize,
+        filters: Vec<(String, Predicate)>,
+    ) {
+        if self.transactions.get_isolation(tid) == IsolationLevel::Serializable {
+            self.lock_manager
+                .lock_predicate(tid, PredicateLock::new(table_id, filters));
+        }
+    }
+
+    // Waits until the transaction may write the tuple into the table without changing the
+    // result of a scan by another SERIALIZABLE transaction
+    pub fn check_predicate_locks(&self, tid: TransactionId, table_id: usize, tuple: &Tuple) {
+        self.lock_manager
+            .check_predicate_locks(tid, table_id, tuple);
+    }
+
+    // Records that the transaction is about to append pages to a table with num_pages
+    // pages, so that the appended pages can be dropped if the transaction aborts
+    pub fn register_bulk_load(&self, tid: TransactionId, table_id: usize, num_pages: usize) {
+        let mut file_changes = self.file_changes.write().unwrap();
+        file_changes
+            .entry(tid)
+            .or_default()
+            .push(FileChange::BulkLoad {
+                table_id,
+                num_pages,
+            });
+    }
+
+    // Keeps commits from changing the table files until the guard is dropped,
+    // so the files hold exactly the changes of the transactions committed before. Other
+    // work goes on, and commits wait for the guard before writing their pages
+    pub fn pause_commits(&self) -> RwLockWriteGuard<'_, ()> {
+        self.checkpoint.write().unwrap()
+    }
+
+    // Retrieves the file that holds the committed pages of the table and how many of its
+    // pages are committed. A running truncate keeps them in the file it swapped out, and
+    // the pages appended by running bulk loads are not committed
+    pub fn get_committed_file(&self, table: &HeapFile) -> (PathBuf, usize) {
+        let db = self.get_db();
+        let config = db.get_config();
+        let file_changes = self.file_changes.read().unwrap();
+        let mut num_pages = usize::MAX;
+        // only the transaction holding the exclusive table lock changes the file
+        for change in file_changes.values().flatten() {
+            match change {
+                FileChange::BulkLoad {
+                    table_id,
+                    num_pages: start,
+                } if *table_id == table.get_id() => num_pages = num_pages.min(*start),
+                FileChange::Truncate {
+                    table_id,
+                    num_pages: old,
+                    ..
+                } if *table_id == table.get_id() => {
+                    let path = heap_file::truncate_backup_path(config, table.get_name(), 0);
+                    return (path, num_pages.min(*old));
+                }
+                _ => {}
+            }
+        }
+        let path = heap_file::table_file_path(config, table.get_name());
+        (path, num_pages.min(table.num_pages()))
+    }
+
+    // Empties the specified table under an exclusive table lock by swapping its file for
+    // an empty one, which takes the same time however large the table is. Readers lock the
+    // table too, so the truncate waits for the transactions that read it and keeps new ones
+    // out until it ends. Aborting brings the old file back, and committing removes it.
+    // A snapshot taken before the commit sees the table empty once it reads it again
+    pub fn truncate_table(&self, tid: TransactionId, table_id: usize) {
+        self.lock_table(tid, table_id);
+        let db = self.get_db();
+        let table = db.get_catalog().get_table_from_id(table_id).unwrap();
+        let undo_len = self
+            .undo_logs
+            .read()
+            .unwrap()
+            .get(&tid)
+            .map_or(0, |records| records.len());
+        let _checkpoint = self.checkpoint.read().unwrap();
+        let mut file_changes = self.file_changes.write().unwrap();
+        let pages = {
+            let mut id_to_page = self.id_to_page.write().unwrap();
+            let pids: Vec<HeapPageId> = id_to_page
+                .keys()
+                .filter(|pid| pid.get_table_id() == table_id)
+                .copied()
+                .collect();
+            pids.into_iter()
+                .map(|pid| (pid, id_to_page.remove(&pid).unwrap()))
+                .collect()
+        };
+        let num_pages = table.num_pages();
+        table.truncate_file();
+        file_changes
+            .entry(tid)
+            .or_default()
+            .push(FileChange::Truncate {
+                table_id,
+                num_pages,
+                undo_len,
+                pages,
+            });
+    }
+
+    // Retrieves the page access counters of the specified transaction
+    pub fn get_page_stats(&self, tid: TransactionId) -> PageStats {
+        let mut stats = self
+            .page_stats
+            .read()
+            .unwrap()
+            .get(&tid)
+            .copied()
+            .unwrap_or_default();
+        stats.lock_waits = self.lock_manager.get_lock_waits(tid);
+        stats
+    }
+
+    // Lists the running transactions with their age and what they are waiting for,
+    // oldest first
+    pub fn get_transaction_snapshot(&self) -> Vec<TransactionInfo> {
+        let locks = self.lock_manager.get_lock_snapshot();
+        self.transactions
+            .get_running()
+            .into_iter()
+            .map(|(tid, isolation, started)| TransactionInfo {
+                tid,
+                isolation,
+                age: started.elapsed(),
+                waiting: self.lock_manager.get_waiting_request(tid),
+                wounded: self.lock_manager.get_wound_reason(tid),
+                num_locks: locks
+                    .iter()
+                    .filter(|info| info.get_tid() == tid && info.is_granted())
+                    .count(),
+            })
+            .collect()
+    }
+
+    // Makes the running transaction abort, undoing its changes and releasing its locks.
+    // The transaction aborts itself at its next lock request or commit, so a transaction
+    // that is blocked on a lock aborts within the lock retry interval
+    pub fn kill_transaction(&self, tid: TransactionId) -> Result<(), String> {
+        let running = self
+            .transactions
+            .get_running()
+            .iter()
+            .any(|(running, _, _)| *running == tid);
+        if !running {
+            return Err(format!("No running transaction {}", tid.get_tid()));
+        }
+        self.lock_manager.kill(tid);
+        Ok(())
+    }
+
+    // Retrieves the lock manager, for example to choose its deadlock policy
+    pub fn get_lock_manager(&self) -> &LockManager {
+        &self.lock_manager
     }
 
-    // Gets the number of pages in the buffer pool
+    // Gets the number of pages the buffer pool caches before it evicts pages
+    #[allow(dead_code)]
     pub fn get_num_pages(&self) -> usize {
         self.num_pages
     }
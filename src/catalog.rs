@@ -1,23 +1,31 @@
-use crate::heap_file::HeapFile;
+use crate::database::{Database, CATALOG_FILE};
+use crate::free_space_map::FreeSpaceMap;
+use crate::heap_file::{self, HeapFile};
+use crate::heap_page::HeapPage;
 use crate::tuple::TupleDesc;
 use crate::types::Type::{IntType, StringType};
 use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader};
-use std::sync::{Arc, RwLock};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::sync::{Arc, Mutex, RwLock, Weak};
 
 pub struct Catalog {
+    db: Weak<Database>,
     // maps table name to table
     tables: RwLock<HashMap<String, Arc<HeapFile>>>,
     // maps table id to table
     table_ids: RwLock<HashMap<usize, Arc<HeapFile>>>,
+    // held while tables are opened, so each is opened and recorded once
+    loading: Mutex<()>,
 }
 
 impl Catalog {
-    pub fn new() -> Self {
+    pub fn new(db: Weak<Database>) -> Self {
         Catalog {
+            db,
             tables: RwLock::new(HashMap::new()),
             table_ids: RwLock::new(HashMap::new()),
+            loading: Mutex::new(()),
         }
     }
 
@@ -41,44 +49,202 @@ impl Catalog {
         table_ids.get(&id).map(Arc::clone)
     }
 
+    // Retrieves the name of the table with the specified id
+    pub fn get_table_name(&self, id: usize) -> Option<String> {
+        let tables = self.tables.read().unwrap();
+        tables
+            .iter()
+            .find(|(_, table)| table.get_id() == id)
+            .map(|(name, _)| name.clone())
+    }
+
     // Retrieves the tuple descriptor for the specified table
+    #[allow(dead_code)]
     pub fn get_tuple_desc(&self, table_id: usize) -> Option<TupleDesc> {
         let table = self.get_table_from_id(table_id);
         table.map(|t| t.get_tuple_desc().clone())
     }
 
-    // Loads the schema from a text file
+    // Loads the schema from a text file, opening the files of its tables in the data
+    // directory of the database. Tables that are new to the database are recorded in its
+    // catalog file, so they are opened again whenever the directory is
     pub fn load_schema(&self, schema_file_path: &str) {
         let schema_file = File::open(schema_file_path).unwrap();
-        let reader = BufReader::new(schema_file);
-        for line in reader.lines() {
+        let _loading = self.loading.lock().unwrap();
+        let mut added = String::new();
+        for line in BufReader::new(schema_file).lines() {
             let line = line.unwrap();
-            let split_parens: Vec<&str> = line.split('(').collect();
-            let table_name = split_parens[0].to_string().replace(' ', "");
-            let file = OpenOptions::new()
-                .create(true)
-                .read(true)
-                .write(true)
-                .open(format!("data/{}.dat", table_name));
-
-            let fields: Vec<&str> = split_parens[1].split(',').collect();
-            let mut field_types = vec![];
-            let mut field_names = vec![];
-            for field in fields.iter() {
-                let field: Vec<&str> = field.split(':').collect();
-                let field_name = field[0].to_string().replace(' ', "");
-                let field_type = field[1].to_string().replace(' ', "");
-                let field_type = field_type.replace(')', "");
-                let field_type = match field_type.as_str() {
-                    "Int" => IntType,
-                    "String" => StringType,
-                    _ => panic!("invalid field type"),
-                };
-                field_names.push(field_name);
-                field_types.push(field_type);
+            if self.load_table(&line).unwrap_or_else(|e| panic!("{}", e)) {
+                added.push_str(&line);
+                added.push('\n');
             }
-            let heap_file = HeapFile::new(file.unwrap(), TupleDesc::new(field_types, field_names));
-            self.add_table(heap_file, table_name);
         }
+        if !added.is_empty() {
+            self.record_tables(&added).unwrap();
+        }
+    }
+
+    // Creates a table from a schema line such as "employees (id: Int, name: String)" and
+    // records it in the catalog file. Fails if the table exists, or its files are left in
+    // the data directory from a table that is no longer in the catalog
+    pub fn create_table(&self, line: &str) -> Result<Arc<HeapFile>, String> {
+        let (table_name, _) = parse_schema_line(line)?;
+        let _loading = self.loading.lock().unwrap();
+        if self.get_table_from_name(&table_name).is_some() {
+            return Err(format!("Table {} already exists", table_name));
+        }
+        let path = self
+            .get_db()
+            .get_config()
+            .get_file_path(&format!("{}.dat", table_name));
+        if path.exists() {
+            return Err(format!(
+                "Cannot create table {}, {} already exists",
+                table_name,
+                path.display()
+            ));
+        }
+        self.load_table(line)?;
+        self.record_tables(&format!("{}\n", line.trim()))?;
+        Ok(self.get_table_from_name(&table_name).unwrap())
+    }
+
+    // Retrieves all tables, sorted by name
+    pub fn get_tables(&self) -> Vec<Arc<HeapFile>> {
+        let tables = self.tables.read().unwrap();
+        let mut tables: Vec<Arc<HeapFile>> = tables.values().map(Arc::clone).collect();
+        tables.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+        tables
+    }
+
+    // Appends schema lines to the catalog file
+    fn record_tables(&self, lines: &str) -> Result<(), String> {
+        let path = self.get_db().get_config().get_file_path(CATALOG_FILE);
+        let error = |e: std::io::Error| format!("Cannot write catalog {}: {}", path.display(), e);
+        let created = !path.exists();
+        let mut catalog_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(error)?;
+        catalog_file.write_all(lines.as_bytes()).map_err(error)?;
+        catalog_file.sync_data().map_err(error)?;
+        if created {
+            heap_file::sync_parent_dir(&path);
+        }
+        Ok(())
+    }
+
+    // Opens the tables recorded in the catalog file of the data directory, if there is one
+    pub fn open_tables(&self) -> Result<(), String> {
+        let path = self.get_db().get_config().get_file_path(CATALOG_FILE);
+        let catalog_file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(format!("Cannot open catalog {}: {}", path.display(), e)),
+        };
+        let _loading = self.loading.lock().unwrap();
+        for line in BufReader::new(catalog_file).lines() {
+            let line =
+                line.map_err(|e| format!("Cannot read catalog {}: {}", path.display(), e))?;
+            self.load_table(&line)?;
+        }
+        Ok(())
+    }
+
+    fn get_db(&self) -> Arc<Database> {
+        self.db.upgrade().expect("Database was dropped")
+    }
+
+    // Opens the files of the table described by a schema line such as
+    // "employees (id: Int, name: String)". Returns false if the line is blank or the
+    // table is already open, keeping a single HeapFile per table. Fails if a page cannot
+    // hold a tuple of the table
+    fn load_table(&self, line: &str) -> Result<bool, String> {
+        if line.trim().is_empty() {
+            return Ok(false);
+        }
+        let (table_name, td) = parse_schema_line(line)?;
+        if self.get_table_from_name(&table_name).is_some() {
+            return Ok(false);
+        }
+        let db = self.get_db();
+        let config = db.get_config();
+        HeapPage::check_page_size(&td, config.get_page_size())
+            .map_err(|e| format!("Table {}: {}", table_name, e))?;
+        heap_file::recover_truncate(config, &table_name)?;
+        let path = heap_file::table_file_path(config, &table_name);
+        let fsm_path = config.get_file_path(&format!("{}.fsm", table_name));
+        let created = !path.exists() || !fsm_path.exists();
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let marker_path = heap_file::bulk_load_marker_path(config, &table_name);
+        heap_file::recover_bulk_load(&file, &marker_path, config.get_page_size())?;
+        let fsm_file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&fsm_path);
+        // the files of a new table must outlast a crash before the catalog lists it
+        if created {
+            heap_file::sync_parent_dir(&path);
+        }
+
+        let heap_file = HeapFile::new(
+            self.db.clone(),
+            table_name.clone(),
+            file,
+            FreeSpaceMap::open(fsm_file.unwrap()),
+            td,
+            config.get_page_size(),
+        );
+        self.add_table(heap_file, table_name);
+        Ok(true)
+    }
+}
+
+// Writes the schema line of a table, which parse_schema_line reads back
+pub fn format_schema_line(table_name: &str, td: &TupleDesc) -> String {
+    let fields: Vec<String> = (0..td.get_num_fields())
+        .map(|i| {
+            format!(
+                "{}: {}",
+                td.get_field_name(i).unwrap(),
+                td.get_field_type(i).unwrap().get_name()
+            )
+        })
+        .collect();
+    format!("{} ({})", table_name, fields.join(", "))
+}
+
+// Reads a schema line such as "employees (id: Int, name: String)" into the table name and
+// its tuple descriptor
+pub fn parse_schema_line(line: &str) -> Result<(String, TupleDesc), String> {
+    let invalid = || format!("Invalid schema line: {}", line);
+    let (table_name, fields) = line.split_once('(').ok_or_else(invalid)?;
+    let table_name = table_name.replace(' ', "");
+    let mut field_types = vec![];
+    let mut field_names = vec![];
+    for field in fields.split(',') {
+        let (field_name, field_type) = field.split_once(':').ok_or_else(invalid)?;
+        let field_name = field_name.replace(' ', "");
+        let field_type = field_type.replace([' ', ')'], "");
+        let field_type = match field_type.as_str() {
+            "Int" => IntType,
+            "String" => StringType,
+            _ => return Err(format!("invalid field type {} in {}", field_type, line)),
+        };
+        field_names.push(field_name);
+        field_types.push(field_type);
+    }
+    if table_name.is_empty() {
+        return Err(invalid());
     }
+    Ok((table_name, TupleDesc::new(field_types, field_names)))
 }
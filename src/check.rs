@@ -0,0 +1,362 @@
+use crate::catalog;
+use crate::database::{self, DatabaseConfig, CATALOG_FILE};
+use crate::heap_page::{HeapPage, HeapPageId};
+use crate::mvcc::VERSION_SIZE;
+use crate::tuple::TupleDesc;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Seek, SeekFrom, Write};
+
+// Directory inside the data directory that repairs move bad pages to
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+// A problem found by the checker, in a table or page if it has one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Issue {
+    table: Option<String>,
+    page_number: Option<usize>,
+    message: String,
+}
+
+impl Issue {
+    #[allow(dead_code)]
+    pub fn get_table(&self) -> Option<&str> {
+        self.table.as_deref()
+    }
+
+    #[allow(dead_code)]
+    pub fn get_page_number(&self) -> Option<usize> {
+        self.page_number
+    }
+
+    #[allow(dead_code)]
+    pub fn get_message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.table, self.page_number) {
+            (Some(table), Some(page_number)) => {
+                write!(f, "{} page {}: {}", table, page_number, self.message)
+            }
+            (Some(table), None) => write!(f, "{}: {}", table, self.message),
+            _ => write!(f, "{}", self.message),
+        }
+    }
+}
+
+// Outcome of checking a data directory
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    num_tables: usize,
+    num_pages: usize,
+    issues: Vec<Issue>,
+    // pages moved to the quarantine directory, as (table, page number)
+    quarantined: Vec<(String, usize)>,
+    // free space maps removed so they are rebuilt when the database is opened
+    rebuilt_maps: Vec<String>,
+}
+
+impl CheckReport {
+    #[allow(dead_code)]
+    pub fn get_num_tables(&self) -> usize {
+        self.num_tables
+    }
+
+    #[allow(dead_code)]
+    pub fn get_num_pages(&self) -> usize {
+        self.num_pages
+    }
+
+    #[allow(dead_code)]
+    pub fn get_issues(&self) -> &[Issue] {
+        &self.issues
+    }
+
+    #[allow(dead_code)]
+    pub fn get_quarantined(&self) -> &[(String, usize)] {
+        &self.quarantined
+    }
+
+    // Whether no problems were found
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    fn add(&mut self, table: Option<&str>, page_number: Option<usize>, message: String) {
+        self.issues.push(Issue {
+            table: table.map(str::to_string),
+            page_number,
+            message,
+        });
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Checked {} tables with {} pages",
+            self.num_tables, self.num_pages
+        )?;
+        for issue in self.issues.iter() {
+            writeln!(f, "  {}", issue)?;
+        }
+        for (table, page_number) in self.quarantined.iter() {
+            writeln!(f, "  quarantined {} page {}", table, page_number)?;
+        }
+        for table in self.rebuilt_maps.iter() {
+            writeln!(
+                f,
+                "  removed free space map of {} to rebuild it on open",
+                table
+            )?;
+        }
+        if self.issues.is_empty() {
+            write!(f, "No problems found")
+        } else {
+            write!(f, "{} problems found", self.issues.len())
+        }
+    }
+}
+
+/**
+ * Checks the files of the database in the data directory of the config without opening
+ * it: the catalog, the page checksums and headers, the slot bitmaps, the stored strings
+ * and the free space maps. There are no indexes yet, so the heap files have nothing else
+ * to agree with. The directory is locked while it is checked, so it must not be open in
+ * a database. With repair, pages that fail the check are copied to the quarantine
+ * directory and replaced by empty pages, and free space maps that disagree with their
+ * tables are removed to be rebuilt.
+ */
+pub fn check(config: &DatabaseConfig, repair: bool) -> Result<CheckReport, String> {
+    let _lock_file = database::lock_data_dir(config)?;
+    let mut report = CheckReport::default();
+    let tables = check_catalog(config, &mut report)?;
+    for (table_name, td) in tables.iter() {
+        check_table(config, table_name, td, repair, &mut report)?;
+    }
+    report.num_tables = tables.len();
+    Ok(report)
+}
+
+// Reads the tables listed in the catalog file, reporting invalid or duplicate entries and
+// table files that are not in the catalog
+fn check_catalog(
+    config: &DatabaseConfig,
+    report: &mut CheckReport,
+) -> Result<Vec<(String, TupleDesc)>, String> {
+    let path = config.get_file_path(CATALOG_FILE);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            report.add(None, None, format!("{} is missing", CATALOG_FILE));
+            String::new()
+        }
+        Err(e) => return Err(format!("Cannot read catalog {}: {}", path.display(), e)),
+    };
+    let mut tables: Vec<(String, TupleDesc)> = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match catalog::parse_schema_line(line) {
+            Ok((table_name, _)) if tables.iter().any(|(name, _)| *name == table_name) => {
+                report.add(
+                    Some(&table_name),
+                    None,
+                    format!("listed again on line {} of the catalog", i + 1),
+                );
+            }
+            Ok(table) => tables.push(table),
+            Err(e) => report.add(None, None, format!("catalog line {}: {}", i + 1, e)),
+        }
+    }
+
+    let names: HashSet<&str> = tables.iter().map(|(name, _)| name.as_str()).collect();
+    let entries = fs::read_dir(config.get_data_dir())
+        .map_err(|e| format!("Cannot list {}: {}", config.get_data_dir().display(), e))?;
+    let mut orphans = vec![];
+    for entry in entries {
+        let file_name = entry.map_err(|e| e.to_string())?.file_name();
+        let file_name = file_name.to_string_lossy();
+        if let Some((table_name, "dat" | "fsm" | "load")) = file_name.rsplit_once('.') {
+            if !names.contains(table_name) {
+                orphans.push(file_name.to_string());
+            }
+        }
+    }
+    orphans.sort();
+    for file_name in orphans {
+        report.add(
+            None,
+            None,
+            format!("{} does not belong to a table in the catalog", file_name),
+        );
+    }
+    Ok(tables)
+}
+
+// Checks every page of the table and its free space map
+fn check_table(
+    config: &DatabaseConfig,
+    table_name: &str,
+    td: &TupleDesc,
+    repair: bool,
+    report: &mut CheckReport,
+) -> Result<(), String> {
+    let path = config.get_file_path(&format!("{}.dat", table_name));
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            report.add(Some(table_name), None, "data file is missing".to_string());
+            return Ok(());
+        }
+        Err(e) => return Err(format!("Cannot read {}: {}", path.display(), e)),
+    };
+    let page_size = config.get_page_size();
+    let num_slots = HeapPage::slots_per_page(td, page_size);
+    let mut bad_pages = vec![];
+    for (page_number, page) in data.chunks(page_size).enumerate() {
+        report.num_pages += 1;
+        if page.len() < page_size {
+            report.add(
+                Some(table_name),
+                Some(page_number),
+                format!("partial page of {} bytes", page.len()),
+            );
+            bad_pages.push(page_number);
+            continue;
+        }
+        let problems = check_page(HeapPageId::new(0, page_number), page, td, num_slots);
+        if !problems.is_empty() {
+            bad_pages.push(page_number);
+        }
+        for problem in problems {
+            report.add(Some(table_name), Some(page_number), problem);
+        }
+    }
+    let num_pages = data.len().div_ceil(page_size);
+    let fsm_ok = check_free_space_map(config, table_name, num_pages, num_slots, report)?;
+
+    if repair {
+        for page_number in bad_pages.iter() {
+            quarantine_page(config, table_name, *page_number, &data)?;
+            report
+                .quarantined
+                .push((table_name.to_string(), *page_number));
+        }
+        if !bad_pages.is_empty() || !fsm_ok {
+            let fsm_path = config.get_file_path(&format!("{}.fsm", table_name));
+            if fs::remove_file(&fsm_path).is_ok() {
+                report.rebuilt_maps.push(table_name.to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Checks the page header and checksum, that no bitmap bits are set past the last slot and
+// that every used slot holds a tuple that can be parsed, returning the problems found
+fn check_page(pid: HeapPageId, data: &[u8], td: &TupleDesc, num_slots: usize) -> Vec<String> {
+    if data.iter().all(|byte| *byte == 0) {
+        return vec![];
+    }
+    if let Err(e) = HeapPage::verify(pid, data) {
+        // nothing after the header can be trusted
+        return vec![e];
+    }
+    let mut problems = vec![];
+    let bitmap = &data[HeapPage::bitmap_range(td, data.len())];
+    let is_used = |slot: usize| bitmap[slot / 8] & (1 << (slot % 8)) != 0;
+    let extra_bits = (num_slots..bitmap.len() * 8)
+        .filter(|slot| is_used(*slot))
+        .count();
+    if extra_bits > 0 {
+        problems.push(format!(
+            "{} bitmap bits set past the last of {} slots",
+            extra_bits, num_slots
+        ));
+    }
+    for slot in (0..num_slots).filter(|slot| is_used(*slot)) {
+        let mut offset = HeapPage::slot_offset(td, data.len(), slot) + VERSION_SIZE;
+        for i in 0..td.get_num_fields() {
+            let field_type = td.get_field_type(i).unwrap();
+            if let Err(e) = field_type.parse(&data[offset..]) {
+                problems.push(format!(
+                    "slot {} field {}: {}",
+                    slot,
+                    td.get_field_name(i).unwrap(),
+                    e
+                ));
+            }
+            offset += field_type.get_len();
+        }
+    }
+    problems
+}
+
+// Checks that the free space map of the table records no more free slots than a page has.
+// The map is only a hint that inserts correct, so a map that is shorter or longer than
+// the table is fine. Returns whether the map is consistent
+fn check_free_space_map(
+    config: &DatabaseConfig,
+    table_name: &str,
+    num_pages: usize,
+    num_slots: usize,
+    report: &mut CheckReport,
+) -> Result<bool, String> {
+    let path = config.get_file_path(&format!("{}.fsm", table_name));
+    let free_slots = match fs::read(&path) {
+        Ok(free_slots) => free_slots,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(format!("Cannot read {}: {}", path.display(), e)),
+    };
+    let mut consistent = true;
+    for (page_number, free) in free_slots.iter().take(num_pages).enumerate() {
+        if *free as usize > num_slots {
+            report.add(
+                Some(table_name),
+                Some(page_number),
+                format!(
+                    "free space map records {} free slots but the page has {}",
+                    free, num_slots
+                ),
+            );
+            consistent = false;
+        }
+    }
+    Ok(consistent)
+}
+
+// Copies the page to the quarantine directory and replaces it in the table with an empty
+// page, or cuts off a partial page at the end of the file
+fn quarantine_page(
+    config: &DatabaseConfig,
+    table_name: &str,
+    page_number: usize,
+    data: &[u8],
+) -> Result<(), String> {
+    let page_size = config.get_page_size();
+    let start = page_number * page_size;
+    let page = &data[start..data.len().min(start + page_size)];
+    let quarantine_dir = config.get_file_path(QUARANTINE_DIR);
+    fs::create_dir_all(&quarantine_dir).map_err(|e| e.to_string())?;
+    let quarantine_path = quarantine_dir.join(format!("{}.{}.page", table_name, page_number));
+    fs::write(&quarantine_path, page)
+        .map_err(|e| format!("Cannot write {}: {}", quarantine_path.display(), e))?;
+
+    let path = config.get_file_path(&format!("{}.dat", table_name));
+    let error = |e: std::io::Error| format!("Cannot repair {}: {}", path.display(), e);
+    let mut file = OpenOptions::new().write(true).open(&path).map_err(error)?;
+    if page.len() < page_size {
+        file.set_len(start as u64).map_err(error)?;
+    } else {
+        file.seek(SeekFrom::Start(start as u64)).map_err(error)?;
+        file.write_all(&vec![0; page_size]).map_err(error)?;
+    }
+    file.sync_all().map_err(error)
+}
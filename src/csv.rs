@@ -0,0 +1,443 @@
+use crate::fields::FieldVal;
+use crate::table::{Table, TableIterator};
+use crate::transaction::Transaction;
+use crate::tuple::{Tuple, TupleDesc};
+use std::fmt;
+use std::io::{BufRead, Write};
+
+// Number of rows inserted at a time during an import into a table that has pages
+const IMPORT_BATCH_SIZE: usize = 256;
+
+// A record of a CSV file with the line it starts on and its text as it was read, which
+// always ends with a line break
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    line: usize,
+    fields: Vec<String>,
+    raw: String,
+}
+
+impl Record {
+    #[allow(dead_code)]
+    pub fn get_line(&self) -> usize {
+        self.line
+    }
+
+    #[allow(dead_code)]
+    pub fn get_fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    #[allow(dead_code)]
+    pub fn get_raw(&self) -> &str {
+        &self.raw
+    }
+}
+
+// Reads records from CSV text. Fields are separated by commas and may be quoted with
+// double quotes, in which case they can hold commas, line breaks and doubled quotes
+pub struct CsvReader<R: BufRead> {
+    reader: R,
+    line: usize,
+}
+
+impl<R: BufRead> CsvReader<R> {
+    pub fn new(reader: R) -> Self {
+        CsvReader { reader, line: 0 }
+    }
+
+    // Reads the next record, or None at the end of the input. Fails on a quote that is
+    // never closed or a quote in the middle of an unquoted field
+    pub fn read_record(&mut self) -> Result<Option<Record>, String> {
+        let mut raw = String::new();
+        let start = self.line + 1;
+        let mut fields = vec![];
+        let mut field = String::new();
+        let mut in_quotes = false;
+        let mut quoted = false;
+        loop {
+            let mut line = String::new();
+            let read = self
+                .reader
+                .read_line(&mut line)
+                .map_err(|e| format!("Cannot read line {}: {}", self.line + 1, e))?;
+            if read == 0 {
+                if in_quotes {
+                    return Err(format!("Unclosed quote in record on line {}", start));
+                }
+                if raw.is_empty() {
+                    return Ok(None);
+                }
+                break;
+            }
+            self.line += 1;
+            raw.push_str(&line);
+            let mut chars = line.chars().peekable();
+            while let Some(c) = chars.next() {
+                if in_quotes {
+                    match c {
+                        '"' if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            field.push('"');
+                        }
+                        '"' => in_quotes = false,
+                        _ => field.push(c),
+                    }
+                    continue;
+                }
+                match c {
+                    ',' => {
+                        fields.push(std::mem::take(&mut field));
+                        quoted = false;
+                    }
+                    '"' if field.is_empty() && !quoted => {
+                        in_quotes = true;
+                        quoted = true;
+                    }
+                    '"' => {
+                        return Err(format!("Unexpected quote in record on line {}", start));
+                    }
+                    '\r' | '\n' => {}
+                    _ if quoted => {
+                        return Err(format!(
+                            "Unexpected text after a quoted field on line {}",
+                            start
+                        ))
+                    }
+                    _ => field.push(c),
+                }
+            }
+            if !in_quotes {
+                break;
+            }
+        }
+        fields.push(field);
+        if !raw.ends_with('\n') {
+            raw.push('\n');
+        }
+        Ok(Some(Record {
+            line: start,
+            fields,
+            raw,
+        }))
+    }
+}
+
+impl<R: BufRead> Iterator for CsvReader<R> {
+    type Item = Result<Record, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+// Formats the fields as a line of CSV, quoting fields that hold commas, quotes or line
+// breaks or that start or end with spaces
+pub fn format_record<S: AsRef<str>>(fields: &[S]) -> String {
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|field| {
+            let field = field.as_ref();
+            let needs_quotes = field.contains([',', '"', '\n', '\r'])
+                || field.starts_with(' ')
+                || field.ends_with(' ');
+            if needs_quotes {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_string()
+            }
+        })
+        .collect();
+    format!("{}\n", fields.join(","))
+}
+
+// A row of an imported file that could not be loaded
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowError {
+    line: usize,
+    message: String,
+}
+
+impl RowError {
+    #[allow(dead_code)]
+    pub fn get_line(&self) -> usize {
+        self.line
+    }
+
+    #[allow(dead_code)]
+    pub fn get_message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for RowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+// Outcome of importing a file into a table, in CSV or JSON Lines
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    num_imported: usize,
+    errors: Vec<RowError>,
+}
+
+impl ImportReport {
+    #[allow(dead_code)]
+    pub fn get_num_imported(&self) -> usize {
+        self.num_imported
+    }
+
+    #[allow(dead_code)]
+    pub fn get_errors(&self) -> &[RowError] {
+        &self.errors
+    }
+
+    pub(crate) fn add_imported(&mut self, num_rows: usize) {
+        self.num_imported += num_rows;
+    }
+
+    pub(crate) fn add_error(&mut self, line: usize, message: String) {
+        self.errors.push(RowError { line, message });
+    }
+}
+
+impl fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for error in self.errors.iter() {
+            writeln!(f, "  {}", error)?;
+        }
+        write!(
+            f,
+            "Imported {} rows, rejected {}",
+            self.num_imported,
+            self.errors.len()
+        )
+    }
+}
+
+/**
+ * Imports CSV text into the table as part of the transaction. The first record is a header
+ * naming every field of the table once, in any order. Every other record is converted to
+ * the field types of the table; records that cannot be read or converted are skipped and
+ * reported, and written to rejects if given, after the header, so the file can be fixed
+ * and imported again. Fails without importing anything if the header does not match the
+ * table. A table without pages is bulk loaded, see load_tuples.
+ */
+pub fn import<R: BufRead>(
+    table: &Table,
+    reader: R,
+    txn: &Transaction,
+    mut rejects: Option<&mut dyn Write>,
+) -> Result<ImportReport, String> {
+    let td = table.get_tuple_desc();
+    let mut reader = CsvReader::new(reader);
+    let header = reader
+        .read_record()?
+        .ok_or("The file is empty, expected a header")?;
+    let columns = map_header(&header, td)?;
+    let mut reject = |record: &Record, message: String, report: &mut ImportReport| {
+        report.errors.push(RowError {
+            line: record.line,
+            message,
+        });
+        match rejects.as_mut() {
+            Some(rejects) if report.errors.len() == 1 => rejects
+                .write_all(header.raw.as_bytes())
+                .and_then(|_| rejects.write_all(record.raw.as_bytes())),
+            Some(rejects) => rejects.write_all(record.raw.as_bytes()),
+            None => Ok(()),
+        }
+        .map_err(|e| format!("Cannot write rejected rows: {}", e))
+    };
+
+    let mut report = ImportReport::default();
+    // a rejected row that cannot be written stops the import
+    let mut failure = None;
+    let tuples = std::iter::from_fn(|| loop {
+        let record = match reader.read_record() {
+            Ok(Some(record)) => record,
+            Ok(None) => return None,
+            Err(e) => {
+                // the reader cannot tell where the next record starts
+                report.errors.push(RowError {
+                    line: reader.line,
+                    message: e,
+                });
+                return None;
+            }
+        };
+        if record.fields.len() == 1 && record.fields[0].is_empty() {
+            continue;
+        }
+        match to_tuple(&record, &columns, td) {
+            Ok(tuple) => return Some(tuple),
+            Err(e) => {
+                if let Err(e) = reject(&record, e, &mut report) {
+                    failure = Some(e);
+                    return None;
+                }
+            }
+        }
+    });
+    let num_imported = load_tuples(table, tuples, txn);
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    report.num_imported += num_imported;
+    Ok(report)
+}
+
+// Adds the imported tuples to the table and returns how many were added. A table without
+// pages, such as a new or truncated one, is bulk loaded: the tuples are written to fresh
+// pages under an exclusive table lock held until the transaction ends. Other tables get
+// the tuples inserted in batches, which fills the free space of their pages and lets
+// other transactions keep using them
+pub(crate) fn load_tuples<I>(table: &Table, tuples: I, txn: &Transaction) -> usize
+where
+    I: Iterator<Item = Tuple>,
+{
+    if table.get_num_pages() == 0 {
+        return table.bulk_load(tuples, txn);
+    }
+    let mut tuples = tuples.peekable();
+    let mut count = 0;
+    while tuples.peek().is_some() {
+        let batch: Vec<Tuple> = tuples.by_ref().take(IMPORT_BATCH_SIZE).collect();
+        count += batch.len();
+        table.insert_many_tuples(batch, txn);
+    }
+    count
+}
+
+// Finds the field of the table that each column of the header holds
+fn map_header(header: &Record, td: &TupleDesc) -> Result<Vec<usize>, String> {
+    let mut columns: Vec<usize> = vec![];
+    for name in header.fields.iter() {
+        let i = td
+            .name_to_id(name.trim())
+            .ok_or(format!("The table has no field named {}", name.trim()))?;
+        if columns.contains(&i) {
+            return Err(format!("The header names {} twice", name.trim()));
+        }
+        columns.push(i);
+    }
+    let missing: Vec<&str> = (0..td.get_num_fields())
+        .filter(|i| !columns.contains(i))
+        .map(|i| td.get_field_name(i).unwrap().as_str())
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!("The header is missing {}", missing.join(", ")));
+    }
+    Ok(columns)
+}
+
+// Converts the fields of the record, in the order of the header, into a tuple
+fn to_tuple(record: &Record, columns: &[usize], td: &TupleDesc) -> Result<Tuple, String> {
+    if record.fields.len() != columns.len() {
+        return Err(format!(
+            "expected {} fields but found {}",
+            columns.len(),
+            record.fields.len()
+        ));
+    }
+    let mut fields: Vec<Option<FieldVal>> = vec![None; columns.len()];
+    for (text, i) in record.fields.iter().zip(columns) {
+        let field_type = td.get_field_type(*i).unwrap();
+        let value = field_type
+            .coerce(text)
+            .map_err(|e| format!("{}: {}", td.get_field_name(*i).unwrap(), e))?;
+        fields[*i] = Some(value);
+    }
+    Ok(Tuple::new(fields.into_iter().flatten().collect(), td))
+}
+
+// Writes the tuples as CSV with a header of the field names, returning the number of rows
+pub fn export_tuples<I>(td: &TupleDesc, tuples: I, writer: &mut dyn Write) -> Result<usize, String>
+where
+    I: IntoIterator<Item = Tuple>,
+{
+    let error = |e: std::io::Error| format!("Cannot write CSV: {}", e);
+    let names: Vec<&String> = (0..td.get_num_fields())
+        .map(|i| td.get_field_name(i).unwrap())
+        .collect();
+    writer
+        .write_all(format_record(&names).as_bytes())
+        .map_err(error)?;
+    let mut count = 0;
+    for tuple in tuples {
+        let values: Vec<String> = tuple.get_fields().iter().map(|f| f.to_string()).collect();
+        writer
+            .write_all(format_record(&values).as_bytes())
+            .map_err(error)?;
+        count += 1;
+    }
+    writer.flush().map_err(error)?;
+    Ok(count)
+}
+
+// Writes every tuple of the table visible to the transaction as CSV
+#[allow(dead_code)]
+pub fn export_table(
+    table: &Table,
+    txn: &Transaction,
+    writer: &mut dyn Write,
+) -> Result<usize, String> {
+    export_query(table.scan(usize::MAX, txn), writer)
+}
+
+// Runs the query and writes its results as CSV
+#[allow(dead_code)]
+pub fn export_query(mut query: TableIterator, writer: &mut dyn Write) -> Result<usize, String> {
+    query.fetch()?;
+    let td = query.get_tuple_desc();
+    export_tuples(&td, query, writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(text: &str) -> Vec<Result<Record, String>> {
+        CsvReader::new(text.as_bytes()).collect()
+    }
+
+    #[test]
+    fn test_read_records() {
+        let records = read_all("id,name\r\n1, Alice \n2,\"Bob, \"\"Jr\"\"\nSmith\"\n3,\n");
+        let fields: Vec<Vec<String>> = records
+            .iter()
+            .map(|record| record.as_ref().unwrap().get_fields().to_vec())
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                vec!["id", "name"],
+                vec!["1", " Alice "],
+                vec!["2", "Bob, \"Jr\"\nSmith"],
+                vec!["3", ""],
+            ]
+        );
+        let record = records[2].as_ref().unwrap();
+        assert_eq!(record.get_line(), 3);
+        assert_eq!(record.get_raw(), "2,\"Bob, \"\"Jr\"\"\nSmith\"\n");
+        assert_eq!(records[3].as_ref().unwrap().get_line(), 5);
+
+        assert!(read_all("1,\"open\n").pop().unwrap().is_err());
+        assert!(read_all("1,a\"b\n")[0].is_err());
+        assert!(read_all("1,\"a\"b\n")[0].is_err());
+    }
+
+    #[test]
+    fn test_format_record() {
+        assert_eq!(format_record(&["1", "Alice"]), "1,Alice\n");
+        assert_eq!(
+            format_record(&["a,b", "say \"hi\"", " padded", "two\nlines"]),
+            "\"a,b\",\"say \"\"hi\"\"\",\" padded\",\"two\nlines\"\n"
+        );
+        let line = format_record(&["x, \"y\"", "", "z"]);
+        let record = CsvReader::new(line.as_bytes()).read_record().unwrap();
+        assert_eq!(record.unwrap().get_fields(), &["x, \"y\"", "", "z"]);
+    }
+}
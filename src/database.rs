@@ -1,29 +1,189 @@
-use crate::buffer_pool::BufferPool;
+use crate::buffer_pool::{BufferPool, DEFAULT_PAGES, DEFAULT_PAGE_SIZE};
 use crate::catalog::Catalog;
+use crate::group_commit::SyncMode;
+use crate::heap_file;
+use crate::heap_page::HeapPage;
+use crate::lock_manager::DeadlockPolicy;
+use crate::transaction::{TransactionId, TransactionIds};
+use crate::tuple::TupleDesc;
+use crate::types::Type;
 use lazy_static::lazy_static;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
+
+// Directory holding the files of the global database
+pub const DEFAULT_DATA_DIR: &str = "data";
+// File in the data directory listing the schemas of its tables
+pub const CATALOG_FILE: &str = "catalog";
+// File in the data directory locked by the process that opened it
+pub const LOCK_FILE: &str = "LOCK";
+// File in the data directory recording the page size its files were written with
+pub const PAGE_SIZE_FILE: &str = "page_size";
 
 lazy_static! {
     // Global database instance
-    static ref GLOBAL_DB: Arc<Database> = Arc::new(Database::new());
+    static ref GLOBAL_DB: Arc<Database> = Database::open(DatabaseConfig::default()).unwrap();
 }
 
-// Retrieves a reference to the global database instance
+// Retrieves a reference to the global database instance, which Transaction::begin and
+// Table::new use when no other database is given
 pub fn get_global_db() -> Arc<Database> {
     Arc::clone(&GLOBAL_DB)
 }
 
+// Options a database is opened with
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatabaseConfig {
+    data_dir: PathBuf,
+    page_size: usize,
+    pool_size: usize,
+    deadlock_policy: DeadlockPolicy,
+    lock_timeout: Option<Duration>,
+    sync_mode: SyncMode,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            data_dir: PathBuf::from(DEFAULT_DATA_DIR),
+            page_size: DEFAULT_PAGE_SIZE,
+            pool_size: DEFAULT_PAGES,
+            deadlock_policy: DeadlockPolicy::default(),
+            lock_timeout: None,
+            sync_mode: SyncMode::default(),
+        }
+    }
+}
+
+impl DatabaseConfig {
+    // Creates the config of an existing data directory with the page size recorded in it,
+    // or the default page size if the directory records none
+    pub fn for_data_dir(data_dir: impl Into<PathBuf>) -> Result<Self, String> {
+        let config = DatabaseConfig::default().data_dir(data_dir);
+        Ok(match read_page_size(&config)? {
+            Some(page_size) => config.page_size(page_size),
+            None => config,
+        })
+    }
+
+    // Sets the directory holding the table files, created when the database is opened
+    pub fn data_dir(mut self, data_dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = data_dir.into();
+        self
+    }
+
+    // Sets the number of bytes in a page. A data directory records the page size it was
+    // created with and cannot be opened with another one
+    pub fn page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
+    // Sets the number of pages the buffer pool caches. Pages no transaction is using are
+    // evicted to stay within it
+    #[allow(dead_code)]
+    pub fn pool_size(mut self, pool_size: usize) -> Self {
+        self.pool_size = pool_size;
+        self
+    }
+
+    pub fn deadlock_policy(mut self, deadlock_policy: DeadlockPolicy) -> Self {
+        self.deadlock_policy = deadlock_policy;
+        self
+    }
+
+    // Sets how long a lock request may wait before its transaction aborts. Requests wait
+    // without a limit by default
+    pub fn lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = Some(timeout);
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn sync_mode(mut self, sync_mode: SyncMode) -> Self {
+        self.sync_mode = sync_mode;
+        self
+    }
+
+    pub fn get_data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    // Retrieves the path of a file in the data directory
+    pub fn get_file_path(&self, file_name: &str) -> PathBuf {
+        self.data_dir.join(file_name)
+    }
+
+    pub fn get_page_size(&self) -> usize {
+        self.page_size
+    }
+
+    pub fn get_pool_size(&self) -> usize {
+        self.pool_size
+    }
+
+    pub fn get_deadlock_policy(&self) -> DeadlockPolicy {
+        self.deadlock_policy
+    }
+
+    pub fn get_lock_timeout(&self) -> Option<Duration> {
+        self.lock_timeout
+    }
+
+    pub fn get_sync_mode(&self) -> SyncMode {
+        self.sync_mode
+    }
+}
+
 pub struct Database {
+    config: DatabaseConfig,
     buffer_pool: BufferPool,
     catalog: Catalog,
+    transaction_ids: TransactionIds,
+    // locked until the database is dropped or the process exits
+    #[allow(dead_code)]
+    lock_file: File,
 }
 
 impl Database {
-    pub fn new() -> Self {
-        Database {
-            buffer_pool: BufferPool::new(),
-            catalog: Catalog::new(),
+    // Opens the database stored in the data directory of the config, creating the
+    // directory if needed, and opens the tables recorded in its catalog. Its components
+    // hold a handle to the database instead of using the global one, so several databases
+    // can be open at once. Fails if another database has the directory open
+    pub fn open(config: DatabaseConfig) -> Result<Arc<Self>, String> {
+        // tables are checked as they are opened, but no page can be smaller than one
+        // holding a single Int
+        let td = TupleDesc::new(vec![Type::IntType], vec!["id".to_string()]);
+        HeapPage::check_page_size(&td, config.page_size)?;
+        let created = !config.data_dir.exists();
+        fs::create_dir_all(&config.data_dir).map_err(|e| {
+            format!(
+                "Cannot create data directory {}: {}",
+                config.data_dir.display(),
+                e
+            )
+        })?;
+        if created {
+            heap_file::sync_parent_dir(&config.data_dir);
         }
+        let lock_file = lock_data_dir(&config)?;
+        record_page_size(&config)?;
+        let db = Arc::new_cyclic(|db| Database {
+            buffer_pool: BufferPool::new(db.clone(), &config),
+            catalog: Catalog::new(db.clone()),
+            transaction_ids: TransactionIds::new(),
+            lock_file,
+            config,
+        });
+        db.catalog.open_tables()?;
+        Ok(db)
+    }
+
+    pub fn get_config(&self) -> &DatabaseConfig {
+        &self.config
     }
 
     pub fn get_buffer_pool(&self) -> &BufferPool {
@@ -33,4 +193,73 @@ impl Database {
     pub fn get_catalog(&self) -> &Catalog {
         &self.catalog
     }
+
+    // Hands out the id of a new transaction, larger than the ids of all earlier ones
+    pub fn next_transaction_id(&self) -> TransactionId {
+        self.transaction_ids.next()
+    }
+}
+
+// Reads the page size recorded in the data directory, if it has one
+pub fn read_page_size(config: &DatabaseConfig) -> Result<Option<usize>, String> {
+    let path = config.get_file_path(PAGE_SIZE_FILE);
+    match fs::read_to_string(&path) {
+        Ok(text) => text
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("Invalid page size {:?} in {}", text.trim(), path.display())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("Cannot read {}: {}", path.display(), e)),
+    }
+}
+
+// Records the page size of the config in a data directory that has none yet, and fails if
+// the directory was created with another page size
+fn record_page_size(config: &DatabaseConfig) -> Result<(), String> {
+    match read_page_size(config)? {
+        Some(page_size) if page_size != config.page_size => Err(format!(
+            "Data directory {} has pages of {} bytes, not {}",
+            config.data_dir.display(),
+            page_size,
+            config.page_size
+        )),
+        Some(_) => Ok(()),
+        None => {
+            let path = config.get_file_path(PAGE_SIZE_FILE);
+            fs::write(&path, config.page_size.to_string())
+                .and_then(|_| File::open(&path)?.sync_all())
+                .map_err(|e| format!("Cannot write {}: {}", path.display(), e))?;
+            heap_file::sync_parent_dir(&path);
+            Ok(())
+        }
+    }
+}
+
+// Takes an exclusive lock on the lock file of the data directory and records the id of
+// the process in it. The operating system releases the lock when the process exits, so
+// a crash does not leave the directory locked
+pub fn lock_data_dir(config: &DatabaseConfig) -> Result<File, String> {
+    let path = config.get_file_path(LOCK_FILE);
+    let error = |e: std::io::Error| format!("Cannot lock {}: {}", path.display(), e);
+    let mut lock_file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(&path)
+        .map_err(error)?;
+    match lock_file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            return Err(format!(
+                "Data directory {} is already open in another database",
+                config.data_dir.display()
+            ))
+        }
+        Err(TryLockError::Error(e)) => return Err(error(e)),
+    }
+    lock_file.set_len(0).map_err(error)?;
+    writeln!(lock_file, "{}", std::process::id()).map_err(error)?;
+    Ok(lock_file)
 }
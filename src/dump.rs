@@ -0,0 +1,274 @@
+use crate::catalog;
+use crate::database::Database;
+use crate::fields::FieldVal;
+use crate::table::Table;
+use crate::transaction::{IsolationLevel, Transaction};
+use crate::tuple::{Tuple, TupleDesc};
+use crate::types::Type;
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::sync::Arc;
+
+// First line of every dump, naming the version of the format
+pub const DUMP_HEADER: &str = "-- rustic_db dump, format 1";
+
+// Number of tables and rows written by a dump or read by a restore
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DumpReport {
+    num_tables: usize,
+    num_rows: usize,
+}
+
+impl DumpReport {
+    #[allow(dead_code)]
+    pub fn get_num_tables(&self) -> usize {
+        self.num_tables
+    }
+
+    #[allow(dead_code)]
+    pub fn get_num_rows(&self) -> usize {
+        self.num_rows
+    }
+}
+
+impl fmt::Display for DumpReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} tables, {} rows", self.num_tables, self.num_rows)
+    }
+}
+
+/**
+ * Writes every table of the database as SQL: a CREATE TABLE statement with the schema line
+ * of the table, followed by an INSERT statement per row. All tables are read in a single
+ * REPEATABLE READ transaction, so the dump is a consistent snapshot of the committed data
+ * even while other transactions write, and does not block them.
+ */
+pub fn dump(db: &Arc<Database>, writer: &mut dyn Write) -> Result<DumpReport, String> {
+    let error = |e: std::io::Error| format!("Cannot write dump: {}", e);
+    let txn = Transaction::builder()
+        .database(db)
+        .isolation(IsolationLevel::RepeatableRead)
+        .begin();
+    let mut report = DumpReport::default();
+    writeln!(writer, "{}", DUMP_HEADER).map_err(error)?;
+    for heap_file in db.get_catalog().get_tables() {
+        let table = Table::open(db, heap_file.get_name())?;
+        let mut query = table.scan(usize::MAX, &txn);
+        query.fetch()?;
+        let schema = catalog::format_schema_line(heap_file.get_name(), table.get_tuple_desc());
+        writeln!(writer, "\nCREATE TABLE {};", schema).map_err(error)?;
+        for tuple in query {
+            writeln!(writer, "{}", format_insert(heap_file.get_name(), &tuple)).map_err(error)?;
+            report.num_rows += 1;
+        }
+        report.num_tables += 1;
+    }
+    writer.flush().map_err(error)?;
+    txn.commit()?;
+    Ok(report)
+}
+
+// Writes an INSERT statement for the tuple
+fn format_insert(table_name: &str, tuple: &Tuple) -> String {
+    let values: Vec<String> = tuple
+        .get_fields()
+        .iter()
+        .map(|field| match field {
+            FieldVal::IntField(field) => field.get_value().to_string(),
+            FieldVal::StringField(field) => format!("'{}'", field.get_value().replace('\'', "''")),
+        })
+        .collect();
+    format!("INSERT INTO {} VALUES ({});", table_name, values.join(", "))
+}
+
+/**
+ * Recreates the tables of a dump in the database and loads their rows. The tables must not
+ * exist yet. Rows are bulk loaded in one transaction, which is rolled back if a statement
+ * fails, but like other DDL the tables created before the failure remain.
+ */
+pub fn restore<R: BufRead>(db: &Arc<Database>, reader: R) -> Result<DumpReport, String> {
+    let txn = Transaction::builder().database(db).begin();
+    let mut report = DumpReport::default();
+    // rows are loaded once the statements for their table end
+    let mut pending: Option<(Table, Vec<Tuple>)> = None;
+    let flush = |pending: &mut Option<(Table, Vec<Tuple>)>, report: &mut DumpReport| {
+        if let Some((table, rows)) = pending.take() {
+            report.num_rows += table.bulk_load(rows, &txn);
+        }
+    };
+
+    let mut statements = StatementReader::new(reader);
+    while let Some((line, statement)) = statements.read_statement()? {
+        let statement = statement.strip_suffix(';').unwrap_or(&statement);
+        let error = |e: String| format!("Line {}: {}", line, e);
+        if let Some(schema) = statement.strip_prefix("CREATE TABLE ") {
+            flush(&mut pending, &mut report);
+            let heap_file = db.get_catalog().create_table(schema).map_err(error)?;
+            pending = Some((Table::open(db, heap_file.get_name())?, vec![]));
+            report.num_tables += 1;
+        } else if let Some(insert) = statement.strip_prefix("INSERT INTO ") {
+            let (table_name, values) = insert
+                .split_once(" VALUES ")
+                .ok_or_else(|| error("expected VALUES".to_string()))?;
+            if pending
+                .as_ref()
+                .is_none_or(|(table, _)| table.get_name() != table_name)
+            {
+                flush(&mut pending, &mut report);
+                pending = Some((Table::open(db, table_name).map_err(error)?, vec![]));
+            }
+            let (table, rows) = pending.as_mut().unwrap();
+            rows.push(parse_values(values, table.get_tuple_desc()).map_err(error)?);
+        } else {
+            return Err(error(format!("unknown statement {}", statement)));
+        }
+    }
+    flush(&mut pending, &mut report);
+    drop(pending);
+    txn.commit()?;
+    Ok(report)
+}
+
+// Parses the value list of an INSERT statement, such as (1, 'O''Brien'), into a tuple
+fn parse_values(values: &str, td: &TupleDesc) -> Result<Tuple, String> {
+    let values = values
+        .trim()
+        .strip_prefix('(')
+        .and_then(|values| values.strip_suffix(')'))
+        .ok_or("expected values in parentheses")?;
+    let mut chars = values.chars().peekable();
+    let mut fields = vec![];
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let i = fields.len();
+        let field_type = td
+            .get_field_type(i)
+            .ok_or(format!("expected {} values", td.get_num_fields()))?;
+        let field = if chars.next_if_eq(&'\'').is_some() {
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    Some('\'') if chars.next_if_eq(&'\'').is_some() => text.push('\''),
+                    Some('\'') => break,
+                    Some(c) => text.push(c),
+                    None => return Err("unclosed string".to_string()),
+                }
+            }
+            match field_type {
+                Type::StringType => field_type.coerce(&text),
+                Type::IntType => Err(format!("expected an Int but found '{}'", text)),
+            }
+        } else {
+            let text: String = std::iter::from_fn(|| chars.next_if(|c| *c != ',')).collect();
+            match field_type {
+                Type::IntType => field_type.coerce(&text),
+                Type::StringType => Err(format!("expected a quoted String but found {}", text)),
+            }
+        };
+        fields.push(field.map_err(|e| format!("{}: {}", td.get_field_name(i).unwrap(), e))?);
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        match chars.next() {
+            Some(',') => continue,
+            None if fields.len() == td.get_num_fields() => return Ok(Tuple::new(fields, td)),
+            None => return Err(format!("expected {} values", td.get_num_fields())),
+            Some(c) => return Err(format!("unexpected '{}' after a value", c)),
+        }
+    }
+}
+
+// Reads the statements of a dump, which end with a semicolon at the end of a line outside
+// of a quoted string. Lines starting with -- between statements are comments
+struct StatementReader<R: BufRead> {
+    reader: R,
+    line: usize,
+}
+
+impl<R: BufRead> StatementReader<R> {
+    fn new(reader: R) -> Self {
+        StatementReader { reader, line: 0 }
+    }
+
+    // Reads the next statement with the line it starts on, or None at the end of the input
+    fn read_statement(&mut self) -> Result<Option<(usize, String)>, String> {
+        let mut statement = String::new();
+        let mut start = 0;
+        let mut in_quotes = false;
+        loop {
+            let mut line = String::new();
+            let read = self
+                .reader
+                .read_line(&mut line)
+                .map_err(|e| format!("Cannot read line {}: {}", self.line + 1, e))?;
+            if read == 0 {
+                if statement.trim().is_empty() {
+                    return Ok(None);
+                }
+                return Err(format!("Line {}: statement does not end with ;", start));
+            }
+            self.line += 1;
+            if statement.is_empty() && (line.trim().is_empty() || line.starts_with("--")) {
+                continue;
+            }
+            if statement.is_empty() {
+                start = self.line;
+            }
+            // doubled quotes inside a string toggle twice, so they leave it quoted
+            in_quotes ^= line.matches('\'').count() % 2 == 1;
+            statement.push_str(&line);
+            if !in_quotes && line.trim_end().ends_with(';') {
+                // keep line breaks inside strings, but not the one ending the statement
+                statement.truncate(statement.trim_end().len());
+                return Ok(Some((start, statement)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fields::{IntField, StringField};
+
+    #[test]
+    fn test_values() {
+        let td = TupleDesc::new(
+            vec![Type::IntType, Type::StringType],
+            vec!["id".to_string(), "name".to_string()],
+        );
+        let tuple = Tuple::new(
+            vec![
+                FieldVal::IntField(IntField::new(-3)),
+                FieldVal::StringField(StringField::new("O'Brien;\n, (x)".to_string(), 14)),
+            ],
+            &td,
+        );
+        let insert = format_insert("people", &tuple);
+        assert_eq!(
+            insert,
+            "INSERT INTO people VALUES (-3, 'O''Brien;\n, (x)');"
+        );
+        let text = format!("-- comment\n\n{}\n{}\n", insert, insert);
+        let mut statements = StatementReader::new(text.as_bytes());
+        let (line, statement) = statements.read_statement().unwrap().unwrap();
+        assert_eq!((line, statement.as_str()), (3, insert.as_str()));
+        let (line, _) = statements.read_statement().unwrap().unwrap();
+        assert_eq!(line, 5);
+        assert_eq!(statements.read_statement().unwrap(), None);
+
+        let values = insert.strip_prefix("INSERT INTO people VALUES ").unwrap();
+        let values = values.strip_suffix(';').unwrap();
+        assert_eq!(parse_values(values, &td).unwrap(), tuple);
+        let errors = [
+            ("(1)", "expected 2 values"),
+            ("(1, 'a', 2)", "expected 2 values"),
+            ("('1', 'a')", "id: expected an Int but found '1'"),
+            ("(x, 'a')", "id: \"x\" is not a valid Int"),
+            ("(1, a)", "name: expected a quoted String but found a"),
+            ("(1, 'a)", "unclosed string"),
+            ("(1, 'a' b)", "unexpected 'b' after a value"),
+        ];
+        for (values, error) in errors {
+            assert_eq!(parse_values(values, &td).unwrap_err(), error);
+        }
+    }
+}
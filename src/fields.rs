@@ -1,4 +1,5 @@
 use crate::types::{Type, STRING_SIZE};
+use std::fmt;
 
 // Wrapper for different types of fields
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -9,13 +10,22 @@ pub enum FieldVal {
 
 impl FieldVal {
     // Extracts the inner IntField
+    #[allow(dead_code)]
     pub fn into_int(self) -> Option<IntField> {
         match self {
             FieldVal::IntField(int_field) => Some(int_field),
             _ => None,
         }
     }
+    // Get the type of the wrapped field
+    pub fn get_type(&self) -> Type {
+        match self {
+            FieldVal::IntField(int_field) => int_field.get_type(),
+            FieldVal::StringField(string_field) => string_field.get_type(),
+        }
+    }
     // Extracts the inner StringField
+    #[allow(dead_code)]
     pub fn into_string(self) -> Option<StringField> {
         match self {
             FieldVal::StringField(string_field) => Some(string_field),
@@ -24,6 +34,16 @@ impl FieldVal {
     }
 }
 
+// Formats the value as it was given, without quotes around strings
+impl fmt::Display for FieldVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FieldVal::IntField(int_field) => write!(f, "{}", int_field.get_value()),
+            FieldVal::StringField(string_field) => write!(f, "{}", string_field.value),
+        }
+    }
+}
+
 // Trait for different types of fields
 pub trait Field {
     // Get the type of the field
@@ -0,0 +1,229 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::Mutex;
+
+// Free slot counts are stored in one byte, so anything above this is recorded as this
+pub const MAX_TRACKED_SLOTS: usize = u8::MAX as usize;
+
+/**
+ * Persistent map of the number of free tuple slots on every page of a heap file.
+ * The map is stored in a side file with one byte per page and is only a hint:
+ * inserts still check the page itself and correct the entry if it was stale.
+ */
+pub struct FreeSpaceMap {
+    file: Mutex<File>,
+    state: Mutex<FreeSpaceState>,
+}
+
+struct FreeSpaceState {
+    // free slots per page number
+    free_slots: Vec<u8>,
+    // pages with at least one free slot, so the first candidate is found without a scan
+    pages_with_space: BTreeSet<usize>,
+    // entries changed since the last flush
+    dirty: BTreeSet<usize>,
+    // the map shrank since the last flush
+    truncated: bool,
+}
+
+impl FreeSpaceMap {
+    // Loads the map stored in the specified file
+    pub fn open(mut file: File) -> Self {
+        let mut free_slots = vec![];
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut free_slots).unwrap();
+        let pages_with_space = free_slots
+            .iter()
+            .enumerate()
+            .filter(|(_, free)| **free > 0)
+            .map(|(page_no, _)| page_no)
+            .collect();
+        FreeSpaceMap {
+            file: Mutex::new(file),
+            state: Mutex::new(FreeSpaceState {
+                free_slots,
+                pages_with_space,
+                dirty: BTreeSet::new(),
+                truncated: false,
+            }),
+        }
+    }
+
+    // Number of pages tracked by the map
+    pub fn num_pages(&self) -> usize {
+        self.state.lock().unwrap().free_slots.len()
+    }
+
+    // Retrieves the recorded number of free slots on the page
+    #[allow(dead_code)]
+    pub fn get_free_slots(&self, page_no: usize) -> Option<usize> {
+        let state = self.state.lock().unwrap();
+        state.free_slots.get(page_no).map(|free| *free as usize)
+    }
+
+    // Finds the lowest numbered page recorded as having a free slot
+    pub fn find_page(&self) -> Option<usize> {
+        let state = self.state.lock().unwrap();
+        state.pages_with_space.first().copied()
+    }
+
+    // Records the number of free slots on the page
+    pub fn update(&self, page_no: usize, free_slots: usize) {
+        let mut state = self.state.lock().unwrap();
+        let free = free_slots.min(MAX_TRACKED_SLOTS) as u8;
+        if page_no >= state.free_slots.len() {
+            let old_len = state.free_slots.len();
+            state.free_slots.resize(page_no + 1, 0);
+            state.dirty.extend(old_len..page_no);
+        } else if state.free_slots[page_no] == free {
+            return;
+        }
+        state.free_slots[page_no] = free;
+        if free > 0 {
+            state.pages_with_space.insert(page_no);
+        } else {
+            state.pages_with_space.remove(&page_no);
+        }
+        state.dirty.insert(page_no);
+    }
+
+    // Forgets every page from page number num_pages onwards
+    pub fn truncate(&self, num_pages: usize) {
+        let mut state = self.state.lock().unwrap();
+        if num_pages >= state.free_slots.len() {
+            return;
+        }
+        state.free_slots.truncate(num_pages);
+        state
+            .pages_with_space
+            .retain(|page_no| *page_no < num_pages);
+        state.dirty.retain(|page_no| *page_no < num_pages);
+        state.truncated = true;
+    }
+
+    // Replaces every entry with the free slot counts of another file and returns the old
+    // entries, for a table whose file is swapped
+    pub fn replace(&self, free_slots: Vec<u8>) -> Vec<u8> {
+        let mut state = self.state.lock().unwrap();
+        state.pages_with_space = free_slots
+            .iter()
+            .enumerate()
+            .filter(|(_, free)| **free > 0)
+            .map(|(page_no, _)| page_no)
+            .collect();
+        state.dirty = (0..free_slots.len()).collect();
+        state.truncated = true;
+        std::mem::replace(&mut state.free_slots, free_slots)
+    }
+
+    // Writes the changed entries to disk
+    pub fn flush(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.dirty.is_empty() && !state.truncated {
+            return;
+        }
+        let mut file = self.file.lock().unwrap();
+        if state.truncated {
+            file.set_len(state.free_slots.len() as u64).unwrap();
+        }
+        // write runs of consecutive dirty entries together
+        let dirty: Vec<usize> = state.dirty.iter().copied().collect();
+        let mut i = 0;
+        while i < dirty.len() {
+            let start = dirty[i];
+            let mut end = start + 1;
+            while i + 1 < dirty.len() && dirty[i + 1] == end {
+                i += 1;
+                end += 1;
+            }
+            file.seek(SeekFrom::Start(start as u64)).unwrap();
+            file.write_all(&state.free_slots[start..end]).unwrap();
+            i += 1;
+        }
+        state.dirty.clear();
+        state.truncated = false;
+    }
+
+    // Waits until the flushed entries are on disk
+    pub fn sync(&self) {
+        self.file.lock().unwrap().sync_all().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::path::PathBuf;
+    use uuid::Uuid;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("rustic_db_{}.fsm", Uuid::new_v4()))
+    }
+
+    fn open_file(path: &PathBuf) -> File {
+        OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_find_page_with_space() {
+        let path = temp_path();
+        let fsm = FreeSpaceMap::open(open_file(&path));
+        assert_eq!(fsm.find_page(), None);
+        fsm.update(0, 0);
+        fsm.update(3, 2);
+        fsm.update(1, 1);
+        assert_eq!(fsm.find_page(), Some(1));
+        fsm.update(1, 0);
+        assert_eq!(fsm.find_page(), Some(3));
+        // pages skipped over are recorded as full
+        assert_eq!(fsm.get_free_slots(2), Some(0));
+        assert_eq!(fsm.num_pages(), 4);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_flush_and_reopen() {
+        let path = temp_path();
+        let fsm = FreeSpaceMap::open(open_file(&path));
+        fsm.update(0, 0);
+        fsm.update(1, 1000);
+        fsm.update(2, 7);
+        fsm.flush();
+        fsm.truncate(2);
+        fsm.flush();
+
+        let fsm = FreeSpaceMap::open(open_file(&path));
+        assert_eq!(fsm.num_pages(), 2);
+        assert_eq!(fsm.get_free_slots(1), Some(MAX_TRACKED_SLOTS));
+        assert_eq!(fsm.get_free_slots(2), None);
+        assert_eq!(fsm.find_page(), Some(1));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_replace() {
+        let path = temp_path();
+        let fsm = FreeSpaceMap::open(open_file(&path));
+        fsm.update(0, 0);
+        fsm.update(1, 3);
+        let old = fsm.replace(vec![]);
+        fsm.flush();
+        assert_eq!(fsm.find_page(), None);
+        assert_eq!(FreeSpaceMap::open(open_file(&path)).num_pages(), 0);
+
+        fsm.replace(old);
+        fsm.flush();
+        assert_eq!(fsm.find_page(), Some(1));
+        let fsm = FreeSpaceMap::open(open_file(&path));
+        assert_eq!(fsm.get_free_slots(1), Some(3));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashSet;
+use std::sync::{Condvar, Mutex};
+
+// How much of a commit reaches the disk before the commit returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum SyncMode {
+    // Pages are handed to the operating system without waiting for the disk, so a power
+    // loss may drop recently committed transactions
+    Off,
+    // The data files written by the transaction are synced before it commits
+    #[default]
+    Normal,
+    // The data files and their metadata are synced together with the free space maps
+    #[allow(dead_code)]
+    Full,
+}
+
+/**
+ * Batches the syncs of concurrently committing transactions. The first transaction to
+ * commit syncs its files while the transactions committing meanwhile queue up their
+ * files, and the next of them syncs the files of the whole queue at once. Every commit
+ * still waits for its own files to reach the disk.
+ */
+pub struct GroupCommit {
+    state: Mutex<GroupCommitState>,
+    synced: Condvar,
+}
+
+#[derive(Default)]
+struct GroupCommitState {
+    // tables written by the commits of the batch being collected
+    pending: HashSet<usize>,
+    // number of the batch being collected, earlier batches are synced or syncing
+    next_batch: u64,
+    // batches up to this one are on disk
+    last_synced: u64,
+    // a commit is syncing the previous batch
+    syncing: bool,
+    num_commits: usize,
+    num_syncs: usize,
+}
+
+impl GroupCommit {
+    pub fn new() -> Self {
+        GroupCommit {
+            state: Mutex::new(GroupCommitState {
+                next_batch: 1,
+                ..Default::default()
+            }),
+            synced: Condvar::new(),
+        }
+    }
+
+    // Waits until the tables written by a committing transaction are on disk. Whichever
+    // waiting commit finds no sync in progress calls sync for the tables of every commit
+    // in its batch
+    pub fn commit<F>(&self, table_ids: HashSet<usize>, sync: F)
+    where
+        F: Fn(&HashSet<usize>),
+    {
+        let mut state = self.state.lock().unwrap();
+        state.num_commits += 1;
+        state.pending.extend(table_ids);
+        let batch = state.next_batch;
+        while state.last_synced < batch {
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+            // close the batch, later commits join the next one
+            state.syncing = true;
+            state.next_batch += 1;
+            let table_ids = std::mem::take(&mut state.pending);
+            drop(state);
+            sync(&table_ids);
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            state.last_synced = batch;
+            state.num_syncs += 1;
+            self.synced.notify_all();
+        }
+    }
+
+    // Number of commits that waited for their tables to be synced
+    #[allow(dead_code)]
+    pub fn get_num_commits(&self) -> usize {
+        self.state.lock().unwrap().num_commits
+    }
+
+    // Number of batches synced, at most one per commit
+    #[allow(dead_code)]
+    pub fn get_num_syncs(&self) -> usize {
+        self.state.lock().unwrap().num_syncs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_concurrent_commits_share_syncs() {
+        let group_commit = Arc::new(GroupCommit::new());
+        let on_disk = Arc::new(Mutex::new(HashSet::<usize>::new()));
+        let handles: Vec<_> = (0..8)
+            .map(|table_id| {
+                let group_commit = Arc::clone(&group_commit);
+                let on_disk = Arc::clone(&on_disk);
+                thread::spawn(move || {
+                    group_commit.commit(HashSet::from([table_id]), |table_ids| {
+                        thread::sleep(Duration::from_millis(50));
+                        on_disk.lock().unwrap().extend(table_ids);
+                    });
+                    // the commit returns only once its table was synced
+                    assert!(on_disk.lock().unwrap().contains(&table_id));
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(group_commit.get_num_commits(), 8);
+        assert_eq!(on_disk.lock().unwrap().len(), 8);
+        // commits arriving during a sync are synced together by the next one
+        assert!(group_commit.get_num_syncs() < 8);
+    }
+}
@@ -1,28 +1,73 @@
-use crate::buffer_pool::PAGE_SIZE;
-use crate::database;
+use crate::database::{Database, DatabaseConfig};
+use crate::free_space_map::FreeSpaceMap;
+use crate::group_commit::SyncMode;
 use crate::heap_page::{HeapPage, HeapPageId, Permission};
+use crate::lock_manager::LockMode;
+use crate::mvcc::{Snapshot, TupleVersion};
 use crate::transaction::TransactionId;
-use crate::tuple::{Tuple, TupleDesc};
+use crate::tuple::{RecordId, Tuple, TupleDesc};
 
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::sync::{Arc, Mutex, RwLock};
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, Weak};
 use uuid::Uuid;
 
+// Number of pages collected in memory before a bulk load writes them out
+pub const BULK_LOAD_BATCH_PAGES: usize = 64;
+
 // Representation of a table stored in a file on disk
 pub struct HeapFile {
+    db: Weak<Database>,
+    name: String,
     file: Mutex<File>,
+    fsm: FreeSpaceMap,
+    // files and free space map entries the table had before each truncate of the running
+    // transaction, the latest last
+    truncated: Mutex<Vec<(File, Vec<u8>)>>,
     td: TupleDesc,
     id: usize,
+    page_size: usize,
 }
 
 impl HeapFile {
-    pub fn new(file: File, td: TupleDesc) -> Self {
-        HeapFile {
+    pub fn new(
+        db: Weak<Database>,
+        name: String,
+        file: File,
+        fsm: FreeSpaceMap,
+        td: TupleDesc,
+        page_size: usize,
+    ) -> Self {
+        let heap_file = HeapFile {
+            db,
+            name,
             file: Mutex::new(file),
+            fsm,
+            truncated: Mutex::new(vec![]),
             td,
             id: Uuid::new_v4().as_u128() as usize,
+            page_size,
+        };
+        heap_file.rebuild_free_space_map();
+        heap_file
+    }
+
+    // Fills in free space map entries for pages the map does not know about yet, such as
+    // the pages of a file written before the map existed. Corrupted pages are recorded
+    // as full so inserts stay away from them
+    fn rebuild_free_space_map(&self) {
+        let num_pages = self.num_pages();
+        if self.fsm.num_pages() > num_pages {
+            self.fsm.truncate(num_pages);
         }
+        for page_no in self.fsm.num_pages()..num_pages {
+            match self.read_page(&HeapPageId::new(self.id, page_no)) {
+                Ok(page) => self.record_free_space(&page),
+                Err(_) => self.fsm.update(page_no, 0),
+            }
+        }
+        self.fsm.flush();
     }
 
     // Retrieves the unique id of this table
@@ -30,29 +75,68 @@ impl HeapFile {
         self.id
     }
 
+    // Retrieves the name of this table
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    // Retrieves the database the table belongs to
+    pub fn get_db(&self) -> Arc<Database> {
+        self.db.upgrade().expect("Database was dropped")
+    }
+
+    // Retrieves the number of bytes in a page of this file
+    pub fn get_page_size(&self) -> usize {
+        self.page_size
+    }
+
     // Retrieves the tuple descriptor for this table
     pub fn get_tuple_desc(&self) -> &TupleDesc {
         &self.td
     }
 
-    // Retrieves the page with the specified pid from disk
-    pub fn read_page(&self, pid: &HeapPageId) -> HeapPage {
-        let mut data = vec![0; PAGE_SIZE];
+    // Retrieves the page with the specified pid from disk. Fails with a corruption error
+    // naming the table and page if the page does not match its checksum, or is cut short
+    // at the end of the file
+    pub fn read_page(&self, pid: &HeapPageId) -> Result<HeapPage, String> {
+        let mut data = vec![0; self.page_size];
         let mut file = self.file.lock().unwrap();
         let mut num_pages =
-            (file.metadata().unwrap().len() as f64 / PAGE_SIZE as f64).ceil() as usize;
+            (file.metadata().unwrap().len() as f64 / self.page_size as f64).ceil() as usize;
         let page_no = pid.get_page_number();
         while num_pages <= page_no {
-            file.seek(SeekFrom::Start((num_pages * PAGE_SIZE) as u64))
+            file.seek(SeekFrom::Start((num_pages * self.page_size) as u64))
                 .unwrap();
             file.write_all(&data).unwrap();
             num_pages += 1;
         }
 
-        file.seek(SeekFrom::Start((page_no * PAGE_SIZE) as u64))
-            .unwrap();
+        let corruption =
+            |e: String| format!("Corruption in table {} page {}: {}", self.name, page_no, e);
+        let start = (page_no * self.page_size) as u64;
+        let len = file.metadata().unwrap().len() - start;
+        if len < self.page_size as u64 {
+            return Err(corruption(format!("partial page of {} bytes", len)));
+        }
+        file.seek(SeekFrom::Start(start)).unwrap();
         file.read_exact(&mut data).unwrap();
-        HeapPage::new(*pid, data, self.td.clone())
+        HeapPage::new(*pid, data, self.td.clone()).map_err(corruption)
+    }
+
+    // Reads the bytes of the page from disk without parsing them, for inspecting damaged
+    // pages. The last page may be shorter than a page if the file was cut off
+    pub fn read_page_data(&self, page_number: usize) -> Result<Vec<u8>, String> {
+        let mut file = self.file.lock().unwrap();
+        let start = (page_number * self.page_size) as u64;
+        let len = file.metadata().map_err(|e| e.to_string())?.len();
+        if start >= len {
+            return Err(format!("Table {} has no page {}", self.name, page_number));
+        }
+        let mut data = vec![0; (len - start).min(self.page_size as u64) as usize];
+        file.seek(SeekFrom::Start(start))
+            .and_then(|_| file.read_exact(&mut data))
+            .map_err(|e| e.to_string())?;
+        Ok(data)
     }
 
     // Writes the specified page to disk
@@ -60,55 +144,384 @@ impl HeapFile {
         let pid = page.get_id();
         let data = page.get_page_data();
         let mut file = self.file.lock().unwrap();
-        file.seek(SeekFrom::Start((pid.get_page_number() * PAGE_SIZE) as u64))
-            .unwrap();
+        file.seek(SeekFrom::Start(
+            (pid.get_page_number() * self.page_size) as u64,
+        ))
+        .unwrap();
         file.write_all(&data).unwrap();
+        self.record_free_space(page);
+    }
+
+    // Waits until the pages written to the file are on disk. Full also syncs the file's
+    // metadata and its free space map
+    pub fn sync(&self, mode: SyncMode) {
+        let file = self.file.lock().unwrap();
+        match mode {
+            SyncMode::Off => {}
+            SyncMode::Normal => file.sync_data().unwrap(),
+            SyncMode::Full => {
+                file.sync_all().unwrap();
+                self.fsm.sync();
+            }
+        }
+    }
+
+    // Updates the free space map entry for the specified page. Slots of deleted versions
+    // count as free since inserts prune them once no transaction can see them
+    pub fn record_free_space(&self, page: &HeapPage) {
+        self.fsm.update(
+            page.get_id().get_page_number(),
+            page.get_num_reusable_slots(),
+        );
+    }
+
+    // Persists the changes made to the free space map
+    pub fn flush_free_space_map(&self) {
+        self.fsm.flush();
+    }
+
+    // Retrieves the free space map of this file
+    #[allow(dead_code)]
+    pub fn get_free_space_map(&self) -> &FreeSpaceMap {
+        &self.fsm
     }
 
     // Calculates the number of pages in this HeapFile
     pub fn num_pages(&self) -> usize {
         let file = self.file.lock().unwrap();
-        (file.metadata().unwrap().len() as f64 / PAGE_SIZE as f64).ceil() as usize
+        (file.metadata().unwrap().len() as f64 / self.page_size as f64).ceil() as usize
     }
 
     // Adds the specified tuple to the file
-    pub fn add_tuple(&self, tid: TransactionId, tuple: Tuple) {
+    pub fn add_tuple(&self, tid: TransactionId, tuple: Tuple) -> Result<(), String> {
         let table_id = self.get_id();
-        let db = database::get_global_db();
+        let db = self.get_db();
         let bp = db.get_buffer_pool();
-        let mut page_no = 0;
+        let transactions = bp.get_transaction_table();
+        let xmin = transactions.stamp(tid);
+        bp.check_predicate_locks(tid, table_id, &tuple);
 
-        // find the first page with an empty slot
+        // go straight to a page the free space map says has room, or append a new page
         loop {
+            let page_no = self.fsm.find_page().unwrap_or_else(|| self.num_pages());
             let pid = HeapPageId::new(table_id, page_no);
-            let page = bp.get_page(tid, pid, Permission::Read).unwrap();
-            let page_read = page.read().unwrap();
-            if page_read.get_num_empty_slots() > 0 {
-                drop(page_read);
-                let page = bp.get_page(tid, pid, Permission::Write).unwrap();
-                let mut page_writer = page.write().unwrap();
-                page_writer.add_tuple(tuple).unwrap();
+            let page = match bp.get_page(tid, pid, Permission::Write) {
+                Ok(page) => page,
+                Err(error) => {
+                    // keep later inserts away from the corrupted page
+                    self.fsm.update(page_no, 0);
+                    return Err(error);
+                }
+            };
+            let mut page_writer = page.write().unwrap();
+            if page_writer.get_num_empty_slots() == 0
+                && page_writer.prune(|xmax| transactions.is_dead_to_all(xmax)) > 0
+            {
+                page_writer.mark_dirty(true, tid);
+            }
+            if let Some(slot) = self.claim_free_slot(tid, &page_writer) {
+                bp.log_undo(tid, &page_writer, slot);
+                page_writer.add_tuple_at(slot, tuple, xmin).unwrap();
                 page_writer.mark_dirty(true, tid);
-                return;
+                self.record_free_space(&page_writer);
+                drop(page_writer);
+                bp.get_lock_manager().escalate_if_needed(tid, table_id);
+                return Ok(());
+            }
+            // the map was stale, the deleted versions are still visible to someone, or the
+            // free slots are locked by other transactions, so stop sending inserts here
+            // and look again
+            self.fsm.update(page_no, 0);
+        }
+    }
+
+    // Finds an empty slot on the page that no other transaction holds a lock on and
+    // locks it exclusively for the transaction
+    fn claim_free_slot(&self, tid: TransactionId, page: &HeapPage) -> Option<usize> {
+        let db = self.get_db();
+        let lock_manager = db.get_buffer_pool().get_lock_manager();
+        page.get_free_slots()
+            .find(|slot| lock_manager.try_lock_row(tid, RecordId::new(page.get_id(), *slot)))
+    }
+
+    // Checks that the transaction may write the tuple version in the slot and returns it.
+    // Writing a version that another transaction deleted after our snapshot was taken,
+    // or that was created by a transaction our snapshot cannot see, is a write-write
+    // conflict
+    fn get_writable_version(
+        page: &HeapPage,
+        slot: usize,
+        snapshot: &Snapshot,
+    ) -> Result<TupleVersion, String> {
+        let version = *page
+            .get_version(slot)
+            .ok_or("Tuple not on this page".to_string())?;
+        if let Some(xmax) = version.get_xmax() {
+            if snapshot.sees(xmax) {
+                return Err("Tuple not on this page".to_string());
             }
-            page_no += 1;
+            return Err("Tuple was concurrently updated by another transaction".to_string());
         }
+        if !snapshot.sees(version.get_xmin()) {
+            return Err("Tuple was concurrently updated by another transaction".to_string());
+        }
+        Ok(version)
     }
 
-    // TODO: Deletes the specified tuple from the file
-    pub fn delete_tuple(&self, tid: TransactionId, tuple: Tuple) {
-        let db = database::get_global_db();
+    // Replaces the stored tuple identified by the tuple's record id with the tuple.
+    // The new version takes over the tuple's slot and keeps its record id, while the old
+    // version is copied to a free slot on the same page for transactions that still see
+    // it. If the page has no room for the copy, the new version moves to another page
+    pub fn update_tuple(&self, tid: TransactionId, tuple: Tuple) -> Result<(), String> {
+        let db = self.get_db();
         let bp = db.get_buffer_pool();
+        let transactions = bp.get_transaction_table();
         let rid = tuple.get_record_id();
-        let pid = rid.get_page_id();
-        let page = bp.get_page(tid, pid, Permission::Write).unwrap();
+        let slot = rid.get_tuple_no();
+        bp.lock_row(tid, rid, true);
+        bp.check_predicate_locks(tid, self.id, &tuple);
+        let snapshot = bp.get_snapshot(tid);
+        let stamp = snapshot.get_stamp();
+        let page = bp.get_page(tid, rid.get_page_id(), Permission::Write)?;
         let mut page_writer = page.write().unwrap();
-        page_writer.delete_tuple(tuple).unwrap();
+        let version = Self::get_writable_version(&page_writer, slot, &snapshot)?;
+
+        if version.get_xmin() == stamp {
+            // nobody else can see our own version, so overwrite it
+            bp.log_undo(tid, &page_writer, slot);
+            page_writer.update_tuple(tuple)?;
+        } else {
+            if page_writer.get_num_empty_slots() == 0 {
+                page_writer.prune(|xmax| transactions.is_dead_to_all(xmax));
+            }
+            let mut old_version = version;
+            old_version.set_xmax(Some(stamp));
+            bp.log_undo(tid, &page_writer, slot);
+            if let Some(copy) = self.claim_free_slot(tid, &page_writer) {
+                let old_tuple = page_writer.get_tuple(slot).clone();
+                bp.log_undo(tid, &page_writer, copy);
+                page_writer.add_tuple_at(copy, old_tuple, version.get_xmin())?;
+                page_writer.set_version(copy, old_version);
+                page_writer.update_tuple(tuple)?;
+                page_writer.set_version(slot, TupleVersion::new(stamp));
+            } else {
+                page_writer.set_version(slot, old_version);
+                page_writer.mark_dirty(true, tid);
+                self.record_free_space(&page_writer);
+                drop(page_writer);
+                return self.add_tuple(tid, tuple);
+            }
+        }
         page_writer.mark_dirty(true, tid);
+        self.record_free_space(&page_writer);
+        Ok(())
     }
 
-    // Retrieves an iterator over the pages in this file
-    pub fn iter(&self, tid: TransactionId) -> HeapFileIterator {
+    // Deletes the tuple identified by the tuple's record id from the file. The version
+    // stays on the page, stamped as deleted, until no transaction can see it anymore
+    pub fn delete_tuple(&self, tid: TransactionId, tuple: Tuple) -> Result<(), String> {
+        let db = self.get_db();
+        let bp = db.get_buffer_pool();
+        let rid = tuple.get_record_id();
+        let slot = rid.get_tuple_no();
+        bp.lock_row(tid, rid, true);
+        let snapshot = bp.get_snapshot(tid);
+        let page = bp.get_page(tid, rid.get_page_id(), Permission::Write)?;
+        let mut page_writer = page.write().unwrap();
+        let mut version = Self::get_writable_version(&page_writer, slot, &snapshot)?;
+        bp.log_undo(tid, &page_writer, slot);
+        if version.get_xmin() == snapshot.get_stamp() {
+            // our own insert was never visible to anyone else
+            page_writer.delete_tuple(tuple)?;
+        } else {
+            version.set_xmax(Some(snapshot.get_stamp()));
+            page_writer.set_version(slot, version);
+        }
+        page_writer.mark_dirty(true, tid);
+        self.record_free_space(&page_writer);
+        Ok(())
+    }
+
+    // Swaps the file of the table for an empty one. The old file is renamed next to it,
+    // along with the record of an uncommitted bulk load into it, so that restore_truncated
+    // can bring it back and a restart after a crash drops the truncate. Callers must hold
+    // an exclusive lock on the table
+    pub fn truncate_file(&self) {
+        let db = self.get_db();
+        let config = db.get_config();
+        let path = table_file_path(config, &self.name);
+        let mut truncated = self.truncated.lock().unwrap();
+        if truncated.is_empty() {
+            rename_if_exists(
+                &bulk_load_marker_path(config, &self.name),
+                &truncated_marker_path(config, &self.name),
+            );
+        }
+        let mut file = self.file.lock().unwrap();
+        fs::rename(
+            &path,
+            truncate_backup_path(config, &self.name, truncated.len()),
+        )
+        .unwrap();
+        let empty = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        sync_parent_dir(&path);
+        let old_file = std::mem::replace(&mut *file, empty);
+        truncated.push((old_file, self.fsm.replace(vec![])));
+        self.fsm.flush();
+    }
+
+    // Brings back the file the table had before its latest truncate, dropping the file
+    // written since
+    pub fn restore_truncated(&self) {
+        let db = self.get_db();
+        let config = db.get_config();
+        let path = table_file_path(config, &self.name);
+        let mut truncated = self.truncated.lock().unwrap();
+        let Some((old_file, free_slots)) = truncated.pop() else {
+            return;
+        };
+        let mut file = self.file.lock().unwrap();
+        fs::rename(
+            truncate_backup_path(config, &self.name, truncated.len()),
+            &path,
+        )
+        .unwrap();
+        if truncated.is_empty() {
+            let marker_path = bulk_load_marker_path(config, &self.name);
+            remove_if_exists(&marker_path);
+            rename_if_exists(&truncated_marker_path(config, &self.name), &marker_path);
+        }
+        sync_parent_dir(&path);
+        *file = old_file;
+        self.fsm.replace(free_slots);
+        self.fsm.flush();
+    }
+
+    // Removes the files kept for restoring the table once the transaction that truncated it
+    // commits
+    pub fn clear_truncated(&self) {
+        let db = self.get_db();
+        let config = db.get_config();
+        let mut truncated = self.truncated.lock().unwrap();
+        for k in 0..truncated.len() {
+            remove_if_exists(&truncate_backup_path(config, &self.name, k));
+        }
+        remove_if_exists(&truncated_marker_path(config, &self.name));
+        sync_parent_dir(&table_file_path(config, &self.name));
+        truncated.clear();
+    }
+
+    // Drops every page from page number num_pages onwards. A file that already has fewer
+    // pages is left as it is
+    pub fn truncate_to(&self, num_pages: usize) {
+        let file = self.file.lock().unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len.min((num_pages * self.page_size) as u64))
+            .unwrap();
+        self.fsm.truncate(num_pages);
+        self.fsm.flush();
+    }
+
+    // Appends the tuples on fresh pages at the end of the file and returns the number of
+    // tuples loaded. Pages are filled in memory and written in batches without going
+    // through the buffer pool, so the table is locked exclusively for the transaction.
+    // The appended pages are dropped again if the transaction aborts
+    pub fn bulk_load<I>(&self, tid: TransactionId, tuples: I) -> usize
+    where
+        I: IntoIterator<Item = Tuple>,
+    {
+        let db = self.get_db();
+        let bp = db.get_buffer_pool();
+        bp.lock_table(tid, self.id);
+        let xmin = bp.get_transaction_table().stamp(tid);
+        let start_page = self.num_pages();
+        bp.register_bulk_load(tid, self.id, start_page);
+        self.mark_bulk_load(start_page);
+
+        let mut count = 0;
+        let mut batch_start = start_page;
+        let mut batch: Vec<u8> = Vec::with_capacity(BULK_LOAD_BATCH_PAGES * self.page_size);
+        let mut page = HeapPage::empty(
+            HeapPageId::new(self.id, start_page),
+            self.td.clone(),
+            self.page_size,
+        );
+        page.set_lsn(xmin);
+        let mut page_empty = true;
+        for tuple in tuples {
+            if page.get_num_empty_slots() == 0 {
+                self.record_free_space(&page);
+                batch.extend(page.get_page_data());
+                let next_pid = HeapPageId::new(self.id, page.get_id().get_page_number() + 1);
+                page = HeapPage::empty(next_pid, self.td.clone(), self.page_size);
+                page.set_lsn(xmin);
+                if batch.len() == BULK_LOAD_BATCH_PAGES * self.page_size {
+                    self.write_pages(batch_start, &batch);
+                    batch_start = next_pid.get_page_number();
+                    batch.clear();
+                }
+            }
+            page.add_tuple(tuple, xmin).unwrap();
+            page_empty = false;
+            count += 1;
+        }
+        if !page_empty {
+            self.record_free_space(&page);
+            batch.extend(page.get_page_data());
+        }
+        if !batch.is_empty() {
+            self.write_pages(batch_start, &batch);
+        }
+        self.fsm.flush();
+        count
+    }
+
+    // Records on disk that the pages from start_page onwards belong to a bulk load that has
+    // not committed, before any of them is written. The epoch of the versions on those
+    // pages counts as committed after a restart, so the pages are dropped on open instead.
+    // An earlier load of the same transaction keeps its lower start page
+    fn mark_bulk_load(&self, start_page: usize) {
+        let path = bulk_load_marker_path(self.get_db().get_config(), &self.name);
+        if path.exists() {
+            return;
+        }
+        let mut marker = File::create(&path).unwrap();
+        marker.write_all(start_page.to_string().as_bytes()).unwrap();
+        marker.sync_all().unwrap();
+        sync_parent_dir(&path);
+    }
+
+    // Removes the record of the bulk loads into the table once their transaction commits
+    // or rolls back
+    pub fn clear_bulk_load(&self) {
+        let path = bulk_load_marker_path(self.get_db().get_config(), &self.name);
+        if remove_if_exists(&path) {
+            sync_parent_dir(&path);
+        }
+    }
+
+    // Writes consecutive pages starting at the specified page number with a single write
+    fn write_pages(&self, start_page: usize, data: &[u8]) {
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start((start_page * self.page_size) as u64))
+            .unwrap();
+        file.write_all(data).unwrap();
+    }
+
+    // Retrieves an iterator over the pages in this file, which yields an error for each
+    // page that cannot be read. The table is locked first, so scans wait for a running
+    // truncate and truncates wait for the transactions that scanned the table
+    pub fn iter(&self, tid: TransactionId) -> HeapFileIterator<'_> {
+        let db = self.get_db();
+        db.get_buffer_pool()
+            .get_lock_manager()
+            .lock_table(tid, self.id, LockMode::IntentionShared);
         HeapFileIterator {
             heap_file: self,
             current_page_index: 0,
@@ -117,7 +530,8 @@ impl HeapFile {
     }
 
     // Retrieves an iterator over the pages in this file
-    pub fn iter_mut(&self, tid: TransactionId) -> HeapFileIteratorMut {
+    #[allow(dead_code)]
+    pub fn iter_mut(&self, tid: TransactionId) -> HeapFileIteratorMut<'_> {
         HeapFileIteratorMut {
             heap_file: self,
             current_page_index: 0,
@@ -126,6 +540,100 @@ impl HeapFile {
     }
 }
 
+// Retrieves the file that stores the pages of a table
+pub fn table_file_path(config: &DatabaseConfig, table_name: &str) -> PathBuf {
+    config.get_file_path(&format!("{}.dat", table_name))
+}
+
+// Retrieves the file that keeps the pages a table had before the k-th truncate of the
+// running transaction. The first one holds the committed pages
+pub fn truncate_backup_path(config: &DatabaseConfig, table_name: &str, k: usize) -> PathBuf {
+    config.get_file_path(&format!("{}.truncated.{}", table_name, k))
+}
+
+// Retrieves the file that records the uncommitted bulk load into the pages a table had
+// before the running transaction truncated it
+fn truncated_marker_path(config: &DatabaseConfig, table_name: &str) -> PathBuf {
+    config.get_file_path(&format!("{}.truncated.load", table_name))
+}
+
+// Retrieves the file that records the uncommitted bulk load into a table, if there is one
+pub fn bulk_load_marker_path(config: &DatabaseConfig, table_name: &str) -> PathBuf {
+    config.get_file_path(&format!("{}.load", table_name))
+}
+
+// Drops the pages of a bulk load that had not committed when the database stopped, as
+// recorded by the marker file, and removes the marker
+pub fn recover_bulk_load(file: &File, marker_path: &Path, page_size: usize) -> Result<(), String> {
+    let error = |e: std::io::Error| format!("Cannot recover {}: {}", marker_path.display(), e);
+    let start_page = match fs::read_to_string(marker_path) {
+        Ok(start_page) => start_page,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(error(e)),
+    };
+    let start_page: u64 = start_page
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid bulk load marker {}", marker_path.display()))?;
+    let len = file.metadata().map_err(error)?.len();
+    file.set_len(len.min(start_page * page_size as u64))
+        .and_then(|_| file.sync_all())
+        .map_err(error)?;
+    fs::remove_file(marker_path).map_err(error)?;
+    sync_parent_dir(marker_path);
+    Ok(())
+}
+
+// Brings back the file of a table whose truncate had not committed when the database
+// stopped, together with the record of the bulk load into it, and removes the files written
+// since. Runs before the table's files are opened
+pub fn recover_truncate(config: &DatabaseConfig, table_name: &str) -> Result<(), String> {
+    let backup = truncate_backup_path(config, table_name, 0);
+    if !backup.exists() {
+        return Ok(());
+    }
+    let path = table_file_path(config, table_name);
+    let error = |e: std::io::Error| format!("Cannot recover {}: {}", backup.display(), e);
+    let mut k = 1;
+    while truncate_backup_path(config, table_name, k).exists() {
+        fs::remove_file(truncate_backup_path(config, table_name, k)).map_err(error)?;
+        k += 1;
+    }
+    fs::rename(&backup, &path).map_err(error)?;
+    let marker_path = bulk_load_marker_path(config, table_name);
+    remove_if_exists(&marker_path);
+    rename_if_exists(&truncated_marker_path(config, table_name), &marker_path);
+    sync_parent_dir(&path);
+    Ok(())
+}
+
+// Removes the file and tells whether it existed
+fn remove_if_exists(path: &Path) -> bool {
+    match fs::remove_file(path) {
+        Ok(()) => true,
+        Err(e) if e.kind() == ErrorKind::NotFound => false,
+        Err(e) => panic!("Cannot remove {}: {}", path.display(), e),
+    }
+}
+
+// Renames the file if it exists
+fn rename_if_exists(from: &Path, to: &Path) {
+    match fs::rename(from, to) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => panic!("Cannot rename {}: {}", from.display(), e),
+    }
+}
+
+// Makes the creation or removal of a file in the data directory durable
+pub fn sync_parent_dir(path: &Path) {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    File::open(dir).and_then(|dir| dir.sync_all()).unwrap();
+}
+
 pub struct HeapFileIterator<'a> {
     heap_file: &'a HeapFile,
     current_page_index: usize,
@@ -133,22 +641,22 @@ pub struct HeapFileIterator<'a> {
 }
 
 impl<'a> Iterator for HeapFileIterator<'a> {
-    type Item = Arc<RwLock<HeapPage>>;
+    type Item = Result<Arc<RwLock<HeapPage>>, String>;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.current_page_index < self.heap_file.num_pages() {
             let pid = HeapPageId::new(self.heap_file.get_id(), self.current_page_index);
-            let db = database::get_global_db();
+            let db = self.heap_file.get_db();
             let bp = db.get_buffer_pool();
-            let page = bp.get_page(self.tid, pid, Permission::Read).unwrap();
             self.current_page_index += 1;
-            Some(page)
+            Some(bp.get_page(self.tid, pid, Permission::Read))
         } else {
             None
         }
     }
 }
 
+#[allow(dead_code)]
 pub struct HeapFileIteratorMut<'a> {
     heap_file: &'a HeapFile,
     current_page_index: usize,
@@ -156,16 +664,15 @@ pub struct HeapFileIteratorMut<'a> {
 }
 
 impl<'a> Iterator for HeapFileIteratorMut<'a> {
-    type Item = Arc<RwLock<HeapPage>>;
+    type Item = Result<Arc<RwLock<HeapPage>>, String>;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.current_page_index < self.heap_file.num_pages() {
             let pid = HeapPageId::new(self.heap_file.get_id(), self.current_page_index);
-            let db = database::get_global_db();
+            let db = self.heap_file.get_db();
             let bp = db.get_buffer_pool();
-            let page = bp.get_page(self.tid, pid, Permission::Write).unwrap();
             self.current_page_index += 1;
-            Some(page)
+            Some(bp.get_page(self.tid, pid, Permission::Write))
         } else {
             None
         }
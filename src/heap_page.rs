@@ -1,6 +1,8 @@
-use crate::buffer_pool::PAGE_SIZE;
+use crate::checksum;
+use crate::mvcc::{Snapshot, TupleVersion, VERSION_SIZE};
 use crate::transaction::TransactionId;
-use crate::tuple::{Tuple, TupleDesc};
+use crate::tuple::{RecordId, Tuple, TupleDesc};
+use std::ops::Range;
 
 #[derive(Debug, PartialEq, Eq, Clone, Hash, Copy)]
 pub enum Permission {
@@ -31,85 +33,210 @@ impl HeapPageId {
         self.page_number
     }
 
+    #[allow(dead_code)]
     pub fn serialize(&self) -> Vec<usize> {
         vec![self.table_id, self.page_number]
     }
 }
 
+// Number of bytes of the header at the start of every page
+pub const PAGE_HEADER_SIZE: usize = 16;
+// Version of the page layout, stored in the page header
+pub const PAGE_FORMAT_VERSION: u16 = 1;
+
 /**
  * Representation for a set of bytes of data read from disk.
- * Format is header bytes + tuple bytes. Header bytes indicate
- * whether or not a tuple is present in that slot on the page.
- * The number of bytes for header is equal to ceiling(# tuple slots / 8)
+ * Format is page header + slot bitmap + tuple slots. The page header holds a CRC-32C
+ * checksum of the rest of the page and its page number (4 bytes), the format version
+ * (2 bytes, then 2 reserved bytes) and the LSN of the page (8 bytes).
+ * Bitmap bytes indicate whether or not a tuple is present in that slot on the page.
+ * The number of bytes for the bitmap is equal to ceiling(# tuple slots / 8)
+ * Each slot holds the version stamps of the tuple followed by the tuple bytes.
+ * A page of only zeros has never been written and holds no tuples.
  */
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct HeapPage {
     pid: HeapPageId,
     td: TupleDesc,
+    // sequence number of the last change written to the page
+    lsn: u64,
     header_size: usize,
     header: Vec<u8>,
     tuples: Vec<Tuple>,
+    versions: Vec<TupleVersion>,
     num_slots: usize,
-    old_data: Vec<u8>,
+    page_size: usize,
     dirtied_by: Option<TransactionId>,
 }
 
 impl HeapPage {
-    pub fn new(pid: HeapPageId, data: Vec<u8>, td: TupleDesc) -> Self {
-        let num_slots = (PAGE_SIZE * 8) / (td.get_size() * 8 + 1);
-        let old_data = vec![0; PAGE_SIZE];
+    // Reads the page from its bytes, which make up a whole page. Fails if the checksum
+    // does not match, as after a torn write, or the page cannot be parsed
+    pub fn new(pid: HeapPageId, data: Vec<u8>, td: TupleDesc) -> Result<Self, String> {
+        let page_size = data.len();
+        if data.iter().all(|byte| *byte == 0) {
+            return Ok(Self::empty(pid, td, page_size));
+        }
+        let lsn = Self::verify(pid, &data)?;
+        let num_slots = Self::slots_per_page(&td, page_size);
 
         let header_size = (num_slots as f64 / 8.0).ceil() as usize;
-        let header = data[..header_size].to_vec();
+        let header = data[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + header_size].to_vec();
 
         let mut tuples = vec![];
+        let mut versions = vec![];
+        let slot_size = Self::slot_size(&td);
 
         for i in 0..num_slots {
-            if Self::get_slot(&header, i) {
-                let start = header_size + i * td.get_size();
-                let end = start + td.get_size();
-                let tuple_data = data[start..end].to_vec();
-                tuples.push(Tuple::deserialize(&tuple_data, &td));
+            let start = PAGE_HEADER_SIZE + header_size + i * slot_size;
+            let mut tuple = if Self::get_slot(&header, i) {
+                versions.push(TupleVersion::deserialize(&data[start..]));
+                let tuple_data = &data[start + VERSION_SIZE..start + slot_size];
+                Tuple::deserialize(tuple_data, &td)
+                    .map_err(|e| format!("slot {} is unreadable: {}", i, e))?
             } else {
-                tuples.push(Tuple::new(vec![], &td));
-            }
+                versions.push(TupleVersion::new(0));
+                Tuple::new(vec![], &td)
+            };
+            tuple.set_record_id(RecordId::new(pid, i));
+            tuples.push(tuple);
         }
 
-        HeapPage {
+        Ok(HeapPage {
             pid,
             td,
+            lsn,
             header_size,
             header,
             tuples,
+            versions,
+            num_slots,
+            page_size,
+            dirtied_by: None,
+        })
+    }
+
+    // Makes a page of page_size bytes without any tuples
+    pub fn empty(pid: HeapPageId, td: TupleDesc, page_size: usize) -> Self {
+        let num_slots = Self::slots_per_page(&td, page_size);
+        let header_size = (num_slots as f64 / 8.0).ceil() as usize;
+        let tuples = (0..num_slots)
+            .map(|i| {
+                let mut tuple = Tuple::new(vec![], &td);
+                tuple.set_record_id(RecordId::new(pid, i));
+                tuple
+            })
+            .collect();
+        HeapPage {
+            pid,
+            lsn: 0,
+            header_size,
+            header: vec![0; header_size],
+            tuples,
+            versions: vec![TupleVersion::new(0); num_slots],
             num_slots,
-            old_data,
+            page_size,
             dirtied_by: None,
+            td,
+        }
+    }
+
+    // Checks the format version and checksum in the page header and returns the LSN
+    pub fn verify(pid: HeapPageId, data: &[u8]) -> Result<u64, String> {
+        if data.len() < PAGE_HEADER_SIZE {
+            return Err(format!("page has only {} bytes", data.len()));
+        }
+        let version = u16::from_be_bytes([data[4], data[5]]);
+        if version != PAGE_FORMAT_VERSION {
+            return Err(format!("unknown page format version {}", version));
+        }
+        let stored = u32::from_be_bytes(data[0..4].try_into().unwrap());
+        let computed = Self::checksum(pid, data);
+        if stored != computed {
+            return Err(format!(
+                "checksum mismatch, stored {:08x} but computed {:08x}",
+                stored, computed
+            ));
+        }
+        Ok(u64::from_be_bytes(data[8..16].try_into().unwrap()))
+    }
+
+    // Checksum of the page bytes after the checksum field, seeded with the page number
+    // so a page written to the wrong place is detected as well
+    fn checksum(pid: HeapPageId, data: &[u8]) -> u32 {
+        let page_number = (pid.get_page_number() as u64).to_be_bytes();
+        checksum::crc32c_update(checksum::crc32c(&page_number), &data[4..])
+    }
+
+    // Number of tuple slots that fit on a page of page_size bytes after the page header,
+    // accounting for one bitmap bit per slot
+    pub fn slots_per_page(td: &TupleDesc, page_size: usize) -> usize {
+        (page_size.saturating_sub(PAGE_HEADER_SIZE) * 8) / (Self::slot_size(td) * 8 + 1)
+    }
+
+    // Checks that a page of page_size bytes holds the header, the slot bitmap and at least
+    // one tuple with its version stamps
+    pub fn check_page_size(td: &TupleDesc, page_size: usize) -> Result<(), String> {
+        if Self::slots_per_page(td, page_size) == 0 {
+            return Err(format!(
+                "Page size {} cannot hold a tuple of {} bytes, which needs pages of at least {} bytes",
+                page_size,
+                td.get_size(),
+                PAGE_HEADER_SIZE + 1 + Self::slot_size(td)
+            ));
         }
+        Ok(())
+    }
+
+    // Byte range of the slot bitmap on a page of page_size bytes
+    pub fn bitmap_range(td: &TupleDesc, page_size: usize) -> Range<usize> {
+        let num_slots = Self::slots_per_page(td, page_size);
+        PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + num_slots.div_ceil(8)
+    }
+
+    // Offset of the slot on a page of page_size bytes, where its version stamps start
+    pub fn slot_offset(td: &TupleDesc, page_size: usize, slot: usize) -> usize {
+        Self::bitmap_range(td, page_size).end + slot * Self::slot_size(td)
+    }
+
+    // Number of bytes taken by one slot, a tuple and its version stamps
+    pub fn slot_size(td: &TupleDesc) -> usize {
+        VERSION_SIZE + td.get_size()
     }
 
     pub fn get_id(&self) -> HeapPageId {
         self.pid
     }
 
-    pub fn get_before_image(&self) -> HeapPage {
-        HeapPage::new(self.pid, self.old_data.clone(), self.td.clone())
+    // Sequence number of the last change written to the page, the stamp of the last
+    // transaction that committed changes to it
+    #[allow(dead_code)]
+    pub fn get_lsn(&self) -> u64 {
+        self.lsn
     }
 
-    pub fn set_before_image(&mut self) {
-        self.old_data = self.get_page_data();
+    pub fn set_lsn(&mut self, lsn: u64) {
+        self.lsn = lsn;
     }
 
+    // Serializes the page, filling in the checksum of the page header
     pub fn get_page_data(&self) -> Vec<u8> {
-        let mut data = self.header.clone();
+        let mut data = vec![0; PAGE_HEADER_SIZE];
+        data[4..6].copy_from_slice(&PAGE_FORMAT_VERSION.to_be_bytes());
+        data[8..16].copy_from_slice(&self.lsn.to_be_bytes());
+        data.extend(&self.header);
         for i in 0..self.num_slots {
             if Self::get_slot(&self.header, i) {
+                data.extend(self.versions[i].serialize());
                 data.extend(self.tuples[i].serialize());
             } else {
-                data.extend(vec![0; self.td.get_size()]);
+                data.extend(vec![0; Self::slot_size(&self.td)]);
             }
         }
         // pad the rest of the page with 0s
-        data.extend(vec![0; PAGE_SIZE - data.len()]);
+        data.extend(vec![0; self.page_size - data.len()]);
+        let checksum = Self::checksum(self.pid, &data);
+        data[0..4].copy_from_slice(&checksum.to_be_bytes());
         data
     }
 
@@ -136,21 +263,64 @@ impl HeapPage {
         }
     }
 
+    #[allow(dead_code)]
     fn create_empty_page_data(&self) -> Vec<u8> {
-        vec![0; PAGE_SIZE]
+        vec![0; self.page_size]
     }
 
-    pub fn add_tuple(&mut self, t: Tuple) -> Result<(), String> {
-        let mut i = 0;
-        while i < self.num_slots {
-            if !Self::get_slot(&self.header, i) {
-                self.tuples[i] = t;
+    // Adds the tuple as a version created by the transaction with the stamp xmin
+    pub fn add_tuple(&mut self, t: Tuple, xmin: u64) -> Result<RecordId, String> {
+        let slot = self.get_free_slots().next().ok_or("No empty slots")?;
+        self.add_tuple_at(slot, t, xmin)
+    }
+
+    // Adds the tuple to the specified empty slot as a version created with the stamp xmin
+    pub fn add_tuple_at(
+        &mut self,
+        slot: usize,
+        mut t: Tuple,
+        xmin: u64,
+    ) -> Result<RecordId, String> {
+        if slot >= self.num_slots || Self::get_slot(&self.header, slot) {
+            return Err("Slot is not empty".to_string());
+        }
+        let rid = RecordId::new(self.pid, slot);
+        t.set_record_id(rid);
+        self.tuples[slot] = t;
+        self.versions[slot] = TupleVersion::new(xmin);
+        Self::set_slot(&mut self.header, slot, true);
+        Ok(rid)
+    }
+
+    // Iterates over the numbers of the empty slots
+    pub fn get_free_slots(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.num_slots).filter(|i| !Self::get_slot(&self.header, *i))
+    }
+
+    // Retrieves the version stamps and tuple stored in the slot, or None if it is empty
+    pub fn get_slot_image(&self, i: usize) -> Option<(TupleVersion, Tuple)> {
+        self.get_version(i)
+            .map(|version| (*version, self.tuples[i].clone()))
+    }
+
+    // Replaces the contents of the slot with an image from get_slot_image
+    pub fn set_slot_image(&mut self, i: usize, image: Option<(TupleVersion, Tuple)>) {
+        let rid = RecordId::new(self.pid, i);
+        match image {
+            Some((version, mut tuple)) => {
+                tuple.set_record_id(rid);
+                self.tuples[i] = tuple;
+                self.versions[i] = version;
                 Self::set_slot(&mut self.header, i, true);
-                return Ok(());
             }
-            i += 1;
+            None => {
+                let mut empty = Tuple::new(vec![], &self.td);
+                empty.set_record_id(rid);
+                self.tuples[i] = empty;
+                self.versions[i] = TupleVersion::new(0);
+                Self::set_slot(&mut self.header, i, false);
+            }
         }
-        Err("No empty slots".to_string())
     }
 
     pub fn delete_tuple(&mut self, t: Tuple) -> Result<(), String> {
@@ -163,11 +333,68 @@ impl HeapPage {
             return Err("Tuple not on this page".to_string());
         }
 
-        self.tuples[tuple_no] = Tuple::new(vec![], &self.td);
+        let mut empty = Tuple::new(vec![], &self.td);
+        empty.set_record_id(rid);
+        self.tuples[tuple_no] = empty;
         Self::set_slot(&mut self.header, tuple_no, false);
         Ok(())
     }
 
+    // Overwrites the tuple in the slot given by its record id, keeping its version stamps.
+    // Tuples are fixed width, so the new tuple always fits and keeps its record id
+    pub fn update_tuple(&mut self, t: Tuple) -> Result<(), String> {
+        let rid = t.get_record_id();
+        let tuple_no = rid.get_tuple_no();
+        if rid.get_page_id() != self.pid || !Self::get_slot(&self.header, tuple_no) {
+            return Err("Tuple not on this page".to_string());
+        }
+        if t.get_tuple_desc() != &self.td {
+            return Err("Tuple does not match the page's tuple descriptor".to_string());
+        }
+        self.tuples[tuple_no] = t;
+        Ok(())
+    }
+
+    // Retrieves the version stamps of the tuple in the slot, if the slot is in use
+    pub fn get_version(&self, i: usize) -> Option<&TupleVersion> {
+        if Self::get_slot(&self.header, i) {
+            Some(&self.versions[i])
+        } else {
+            None
+        }
+    }
+
+    // Replaces the version stamps of the tuple in the slot
+    pub fn set_version(&mut self, i: usize, version: TupleVersion) {
+        self.versions[i] = version;
+    }
+
+    // Frees the slots of versions deleted by transactions for which is_dead returns true
+    // and returns the number of slots freed
+    pub fn prune<F: Fn(u64) -> bool>(&mut self, is_dead: F) -> usize {
+        let mut count = 0;
+        for i in 0..self.num_slots {
+            if !Self::get_slot(&self.header, i) {
+                continue;
+            }
+            if self.versions[i].get_xmax().is_some_and(&is_dead) {
+                let mut empty = Tuple::new(vec![], &self.td);
+                empty.set_record_id(RecordId::new(self.pid, i));
+                self.tuples[i] = empty;
+                Self::set_slot(&mut self.header, i, false);
+                count += 1;
+            }
+        }
+        count
+    }
+
+    // Number of slots that are empty or hold a deleted version that may be pruned
+    pub fn get_num_reusable_slots(&self) -> usize {
+        (0..self.num_slots)
+            .filter(|i| !Self::get_slot(&self.header, *i) || self.versions[*i].get_xmax().is_some())
+            .count()
+    }
+
     pub fn get_num_empty_slots(&self) -> usize {
         let mut count = 0;
         for i in 0..self.num_slots {
@@ -186,14 +413,27 @@ impl HeapPage {
         }
     }
 
+    #[allow(dead_code)]
     pub fn is_dirty(&self) -> bool {
         self.dirtied_by.is_some()
     }
 
-    pub fn iter(&self) -> HeapPageIterator {
+    // Iterates over every stored tuple version, whether or not it is visible
+    #[allow(dead_code)]
+    pub fn iter(&self) -> HeapPageIterator<'_> {
         HeapPageIterator {
             page: self,
             index: 0,
+            snapshot: None,
+        }
+    }
+
+    // Iterates over the tuples visible to the snapshot
+    pub fn iter_visible<'a>(&'a self, snapshot: &'a Snapshot) -> HeapPageIterator<'a> {
+        HeapPageIterator {
+            page: self,
+            index: 0,
+            snapshot: Some(snapshot),
         }
     }
 
@@ -210,6 +450,7 @@ impl HeapPage {
 pub struct HeapPageIterator<'a> {
     page: &'a HeapPage,
     index: usize,
+    snapshot: Option<&'a Snapshot>,
 }
 
 impl<'a> Iterator for HeapPageIterator<'a> {
@@ -220,7 +461,10 @@ impl<'a> Iterator for HeapPageIterator<'a> {
             return None;
         }
         while self.index < self.page.num_slots {
-            if HeapPage::get_slot(&self.page.header, self.index) {
+            let visible = self
+                .snapshot
+                .is_none_or(|snapshot| snapshot.is_visible(&self.page.versions[self.index]));
+            if HeapPage::get_slot(&self.page.header, self.index) && visible {
                 let tuple = &self.page.tuples[self.index];
                 self.index += 1;
                 return Some(tuple);
@@ -230,3 +474,71 @@ impl<'a> Iterator for HeapPageIterator<'a> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fields::{FieldVal, IntField, StringField};
+    use crate::types::Type;
+
+    fn make_page() -> HeapPage {
+        let td = TupleDesc::new(
+            vec![Type::IntType, Type::StringType],
+            vec!["id".to_string(), "name".to_string()],
+        );
+        let mut page = HeapPage::empty(HeapPageId::new(1, 3), td.clone(), 4096);
+        let tuple = Tuple::new(
+            vec![
+                FieldVal::IntField(IntField::new(7)),
+                FieldVal::StringField(StringField::new("Alice".to_string(), 5)),
+            ],
+            &td,
+        );
+        page.add_tuple(tuple, 42).unwrap();
+        page.set_lsn(42);
+        page
+    }
+
+    #[test]
+    fn test_page_header_round_trip() {
+        let page = make_page();
+        let data = page.get_page_data();
+        assert_eq!(data.len(), 4096);
+        let read = HeapPage::new(page.get_id(), data, page.td.clone()).unwrap();
+        assert_eq!(read.get_lsn(), 42);
+        assert_eq!(read.get_tuple(0), page.get_tuple(0));
+        // pages that were never written read as empty
+        let empty = HeapPage::new(page.get_id(), vec![0; 4096], page.td.clone()).unwrap();
+        assert_eq!(empty.get_num_empty_slots(), empty.num_tuples());
+    }
+
+    #[test]
+    fn test_corrupted_pages_are_detected() {
+        let page = make_page();
+        let td = page.td.clone();
+
+        // a torn write leaves part of the old page behind
+        let mut torn = page.get_page_data();
+        torn[2048..].fill(0xAB);
+        let error = HeapPage::new(page.get_id(), torn, td.clone()).unwrap_err();
+        assert!(error.contains("checksum mismatch"));
+
+        // a page written to the wrong place fails the checksum as well
+        let moved = HeapPage::new(HeapPageId::new(1, 4), page.get_page_data(), td.clone());
+        assert!(moved.is_err());
+
+        let mut version = page.get_page_data();
+        version[5] = 9;
+        let error = HeapPage::new(page.get_id(), version, td.clone()).unwrap_err();
+        assert_eq!(error, "unknown page format version 9");
+
+        // bytes that match the checksum but cannot be parsed are reported, not panicked on
+        let mut invalid = page.get_page_data();
+        let name = PAGE_HEADER_SIZE + page.header_size + VERSION_SIZE + 4;
+        invalid[name + 4] = 0xFF;
+        let checksum = HeapPage::checksum(page.get_id(), &invalid);
+        invalid[0..4].copy_from_slice(&checksum.to_be_bytes());
+        let error = HeapPage::new(page.get_id(), invalid, td).unwrap_err();
+        assert!(error.contains("slot 0 is unreadable"));
+    }
+}
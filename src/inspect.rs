@@ -0,0 +1,396 @@
+use crate::catalog;
+use crate::database::{DatabaseConfig, CATALOG_FILE};
+use crate::heap_file::HeapFile;
+use crate::heap_page::{HeapPage, HeapPageId, PAGE_HEADER_SIZE};
+use crate::json;
+use crate::mvcc::{TupleVersion, VERSION_SIZE};
+use crate::tuple::{RecordId, Tuple, TupleDesc};
+use std::fmt;
+use std::fs;
+use std::ops::Range;
+
+// Whether a page matched its checksum when it was inspected
+#[derive(Debug, Clone, PartialEq)]
+pub enum PageStatus {
+    // all zeros, the page was never written
+    Unwritten,
+    Valid,
+    // the header or checksum is wrong, so the decoded contents may be garbage
+    Corrupt(String),
+    // the file ends in the middle of the page
+    Partial,
+}
+
+// Decoded slot of an inspected page
+#[derive(Debug, Clone)]
+pub struct SlotInfo {
+    record_id: RecordId,
+    version: TupleVersion,
+    // the tuple, or why it could not be parsed
+    tuple: Result<Tuple, String>,
+}
+
+impl SlotInfo {
+    #[allow(dead_code)]
+    pub fn get_record_id(&self) -> RecordId {
+        self.record_id
+    }
+
+    #[allow(dead_code)]
+    pub fn get_version(&self) -> TupleVersion {
+        self.version
+    }
+
+    #[allow(dead_code)]
+    pub fn get_tuple(&self) -> Result<&Tuple, &String> {
+        self.tuple.as_ref()
+    }
+}
+
+// Decoded page of a heap file, including the parts a damaged page gets wrong
+#[derive(Debug, Clone)]
+pub struct PageInfo {
+    page_number: usize,
+    status: PageStatus,
+    stored_checksum: u32,
+    format_version: u16,
+    lsn: u64,
+    num_slots: usize,
+    // one bit per slot, set if the slot is in use
+    bitmap: Vec<bool>,
+    // number of bits set past the last slot
+    extra_bits: usize,
+    // occupied slots
+    slots: Vec<SlotInfo>,
+    // bytes of empty slots and unused bytes at the end of the page
+    free_bytes: usize,
+}
+
+impl PageInfo {
+    #[allow(dead_code)]
+    pub fn get_page_number(&self) -> usize {
+        self.page_number
+    }
+
+    #[allow(dead_code)]
+    pub fn get_status(&self) -> &PageStatus {
+        &self.status
+    }
+
+    #[allow(dead_code)]
+    pub fn get_lsn(&self) -> u64 {
+        self.lsn
+    }
+
+    #[allow(dead_code)]
+    pub fn get_bitmap(&self) -> &[bool] {
+        &self.bitmap
+    }
+
+    #[allow(dead_code)]
+    pub fn get_slots(&self) -> &[SlotInfo] {
+        &self.slots
+    }
+
+    pub fn get_num_free_slots(&self) -> usize {
+        self.num_slots - self.slots.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn get_free_bytes(&self) -> usize {
+        self.free_bytes
+    }
+
+    // Describes the page as a JSON object
+    pub fn to_json(&self) -> String {
+        let status = match &self.status {
+            PageStatus::Unwritten => "\"unwritten\"".to_string(),
+            PageStatus::Valid => "\"valid\"".to_string(),
+            PageStatus::Corrupt(reason) => format!("\"corrupt\",\"error\":{}", json::quote(reason)),
+            PageStatus::Partial => "\"partial\"".to_string(),
+        };
+        let bitmap: String = self
+            .bitmap
+            .iter()
+            .map(|used| if *used { '1' } else { '0' })
+            .collect();
+        let slots: Vec<String> = self.slots.iter().map(slot_to_json).collect();
+        format!(
+            concat!(
+                "{{\"page\":{},\"status\":{},\"checksum\":\"{:08x}\",",
+                "\"format_version\":{},\"lsn\":{},\"num_slots\":{},\"bitmap\":\"{}\",",
+                "\"extra_bits\":{},\"free_slots\":{},\"free_bytes\":{},\"slots\":[{}]}}"
+            ),
+            self.page_number,
+            status,
+            self.stored_checksum,
+            self.format_version,
+            self.lsn,
+            self.num_slots,
+            bitmap,
+            self.extra_bits,
+            self.get_num_free_slots(),
+            self.free_bytes,
+            slots.join(",")
+        )
+    }
+}
+
+impl fmt::Display for PageInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match &self.status {
+            PageStatus::Unwritten => "never written".to_string(),
+            PageStatus::Valid => format!("checksum ok ({:08x})", self.stored_checksum),
+            PageStatus::Corrupt(reason) => format!("CORRUPT: {}", reason),
+            PageStatus::Partial => "PARTIAL".to_string(),
+        };
+        writeln!(
+            f,
+            "Page {}: {}, format version {}, lsn {}",
+            self.page_number, status, self.format_version, self.lsn
+        )?;
+        writeln!(
+            f,
+            "  {} of {} slots used, {} free slots, {} free bytes",
+            self.slots.len(),
+            self.num_slots,
+            self.get_num_free_slots(),
+            self.free_bytes
+        )?;
+        let bits: Vec<String> = self
+            .bitmap
+            .chunks(8)
+            .map(|byte| {
+                byte.iter()
+                    .map(|used| if *used { '1' } else { '0' })
+                    .collect()
+            })
+            .collect();
+        write!(f, "  bitmap {}", bits.join(" "))?;
+        if self.extra_bits > 0 {
+            write!(f, " ({} bits set past the last slot)", self.extra_bits)?;
+        }
+        for slot in self.slots.iter() {
+            let version = &slot.version;
+            let xmax = version
+                .get_xmax()
+                .map_or("-".to_string(), |xmax| xmax.to_string());
+            let tuple = match &slot.tuple {
+                Ok(tuple) => tuple.to_string(),
+                Err(error) => format!("<{}>", error),
+            };
+            write!(
+                f,
+                "\n  record (page {}, slot {}) xmin {} xmax {}: {}",
+                slot.record_id.get_page_id().get_page_number(),
+                slot.record_id.get_tuple_no(),
+                version.get_xmin(),
+                xmax,
+                tuple
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Decodes the bytes of a page without trusting them: the header, bitmap and slots are
+// read even if the checksum does not match
+pub fn inspect_page(pid: HeapPageId, data: &[u8], td: &TupleDesc, page_size: usize) -> PageInfo {
+    let num_slots = HeapPage::slots_per_page(td, page_size);
+    let status = if data.len() < page_size {
+        PageStatus::Partial
+    } else if data.iter().all(|byte| *byte == 0) {
+        PageStatus::Unwritten
+    } else {
+        match HeapPage::verify(pid, data) {
+            Ok(_) => PageStatus::Valid,
+            Err(reason) => PageStatus::Corrupt(reason),
+        }
+    };
+    // decode a partial page as if the missing bytes were zeros
+    let mut page = data.to_vec();
+    page.resize(page_size, 0);
+
+    let bitmap_bytes = &page[HeapPage::bitmap_range(td, page_size)];
+    let is_used = |slot: usize| bitmap_bytes[slot / 8] & (1 << (slot % 8)) != 0;
+    let bitmap: Vec<bool> = (0..num_slots).map(is_used).collect();
+    let extra_bits = (num_slots..bitmap_bytes.len() * 8)
+        .filter(|slot| is_used(*slot))
+        .count();
+    let slot_size = HeapPage::slot_size(td);
+    let slots: Vec<SlotInfo> = (0..num_slots)
+        .filter(|slot| bitmap[*slot])
+        .map(|slot| {
+            let start = HeapPage::slot_offset(td, page_size, slot);
+            let tuple_data = &page[start + VERSION_SIZE..start + slot_size];
+            let record_id = RecordId::new(pid, slot);
+            SlotInfo {
+                record_id,
+                version: TupleVersion::deserialize(&page[start..]),
+                tuple: Tuple::deserialize(tuple_data, td).map(|mut tuple| {
+                    tuple.set_record_id(record_id);
+                    tuple
+                }),
+            }
+        })
+        .collect();
+    let used_bytes = HeapPage::slot_offset(td, page_size, slots.len());
+    PageInfo {
+        page_number: pid.get_page_number(),
+        status,
+        stored_checksum: u32::from_be_bytes(page[0..4].try_into().unwrap()),
+        format_version: u16::from_be_bytes([page[4], page[5]]),
+        lsn: u64::from_be_bytes(page[8..PAGE_HEADER_SIZE].try_into().unwrap()),
+        num_slots,
+        bitmap,
+        extra_bits,
+        slots,
+        free_bytes: page_size - used_bytes,
+    }
+}
+
+// Decodes the pages of the heap file in the range as they are on disk, bypassing the
+// buffer pool, so changes of running transactions are not included
+#[allow(dead_code)]
+pub fn inspect_heap_file(
+    heap_file: &HeapFile,
+    pages: Range<usize>,
+) -> Result<Vec<PageInfo>, String> {
+    let page_size = heap_file.get_page_size();
+    pages
+        .map(|page_number| {
+            let data = heap_file.read_page_data(page_number)?;
+            let pid = HeapPageId::new(heap_file.get_id(), page_number);
+            Ok(inspect_page(
+                pid,
+                &data,
+                heap_file.get_tuple_desc(),
+                page_size,
+            ))
+        })
+        .collect()
+}
+
+/**
+ * Decodes the pages of a table in the range straight from the files in the data directory
+ * of the config, without opening the database: the schema comes from the catalog file and
+ * the pages from the table's file. Nothing is locked or written, so the directory may be
+ * open in a database, whose uncommitted changes are not on disk. Without a range, every
+ * page of the file is decoded.
+ */
+pub fn inspect_table(
+    config: &DatabaseConfig,
+    table_name: &str,
+    pages: Option<Range<usize>>,
+) -> Result<Vec<PageInfo>, String> {
+    let path = config.get_file_path(CATALOG_FILE);
+    let contents = fs::read_to_string(&path)
+        .map_err(|e| format!("Cannot read catalog {}: {}", path.display(), e))?;
+    let td = contents
+        .lines()
+        .filter_map(|line| catalog::parse_schema_line(line).ok())
+        .find(|(name, _)| name == table_name)
+        .map(|(_, td)| td)
+        .ok_or(format!("No table named {}", table_name))?;
+    let path = config.get_file_path(&format!("{}.dat", table_name));
+    let data = fs::read(&path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    let page_size = config.get_page_size();
+    let chunks: Vec<&[u8]> = data.chunks(page_size).collect();
+    pages
+        .unwrap_or(0..chunks.len())
+        .map(|page_number| {
+            let page = chunks
+                .get(page_number)
+                .ok_or(format!("Table {} has no page {}", table_name, page_number))?;
+            let pid = HeapPageId::new(0, page_number);
+            Ok(inspect_page(pid, page, &td, page_size))
+        })
+        .collect()
+}
+
+// Describes the inspected pages of a table as a JSON object
+pub fn to_json(table_name: &str, pages: &[PageInfo]) -> String {
+    let pages: Vec<String> = pages.iter().map(PageInfo::to_json).collect();
+    format!(
+        "{{\"table\":{},\"pages\":[{}]}}",
+        json::quote(table_name),
+        pages.join(",")
+    )
+}
+
+fn slot_to_json(slot: &SlotInfo) -> String {
+    let xmax = slot
+        .version
+        .get_xmax()
+        .map_or("null".to_string(), |xmax| xmax.to_string());
+    let tuple = match &slot.tuple {
+        Ok(tuple) => format!("\"tuple\":{}", json::tuple_to_json(tuple)),
+        Err(error) => format!("\"error\":{}", json::quote(error)),
+    };
+    format!(
+        "{{\"record_id\":{{\"page\":{},\"slot\":{}}},\"xmin\":{},\"xmax\":{},{}}}",
+        slot.record_id.get_page_id().get_page_number(),
+        slot.record_id.get_tuple_no(),
+        slot.version.get_xmin(),
+        xmax,
+        tuple
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fields::{FieldVal, IntField, StringField};
+    use crate::types::Type;
+
+    #[test]
+    fn test_inspect_page() {
+        let td = TupleDesc::new(
+            vec![Type::IntType, Type::StringType],
+            vec!["id".to_string(), "name".to_string()],
+        );
+        let pid = HeapPageId::new(1, 2);
+        let mut page = HeapPage::empty(pid, td.clone(), 4096);
+        let tuple = Tuple::new(
+            vec![
+                FieldVal::IntField(IntField::new(7)),
+                FieldVal::StringField(StringField::new("Al \"Bo\"".to_string(), 7)),
+            ],
+            &td,
+        );
+        page.add_tuple_at(3, tuple, 42).unwrap();
+        page.set_lsn(42);
+        let mut data = page.get_page_data();
+
+        let info = inspect_page(pid, &data, &td, 4096);
+        assert_eq!(info.get_status(), &PageStatus::Valid);
+        assert_eq!(info.get_lsn(), 42);
+        assert_eq!(info.get_bitmap().iter().filter(|used| **used).count(), 1);
+        assert!(info.get_bitmap()[3]);
+        assert_eq!(info.get_num_free_slots(), 13);
+        assert_eq!(info.get_slots()[0].get_record_id(), RecordId::new(pid, 3));
+        assert!(info
+            .to_string()
+            .contains("record (page 2, slot 3) xmin 42 xmax -: {id: 7, name: Al \"Bo\"}"));
+        assert!(info.to_json().contains(
+            r#"{"record_id":{"page":2,"slot":3},"xmin":42,"xmax":null,"tuple":{"id":7,"name":"Al \"Bo\""}}"#
+        ));
+
+        // damaged pages are still decoded as far as possible
+        let slot = HeapPage::slot_offset(&td, 4096, 3) + VERSION_SIZE;
+        data[slot + 8] = 0xFF;
+        let info = inspect_page(pid, &data, &td, 4096);
+        assert!(matches!(info.get_status(), PageStatus::Corrupt(_)));
+        let error = info.get_slots()[0].get_tuple().unwrap_err();
+        assert!(error.contains("UTF-8"));
+        assert!(info
+            .to_json()
+            .contains(r#""status":"corrupt","error":"checksum mismatch"#));
+
+        let info = inspect_page(pid, &data[..100], &td, 4096);
+        assert_eq!(info.get_status(), &PageStatus::Partial);
+        let info = inspect_page(pid, &[0; 4096], &td, 4096);
+        assert_eq!(info.get_status(), &PageStatus::Unwritten);
+        assert!(info.get_slots().is_empty());
+    }
+}
@@ -0,0 +1,230 @@
+use crate::lock_manager::AbortReason;
+use crate::transaction::TransactionId;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+// Flag shared by a transaction and the threads that may cancel it. Operators check it
+// between pages and tuples, and lock requests while they wait
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    // Asks the transaction to abort at its next check
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+// Cancellation token, timeouts and statement progress of one transaction
+struct Activity {
+    token: CancellationToken,
+    // how long a single statement may run, forever if None
+    statement_timeout: Option<Duration>,
+    // how long the transaction may sit between statements, forever if None
+    idle_timeout: Option<Duration>,
+    // number of statements running, since statements may run other statements
+    depth: usize,
+    // when the outermost running statement started, or the last one ended
+    since: Instant,
+    // why the transaction was interrupted, kept until it ends
+    interrupted: Option<AbortReason>,
+}
+
+impl Activity {
+    fn check(&mut self) -> Option<AbortReason> {
+        if self.interrupted.is_none() {
+            let timeout = if self.depth > 0 {
+                self.statement_timeout
+                    .map(|timeout| (timeout, AbortReason::StatementTimeout))
+            } else {
+                self.idle_timeout
+                    .map(|timeout| (timeout, AbortReason::IdleTimeout))
+            };
+            if self.token.is_cancelled() {
+                self.interrupted = Some(AbortReason::Cancelled);
+            } else if let Some((timeout, reason)) = timeout {
+                if self.since.elapsed() >= timeout {
+                    self.interrupted = Some(reason);
+                }
+            }
+        }
+        self.interrupted
+    }
+}
+
+// Tracks the statements of running transactions to enforce their timeouts and
+// cancellation tokens
+#[derive(Default)]
+pub struct Interrupts {
+    activities: RwLock<HashMap<TransactionId, Activity>>,
+}
+
+impl Interrupts {
+    pub fn new() -> Self {
+        Interrupts::default()
+    }
+
+    // Starts tracking a transaction that just began
+    pub fn register(
+        &self,
+        tid: TransactionId,
+        token: CancellationToken,
+        statement_timeout: Option<Duration>,
+        idle_timeout: Option<Duration>,
+    ) {
+        self.activities.write().unwrap().insert(
+            tid,
+            Activity {
+                token,
+                statement_timeout,
+                idle_timeout,
+                depth: 0,
+                since: Instant::now(),
+                interrupted: None,
+            },
+        );
+    }
+
+    // Stops tracking a transaction that ended
+    pub fn remove(&self, tid: TransactionId) {
+        self.activities.write().unwrap().remove(&tid);
+    }
+
+    // Marks the start of a statement, unless the transaction was already interrupted
+    pub fn begin_statement(&self, tid: TransactionId) -> Result<(), AbortReason> {
+        let mut activities = self.activities.write().unwrap();
+        let Some(activity) = activities.get_mut(&tid) else {
+            return Ok(());
+        };
+        if let Some(reason) = activity.check() {
+            return Err(reason);
+        }
+        if activity.depth == 0 {
+            activity.since = Instant::now();
+        }
+        activity.depth += 1;
+        Ok(())
+    }
+
+    // Marks the end of a statement, after which the transaction counts as idle
+    pub fn end_statement(&self, tid: TransactionId) {
+        let mut activities = self.activities.write().unwrap();
+        if let Some(activity) = activities.get_mut(&tid) {
+            activity.depth = activity.depth.saturating_sub(1);
+            if activity.depth == 0 {
+                activity.since = Instant::now();
+            }
+        }
+    }
+
+    // Checks whether a statement of the transaction is running
+    pub fn in_statement(&self, tid: TransactionId) -> bool {
+        let activities = self.activities.read().unwrap();
+        activities
+            .get(&tid)
+            .is_some_and(|activity| activity.depth > 0)
+    }
+
+    // Checks whether the transaction was cancelled or ran out of time. Once interrupted,
+    // a transaction stays interrupted until it ends
+    pub fn check(&self, tid: TransactionId) -> Option<AbortReason> {
+        let mut activities = self.activities.write().unwrap();
+        activities.get_mut(&tid)?.check()
+    }
+
+    // Checks whether the transaction has been idle for longer than its idle timeout. Its
+    // next statement then fails, so another transaction may abort it on its behalf
+    pub fn check_idle(&self, tid: TransactionId) -> bool {
+        let mut activities = self.activities.write().unwrap();
+        activities.get_mut(&tid).is_some_and(|activity| {
+            activity.depth == 0 && activity.check() == Some(AbortReason::IdleTimeout)
+        })
+    }
+
+    // Describes why the transaction was interrupted, as returned to its statements
+    pub fn get_error(&self, tid: TransactionId, reason: AbortReason) -> String {
+        let activities = self.activities.read().unwrap();
+        let activity = activities.get(&tid);
+        let cause = match reason {
+            AbortReason::StatementTimeout => format!(
+                "statement timeout of {:?} exceeded",
+                activity
+                    .and_then(|activity| activity.statement_timeout)
+                    .unwrap_or_default()
+            ),
+            AbortReason::IdleTimeout => format!(
+                "idle timeout of {:?} exceeded",
+                activity
+                    .and_then(|activity| activity.idle_timeout)
+                    .unwrap_or_default()
+            ),
+            reason => reason.describe().to_string(),
+        };
+        format!("Transaction {} aborted: {}", tid.get_tid(), cause)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::TransactionIds;
+    use std::thread;
+
+    #[test]
+    fn test_statement_and_idle_timeouts() {
+        let ids = TransactionIds::new();
+        let interrupts = Interrupts::new();
+        let (busy, idle) = (ids.next(), ids.next());
+        let timeout = Some(Duration::from_millis(50));
+        interrupts.register(busy, CancellationToken::new(), timeout, None);
+        interrupts.register(idle, CancellationToken::new(), None, timeout);
+
+        interrupts.begin_statement(busy).unwrap();
+        // nested statements count towards the outermost one
+        interrupts.begin_statement(busy).unwrap();
+        interrupts.end_statement(busy);
+        assert_eq!(interrupts.check(busy), None);
+        assert!(!interrupts.check_idle(idle));
+
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(interrupts.check(busy), Some(AbortReason::StatementTimeout));
+        assert!(interrupts.check_idle(idle));
+        assert_eq!(
+            interrupts.begin_statement(idle),
+            Err(AbortReason::IdleTimeout)
+        );
+        // the busy transaction stays interrupted once its statement ended
+        interrupts.end_statement(busy);
+        assert!(!interrupts.check_idle(busy));
+        assert_eq!(interrupts.check(busy), Some(AbortReason::StatementTimeout));
+    }
+
+    #[test]
+    fn test_cancellation() {
+        let ids = TransactionIds::new();
+        let interrupts = Interrupts::new();
+        let tid = ids.next();
+        let token = CancellationToken::new();
+        interrupts.register(tid, token.clone(), None, None);
+        interrupts.begin_statement(tid).unwrap();
+        assert_eq!(interrupts.check(tid), None);
+        token.cancel();
+        assert_eq!(interrupts.check(tid), Some(AbortReason::Cancelled));
+        interrupts.remove(tid);
+        // untracked transactions are never interrupted
+        assert_eq!(interrupts.check(tid), None);
+        assert_eq!(interrupts.begin_statement(tid), Ok(()));
+    }
+}
@@ -0,0 +1,473 @@
+use crate::csv::{self, ImportReport};
+use crate::fields::FieldVal;
+use crate::table::{Table, TableIterator};
+use crate::transaction::Transaction;
+use crate::tuple::{Tuple, TupleDesc};
+use crate::types::Type;
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::iter::Peekable;
+use std::str::Chars;
+
+// A parsed JSON value. Numbers keep their text so integers are never rounded
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<JsonValue>),
+    // keys in the order they appear
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    // Parses a single JSON value, which may be surrounded by whitespace
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut chars = text.chars().peekable();
+        let value = parse_value(&mut chars)?;
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            Some(c) => Err(format!("unexpected '{}' after the value", c)),
+            None => Ok(value),
+        }
+    }
+
+    // Names the kind of value for error messages
+    pub fn kind(&self) -> &'static str {
+        match self {
+            JsonValue::Null => "null",
+            JsonValue::Bool(_) => "a boolean",
+            JsonValue::Number(_) => "a number",
+            JsonValue::String(_) => "a string",
+            JsonValue::Array(_) => "an array",
+            JsonValue::Object(_) => "an object",
+        }
+    }
+}
+
+// Formats the value as compact JSON
+impl fmt::Display for JsonValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonValue::Null => write!(f, "null"),
+            JsonValue::Bool(value) => write!(f, "{}", value),
+            JsonValue::Number(value) => write!(f, "{}", value),
+            JsonValue::String(value) => write!(f, "{}", quote(value)),
+            JsonValue::Array(values) => {
+                let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+                write!(f, "[{}]", values.join(","))
+            }
+            JsonValue::Object(members) => {
+                let members: Vec<String> = members
+                    .iter()
+                    .map(|(key, value)| format!("{}:{}", quote(key), value))
+                    .collect();
+                write!(f, "{{{}}}", members.join(","))
+            }
+        }
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+}
+
+fn expect(chars: &mut Peekable<Chars>, expected: char) -> Result<(), String> {
+    match chars.next() {
+        Some(c) if c == expected => Ok(()),
+        Some(c) => Err(format!("expected '{}' but found '{}'", expected, c)),
+        None => Err(format!("expected '{}' but the text ended", expected)),
+    }
+}
+
+fn parse_value(chars: &mut Peekable<Chars>) -> Result<JsonValue, String> {
+    skip_whitespace(chars);
+    match chars.peek() {
+        None => Err("expected a value but the text ended".to_string()),
+        Some('{') => {
+            chars.next();
+            let mut members = vec![];
+            skip_whitespace(chars);
+            if chars.next_if_eq(&'}').is_some() {
+                return Ok(JsonValue::Object(members));
+            }
+            loop {
+                skip_whitespace(chars);
+                expect(chars, '"')?;
+                let key = parse_string(chars)?;
+                skip_whitespace(chars);
+                expect(chars, ':')?;
+                members.push((key, parse_value(chars)?));
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => continue,
+                    Some('}') => return Ok(JsonValue::Object(members)),
+                    _ => return Err("expected ',' or '}' in an object".to_string()),
+                }
+            }
+        }
+        Some('[') => {
+            chars.next();
+            let mut values = vec![];
+            skip_whitespace(chars);
+            if chars.next_if_eq(&']').is_some() {
+                return Ok(JsonValue::Array(values));
+            }
+            loop {
+                values.push(parse_value(chars)?);
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => continue,
+                    Some(']') => return Ok(JsonValue::Array(values)),
+                    _ => return Err("expected ',' or ']' in an array".to_string()),
+                }
+            }
+        }
+        Some('"') => {
+            chars.next();
+            parse_string(chars).map(JsonValue::String)
+        }
+        Some(c) if *c == '-' || c.is_ascii_digit() => parse_number(chars),
+        Some(_) => {
+            let word: String =
+                std::iter::from_fn(|| chars.next_if(|c| c.is_alphanumeric())).collect();
+            match word.as_str() {
+                "null" => Ok(JsonValue::Null),
+                "true" => Ok(JsonValue::Bool(true)),
+                "false" => Ok(JsonValue::Bool(false)),
+                "" => Err(format!("unexpected '{}'", chars.peek().unwrap())),
+                _ => Err(format!("unexpected '{}'", word)),
+            }
+        }
+    }
+}
+
+// Parses the rest of a string whose opening quote was consumed
+fn parse_string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+    let mut value = String::new();
+    loop {
+        match chars.next() {
+            None => return Err("unclosed string".to_string()),
+            Some('"') => return Ok(value),
+            Some('\\') => match chars.next() {
+                Some('"') => value.push('"'),
+                Some('\\') => value.push('\\'),
+                Some('/') => value.push('/'),
+                Some('b') => value.push('\u{8}'),
+                Some('f') => value.push('\u{c}'),
+                Some('n') => value.push('\n'),
+                Some('r') => value.push('\r'),
+                Some('t') => value.push('\t'),
+                Some('u') => {
+                    let mut code = parse_hex(chars)?;
+                    // combine a surrogate pair into one character
+                    if (0xd800..0xdc00).contains(&code) {
+                        expect(chars, '\\')?;
+                        expect(chars, 'u')?;
+                        let low = parse_hex(chars)?;
+                        if !(0xdc00..0xe000).contains(&low) {
+                            return Err("invalid surrogate pair".to_string());
+                        }
+                        code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                    }
+                    value.push(char::from_u32(code).ok_or("invalid \\u escape")?);
+                }
+                _ => return Err("invalid escape in string".to_string()),
+            },
+            Some(c) if (c as u32) < 0x20 => {
+                return Err("control character in string".to_string());
+            }
+            Some(c) => value.push(c),
+        }
+    }
+}
+
+fn parse_hex(chars: &mut Peekable<Chars>) -> Result<u32, String> {
+    let digits: String = chars.take(4).collect();
+    if digits.len() != 4 {
+        return Err("invalid \\u escape".to_string());
+    }
+    u32::from_str_radix(&digits, 16).map_err(|_| "invalid \\u escape".to_string())
+}
+
+fn parse_number(chars: &mut Peekable<Chars>) -> Result<JsonValue, String> {
+    let text: String = std::iter::from_fn(|| {
+        chars.next_if(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'))
+    })
+    .collect();
+    let digits = text.strip_prefix('-').unwrap_or(&text);
+    let (int, rest) = digits.split_at(
+        digits
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(digits.len()),
+    );
+    let leading_zero = int.len() > 1 && int.starts_with('0');
+    if int.is_empty() || leading_zero || (!rest.is_empty() && text.parse::<f64>().is_err()) {
+        return Err(format!("invalid number {}", text));
+    }
+    Ok(JsonValue::Number(text))
+}
+
+// Quotes the string as a JSON string, escaping quotes, backslashes and control characters
+pub fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Converts a field to JSON, Ints as numbers and Strings as strings
+pub fn field_to_json(field: Option<&FieldVal>) -> JsonValue {
+    match field {
+        Some(FieldVal::IntField(field)) => JsonValue::Number(field.get_value().to_string()),
+        Some(FieldVal::StringField(field)) => JsonValue::String(field.get_value()),
+        None => JsonValue::Null,
+    }
+}
+
+// Converts the tuple to an object keyed by the field names of its TupleDesc. Fields the
+// tuple does not have are null
+pub fn tuple_to_json(tuple: &Tuple) -> JsonValue {
+    let td = tuple.get_tuple_desc();
+    JsonValue::Object(
+        (0..td.get_num_fields())
+            .map(|i| {
+                let name = td.get_field_name(i).unwrap().clone();
+                (name, field_to_json(tuple.get_field(i)))
+            })
+            .collect(),
+    )
+}
+
+// Converts an object to a tuple of the TupleDesc. Every field needs a key holding a
+// value of its type, and there are no NULLs in tables, so null values are rejected
+pub fn json_to_tuple(value: &JsonValue, td: &TupleDesc) -> Result<Tuple, String> {
+    let JsonValue::Object(members) = value else {
+        return Err(format!("expected an object but found {}", value.kind()));
+    };
+    let mut fields: Vec<Option<FieldVal>> = vec![None; td.get_num_fields()];
+    for (key, value) in members.iter() {
+        let i = td
+            .name_to_id(key)
+            .ok_or(format!("the table has no field named {}", key))?;
+        if fields[i].is_some() {
+            return Err(format!("{} is given twice", key));
+        }
+        let field_type = td.get_field_type(i).unwrap();
+        let field = match (field_type, value) {
+            (_, JsonValue::Null) => Err("cannot be null".to_string()),
+            (Type::IntType, JsonValue::Number(number)) => field_type.coerce(number),
+            (Type::StringType, JsonValue::String(string)) => field_type.coerce(string),
+            (Type::IntType, value) => Err(format!("expected a number but found {}", value.kind())),
+            (Type::StringType, value) => {
+                Err(format!("expected a string but found {}", value.kind()))
+            }
+        };
+        fields[i] = Some(field.map_err(|e| format!("{}: {}", key, e))?);
+    }
+    if let Some(i) = fields.iter().position(Option::is_none) {
+        return Err(format!("{} is missing", td.get_field_name(i).unwrap()));
+    }
+    Ok(Tuple::new(fields.into_iter().flatten().collect(), td))
+}
+
+/**
+ * Imports JSON Lines, one object per line, into the table as part of the transaction. The
+ * keys of each object are the field names of the table, in any order. Lines that are not
+ * valid JSON or do not match the table are skipped and reported, and written to rejects if
+ * given. Blank lines are ignored. Like CSV imports, a table without pages is bulk loaded.
+ */
+pub fn import<R: BufRead>(
+    table: &Table,
+    reader: R,
+    txn: &Transaction,
+    mut rejects: Option<&mut dyn Write>,
+) -> Result<ImportReport, String> {
+    let td = table.get_tuple_desc();
+    let mut report = ImportReport::default();
+    // a line that cannot be read or rejected stops the import
+    let mut failure = None;
+    let mut lines = reader.lines().enumerate();
+    let tuples = std::iter::from_fn(|| {
+        for (i, line) in lines.by_ref() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    failure = Some(format!("Cannot read line {}: {}", i + 1, e));
+                    return None;
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match JsonValue::parse(&line).and_then(|value| json_to_tuple(&value, td)) {
+                Ok(tuple) => return Some(tuple),
+                Err(e) => {
+                    report.add_error(i + 1, e);
+                    if let Some(rejects) = rejects.as_mut() {
+                        if let Err(e) = writeln!(rejects, "{}", line) {
+                            failure = Some(format!("Cannot write rejected rows: {}", e));
+                            return None;
+                        }
+                    }
+                }
+            }
+        }
+        None
+    });
+    let num_imported = csv::load_tuples(table, tuples, txn);
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    report.add_imported(num_imported);
+    Ok(report)
+}
+
+// Writes the tuples as JSON Lines, one object per tuple, returning the number written
+pub fn export_tuples<I>(tuples: I, writer: &mut dyn Write) -> Result<usize, String>
+where
+    I: IntoIterator<Item = Tuple>,
+{
+    let error = |e: std::io::Error| format!("Cannot write JSON: {}", e);
+    let mut count = 0;
+    for tuple in tuples {
+        writeln!(writer, "{}", tuple_to_json(&tuple)).map_err(error)?;
+        count += 1;
+    }
+    writer.flush().map_err(error)?;
+    Ok(count)
+}
+
+// Writes the tuples as a single JSON array of objects, one per line
+pub fn write_array<I>(tuples: I, writer: &mut dyn Write) -> Result<usize, String>
+where
+    I: IntoIterator<Item = Tuple>,
+{
+    let error = |e: std::io::Error| format!("Cannot write JSON: {}", e);
+    let mut count = 0;
+    write!(writer, "[").map_err(error)?;
+    for tuple in tuples {
+        let separator = if count == 0 { "" } else { "," };
+        write!(writer, "{}\n{}", separator, tuple_to_json(&tuple)).map_err(error)?;
+        count += 1;
+    }
+    writeln!(writer, "\n]").map_err(error)?;
+    writer.flush().map_err(error)?;
+    Ok(count)
+}
+
+// Writes every tuple of the table visible to the transaction as JSON Lines
+#[allow(dead_code)]
+pub fn export_table(
+    table: &Table,
+    txn: &Transaction,
+    writer: &mut dyn Write,
+) -> Result<usize, String> {
+    export_query(table.scan(usize::MAX, txn), writer)
+}
+
+// Runs the query and writes its results as JSON Lines
+#[allow(dead_code)]
+pub fn export_query(mut query: TableIterator, writer: &mut dyn Write) -> Result<usize, String> {
+    query.fetch()?;
+    export_tuples(query, writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fields::{IntField, StringField};
+
+    #[test]
+    fn test_parse_and_format() {
+        let text = r#" {"id": -12, "name": "A \"q\"\n\u00e9\ud83d\ude00", "tags": [true, null, 1.5e3], "x": {}} "#;
+        let value = JsonValue::parse(text).unwrap();
+        let JsonValue::Object(members) = &value else {
+            panic!("expected an object");
+        };
+        assert_eq!(
+            members[0],
+            ("id".to_string(), JsonValue::Number("-12".to_string()))
+        );
+        assert_eq!(
+            members[1].1,
+            JsonValue::String("A \"q\"\n\u{e9}\u{1f600}".to_string())
+        );
+        assert_eq!(
+            value.to_string(),
+            "{\"id\":-12,\"name\":\"A \\\"q\\\"\\n\u{e9}\u{1f600}\",\"tags\":[true,null,1.5e3],\"x\":{}}"
+        );
+        assert_eq!(JsonValue::parse(&value.to_string()).unwrap(), value);
+
+        for invalid in [
+            "",
+            "{",
+            "[1,]",
+            "{\"a\" 1}",
+            "01",
+            "1 2",
+            "\"\\x\"",
+            "nul",
+            "-",
+        ] {
+            assert!(JsonValue::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_tuples() {
+        let td = TupleDesc::new(
+            vec![Type::IntType, Type::StringType],
+            vec!["id".to_string(), "name".to_string()],
+        );
+        let tuple = Tuple::new(
+            vec![
+                FieldVal::IntField(IntField::new(7)),
+                FieldVal::StringField(StringField::new("Al\"ice".to_string(), 6)),
+            ],
+            &td,
+        );
+        let json = tuple_to_json(&tuple);
+        assert_eq!(json.to_string(), "{\"id\":7,\"name\":\"Al\\\"ice\"}");
+        assert_eq!(json_to_tuple(&json, &td).unwrap(), tuple);
+        let partial = Tuple::new(vec![FieldVal::IntField(IntField::new(7))], &td);
+        assert_eq!(
+            tuple_to_json(&partial).to_string(),
+            "{\"id\":7,\"name\":null}"
+        );
+
+        let errors = [
+            ("[1]", "expected an object but found an array"),
+            ("{\"id\":1}", "name is missing"),
+            ("{\"id\":null,\"name\":\"a\"}", "id: cannot be null"),
+            (
+                "{\"id\":\"1\",\"name\":\"a\"}",
+                "id: expected a number but found a string",
+            ),
+            (
+                "{\"id\":1.5,\"name\":\"a\"}",
+                "id: \"1.5\" is not a valid Int",
+            ),
+            (
+                "{\"id\":1,\"name\":\"a\",\"age\":3}",
+                "the table has no field named age",
+            ),
+        ];
+        for (text, error) in errors {
+            let value = JsonValue::parse(text).unwrap();
+            assert_eq!(json_to_tuple(&value, &td).unwrap_err(), error);
+        }
+    }
+}
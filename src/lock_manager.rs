@@ -1,172 +1,1081 @@
-use crate::database;
-use crate::heap_page::HeapPageId;
-use crate::heap_page::Permission;
+use crate::database::Database;
+use crate::table::{Filterable, Predicate};
 use crate::transaction::TransactionId;
-use std::borrow::BorrowMut;
+use crate::tuple::{RecordId, Tuple};
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::sync::RwLock;
-use std::sync::RwLockWriteGuard;
+use std::collections::VecDeque;
+use std::sync::{Arc, RwLock, Weak};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-#[derive(Debug, PartialEq, Eq, Clone, Hash, Copy)]
-struct Lock {
+// How long a waiting lock request sleeps before checking the lock again
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+// Number of row locks a transaction may hold on one table before they are escalated
+// to a single table lock
+pub const DEFAULT_ESCALATION_THRESHOLD: usize = 1000;
+// Number of aborted lock requests remembered for get_abort_history
+pub const ABORT_HISTORY_SIZE: usize = 100;
+
+// How conflicting lock requests are kept from deadlocking
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum DeadlockPolicy {
+    // An older requester waits, a younger requester aborts
+    #[default]
+    WaitDie,
+    // An older requester aborts the younger holders, a younger requester waits
+    WoundWait,
+    // Every requester waits, and a victim is aborted only once the waits-for graph
+    // has a cycle
+    Detection,
+}
+
+// Modes of the lock hierarchy. Rows are only locked in Shared or Exclusive mode, and
+// the intention modes on a table announce row locks of that kind
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LockMode {
+    IntentionShared,
+    IntentionExclusive,
+    Shared,
+    SharedIntentionExclusive,
+    Exclusive,
+}
+
+impl LockMode {
+    // Checks whether two transactions may hold the modes on the same target at once
+    pub fn is_compatible(&self, other: &LockMode) -> bool {
+        use LockMode::*;
+        matches!(
+            (self, other),
+            (
+                IntentionShared,
+                IntentionShared | IntentionExclusive | Shared | SharedIntentionExclusive
+            ) | (IntentionExclusive, IntentionShared | IntentionExclusive)
+                | (Shared, IntentionShared | Shared)
+                | (SharedIntentionExclusive, IntentionShared)
+        )
+    }
+
+    // Gets the weakest mode that allows everything either mode allows
+    pub fn combine(&self, other: &LockMode) -> LockMode {
+        use LockMode::*;
+        match (self, other) {
+            (a, b) if a == b => *a,
+            (Exclusive, _) | (_, Exclusive) => Exclusive,
+            (SharedIntentionExclusive, _) | (_, SharedIntentionExclusive) => {
+                SharedIntentionExclusive
+            }
+            (Shared, IntentionExclusive) | (IntentionExclusive, Shared) => SharedIntentionExclusive,
+            (Shared, _) | (_, Shared) => Shared,
+            _ => IntentionExclusive,
+        }
+    }
+
+    // Checks whether holding this mode already allows everything the other mode allows
+    pub fn covers(&self, other: &LockMode) -> bool {
+        self.combine(other) == *self
+    }
+
+    // Checks whether this mode on a table already grants the row mode on all its rows
+    fn covers_rows(&self, row_mode: &LockMode) -> bool {
+        match self {
+            LockMode::Exclusive => true,
+            LockMode::Shared | LockMode::SharedIntentionExclusive => *row_mode == LockMode::Shared,
+            _ => false,
+        }
+    }
+}
+
+// Something that can be locked: a whole table or a single row of a table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LockTarget {
+    Table(usize),
+    Row(RecordId),
+}
+
+impl LockTarget {
+    pub fn get_table_id(&self) -> usize {
+        match self {
+            LockTarget::Table(table_id) => *table_id,
+            LockTarget::Row(rid) => rid.get_page_id().get_table_id(),
+        }
+    }
+
+    fn is_row_of(&self, table_id: usize) -> bool {
+        matches!(self, LockTarget::Row(_)) && self.get_table_id() == table_id
+    }
+}
+
+#[derive(Default)]
+struct LockTable {
+    // mode of every transaction holding a lock on each target
+    holders: HashMap<LockTarget, HashMap<TransactionId, LockMode>>,
+    // targets locked by each transaction
+    held: HashMap<TransactionId, HashSet<LockTarget>>,
+}
+
+impl LockTable {
+    fn get_mode(&self, tid: TransactionId, target: &LockTarget) -> Option<LockMode> {
+        self.holders.get(target)?.get(&tid).copied()
+    }
+
+    fn grant(&mut self, tid: TransactionId, target: LockTarget, mode: LockMode) {
+        self.holders.entry(target).or_default().insert(tid, mode);
+        self.held.entry(tid).or_default().insert(target);
+    }
+
+    fn release(&mut self, tid: TransactionId, target: &LockTarget) {
+        if let Some(holders) = self.holders.get_mut(target) {
+            holders.remove(&tid);
+            if holders.is_empty() {
+                self.holders.remove(target);
+            }
+        }
+        if let Some(held) = self.held.get_mut(&tid) {
+            held.remove(target);
+        }
+    }
+
+    fn get_row_locks(&self, tid: TransactionId, table_id: usize) -> Vec<LockTarget> {
+        self.held
+            .get(&tid)
+            .into_iter()
+            .flatten()
+            .filter(|target| target.is_row_of(table_id))
+            .copied()
+            .collect()
+    }
+}
+
+// Lock on every row of a table that satisfies all of the filters, including rows that do
+// not exist yet. Taken by SERIALIZABLE scans so that concurrent writers cannot add
+// phantom rows to their results
+#[derive(Debug, Clone, PartialEq)]
+pub struct PredicateLock {
+    table_id: usize,
+    filters: Vec<(String, Predicate)>,
+}
+
+impl PredicateLock {
+    pub fn new(table_id: usize, filters: Vec<(String, Predicate)>) -> Self {
+        PredicateLock { table_id, filters }
+    }
+
+    pub fn get_table_id(&self) -> usize {
+        self.table_id
+    }
+
+    pub fn get_filters(&self) -> &[(String, Predicate)] {
+        &self.filters
+    }
+
+    // Checks whether writing the tuple into the table could change the result of the scan
+    pub fn matches(&self, table_id: usize, tuple: &Tuple) -> bool {
+        self.table_id == table_id
+            && self
+                .filters
+                .iter()
+                .all(|(field_name, predicate)| tuple.filter(field_name, predicate))
+    }
+}
+
+// A lock a transaction holds or is waiting for
+#[derive(Debug, Clone, PartialEq)]
+pub enum LockRequest {
+    Lock(LockTarget, LockMode),
+    Predicate(PredicateLock),
+    // writing a row into the table, which waits for the predicate locks it matches
+    PredicateWrite(usize),
+}
+
+impl LockRequest {
+    pub fn get_table_id(&self) -> usize {
+        match self {
+            LockRequest::Lock(target, _) => target.get_table_id(),
+            LockRequest::Predicate(predicate) => predicate.get_table_id(),
+            LockRequest::PredicateWrite(table_id) => *table_id,
+        }
+    }
+}
+
+// A lock held or waited for by a transaction, as reported by get_lock_snapshot
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockInfo {
     tid: TransactionId,
-    pid: HeapPageId,
-    exclusive: bool,
+    request: LockRequest,
+    granted: bool,
+    // transactions the request is waiting for, empty once granted
+    blockers: Vec<TransactionId>,
+}
+
+impl LockInfo {
+    pub fn get_tid(&self) -> TransactionId {
+        self.tid
+    }
+
+    pub fn get_request(&self) -> &LockRequest {
+        &self.request
+    }
+
+    pub fn is_granted(&self) -> bool {
+        self.granted
+    }
+
+    pub fn get_blockers(&self) -> &[TransactionId] {
+        &self.blockers
+    }
+}
+
+// Why the lock manager aborted a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AbortReason {
+    // a younger transaction requested a lock held by an older one under WAIT-DIE
+    Died,
+    // an older transaction requested a lock held by this one under WOUND-WAIT
+    Wounded,
+    // the transaction was chosen as the victim of a deadlock cycle
+    Deadlock,
+    // a lock request waited longer than the lock timeout
+    Timeout,
+    // an administrator killed the transaction
+    Killed,
+    // the cancellation token of the transaction was cancelled
+    Cancelled,
+    // a statement ran longer than the statement timeout of the transaction
+    StatementTimeout,
+    // the transaction sat between statements longer than its idle timeout while another
+    // transaction waited for its locks
+    IdleTimeout,
+}
+
+impl AbortReason {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            AbortReason::Died => "died",
+            AbortReason::Wounded => "wounded",
+            AbortReason::Deadlock => "deadlock",
+            AbortReason::Timeout => "timeout",
+            AbortReason::Killed => "killed",
+            AbortReason::Cancelled => "cancelled",
+            AbortReason::StatementTimeout => "statement timeout",
+            AbortReason::IdleTimeout => "idle timeout",
+        }
+    }
+}
+
+// A transaction aborted by the lock manager, as reported by get_abort_history
+#[derive(Debug, Clone, PartialEq)]
+pub struct AbortRecord {
+    tid: TransactionId,
+    reason: AbortReason,
+    // transactions holding the lock the aborted transaction was waiting for
+    blockers: Vec<TransactionId>,
+    time: SystemTime,
+}
+
+impl AbortRecord {
+    pub fn get_tid(&self) -> TransactionId {
+        self.tid
+    }
+
+    pub fn get_reason(&self) -> AbortReason {
+        self.reason
+    }
+
+    pub fn get_blockers(&self) -> &[TransactionId] {
+        &self.blockers
+    }
+
+    pub fn get_time(&self) -> SystemTime {
+        self.time
+    }
+}
+
+// A lock request that is waiting for other transactions
+struct Waiting {
+    request: LockRequest,
+    blockers: HashSet<TransactionId>,
 }
 
 pub struct LockManager {
-    page_to_locks: RwLock<HashMap<HeapPageId, HashSet<Lock>>>,
-    transaction_to_locks: RwLock<HashMap<TransactionId, HashSet<Lock>>>,
+    // database whose transactions are aborted, none for a standalone lock manager
+    db: Weak<Database>,
+    locks: RwLock<LockTable>,
+    // predicates scanned by each transaction
+    predicate_locks: RwLock<HashMap<TransactionId, Vec<PredicateLock>>>,
+    // number of lock requests per transaction that had to wait
+    lock_waits: RwLock<HashMap<TransactionId, usize>>,
+    deadlock_policy: RwLock<DeadlockPolicy>,
+    // how long a request may wait before the requester aborts, forever if None
+    lock_timeout: RwLock<Option<Duration>>,
+    escalation_threshold: RwLock<usize>,
+    // request of each waiting transaction and the transactions it is waiting for, which
+    // make up the waits-for graph
+    waiting: RwLock<HashMap<TransactionId, Waiting>>,
+    // transactions chosen to abort by another transaction or an administrator, which
+    // abort themselves the next time they request a lock or try to commit
+    wounded: RwLock<HashMap<TransactionId, AbortReason>>,
+    // most recent aborts, oldest first
+    abort_history: RwLock<VecDeque<AbortRecord>>,
+}
+
+// Progress of a lock request that had to wait
+struct Wait {
+    started: Instant,
+    counted: bool,
+}
+
+impl Wait {
+    fn new() -> Self {
+        Wait {
+            started: Instant::now(),
+            counted: false,
+        }
+    }
 }
 
 impl LockManager {
-    pub fn new() -> Self {
+    pub fn new(db: Weak<Database>) -> Self {
         LockManager {
-            page_to_locks: RwLock::new(HashMap::new()),
-            transaction_to_locks: RwLock::new(HashMap::new()),
+            db,
+            locks: RwLock::new(LockTable::default()),
+            predicate_locks: RwLock::new(HashMap::new()),
+            lock_waits: RwLock::new(HashMap::new()),
+            deadlock_policy: RwLock::new(DeadlockPolicy::default()),
+            lock_timeout: RwLock::new(None),
+            escalation_threshold: RwLock::new(DEFAULT_ESCALATION_THRESHOLD),
+            waiting: RwLock::new(HashMap::new()),
+            wounded: RwLock::new(HashMap::new()),
+            abort_history: RwLock::new(VecDeque::new()),
         }
     }
 
-    // Acquires a lock on the specified page for the specified transaction
-    pub fn acquire_lock(&self, tid: TransactionId, pid: HeapPageId, exclusive: bool) {
-        // early return if the transaction already has the appropriate lock
+    pub fn get_deadlock_policy(&self) -> DeadlockPolicy {
+        *self.deadlock_policy.read().unwrap()
+    }
+
+    // Changes how conflicts of lock requests made from now on are resolved
+    pub fn set_deadlock_policy(&self, policy: DeadlockPolicy) {
+        *self.deadlock_policy.write().unwrap() = policy;
+    }
+
+    pub fn get_lock_timeout(&self) -> Option<Duration> {
+        *self.lock_timeout.read().unwrap()
+    }
+
+    // Sets how long a lock request may wait before its transaction aborts
+    pub fn set_lock_timeout(&self, timeout: Option<Duration>) {
+        *self.lock_timeout.write().unwrap() = timeout;
+    }
+
+    pub fn get_escalation_threshold(&self) -> usize {
+        *self.escalation_threshold.read().unwrap()
+    }
+
+    // Sets how many row locks a transaction may hold on one table before they are
+    // replaced by a single table lock
+    #[allow(dead_code)]
+    pub fn set_escalation_threshold(&self, threshold: usize) {
+        *self.escalation_threshold.write().unwrap() = threshold;
+    }
+
+    // Checks whether another transaction chose the transaction to abort
+    #[allow(dead_code)]
+    pub fn is_wounded(&self, tid: TransactionId) -> bool {
+        self.wounded.read().unwrap().contains_key(&tid)
+    }
+
+    // Aborts the transaction if another transaction or an administrator chose it to abort
+    pub fn abort_if_wounded(&self, tid: TransactionId) {
+        if let Some(reason) = self.get_wound_reason(tid) {
+            self.abort(tid, reason, HashSet::new());
+        }
+    }
+
+    // Makes the transaction abort the next time it requests a lock or tries to commit. A
+    // transaction that is waiting for a lock aborts within the lock retry interval
+    pub fn kill(&self, tid: TransactionId) {
+        self.wound(tid, AbortReason::Killed);
+    }
+
+    // Acquires a lock in the specified mode on the whole table
+    pub fn lock_table(&self, tid: TransactionId, table_id: usize, mode: LockMode) {
+        self.acquire(tid, LockTarget::Table(table_id), mode);
+    }
+
+    // Acquires a shared or exclusive lock on the row, after the matching intention
+    // lock on its table
+    pub fn lock_row(&self, tid: TransactionId, rid: RecordId, exclusive: bool) {
+        let table_id = rid.get_page_id().get_table_id();
+        let (table_mode, row_mode) = if exclusive {
+            (LockMode::IntentionExclusive, LockMode::Exclusive)
+        } else {
+            (LockMode::IntentionShared, LockMode::Shared)
+        };
+        // a table lock that already grants the row makes a row lock unnecessary
+        if self
+            .get_lock_mode(tid, LockTarget::Table(table_id))
+            .is_some_and(|mode| mode.covers_rows(&row_mode))
         {
-            let transaction_locks = self.transaction_to_locks.read().unwrap();
-            if transaction_locks.contains_key(&tid) {
-                let locks = transaction_locks.get(&tid).unwrap();
-                for lock in locks {
-                    if lock.pid == pid && (lock.exclusive == exclusive || !exclusive) {
-                        return;
-                    }
-                }
+            return;
+        }
+        self.acquire(tid, LockTarget::Table(table_id), table_mode);
+        self.acquire(tid, LockTarget::Row(rid), row_mode);
+        self.escalate_if_needed(tid, table_id);
+    }
+
+    // Takes an exclusive lock on the row without waiting, if no other transaction holds
+    // a lock on it. The transaction must already hold an intention exclusive lock on the
+    // table. Used to claim free slots while a page is latched
+    pub fn try_lock_row(&self, tid: TransactionId, rid: RecordId) -> bool {
+        let target = LockTarget::Row(rid);
+        let mut locks = self.locks.write().unwrap();
+        if locks
+            .get_mode(tid, &LockTarget::Table(target.get_table_id()))
+            .is_some_and(|mode| mode.covers_rows(&LockMode::Exclusive))
+        {
+            return true;
+        }
+        if locks
+            .holders
+            .get(&target)
+            .is_some_and(|holders| holders.keys().any(|holder| *holder != tid))
+        {
+            return false;
+        }
+        locks.grant(tid, target, LockMode::Exclusive);
+        true
+    }
+
+    // Locks the rows of the table matching the predicate, including rows inserted later,
+    // after an intention shared lock on the table
+    pub fn lock_predicate(&self, tid: TransactionId, predicate: PredicateLock) {
+        self.acquire(
+            tid,
+            LockTarget::Table(predicate.get_table_id()),
+            LockMode::IntentionShared,
+        );
+        let mut predicate_locks = self.predicate_locks.write().unwrap();
+        let held = predicate_locks.entry(tid).or_default();
+        if !held.contains(&predicate) {
+            held.push(predicate);
+        }
+    }
+
+    // Waits until no other transaction holds a predicate lock matching the tuple the
+    // transaction is about to write into the table. Conflicts are resolved like any
+    // other lock request under the deadlock policy
+    pub fn check_predicate_locks(&self, tid: TransactionId, table_id: usize, tuple: &Tuple) {
+        let mut wait = Wait::new();
+        loop {
+            let holders: HashSet<TransactionId> = self
+                .predicate_locks
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|(holder, predicates)| {
+                    **holder != tid
+                        && predicates
+                            .iter()
+                            .any(|predicate| predicate.matches(table_id, tuple))
+                })
+                .map(|(holder, _)| *holder)
+                .collect();
+            if holders.is_empty() {
+                self.waiting.write().unwrap().remove(&tid);
+                return;
             }
+            let request = LockRequest::PredicateWrite(table_id);
+            self.wait_or_abort(tid, request, holders, &mut wait);
         }
-        // check if there is a conflicting lock on the page
+    }
+
+    // Retrieves the predicate locks held by the transaction
+    #[allow(dead_code)]
+    pub fn get_predicate_locks(&self, tid: TransactionId) -> Vec<PredicateLock> {
+        let predicate_locks = self.predicate_locks.read().unwrap();
+        predicate_locks.get(&tid).cloned().unwrap_or_default()
+    }
+
+    // Replaces the row locks of the transaction on the table with one table lock once
+    // there are more of them than the escalation threshold
+    pub fn escalate_if_needed(&self, tid: TransactionId, table_id: usize) {
+        let exclusive = {
+            let locks = self.locks.read().unwrap();
+            let rows = locks.get_row_locks(tid, table_id);
+            if rows.len() <= self.get_escalation_threshold() {
+                return;
+            }
+            rows.iter()
+                .any(|row| locks.get_mode(tid, row) == Some(LockMode::Exclusive))
+        };
+        let mode = if exclusive {
+            LockMode::Exclusive
+        } else {
+            LockMode::Shared
+        };
+        self.acquire(tid, LockTarget::Table(table_id), mode);
+        let mut locks = self.locks.write().unwrap();
+        for row in locks.get_row_locks(tid, table_id) {
+            locks.release(tid, &row);
+        }
+    }
+
+    // Acquires the lock, waiting while other transactions hold incompatible locks on the
+    // target. A lock the transaction already holds is upgraded to a mode allowing both
+    fn acquire(&self, tid: TransactionId, target: LockTarget, mode: LockMode) {
+        self.abort_if_wounded(tid);
+        let mut wait = Wait::new();
         loop {
-            let mut page_to_locks = self.page_to_locks.write().unwrap();
-            let mut transaction_to_locks = self.transaction_to_locks.write().unwrap();
-
-            if let Some(locks) = page_to_locks.get(&pid) {
-                // upgrade the lock if the transaction already has a lock on the page
-                if locks.len() == 1 && locks.iter().next().unwrap().tid == tid {
-                    if exclusive {
-                        self.upgrade_lock(
-                            tid,
-                            pid,
-                            page_to_locks.borrow_mut(),
-                            transaction_to_locks.borrow_mut(),
-                        );
-                    }
-                    return;
+            let mut locks = self.locks.write().unwrap();
+            let held = locks.get_mode(tid, &target);
+            if held.is_some_and(|held| held.covers(&mode)) {
+                return;
+            }
+            let wanted = held.map_or(mode, |held| held.combine(&mode));
+            let holders: HashSet<TransactionId> = locks
+                .holders
+                .get(&target)
+                .into_iter()
+                .flatten()
+                .filter(|(holder, held)| **holder != tid && !wanted.is_compatible(held))
+                .map(|(holder, _)| *holder)
+                .collect();
+            if holders.is_empty() {
+                locks.grant(tid, target, wanted);
+                self.waiting.write().unwrap().remove(&tid);
+                return;
+            }
+            drop(locks);
+            let request = LockRequest::Lock(target, wanted);
+            self.wait_or_abort(tid, request, holders, &mut wait);
+        }
+    }
+
+    // Resolves a request that conflicts with the locks of the holders according to the
+    // deadlock policy: either aborts the requester, or waits before the caller retries,
+    // possibly after choosing holders to abort
+    fn wait_or_abort(
+        &self,
+        tid: TransactionId,
+        request: LockRequest,
+        holders: HashSet<TransactionId>,
+        wait: &mut Wait,
+    ) {
+        self.abort_if_wounded(tid);
+        if let Some(db) = self.db.upgrade() {
+            let bp = db.get_buffer_pool();
+            if let Some(reason) = bp.get_interrupts().check(tid) {
+                self.abort(tid, reason, holders);
+            }
+            // holders that sit idle for longer than their idle timeout are aborted on their
+            // behalf, so they do not keep their locks until their next statement. Once
+            // counted as idle they cannot start a statement, and their own rollback waits
+            // for this one
+            let idle: Vec<TransactionId> = holders
+                .iter()
+                .filter(|holder| bp.get_interrupts().check_idle(**holder))
+                .copied()
+                .collect();
+            if !idle.is_empty() {
+                for holder in idle {
+                    self.record_abort(holder, AbortReason::IdleTimeout, HashSet::new());
+                    bp.abort_transaction(holder);
                 }
-                // conflict if there are others locks when we want an exclusive lock
-                let mut conflict = exclusive && !locks.is_empty();
-                // or if there is an exclusive lock and we want any lock
-                conflict = conflict || locks.iter().any(|lock| lock.exclusive);
-
-                if conflict {
-                    let abort = locks.iter().any(|lock| lock.tid < tid);
-                    drop(page_to_locks);
-                    drop(transaction_to_locks);
-                    if abort {
-                        // abort the transaction
-                        let db = database::get_global_db();
-                        let bp = db.get_buffer_pool();
-                        bp.abort_transaction(tid);
-                        panic!("Transaction {:?} aborted", tid);
+                return;
+            }
+        }
+        self.waiting.write().unwrap().insert(
+            tid,
+            Waiting {
+                request,
+                blockers: holders.clone(),
+            },
+        );
+        match self.get_deadlock_policy() {
+            DeadlockPolicy::WaitDie => {
+                if holders.iter().any(|holder| *holder < tid) {
+                    self.abort(tid, AbortReason::Died, holders);
+                }
+            }
+            DeadlockPolicy::WoundWait => {
+                for holder in holders.iter().filter(|holder| tid < **holder) {
+                    self.wound(*holder, AbortReason::Wounded);
+                }
+            }
+            DeadlockPolicy::Detection => {
+                if let Some(victim) = self.find_deadlock_victim(tid) {
+                    if victim == tid {
+                        self.abort(tid, AbortReason::Deadlock, holders);
                     }
-                    // wait for the lock to be released
-                    thread::sleep(std::time::Duration::from_millis(500));
-                    continue;
+                    self.wound(victim, AbortReason::Deadlock);
                 }
             }
-            // add the lock to the page and transaction
-            let page_locks = page_to_locks.entry(pid).or_insert(HashSet::new());
-            let transaction_locks = transaction_to_locks.entry(tid).or_insert(HashSet::new());
-            page_locks.insert(Lock {
-                tid,
-                exclusive,
-                pid,
-            });
-            transaction_locks.insert(Lock {
-                tid,
-                exclusive,
-                pid,
-            });
-            return;
         }
+        if self
+            .get_lock_timeout()
+            .is_some_and(|timeout| wait.started.elapsed() >= timeout)
+        {
+            self.abort(tid, AbortReason::Timeout, holders);
+        }
+        // wait for the lock to be released
+        if !wait.counted {
+            wait.counted = true;
+            *self.lock_waits.write().unwrap().entry(tid).or_default() += 1;
+        }
+        thread::sleep(LOCK_RETRY_INTERVAL);
+    }
+
+    // Looks for a cycle in the waits-for graph through the transaction and picks its
+    // youngest member as the victim
+    fn find_deadlock_victim(&self, tid: TransactionId) -> Option<TransactionId> {
+        let waiting = self.waiting.read().unwrap();
+        let waits_for = |tid: &TransactionId| -> Vec<TransactionId> {
+            waiting
+                .get(tid)
+                .map(|waiting| waiting.blockers.iter().copied().collect())
+                .unwrap_or_default()
+        };
+        // depth first search for a path leading back to tid
+        let mut path = vec![tid];
+        let mut next = vec![waits_for(&tid)];
+        let mut visited = HashSet::from([tid]);
+        while let Some(candidates) = next.last_mut() {
+            let Some(holder) = candidates.pop() else {
+                next.pop();
+                path.pop();
+                continue;
+            };
+            if holder == tid {
+                return path.into_iter().max();
+            }
+            if visited.insert(holder) {
+                path.push(holder);
+                next.push(waits_for(&holder));
+            }
+        }
+        None
+    }
+
+    // Chooses the transaction to abort the next time it requests a lock or commits
+    fn wound(&self, tid: TransactionId, reason: AbortReason) {
+        self.wounded.write().unwrap().entry(tid).or_insert(reason);
     }
 
-    // Upgrades a lock from read to write
-    fn upgrade_lock(
+    // Aborts the transaction, undoing its changes and releasing its locks, and records
+    // the abort in the history
+    fn abort(
         &self,
         tid: TransactionId,
-        pid: HeapPageId,
-        page_to_locks: &mut RwLockWriteGuard<HashMap<HeapPageId, HashSet<Lock>>>,
-        transaction_to_locks: &mut RwLockWriteGuard<HashMap<TransactionId, HashSet<Lock>>>,
-    ) {
-        let page_locks = page_to_locks.get_mut(&pid).unwrap();
-        let transaction_locks = transaction_to_locks.get_mut(&tid).unwrap();
-        let old_lock = Lock {
-            tid,
-            pid,
-            exclusive: false,
+        reason: AbortReason,
+        blockers: HashSet<TransactionId>,
+    ) -> ! {
+        self.record_abort(tid, reason, blockers);
+        let db: Option<Arc<Database>> = self.db.upgrade();
+        let error = match &db {
+            Some(db) => db.get_buffer_pool().get_interrupts().get_error(tid, reason),
+            None => format!(
+                "Transaction {} aborted: {}",
+                tid.get_tid(),
+                reason.describe()
+            ),
         };
-        let new_lock = Lock {
+        if let Some(db) = db {
+            db.get_buffer_pool().abort_transaction(tid);
+        }
+        self.release_locks(tid);
+        panic!("{}", error);
+    }
+
+    // Adds the abort to the history, forgetting the oldest one once it is full
+    pub fn record_abort(
+        &self,
+        tid: TransactionId,
+        reason: AbortReason,
+        blockers: HashSet<TransactionId>,
+    ) {
+        let mut blockers: Vec<TransactionId> = blockers.into_iter().collect();
+        blockers.sort();
+        let mut abort_history = self.abort_history.write().unwrap();
+        if abort_history.len() == ABORT_HISTORY_SIZE {
+            abort_history.pop_front();
+        }
+        abort_history.push_back(AbortRecord {
             tid,
-            pid,
-            exclusive: true,
-        };
-        page_locks.remove(&old_lock);
-        page_locks.insert(new_lock);
-        transaction_locks.remove(&old_lock);
-        transaction_locks.insert(new_lock);
+            reason,
+            blockers,
+            time: SystemTime::now(),
+        });
     }
 
     // Releases all locks associated with the specified transaction
     pub fn release_locks(&self, tid: TransactionId) {
-        let mut page_to_locks = self.page_to_locks.write().unwrap();
-        let mut transaction_locks = self.transaction_to_locks.write().unwrap();
-        let held_locks = transaction_locks.entry(tid).or_insert(HashSet::new());
-        for lock in held_locks.iter() {
-            let page_locks = page_to_locks.get_mut(&lock.pid).unwrap();
-            page_locks.remove(lock);
-            if page_locks.is_empty() {
-                page_to_locks.remove(&lock.pid);
-            }
-        }
-        held_locks.clear();
-        transaction_locks.remove(&tid);
-    }
-
-    // Checks if the specified transaction has a lock on the specified page
-    pub fn holds_lock(&self, tid: TransactionId, pid: HeapPageId) -> Option<Permission> {
-        let transaction_locks = self.transaction_to_locks.read().unwrap();
-        match transaction_locks.get(&tid) {
-            Some(locks) => {
-                for lock in locks {
-                    if lock.pid == pid {
-                        return Some(if lock.exclusive {
-                            Permission::Write
-                        } else {
-                            Permission::Read
-                        });
-                    }
-                }
-                None
+        let mut locks = self.locks.write().unwrap();
+        for target in locks.held.remove(&tid).unwrap_or_default() {
+            locks.release(tid, &target);
+        }
+        drop(locks);
+        self.predicate_locks.write().unwrap().remove(&tid);
+        self.lock_waits.write().unwrap().remove(&tid);
+        self.waiting.write().unwrap().remove(&tid);
+        self.wounded.write().unwrap().remove(&tid);
+    }
+
+    // Lists every granted lock and every waiting request, ordered by transaction
+    pub fn get_lock_snapshot(&self) -> Vec<LockInfo> {
+        let mut snapshot = Vec::new();
+        let locks = self.locks.read().unwrap();
+        for (target, holders) in locks.holders.iter() {
+            for (tid, mode) in holders.iter() {
+                snapshot.push(LockInfo {
+                    tid: *tid,
+                    request: LockRequest::Lock(*target, *mode),
+                    granted: true,
+                    blockers: vec![],
+                });
+            }
+        }
+        drop(locks);
+        for (tid, predicates) in self.predicate_locks.read().unwrap().iter() {
+            for predicate in predicates.iter() {
+                snapshot.push(LockInfo {
+                    tid: *tid,
+                    request: LockRequest::Predicate(predicate.clone()),
+                    granted: true,
+                    blockers: vec![],
+                });
+            }
+        }
+        for (tid, waiting) in self.waiting.read().unwrap().iter() {
+            let mut blockers: Vec<TransactionId> = waiting.blockers.iter().copied().collect();
+            blockers.sort();
+            snapshot.push(LockInfo {
+                tid: *tid,
+                request: waiting.request.clone(),
+                granted: false,
+                blockers,
+            });
+        }
+        snapshot.sort_by_key(|info| (info.tid, !info.granted));
+        snapshot
+    }
+
+    // Lists the locks held by the transaction and the request it is waiting for, if any
+    #[allow(dead_code)]
+    pub fn get_locks_held(&self, tid: TransactionId) -> Vec<LockInfo> {
+        self.get_lock_snapshot()
+            .into_iter()
+            .filter(|info| info.tid == tid)
+            .collect()
+    }
+
+    // Retrieves the lock request the transaction is waiting for, if any
+    pub fn get_waiting_request(&self, tid: TransactionId) -> Option<LockRequest> {
+        let waiting = self.waiting.read().unwrap();
+        waiting.get(&tid).map(|waiting| waiting.request.clone())
+    }
+
+    // Retrieves why the transaction was chosen to abort, if it was
+    pub fn get_wound_reason(&self, tid: TransactionId) -> Option<AbortReason> {
+        self.wounded.read().unwrap().get(&tid).copied()
+    }
+
+    // Retrieves the most recent aborts of lock requests, oldest first
+    pub fn get_abort_history(&self) -> Vec<AbortRecord> {
+        self.abort_history.read().unwrap().iter().cloned().collect()
+    }
+
+    // Retrieves the number of lock requests of the specified transaction that had to wait
+    pub fn get_lock_waits(&self, tid: TransactionId) -> usize {
+        let lock_waits = self.lock_waits.read().unwrap();
+        lock_waits.get(&tid).copied().unwrap_or(0)
+    }
+
+    // Retrieves the mode in which the transaction holds a lock on the target, if any
+    pub fn get_lock_mode(&self, tid: TransactionId, target: LockTarget) -> Option<LockMode> {
+        self.locks.read().unwrap().get_mode(tid, &target)
+    }
+
+    // Counts the locks the transaction holds on single rows of the table
+    #[allow(dead_code)]
+    pub fn get_num_row_locks(&self, tid: TransactionId, table_id: usize) -> usize {
+        self.locks
+            .read()
+            .unwrap()
+            .get_row_locks(tid, table_id)
+            .len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fields::{FieldVal, IntField};
+    use crate::heap_page::HeapPageId;
+    use crate::transaction::TransactionIds;
+    use crate::tuple::TupleDesc;
+    use crate::types::Type;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    const TABLE: usize = usize::MAX;
+
+    fn row(tuple_no: usize) -> RecordId {
+        RecordId::new(HeapPageId::new(TABLE, 0), tuple_no)
+    }
+
+    // Waits until the transaction has had to wait for a lock
+    fn wait_until_blocked(lock_manager: &LockManager, tid: TransactionId) {
+        while lock_manager.get_lock_waits(tid) == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_lock_mode_compatibility() {
+        use LockMode::*;
+        let modes = [
+            IntentionShared,
+            IntentionExclusive,
+            Shared,
+            SharedIntentionExclusive,
+            Exclusive,
+        ];
+        let expected = [
+            [true, true, true, true, false],
+            [true, true, false, false, false],
+            [true, false, true, false, false],
+            [true, false, false, false, false],
+            [false, false, false, false, false],
+        ];
+        for (i, a) in modes.iter().enumerate() {
+            for (j, b) in modes.iter().enumerate() {
+                assert_eq!(a.is_compatible(b), expected[i][j], "{:?} {:?}", a, b);
             }
-            None => None,
         }
+        assert_eq!(
+            Shared.combine(&IntentionExclusive),
+            SharedIntentionExclusive
+        );
+        assert_eq!(IntentionShared.combine(&Shared), Shared);
+        assert!(Exclusive.covers(&SharedIntentionExclusive));
+        assert!(!Shared.covers(&IntentionExclusive));
+    }
+
+    #[test]
+    fn test_row_locks() {
+        let ids = TransactionIds::new();
+        let lock_manager = LockManager::new(Weak::new());
+        let (first, second) = (ids.next(), ids.next());
+        // different rows of the same table do not conflict
+        lock_manager.lock_row(first, row(0), true);
+        lock_manager.lock_row(second, row(1), true);
+        assert_eq!(
+            lock_manager.get_lock_mode(first, LockTarget::Table(TABLE)),
+            Some(LockMode::IntentionExclusive)
+        );
+        assert_eq!(
+            lock_manager.get_lock_mode(second, LockTarget::Row(row(1))),
+            Some(LockMode::Exclusive)
+        );
+        // a free slot can only be claimed if nobody else holds it
+        assert!(!lock_manager.try_lock_row(second, row(0)));
+        assert!(lock_manager.try_lock_row(second, row(2)));
+        lock_manager.release_locks(first);
+        lock_manager.release_locks(second);
+        assert_eq!(
+            lock_manager.get_lock_mode(first, LockTarget::Row(row(0))),
+            None
+        );
+    }
+
+    #[test]
+    fn test_lock_escalation() {
+        let ids = TransactionIds::new();
+        let lock_manager = LockManager::new(Weak::new());
+        lock_manager.set_escalation_threshold(3);
+        let tid = ids.next();
+        for tuple_no in 0..3 {
+            lock_manager.lock_row(tid, row(tuple_no), false);
+        }
+        assert_eq!(lock_manager.get_num_row_locks(tid, TABLE), 3);
+        // one more row lock than the threshold replaces them with a table lock
+        lock_manager.lock_row(tid, row(3), true);
+        assert_eq!(lock_manager.get_num_row_locks(tid, TABLE), 0);
+        assert_eq!(
+            lock_manager.get_lock_mode(tid, LockTarget::Table(TABLE)),
+            Some(LockMode::Exclusive)
+        );
+        // later rows are covered by the table lock
+        lock_manager.lock_row(tid, row(4), true);
+        assert_eq!(lock_manager.get_num_row_locks(tid, TABLE), 0);
+    }
+
+    #[test]
+    fn test_predicate_locks() {
+        let ids = TransactionIds::new();
+        let lock_manager = LockManager::new(Weak::new());
+        let (reader, writer) = (ids.next(), ids.next());
+        let td = TupleDesc::new(vec![Type::IntType], vec!["id".to_string()]);
+        let row = |id: i32| Tuple::new(vec![FieldVal::IntField(IntField::new(id))], &td);
+        lock_manager.lock_predicate(
+            reader,
+            PredicateLock::new(TABLE, vec![("id".to_string(), Predicate::GreaterThan(10))]),
+        );
+        assert_eq!(
+            lock_manager.get_lock_mode(reader, LockTarget::Table(TABLE)),
+            Some(LockMode::IntentionShared)
+        );
+
+        // rows outside the predicate or in other tables can be written right away
+        lock_manager.check_predicate_locks(writer, TABLE, &row(5));
+        lock_manager.check_predicate_locks(writer, 0, &row(20));
+        // the younger writer of a matching row dies under WAIT-DIE
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            lock_manager.check_predicate_locks(writer, TABLE, &row(20))
+        }));
+        assert!(result.is_err());
+
+        lock_manager.release_locks(reader);
+        assert!(lock_manager.get_predicate_locks(reader).is_empty());
+        lock_manager.check_predicate_locks(writer, TABLE, &row(20));
+    }
+
+    #[test]
+    fn test_lock_snapshot_and_kill() {
+        let ids = TransactionIds::new();
+        let lock_manager = Arc::new(LockManager::new(Weak::new()));
+        let (waiter, holder) = (ids.next(), ids.next());
+        lock_manager.lock_row(holder, row(0), true);
+
+        let handle = {
+            let lock_manager = Arc::clone(&lock_manager);
+            thread::spawn(move || lock_manager.lock_row(waiter, row(0), false))
+        };
+        wait_until_blocked(&lock_manager, waiter);
+        let snapshot = lock_manager.get_lock_snapshot();
+        assert!(snapshot.iter().any(|info| info.get_tid() == holder
+            && info.is_granted()
+            && *info.get_request()
+                == LockRequest::Lock(LockTarget::Row(row(0)), LockMode::Exclusive)));
+        let waiting = lock_manager.get_locks_held(waiter);
+        let waiting = waiting.iter().find(|info| !info.is_granted()).unwrap();
+        assert_eq!(
+            *waiting.get_request(),
+            LockRequest::Lock(LockTarget::Row(row(0)), LockMode::Shared)
+        );
+        assert_eq!(waiting.get_blockers(), &[holder]);
+
+        // the killed waiter gives up its request and the abort is recorded
+        lock_manager.kill(waiter);
+        assert!(handle.join().is_err());
+        assert_eq!(lock_manager.get_waiting_request(waiter), None);
+        let history = lock_manager.get_abort_history();
+        let record = history
+            .iter()
+            .find(|record| record.get_tid() == waiter)
+            .unwrap();
+        assert_eq!(record.get_reason(), AbortReason::Killed);
     }
 
-    // gets the set of pages locked by the specified transaction
-    pub fn get_locked_pages(&self, tid: TransactionId) -> HashSet<HeapPageId> {
-        let transaction_locks = self.transaction_to_locks.read().unwrap();
-        match transaction_locks.get(&tid) {
-            Some(locks) => locks.iter().map(|lock| lock.pid).collect(),
-            None => HashSet::new(),
+    #[test]
+    fn test_younger_waits_without_deadlock() {
+        let ids = TransactionIds::new();
+        // unlike WAIT-DIE, these policies let a younger requester wait for an older holder
+        for policy in [DeadlockPolicy::WoundWait, DeadlockPolicy::Detection] {
+            let lock_manager = Arc::new(LockManager::new(Weak::new()));
+            lock_manager.set_deadlock_policy(policy);
+            let (older, younger) = (ids.next(), ids.next());
+            lock_manager.lock_row(older, row(0), true);
+
+            let handle = {
+                let lock_manager = Arc::clone(&lock_manager);
+                thread::spawn(move || lock_manager.lock_row(younger, row(0), true))
+            };
+            wait_until_blocked(&lock_manager, younger);
+            lock_manager.release_locks(older);
+            handle.join().unwrap();
+            assert_eq!(
+                lock_manager.get_lock_mode(younger, LockTarget::Row(row(0))),
+                Some(LockMode::Exclusive)
+            );
         }
     }
+
+    #[test]
+    fn test_wound_wait() {
+        let ids = TransactionIds::new();
+        let lock_manager = Arc::new(LockManager::new(Weak::new()));
+        lock_manager.set_deadlock_policy(DeadlockPolicy::WoundWait);
+        let (older, younger) = (ids.next(), ids.next());
+        lock_manager.lock_row(younger, row(0), true);
+
+        let handle = {
+            let lock_manager = Arc::clone(&lock_manager);
+            thread::spawn(move || lock_manager.lock_row(older, row(0), true))
+        };
+        wait_until_blocked(&lock_manager, older);
+        // the younger holder was wounded and aborts on its next request
+        assert!(lock_manager.is_wounded(younger));
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            lock_manager.lock_row(younger, row(1), true)
+        }));
+        assert!(result.is_err());
+        handle.join().unwrap();
+        assert_eq!(
+            lock_manager.get_lock_mode(older, LockTarget::Row(row(0))),
+            Some(LockMode::Exclusive)
+        );
+        assert_eq!(
+            lock_manager.get_lock_mode(younger, LockTarget::Row(row(0))),
+            None
+        );
+    }
+
+    #[test]
+    fn test_deadlock_detection() {
+        let ids = TransactionIds::new();
+        let lock_manager = Arc::new(LockManager::new(Weak::new()));
+        lock_manager.set_deadlock_policy(DeadlockPolicy::Detection);
+        let (first, second) = (ids.next(), ids.next());
+        lock_manager.lock_row(first, row(0), true);
+        lock_manager.lock_row(second, row(1), true);
+
+        let handle = {
+            let lock_manager = Arc::clone(&lock_manager);
+            thread::spawn(move || lock_manager.lock_row(first, row(1), true))
+        };
+        wait_until_blocked(&lock_manager, first);
+        // closing the cycle makes the youngest transaction in it the victim
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            lock_manager.lock_row(second, row(0), true)
+        }));
+        assert!(result.is_err());
+        handle.join().unwrap();
+        assert_eq!(
+            lock_manager.get_lock_mode(first, LockTarget::Row(row(1))),
+            Some(LockMode::Exclusive)
+        );
+    }
+
+    #[test]
+    fn test_lock_timeout() {
+        let ids = TransactionIds::new();
+        let lock_manager = LockManager::new(Weak::new());
+        lock_manager.set_deadlock_policy(DeadlockPolicy::Detection);
+        lock_manager.set_lock_timeout(Some(Duration::from_millis(100)));
+        let (holder, waiter) = (ids.next(), ids.next());
+        lock_manager.lock_table(holder, TABLE, LockMode::Exclusive);
+
+        // there is no deadlock, but the waiter gives up once the timeout passes
+        let start = Instant::now();
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            lock_manager.lock_row(waiter, row(0), false)
+        }));
+        assert!(result.is_err());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(
+            lock_manager.get_lock_mode(waiter, LockTarget::Table(TABLE)),
+            None
+        );
+    }
 }
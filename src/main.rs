@@ -1,25 +1,83 @@
+mod backup;
 mod buffer_pool;
 mod catalog;
+mod check;
+mod checksum;
+mod csv;
 mod database;
+mod dump;
 mod fields;
+mod free_space_map;
+mod group_commit;
 mod heap_file;
 mod heap_page;
+mod inspect;
+mod interrupt;
+mod json;
 mod lock_manager;
+mod mvcc;
+mod plan;
+mod server;
+mod sql;
+mod system_table;
 mod table;
 mod transaction;
 mod tuple;
 mod types;
 mod view;
 
+use std::sync::Arc;
 use std::thread;
+
+// Usage: rustic_db [data directory] [schema file], which default to data and schemas.txt
+//        rustic_db check [data directory] [--repair]
+//        rustic_db inspect <data directory> <table> [page number] [--json]
+//        rustic_db import <data directory> <table> <file> [--rejects <file>] [--format <format>]
+//        rustic_db export <data directory> <table> [file] [--columns <a,b,...>] [--format <format>]
+//        rustic_db dump <data directory> [file]
+//        rustic_db restore <data directory> <file>
+//        rustic_db backup <data directory> <backup directory> [--verify]
+//        rustic_db explain <data directory> <query> [--analyze]
+//        rustic_db serve <data directory> [--tcp <address> | --unix <path>] [--max-connections <n>]
 fn main() {
-    let db = database::get_global_db();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let status = match args.first().map(String::as_str) {
+        Some("check") => Some(run_check(&args[1..])),
+        Some("inspect") => Some(run_inspect(&args[1..]).map(|()| 0)),
+        Some("import") => Some(run_import(&args[1..]).map(|()| 0)),
+        Some("export") => Some(run_export(&args[1..]).map(|()| 0)),
+        Some("dump") => Some(run_dump(&args[1..]).map(|()| 0)),
+        Some("restore") => Some(run_restore(&args[1..]).map(|()| 0)),
+        Some("backup") => Some(run_backup(&args[1..]).map(|()| 0)),
+        Some("explain") => Some(run_explain(&args[1..]).map(|()| 0)),
+        Some("serve") => Some(run_serve(&args[1..]).map(|()| 0)),
+        _ => None,
+    };
+    if let Some(status) = status {
+        let status = status.unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            2
+        });
+        if status != 0 {
+            std::process::exit(status);
+        }
+        return;
+    }
+    let mut args = args.into_iter();
+    let data_dir = args
+        .next()
+        .unwrap_or(database::DEFAULT_DATA_DIR.to_string());
+    let schema_file_path = args.next().unwrap_or("schemas.txt".to_string());
+    let config = database::DatabaseConfig::for_data_dir(data_dir);
+    let db = config
+        .and_then(database::Database::open)
+        .unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        });
 
-    // 1. Load the schemas and tables from the schemas.txt file
-    let mut schema_file_path = std::env::current_dir().unwrap();
-    schema_file_path.push("schemas.txt");
-    db.get_catalog()
-        .load_schema(schema_file_path.to_str().unwrap());
+    // 1. Load the schemas and tables from the schema file
+    db.get_catalog().load_schema(&schema_file_path);
 
     // 2. Retrieve the list of catalogs
     let catalog = db.get_catalog();
@@ -37,12 +95,13 @@ fn main() {
     println!("table name: {:?}", td.get_field_name(0));
     let handles: Vec<_> = (0..3)
         .map(|_| {
-            let db = database::get_global_db();
+            let db = Arc::clone(&db);
             let table = db.get_catalog().get_table_from_id(table_id).unwrap();
             let td = table.get_tuple_desc().clone();
             thread::spawn(move || loop {
                 let res = std::panic::catch_unwind(|| {
-                    let tid = transaction::TransactionId::new();
+                    let txn = transaction::Transaction::builder().database(&db).begin();
+                    let tid = txn.get_tid();
                     let bp = db.get_buffer_pool();
                     let name = format!("Alice_{}", tid.get_tid());
                     for i in 0..3 {
@@ -59,9 +118,10 @@ fn main() {
                                 ],
                                 &td,
                             ),
-                        );
+                        )
+                        .unwrap();
                     }
-                    bp.commit_transaction(tid);
+                    txn.commit().unwrap();
                 });
                 if res.is_err() {
                     println!("thread {:?} aborted", thread::current().id());
@@ -81,27 +141,36 @@ fn main() {
     // 6. Print out the tuples in the employee table
     let mut tuple_count = 0;
     let mut page_count = 0;
-    let tid = transaction::TransactionId::new();
+    let txn = transaction::Transaction::builder().database(&db).begin();
+    let tid = txn.get_tid();
     let table = catalog.get_table_from_id(table_id).unwrap();
+    let snapshot = db.get_buffer_pool().get_snapshot(tid);
     for page in table.iter(tid) {
+        let page = match page {
+            Ok(page) => page,
+            Err(error) => {
+                println!("{}", error);
+                break;
+            }
+        };
         let page = page.read().unwrap();
         page_count += 1;
-        for tuple in page.iter() {
+        for tuple in page.iter_visible(&snapshot) {
             println!("tuple: {}", tuple);
             tuple_count += 1;
         }
     }
-    let bp = db.get_buffer_pool();
-    bp.commit_transaction(tid);
+    txn.commit().unwrap();
 
-    print!("page count: {}\n", page_count);
-    print!("tuple count: {}\n", tuple_count);
+    println!("page count: {}", page_count);
+    println!("tuple count: {}", tuple_count);
 
     // my stuff trying to create user friendly tables
-    print!("my stuff\n\n\n");
+    println!("my stuff\n\n");
 
-    let my_table = table::Table::new("employess".to_string(), "schema.txt".to_string());
+    let my_table = table::Table::open(&db, "employees").unwrap();
 
+    let txn = transaction::Transaction::builder().database(&db).begin();
     my_table.insert_tuple(
         tuple::Tuple::new(
             vec![
@@ -110,10 +179,314 @@ fn main() {
             ],
             &td,
         ),
-        transaction::TransactionId::new(),
+        &txn,
     );
 
-    my_table.print();
+    my_table.print(&txn);
+    txn.commit().unwrap();
+}
+
+// Checks the database in the data directory, printing the report, and returns the exit
+// status, which is 1 if problems were found
+fn run_check(args: &[String]) -> Result<i32, String> {
+    let repair = args.iter().any(|arg| arg == "--repair");
+    let data_dir = args
+        .iter()
+        .find(|arg| *arg != "--repair")
+        .cloned()
+        .unwrap_or(database::DEFAULT_DATA_DIR.to_string());
+    let config = database::DatabaseConfig::for_data_dir(data_dir)?;
+    let report = check::check(&config, repair)?;
+    println!("{}", report);
+    Ok(if report.is_clean() { 0 } else { 1 })
+}
+
+// Prints the decoded pages of a table, or of one page of it, as text or JSON
+fn run_inspect(args: &[String]) -> Result<(), String> {
+    let json = args.iter().any(|arg| arg == "--json");
+    let mut args = args.iter().filter(|arg| *arg != "--json");
+    let usage = "Usage: rustic_db inspect <data directory> <table> [page number] [--json]";
+    let data_dir = args.next().ok_or(usage)?;
+    let table_name = args.next().ok_or(usage)?;
+    let config = database::DatabaseConfig::for_data_dir(data_dir)?;
+    let pages = match args.next() {
+        Some(page_number) => {
+            let page_number: usize = page_number
+                .parse()
+                .map_err(|_| format!("Invalid page number {}", page_number))?;
+            Some(page_number..page_number + 1)
+        }
+        None => None,
+    };
+    let pages = inspect::inspect_table(&config, table_name, pages)?;
+    if json {
+        println!("{}", inspect::to_json(table_name, &pages));
+    } else {
+        for page in pages {
+            println!("{}", page);
+        }
+    }
+    Ok(())
+}
+
+// Formats of imported and exported files. JSON is an array of objects, which only
+// exports write
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FileFormat {
+    Csv,
+    JsonLines,
+    Json,
+}
+
+// Takes the format from the --format option, or else from the extension of the file name,
+// defaulting to CSV
+fn file_format(format: Option<String>, path: Option<&String>) -> Result<FileFormat, String> {
+    let format = format.or_else(|| {
+        let path = std::path::Path::new(path?);
+        Some(path.extension()?.to_str()?.to_lowercase())
+    });
+    match format.as_deref() {
+        Some("jsonl" | "ndjson") => Ok(FileFormat::JsonLines),
+        Some("json") => Ok(FileFormat::Json),
+        Some("csv") | None => Ok(FileFormat::Csv),
+        Some(format) => Err(format!(
+            "Unknown format {}, expected csv, jsonl or json",
+            format
+        )),
+    }
+}
+
+// Removes the option and the value after it from the arguments, returning the value
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>, String> {
+    match args.iter().position(|arg| arg == name) {
+        Some(i) if i + 1 < args.len() => {
+            let value = args.remove(i + 1);
+            args.remove(i);
+            Ok(Some(value))
+        }
+        Some(_) => Err(format!("{} needs a value", name)),
+        None => Ok(None),
+    }
+}
+
+// Imports a CSV or JSON Lines file into a table in one transaction, printing the rows that
+// were rejected
+fn run_import(args: &[String]) -> Result<(), String> {
+    let mut args = args.to_vec();
+    let rejects_path = take_option(&mut args, "--rejects")?;
+    let format = take_option(&mut args, "--format")?;
+    let usage = "Usage: rustic_db import <data directory> <table> <file> [--rejects <file>] \
+                 [--format csv|jsonl]";
+    let [data_dir, table_name, path] = args.as_slice() else {
+        return Err(usage.to_string());
+    };
+    let import = match file_format(format, Some(path))? {
+        FileFormat::Csv => csv::import,
+        FileFormat::JsonLines => json::import,
+        FileFormat::Json => return Err("Import JSON as JSON Lines, one object per line".into()),
+    };
+    let file = std::fs::File::open(path).map_err(|e| format!("Cannot open {}: {}", path, e))?;
+    let mut rejects = match &rejects_path {
+        Some(rejects_path) => Some(
+            std::fs::File::create(rejects_path)
+                .map_err(|e| format!("Cannot create {}: {}", rejects_path, e))?,
+        ),
+        None => None,
+    };
+    let config = database::DatabaseConfig::for_data_dir(data_dir)?;
+    let db = database::Database::open(config)?;
+    let table = table::Table::open(&db, table_name)?;
+    let txn = transaction::Transaction::builder().database(&db).begin();
+    let report = import(
+        &table,
+        std::io::BufReader::new(file),
+        &txn,
+        rejects.as_mut().map(|file| file as &mut dyn std::io::Write),
+    );
+    match report {
+        Ok(report) => {
+            txn.commit()?;
+            println!("{}", report);
+            Ok(())
+        }
+        Err(error) => {
+            txn.rollback()?;
+            Err(error)
+        }
+    }
+}
+
+// Exports a table, or some of its columns, as CSV, JSON Lines or a JSON array to a file or
+// standard output
+fn run_export(args: &[String]) -> Result<(), String> {
+    let mut args = args.to_vec();
+    let columns = take_option(&mut args, "--columns")?;
+    let format = take_option(&mut args, "--format")?;
+    let usage = "Usage: rustic_db export <data directory> <table> [file] [--columns <a,b,...>] \
+                 [--format csv|jsonl|json]";
+    let (data_dir, table_name, path) = match args.as_slice() {
+        [data_dir, table_name] => (data_dir, table_name, None),
+        [data_dir, table_name, path] => (data_dir, table_name, Some(path)),
+        _ => return Err(usage.to_string()),
+    };
+    let format = file_format(format, path)?;
+    let config = database::DatabaseConfig::for_data_dir(data_dir)?;
+    let db = database::Database::open(config)?;
+    let table = table::Table::open(&db, table_name)?;
+    let mut writer: Box<dyn std::io::Write> = match path {
+        Some(path) => Box::new(std::io::BufWriter::new(
+            std::fs::File::create(path).map_err(|e| format!("Cannot create {}: {}", path, e))?,
+        )),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let columns: Option<Vec<String>> =
+        columns.map(|columns| columns.split(',').map(|c| c.trim().to_string()).collect());
+    let td = table.get_tuple_desc();
+    if let Some(column) = columns
+        .iter()
+        .flatten()
+        .find(|c| td.name_to_id(c).is_none())
+    {
+        return Err(format!("The table has no field named {}", column));
+    }
+    let txn = transaction::Transaction::builder().database(&db).begin();
+    let mut query = table.scan(usize::MAX, &txn);
+    if let Some(columns) = columns {
+        query = query.project(columns);
+    }
+    let result = query.fetch().and_then(|_| match format {
+        FileFormat::Csv => csv::export_tuples(&query.get_tuple_desc(), query, &mut writer),
+        FileFormat::JsonLines => json::export_tuples(query, &mut writer),
+        FileFormat::Json => json::write_array(query, &mut writer),
+    });
+    txn.commit()?;
+    if let (Ok(count), Some(path)) = (&result, path) {
+        println!("Exported {} rows to {}", count, path);
+    }
+    result.map(|_| ())
+}
+
+// Dumps every table of the database to a file or standard output
+fn run_dump(args: &[String]) -> Result<(), String> {
+    let usage = "Usage: rustic_db dump <data directory> [file]";
+    let (data_dir, path) = match args {
+        [data_dir] => (data_dir, None),
+        [data_dir, path] => (data_dir, Some(path)),
+        _ => return Err(usage.to_string()),
+    };
+    let config = database::DatabaseConfig::for_data_dir(data_dir)?;
+    let db = database::Database::open(config)?;
+    let mut writer: Box<dyn std::io::Write> = match path {
+        Some(path) => Box::new(std::io::BufWriter::new(
+            std::fs::File::create(path).map_err(|e| format!("Cannot create {}: {}", path, e))?,
+        )),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let report = dump::dump(&db, &mut writer)?;
+    if let Some(path) = path {
+        println!("Dumped {} to {}", report, path);
+    }
+    Ok(())
+}
+
+// Restores a dump into a data directory without the tables of the dump
+fn run_restore(args: &[String]) -> Result<(), String> {
+    let [data_dir, path] = args else {
+        return Err("Usage: rustic_db restore <data directory> <file>".to_string());
+    };
+    let file = std::fs::File::open(path).map_err(|e| format!("Cannot open {}: {}", path, e))?;
+    let config = database::DatabaseConfig::for_data_dir(data_dir)?;
+    let db = database::Database::open(config)?;
+    let report = dump::restore(&db, std::io::BufReader::new(file))?;
+    println!("Restored {}", report);
+    Ok(())
+}
+
+// Backs up the database to a new directory and optionally checks the backup
+fn run_backup(args: &[String]) -> Result<(), String> {
+    let verify = args.iter().any(|arg| arg == "--verify");
+    let args: Vec<&String> = args.iter().filter(|arg| *arg != "--verify").collect();
+    let [data_dir, backup_dir] = args.as_slice() else {
+        return Err(
+            "Usage: rustic_db backup <data directory> <backup directory> [--verify]".to_string(),
+        );
+    };
+    let config = database::DatabaseConfig::for_data_dir(data_dir)?;
+    let db = database::Database::open(config)?;
+    let report = backup::backup(&db, backup_dir)?;
+    println!("{}", report);
+    if verify {
+        let check_report = backup::verify(report.get_config())?;
+        println!("{}", check_report);
+        if !check_report.is_clean() {
+            return Err("The backup failed verification".to_string());
+        }
+    }
+    Ok(())
+}
+
+// Prints the plan of a SELECT with estimated rows and costs. With --analyze the query runs
+// and every operator shows what it did
+fn run_explain(args: &[String]) -> Result<(), String> {
+    let analyze = args.iter().any(|arg| arg == "--analyze");
+    let args: Vec<&String> = args.iter().filter(|arg| *arg != "--analyze").collect();
+    let [data_dir, query] = args.as_slice() else {
+        return Err("Usage: rustic_db explain <data directory> <query> [--analyze]".to_string());
+    };
+    let query = sql::parse(query)?;
+    let config = database::DatabaseConfig::for_data_dir(data_dir)?;
+    let db = database::Database::open(config)?;
+    let txn = transaction::Transaction::builder().database(&db).begin();
+    let lines = sql::explain(&db, query, analyze, &txn)?;
+    txn.commit()?;
+    for line in lines {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+// Serves the database in the data directory until a client sends SHUTDOWN. Listens on
+// 127.0.0.1:5454 unless given another TCP address or a Unix socket
+fn run_serve(args: &[String]) -> Result<(), String> {
+    let mut args = args.to_vec();
+    let tcp = take_option(&mut args, "--tcp")?;
+    let unix = take_option(&mut args, "--unix")?;
+    let max_connections = match take_option(&mut args, "--max-connections")? {
+        Some(n) => n
+            .parse()
+            .map_err(|_| format!("Invalid number of connections {}", n))?,
+        None => server::DEFAULT_MAX_CONNECTIONS,
+    };
+    let deadlock_policy = match take_option(&mut args, "--deadlock-policy")?.as_deref() {
+        Some("wait-die") | None => lock_manager::DeadlockPolicy::WaitDie,
+        Some("wound-wait") => lock_manager::DeadlockPolicy::WoundWait,
+        Some("detection") => lock_manager::DeadlockPolicy::Detection,
+        Some(policy) => return Err(format!("Unknown deadlock policy {}", policy)),
+    };
+    let lock_timeout = take_option(&mut args, "--lock-timeout")?;
+    let usage = "Usage: rustic_db serve <data directory> [--tcp <address> | --unix <path>] \
+                 [--max-connections <n>] [--deadlock-policy wait-die|wound-wait|detection] \
+                 [--lock-timeout <ms>]";
+    let [data_dir] = args.as_slice() else {
+        return Err(usage.to_string());
+    };
+    let mut config =
+        database::DatabaseConfig::for_data_dir(data_dir)?.deadlock_policy(deadlock_policy);
+    if let Some(ms) = lock_timeout {
+        let ms = ms
+            .parse()
+            .map_err(|_| format!("Invalid lock timeout {}", ms))?;
+        config = config.lock_timeout(std::time::Duration::from_millis(ms));
+    }
+    let db = database::Database::open(config)?;
+    let server = match (tcp, unix) {
+        (None, Some(path)) => server::Server::bind_unix(&db, path)?,
+        (tcp, None) => server::Server::bind_tcp(&db, tcp.as_deref().unwrap_or("127.0.0.1:5454"))?,
+        (Some(_), Some(_)) => return Err(usage.to_string()),
+    };
+    let server = server.max_connections(max_connections);
+    println!("Listening on {}", server.get_address());
+    server.run()
 }
 
 #[cfg(test)]
@@ -142,8 +515,8 @@ mod test {
             ],
             &my_table.get_tuple_desc().clone(),
         );
-        let tid = transaction::TransactionId::new();
-        my_table.insert_tuple(tuple_to_insert.clone(), tid);
+        let txn = transaction::Transaction::begin();
+        my_table.insert_tuple(tuple_to_insert.clone(), &txn);
 
         // Insert multiple tuples into the table
         let tuple_collection = (1..20)
@@ -162,18 +535,18 @@ mod test {
                 )
             })
             .collect();
-        my_table.insert_many_tuples(tuple_collection, tid);
+        my_table.insert_many_tuples(tuple_collection, &txn);
 
         // We can then scan the table to see all of our results
         println!("-------------");
         println!("----SCAN-----");
         println!("-------------");
-        let scan = my_table.scan(20, tid);
-        for tuple in scan.into_iter() {
+        let scan = my_table.scan(20, &txn);
+        for tuple in scan {
             println!("{}", tuple);
         }
 
-        let mut scan2 = my_table.scan(5, tid);
+        let mut scan2 = my_table.scan(5, &txn);
 
         // simple filtering, using a predicate
         println!("---------------");
@@ -181,7 +554,7 @@ mod test {
         println!("---------------");
         let pred = table::Predicate::GreaterThan(1);
         scan2.table_filter("id", pred);
-        for tuple in scan2.into_iter() {
+        for tuple in scan2 {
             println!("{}", tuple);
         }
         // performing a filter on the scan, on the field "id" with the predicate "GreaterThan(1)"
@@ -207,11 +580,11 @@ mod test {
                 )
             })
             .collect();
-        my_table2.insert_many_tuples(tuple_collection2, tid);
+        my_table2.insert_many_tuples(tuple_collection2, &txn);
 
         // grab two scans, combine both scans into a join
-        let scan3 = my_table2.scan(5, tid);
-        let scan4 = my_table.scan(20, tid);
+        let scan3 = my_table2.scan(5, &txn);
+        let scan4 = my_table.scan(20, &txn);
         let join = scan3.join(&scan4, "title", "id");
 
         for tuple in join {
@@ -221,11 +594,2057 @@ mod test {
         println!("--------------");
         println!("--PROJECTION--");
         println!("--------------");
-        let scan5 = my_table.scan(2, tid);
+        let scan5 = my_table.scan(2, &txn);
         let proj = scan5.project(vec!["id".to_string()]);
         for tuple in proj {
             println!("{}", tuple);
         }
+
+        // roll back so the shared tables do not grow with every run
+        txn.rollback().unwrap();
+    }
+
+    #[test]
+    fn test_transaction_lifecycle() {
+        let table = reset_table("testrollback", &["Alice"]);
+        let txn = transaction::Transaction::begin();
+        assert_eq!(txn.get_state(), transaction::TransactionState::Active);
+        rename(&table, 0, "Bob", &txn).unwrap();
+        txn.commit().unwrap();
+        assert_eq!(txn.get_state(), transaction::TransactionState::Committed);
+        // a transaction ends only once
+        assert!(txn.commit().is_err());
+        assert!(txn.rollback().is_err());
+
+        let txn = transaction::Transaction::begin();
+        rename(&table, 0, "Carol", &txn).unwrap();
+        txn.rollback().unwrap();
+        assert_eq!(txn.get_state(), transaction::TransactionState::Aborted);
+        assert_eq!(
+            names(&table, &transaction::Transaction::begin()),
+            vec!["Bob"]
+        );
+
+        // dropping an active transaction rolls it back and releases its locks
+        {
+            let txn = transaction::Transaction::begin();
+            rename(&table, 0, "Dan", &txn).unwrap();
+        }
+        // a transaction that dies waiting for a lock is aborted even if the panic is caught
+        let older = transaction::Transaction::begin();
+        rename(&table, 0, "Dan", &older).unwrap();
+        let younger = transaction::Transaction::begin();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            rename(&table, 0, "Frank", &younger)
+        }));
+        assert!(!matches!(result, Ok(Ok(_))));
+        assert_eq!(younger.get_state(), transaction::TransactionState::Aborted);
+        assert!(rename(&table, 0, "Frank", &younger).is_err());
+        assert!(younger.commit().is_err());
+        older.rollback().unwrap();
+        drop(younger);
+
+        let txn = transaction::Transaction::begin();
+        assert_eq!(names(&table, &txn), vec!["Bob"]);
+        rename(&table, 0, "Erin", &txn).unwrap();
+        txn.commit().unwrap();
+        assert_eq!(
+            names(&table, &transaction::Transaction::begin()),
+            vec!["Erin"]
+        );
+    }
+
+    #[test]
+    fn test_explain() {
+        let db = database::get_global_db();
+        let mut schema_file_path = std::env::current_dir().unwrap();
+        schema_file_path.push("schemas.txt");
+        db.get_catalog()
+            .load_schema(schema_file_path.to_str().unwrap());
+
+        let products = table::Table::new("products".to_string(), "schema.txt".to_string());
+        let employees = table::Table::new("employees".to_string(), "schema.txt".to_string());
+        let txn = transaction::Transaction::begin();
+
+        // EXPLAIN only describes the plan
+        let mut scan = products.scan(10, &txn);
+        scan.table_filter("id", table::Predicate::GreaterThan(1));
+        let other = employees.scan(10, &txn);
+        let join = scan.join(&other, "id", "id");
+        let plan = join.explain();
+        println!("{}", plan);
+        assert!(plan.starts_with("Nested Loop Join (id = id)"));
+        assert!(plan.contains("  -> Filter (id > 1)"));
+        assert!(plan.contains("    -> Seq Scan on products (limit 10)"));
+        assert!(plan.contains("  -> Seq Scan on employees (limit 10)"));
+        assert!(!plan.contains("actual"));
+
+        // EXPLAIN ANALYZE runs the plan and reports what each operator did
+        let mut proj = products.scan(3, &txn).project(vec!["name".to_string()]);
+        let analyzed = proj.explain_analyze().unwrap();
+        println!("{}", analyzed);
+        assert_eq!(analyzed.matches("actual rows=").count(), 2);
+        let rows = format!("(actual rows={} ", proj.count());
+        assert!(analyzed.lines().next().unwrap().contains(&rows));
+
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn test_update_where() {
+        let db = database::get_global_db();
+        let mut schema_file_path = std::env::current_dir().unwrap();
+        schema_file_path.push("schemas.txt");
+        db.get_catalog()
+            .load_schema(schema_file_path.to_str().unwrap());
+
+        let table = table::Table::new("testupdates".to_string(), "schema.txt".to_string());
+        let txn = transaction::Transaction::begin();
+        let tuple_collection = (0..5)
+            .map(|i| {
+                tuple::Tuple::new(
+                    vec![
+                        fields::FieldVal::IntField(fields::IntField::new(i)),
+                        fields::FieldVal::StringField(fields::StringField::new(
+                            format!("Alice_{}", i),
+                            7,
+                        )),
+                    ],
+                    &table.get_tuple_desc().clone(),
+                )
+            })
+            .collect();
+        table.insert_many_tuples(tuple_collection, &txn);
+
+        let mut scan = table.scan(100, &txn);
+        scan.table_filter("id", table::Predicate::GreaterThan(2));
+        let before: Vec<tuple::Tuple> = scan.collect();
+
+        // rename every employee with id > 2
+        let count = table
+            .update_where(
+                "id",
+                table::Predicate::GreaterThan(2),
+                vec![(
+                    "name".to_string(),
+                    fields::FieldVal::StringField(fields::StringField::new("Bob".to_string(), 3)),
+                )],
+                &txn,
+            )
+            .unwrap();
+        assert_eq!(count, before.len());
+
+        let mut scan = table.scan(100, &txn);
+        scan.table_filter("name", table::Predicate::Equals("Bob".to_string()));
+        let after: Vec<tuple::Tuple> = scan.collect();
+        assert_eq!(after.len(), before.len());
+        for (old, new) in before.iter().zip(after.iter()) {
+            // updated tuples stay in their slots
+            assert_eq!(old.get_record_id(), new.get_record_id());
+            assert_eq!(old.get_field(0), new.get_field(0));
+        }
+
+        // unknown columns and mismatched types are rejected
+        assert!(table
+            .update_where(
+                "id",
+                table::Predicate::EqualsInt(0),
+                vec![(
+                    "salary".to_string(),
+                    fields::FieldVal::IntField(fields::IntField::new(1))
+                )],
+                &txn,
+            )
+            .is_err());
+        assert!(table
+            .update_where(
+                "id",
+                table::Predicate::EqualsInt(0),
+                vec![(
+                    "name".to_string(),
+                    fields::FieldVal::IntField(fields::IntField::new(1))
+                )],
+                &txn,
+            )
+            .is_err());
+
+        txn.rollback().unwrap();
+    }
+
+    #[test]
+    fn test_delete_where() {
+        let db = database::get_global_db();
+        let mut schema_file_path = std::env::current_dir().unwrap();
+        schema_file_path.push("schemas.txt");
+        db.get_catalog()
+            .load_schema(schema_file_path.to_str().unwrap());
+
+        let table = table::Table::new("testdeletes".to_string(), "schema.txt".to_string());
+        let txn = transaction::Transaction::begin();
+        let tuple_collection = (0..5)
+            .map(|i| {
+                tuple::Tuple::new(
+                    vec![
+                        fields::FieldVal::IntField(fields::IntField::new(i)),
+                        fields::FieldVal::StringField(fields::StringField::new(
+                            format!("Alice_{}", i),
+                            7,
+                        )),
+                    ],
+                    &table.get_tuple_desc().clone(),
+                )
+            })
+            .collect();
+        table.insert_many_tuples(tuple_collection, &txn);
+
+        let total = table.scan(100, &txn).count();
+        let mut scan = table.scan(100, &txn);
+        scan.table_filter("id", table::Predicate::LessThan(2));
+        let matching = scan.count();
+
+        let count = table
+            .delete_where("id", table::Predicate::LessThan(2), &txn)
+            .unwrap();
+        assert_eq!(count, matching);
+        assert_eq!(table.scan(100, &txn).count(), total - matching);
+        let mut scan = table.scan(100, &txn);
+        scan.table_filter("id", table::Predicate::LessThan(2));
+        assert_eq!(scan.count(), 0);
+
+        // deleting through the buffer pool needs only the tuple's record id
+        let tuple = table.scan(1, &txn).next().unwrap();
+        let bp = db.get_buffer_pool();
+        bp.delete_tuple(txn.get_tid(), tuple.clone()).unwrap();
+        assert!(bp.delete_tuple(txn.get_tid(), tuple).is_err());
+        assert_eq!(table.scan(100, &txn).count(), total - matching - 1);
+
+        txn.rollback().unwrap();
+    }
+
+    #[test]
+    fn test_truncate() {
+        let db = database::get_global_db();
+        let mut schema_file_path = std::env::current_dir().unwrap();
+        schema_file_path.push("schemas.txt");
+        db.get_catalog()
+            .load_schema(schema_file_path.to_str().unwrap());
+
+        let table = table::Table::new("testtruncate".to_string(), "schema.txt".to_string());
+        let heap_file = db
+            .get_catalog()
+            .get_table_from_name("testtruncate")
+            .unwrap();
+        let make_tuples = |n: i32| {
+            (0..n)
+                .map(|i| {
+                    tuple::Tuple::new(
+                        vec![
+                            fields::FieldVal::IntField(fields::IntField::new(i)),
+                            fields::FieldVal::StringField(fields::StringField::new(
+                                format!("Alice_{}", i),
+                                7,
+                            )),
+                        ],
+                        &table.get_tuple_desc().clone(),
+                    )
+                })
+                .collect::<Vec<tuple::Tuple>>()
+        };
+        let txn = transaction::Transaction::begin();
+        table.insert_many_tuples(make_tuples(40), &txn);
+        txn.commit().unwrap();
+
+        // aborting brings the tuples back
+        let txn = transaction::Transaction::begin();
+        let count = table.scan(1000, &txn).count();
+        assert!(count >= 40);
+        table.truncate(&txn);
+        assert_eq!(table.scan(1000, &txn).count(), 0);
+        txn.rollback().unwrap();
+
+        let txn = transaction::Transaction::begin();
+        assert_eq!(table.scan(1000, &txn).count(), count);
+        txn.commit().unwrap();
+
+        // rolling back a bulk load after a truncate leaves the file as it was
+        let num_pages = heap_file.num_pages();
+        let txn = transaction::Transaction::begin();
+        table.truncate(&txn);
+        table.bulk_load(make_tuples(100), &txn);
+        txn.rollback().unwrap();
+        assert_eq!(heap_file.num_pages(), num_pages);
+
+        // rolling back to a savepoint before the truncate keeps the changes made before it
+        let txn = transaction::Transaction::begin();
+        table.insert_many_tuples(make_tuples(1), &txn);
+        txn.savepoint("before").unwrap();
+        table.truncate(&txn);
+        table.insert_many_tuples(make_tuples(2), &txn);
+        assert_eq!(table.scan(1000, &txn).count(), 2);
+        txn.rollback_to("before").unwrap();
+        assert_eq!(table.scan(1000, &txn).count(), count + 1);
+        txn.rollback().unwrap();
+
+        // the truncate waits for the transactions that read the table, and the pages are
+        // gone as soon as it commits. It began first, so it waits instead of dying
+        let txn = transaction::Transaction::begin();
+        let reader = transaction::Transaction::builder()
+            .isolation(transaction::IsolationLevel::RepeatableRead)
+            .begin();
+        assert_eq!(table.scan(1000, &reader).count(), count);
+        let handle = thread::spawn(move || {
+            let table = table::Table::new("testtruncate".to_string(), "schema.txt".to_string());
+            table.truncate(&txn);
+            assert_eq!(table.scan(1000, &txn).count(), 0);
+            txn.commit().unwrap();
+        });
+        thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(heap_file.num_pages(), num_pages);
+        reader.commit().unwrap();
+        handle.join().unwrap();
+        assert_eq!(heap_file.num_pages(), 0);
+
+        let txn = transaction::Transaction::begin();
+        assert_eq!(table.scan(1000, &txn).count(), 0);
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let db = database::get_global_db();
+        let mut schema_file_path = std::env::current_dir().unwrap();
+        schema_file_path.push("schemas.txt");
+        db.get_catalog()
+            .load_schema(schema_file_path.to_str().unwrap());
+
+        let table = table::Table::new("testbulkload".to_string(), "schema.txt".to_string());
+        let heap_file = db
+            .get_catalog()
+            .get_table_from_name("testbulkload")
+            .unwrap();
+        let td = table.get_tuple_desc().clone();
+        let make_tuples = move |n: i32| {
+            let td = td.clone();
+            (0..n).map(move |i| {
+                let name = format!("Alice_{}", i);
+                let length = name.len();
+                tuple::Tuple::new(
+                    vec![
+                        fields::FieldVal::IntField(fields::IntField::new(i)),
+                        fields::FieldVal::StringField(fields::StringField::new(
+                            name,
+                            length as u32,
+                        )),
+                    ],
+                    &td,
+                )
+            })
+        };
+
+        empty_table(&table);
+
+        // enough tuples to span more than one batch of pages
+        let txn = transaction::Transaction::begin();
+        assert_eq!(table.bulk_load(make_tuples(1000), &txn), 1000);
+        txn.commit().unwrap();
+        let num_pages = heap_file.num_pages();
+        assert!(num_pages > heap_file::BULK_LOAD_BATCH_PAGES);
+
+        let txn = transaction::Transaction::begin();
+        let loaded: Vec<tuple::Tuple> = table.scan(2000, &txn).collect();
+        assert_eq!(loaded.len(), 1000);
+        assert_eq!(
+            loaded[999].get_field(0),
+            Some(&fields::FieldVal::IntField(fields::IntField::new(999)))
+        );
+        txn.commit().unwrap();
+
+        // aborting drops the appended pages
+        let txn = transaction::Transaction::begin();
+        table.bulk_load(make_tuples(100), &txn);
+        assert!(heap_file.num_pages() > num_pages);
+        txn.rollback().unwrap();
+        assert_eq!(heap_file.num_pages(), num_pages);
+
+        let txn = transaction::Transaction::begin();
+        assert_eq!(table.scan(2000, &txn).count(), 1000);
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn test_free_space_reuse() {
+        let db = database::get_global_db();
+        let mut schema_file_path = std::env::current_dir().unwrap();
+        schema_file_path.push("schemas.txt");
+        db.get_catalog()
+            .load_schema(schema_file_path.to_str().unwrap());
+
+        let table = table::Table::new("testfreespace".to_string(), "schema.txt".to_string());
+        let heap_file = db
+            .get_catalog()
+            .get_table_from_name("testfreespace")
+            .unwrap();
+        let make_tuple = |i: i32| {
+            tuple::Tuple::new(
+                vec![
+                    fields::FieldVal::IntField(fields::IntField::new(i)),
+                    fields::FieldVal::StringField(fields::StringField::new(
+                        format!("Alice_{}", i),
+                        7,
+                    )),
+                ],
+                &table.get_tuple_desc().clone(),
+            )
+        };
+
+        empty_table(&table);
+        let txn = transaction::Transaction::begin();
+        table.insert_many_tuples((0..40).map(make_tuple).collect(), &txn);
+        let num_pages = heap_file.num_pages();
+        let fsm = heap_file.get_free_space_map();
+        assert_eq!(fsm.num_pages(), num_pages);
+        assert_eq!(fsm.find_page(), Some(num_pages - 1));
+
+        // freeing a slot on the first page makes it the insert target again. The row is
+        // deleted by the transaction that inserted it so its slot is freed right away
+        // instead of waiting for every other snapshot to finish
+        table
+            .delete_where("id", table::Predicate::EqualsInt(3), &txn)
+            .unwrap();
+        txn.commit().unwrap();
+        assert_eq!(fsm.find_page(), Some(0));
+
+        let txn = transaction::Transaction::begin();
+        table.insert_tuple(make_tuple(100), &txn);
+        let mut scan = table.scan(100, &txn);
+        scan.table_filter("id", table::Predicate::EqualsInt(100));
+        let inserted = scan.next().unwrap();
+        assert_eq!(inserted.get_record_id().get_page_id().get_page_number(), 0);
+        txn.commit().unwrap();
+        assert_eq!(fsm.find_page(), Some(num_pages - 1));
+        assert_eq!(heap_file.num_pages(), num_pages);
+    }
+
+    #[test]
+    fn test_snapshot_isolation() {
+        let db = database::get_global_db();
+        let mut schema_file_path = std::env::current_dir().unwrap();
+        schema_file_path.push("schemas.txt");
+        db.get_catalog()
+            .load_schema(schema_file_path.to_str().unwrap());
+
+        let table = table::Table::new("testmvcc".to_string(), "schema.txt".to_string());
+        let make_tuple = |i: i32, name: &str| {
+            tuple::Tuple::new(
+                vec![
+                    fields::FieldVal::IntField(fields::IntField::new(i)),
+                    fields::FieldVal::StringField(fields::StringField::new(
+                        name.to_string(),
+                        name.len() as u32,
+                    )),
+                ],
+                &table.get_tuple_desc().clone(),
+            )
+        };
+        let find = |id: i32, txn: &transaction::Transaction| {
+            let mut scan = table.scan(100, txn);
+            scan.table_filter("id", table::Predicate::EqualsInt(id));
+            scan.next()
+        };
+
+        empty_table(&table);
+        let txn = transaction::Transaction::begin();
+        table.insert_many_tuples((0..3).map(|i| make_tuple(i, "Alice")).collect(), &txn);
+        txn.commit().unwrap();
+
+        // the reader's snapshot is taken by its first scan
+        let reader = transaction::Transaction::begin();
+        assert_eq!(table.scan(100, &reader).count(), 3);
+        let original = find(0, &reader).unwrap();
+
+        // a younger writer is not blocked by the reader
+        let writer = transaction::Transaction::begin();
+        table.insert_tuple(make_tuple(10, "Carol"), &writer);
+        let count = table
+            .update_where(
+                "id",
+                table::Predicate::EqualsInt(0),
+                vec![(
+                    "name".to_string(),
+                    fields::FieldVal::StringField(fields::StringField::new("Bob".to_string(), 3)),
+                )],
+                &writer,
+            )
+            .unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(
+            find(0, &writer).unwrap().get_field(1),
+            make_tuple(0, "Bob").get_field(1)
+        );
+        writer.commit().unwrap();
+
+        // the reader keeps seeing the data as of its snapshot
+        assert_eq!(table.scan(100, &reader).count(), 3);
+        // the old version was moved to another slot, so only compare the fields
+        assert_eq!(
+            find(0, &reader).unwrap().get_fields(),
+            original.get_fields()
+        );
+        // and cannot overwrite a row that changed after its snapshot
+        assert!(table
+            .update_where(
+                "id",
+                table::Predicate::EqualsInt(0),
+                vec![(
+                    "name".to_string(),
+                    fields::FieldVal::StringField(fields::StringField::new("Dan".to_string(), 3)),
+                )],
+                &reader,
+            )
+            .is_err());
+        reader.rollback().unwrap();
+
+        // new transactions see the committed changes, and the updated row kept its slot
+        let txn = transaction::Transaction::begin();
+        assert_eq!(table.scan(100, &txn).count(), 4);
+        let updated = find(0, &txn).unwrap();
+        assert_eq!(updated.get_field(1), make_tuple(0, "Bob").get_field(1));
+        assert_eq!(updated.get_record_id(), original.get_record_id());
+        txn.commit().unwrap();
+    }
+
+    // Truncates the table, so tests start from an empty file
+    fn empty_table(table: &table::Table) {
+        let txn = transaction::Transaction::begin();
+        table.truncate(&txn);
+        txn.commit().unwrap();
+    }
+
+    // Loads the schemas and replaces the contents of the table with the named rows
+    fn reset_table(name: &str, rows: &[&str]) -> table::Table {
+        let db = database::get_global_db();
+        let mut schema_file_path = std::env::current_dir().unwrap();
+        schema_file_path.push("schemas.txt");
+        db.get_catalog()
+            .load_schema(schema_file_path.to_str().unwrap());
+
+        let table = table::Table::new(name.to_string(), "schema.txt".to_string());
+        empty_table(&table);
+        let txn = transaction::Transaction::begin();
+        for (i, row) in rows.iter().enumerate() {
+            let tuple = tuple::Tuple::new(
+                vec![
+                    fields::FieldVal::IntField(fields::IntField::new(i as i32)),
+                    fields::FieldVal::StringField(fields::StringField::new(
+                        row.to_string(),
+                        row.len() as u32,
+                    )),
+                ],
+                &table.get_tuple_desc().clone(),
+            );
+            table.insert_tuple(tuple, &txn);
+        }
+        txn.commit().unwrap();
+        table
+    }
+
+    // Sets the name of the row with the id
+    fn rename(
+        table: &table::Table,
+        id: i32,
+        name: &str,
+        txn: &transaction::Transaction,
+    ) -> Result<usize, String> {
+        table.update_where(
+            "id",
+            table::Predicate::EqualsInt(id),
+            vec![(
+                "name".to_string(),
+                fields::FieldVal::StringField(fields::StringField::new(
+                    name.to_string(),
+                    name.len() as u32,
+                )),
+            )],
+            txn,
+        )
+    }
+
+    // Reads the names of every row visible to the transaction
+    fn names(table: &table::Table, txn: &transaction::Transaction) -> Vec<String> {
+        table
+            .scan(100, txn)
+            .map(|tuple| match tuple.get_field(1).unwrap() {
+                fields::FieldVal::StringField(name) => name.get_value(),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_read_uncommitted() {
+        let table = reset_table("testdirtyread", &["Alice"]);
+
+        let writer = transaction::Transaction::begin();
+        rename(&table, 0, "Bob", &writer).unwrap();
+
+        // a dirty read sees the uncommitted change, read committed does not
+        let dirty = transaction::Transaction::builder()
+            .isolation(transaction::IsolationLevel::ReadUncommitted)
+            .begin();
+        let committed = transaction::Transaction::builder()
+            .isolation(transaction::IsolationLevel::ReadCommitted)
+            .begin();
+        assert_eq!(names(&table, &dirty), vec!["Bob"]);
+        assert_eq!(names(&table, &committed), vec!["Alice"]);
+
+        // the change the dirty reader saw never happened
+        writer.rollback().unwrap();
+        assert_eq!(names(&table, &dirty), vec!["Alice"]);
+        dirty.commit().unwrap();
+        committed.commit().unwrap();
+    }
+
+    #[test]
+    fn test_read_committed() {
+        let table = reset_table("testnonrepeatable", &["Alice"]);
+
+        let committed = transaction::Transaction::builder()
+            .isolation(transaction::IsolationLevel::ReadCommitted)
+            .begin();
+        let repeatable = transaction::Transaction::builder()
+            .isolation(transaction::IsolationLevel::RepeatableRead)
+            .begin();
+        assert_eq!(names(&table, &committed), vec!["Alice"]);
+        assert_eq!(names(&table, &repeatable), vec!["Alice"]);
+
+        let writer = transaction::Transaction::begin();
+        rename(&table, 0, "Bob", &writer).unwrap();
+        writer.commit().unwrap();
+
+        // reading the same row again gives a different answer only under read committed
+        assert_eq!(names(&table, &committed), vec!["Bob"]);
+        assert_eq!(names(&table, &repeatable), vec!["Alice"]);
+        committed.commit().unwrap();
+        repeatable.commit().unwrap();
+    }
+
+    #[test]
+    fn test_read_committed_prevents_lost_update() {
+        let table = reset_table("testlostupdate", &["Alice"]);
+
+        // the older transaction waits for the row lock instead of dying
+        let txn = transaction::Transaction::builder()
+            .isolation(transaction::IsolationLevel::ReadCommitted)
+            .begin();
+        let writer = transaction::Transaction::begin();
+        rename(&table, 0, "Bob", &writer).unwrap();
+
+        // the second update waits for the first and must not overwrite it
+        let handle = thread::spawn(move || {
+            let table = table::Table::new("testlostupdate".to_string(), "schema.txt".to_string());
+            let result = rename(&table, 0, "Carol", &txn);
+            txn.rollback().unwrap();
+            result
+        });
+        thread::sleep(std::time::Duration::from_millis(100));
+        writer.commit().unwrap();
+        let error = handle.join().unwrap().unwrap_err();
+        assert!(error.contains("concurrently updated"), "{}", error);
+
+        let txn = transaction::Transaction::begin();
+        assert_eq!(names(&table, &txn), vec!["Bob"]);
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn test_serializable_prevents_write_skew() {
+        // both transactions check that two people are on call before taking one off call
+        let run = |isolation: transaction::IsolationLevel| {
+            let table = reset_table("testwriteskew", &["on_call", "on_call"]);
+            let first = transaction::Transaction::builder()
+                .isolation(isolation)
+                .begin();
+            let second = transaction::Transaction::builder()
+                .isolation(isolation)
+                .begin();
+            assert_eq!(names(&table, &first), vec!["on_call", "on_call"]);
+            assert_eq!(names(&table, &second), vec!["on_call", "on_call"]);
+
+            let handle = thread::spawn(move || {
+                let table =
+                    table::Table::new("testwriteskew".to_string(), "schema.txt".to_string());
+                rename(&table, 1, "off_call", &second).unwrap();
+                second.commit().unwrap();
+            });
+            let second_committed = handle.join().is_ok();
+            rename(&table, 0, "off_call", &first).unwrap();
+            first.commit().unwrap();
+
+            let txn = transaction::Transaction::begin();
+            let on_call = names(&table, &txn)
+                .iter()
+                .filter(|name| *name == "on_call")
+                .count();
+            txn.commit().unwrap();
+            (second_committed, on_call)
+        };
+
+        // both commit and nobody is left on call
+        assert_eq!(run(transaction::IsolationLevel::RepeatableRead), (true, 0));
+        // the younger writer dies on the shared lock of the older reader
+        assert_eq!(run(transaction::IsolationLevel::Serializable), (false, 1));
+    }
+
+    #[test]
+    fn test_savepoints() {
+        let table = reset_table("testsavepoints", &["Alice", "Bob"]);
+        let make_tuple = |i: i32| {
+            let name = format!("Row_{}", i);
+            let length = name.len();
+            tuple::Tuple::new(
+                vec![
+                    fields::FieldVal::IntField(fields::IntField::new(i)),
+                    fields::FieldVal::StringField(fields::StringField::new(name, length as u32)),
+                ],
+                &table.get_tuple_desc().clone(),
+            )
+        };
+
+        let txn = transaction::Transaction::begin();
+        table.insert_many_tuples((2..10).map(make_tuple).collect(), &txn);
+        txn.savepoint("batch").unwrap();
+
+        // a bad batch touches rows from before and after the savepoint
+        table.insert_many_tuples((10..20).map(make_tuple).collect(), &txn);
+        table.bulk_load((20..200).map(make_tuple), &txn);
+        rename(&table, 0, "Carol", &txn).unwrap();
+        rename(&table, 5, "Dan", &txn).unwrap();
+        txn.savepoint("inner").unwrap();
+        assert_eq!(table.scan(1000, &txn).count(), 200);
+
+        txn.rollback_to("batch").unwrap();
+        let rows = names(&table, &txn);
+        assert_eq!(rows.len(), 10);
+        assert_eq!(&rows[..3], &["Alice", "Bob", "Row_2"]);
+        assert_eq!(rows[5], "Row_5");
+        // savepoints set after the one rolled back to are gone, the savepoint itself stays
+        assert!(txn.rollback_to("inner").is_err());
+        rename(&table, 1, "Erin", &txn).unwrap();
+        txn.rollback_to("batch").unwrap();
+        assert_eq!(names(&table, &txn)[1], "Bob");
+
+        table.insert_tuple(make_tuple(10), &txn);
+        txn.release("batch").unwrap();
+        assert!(txn.rollback_to("batch").is_err());
+        txn.commit().unwrap();
+
+        let rows = names(&table, &transaction::Transaction::begin());
+        assert_eq!(rows.len(), 11);
+        assert_eq!(rows[0], "Alice");
+        assert_eq!(rows[10], "Row_10");
+    }
+
+    #[test]
+    fn test_serializable_prevents_phantoms() {
+        let table = reset_table("testphantoms", &["Alice", "Bob", "Carol"]);
+        let db = database::get_global_db();
+        let lock_manager = db.get_buffer_pool().get_lock_manager();
+        let make_tuple = |id: i32, name: &str| {
+            tuple::Tuple::new(
+                vec![
+                    fields::FieldVal::IntField(fields::IntField::new(id)),
+                    fields::FieldVal::StringField(fields::StringField::new(
+                        name.to_string(),
+                        name.len() as u32,
+                    )),
+                ],
+                &table.get_tuple_desc().clone(),
+            )
+        };
+        let range_count = |txn: &transaction::Transaction| {
+            let mut scan = table.scan(100, txn);
+            scan.table_filter("id", table::Predicate::GreaterThan(0));
+            scan.count()
+        };
+
+        // the writer is older than the reader, so it waits for the reader instead of dying
+        let writer = transaction::Transaction::begin();
+        let writer_tid = writer.get_tid();
+        let reader = transaction::Transaction::builder()
+            .isolation(transaction::IsolationLevel::Serializable)
+            .begin();
+        assert_eq!(range_count(&reader), 2);
+
+        // rows outside the scanned range can still be inserted
+        let other = transaction::Transaction::begin();
+        table.insert_tuple(make_tuple(-1, "Dan"), &other);
+        other.commit().unwrap();
+
+        let row = make_tuple(5, "Erin");
+        let handle = thread::spawn(move || {
+            let table = table::Table::new("testphantoms".to_string(), "schema.txt".to_string());
+            table.insert_tuple(row, &writer);
+            writer.commit().unwrap();
+        });
+        while lock_manager.get_lock_waits(writer_tid) == 0 {
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        // repeating the range scan sees the same rows
+        assert_eq!(range_count(&reader), 2);
+        reader.commit().unwrap();
+
+        handle.join().unwrap();
+        let txn = transaction::Transaction::begin();
+        assert_eq!(range_count(&txn), 3);
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn test_system_tables() {
+        let table = reset_table("testmonitor", &["Alice"]);
+        let db = database::get_global_db();
+        let bp = db.get_buffer_pool();
+        // finds the rows of the system table belonging to the transaction
+        let rows_of = |name: &str, tid: transaction::TransactionId| {
+            let txn = transaction::Transaction::begin();
+            let mut scan = table::scan_system_table(name, 1000, &txn).unwrap();
+            scan.table_filter("tid", table::Predicate::EqualsInt(tid.get_tid() as i32));
+            let rows: Vec<Vec<String>> = scan
+                .map(|tuple| {
+                    tuple
+                        .get_fields()
+                        .iter()
+                        .map(|field| match field {
+                            fields::FieldVal::IntField(value) => value.get_value().to_string(),
+                            fields::FieldVal::StringField(value) => value.get_value(),
+                        })
+                        .collect()
+                })
+                .collect();
+            txn.commit().unwrap();
+            rows
+        };
+
+        // the older transaction waits for the row lock of the younger one
+        let waiter = transaction::Transaction::begin();
+        let waiter_tid = waiter.get_tid();
+        let holder = transaction::Transaction::begin();
+        rename(&table, 0, "Bob", &holder).unwrap();
+        let locks = rows_of(system_table::LOCKS, holder.get_tid());
+        assert!(locks
+            .iter()
+            .any(|row| row[1..5] == ["testmonitor", "table", "IX", "granted"]));
+        assert!(locks.iter().any(|row| row[2] == "row 0:0" && row[3] == "X"));
+
+        let handle = thread::spawn(move || {
+            let table = table::Table::new("testmonitor".to_string(), "schema.txt".to_string());
+            rename(&table, 0, "Carol", &waiter).unwrap();
+        });
+        while rows_of(system_table::TRANSACTIONS, waiter_tid)
+            .first()
+            .is_none_or(|row| row[3] != "waiting")
+        {
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let transactions = rows_of(system_table::TRANSACTIONS, waiter_tid);
+        assert_eq!(transactions[0][1], "REPEATABLE READ");
+        // the waiter sees the old version, which the update moved to the next slot
+        assert_eq!(transactions[0][5], "X row 0:1 on testmonitor");
+        let waiting = rows_of(system_table::LOCKS, waiter_tid);
+        let waiting = waiting.iter().find(|row| row[4] == "waiting").unwrap();
+        assert_eq!(waiting[5], holder.get_tid().get_tid().to_string());
+
+        // killing the waiter aborts it and records why
+        bp.kill_transaction(waiter_tid).unwrap();
+        assert!(handle.join().is_err());
+        assert!(rows_of(system_table::TRANSACTIONS, waiter_tid).is_empty());
+        let aborts = rows_of(system_table::LOCK_ABORTS, waiter_tid);
+        assert_eq!(aborts[0][1], "killed");
+        assert!(bp.kill_transaction(waiter_tid).is_err());
+        holder.commit().unwrap();
+        assert!(
+            table::scan_system_table("__nothing", 10, &transaction::Transaction::begin()).is_err()
+        );
+    }
+
+    #[test]
+    fn test_timeouts_and_cancellation() {
+        use std::time::Duration;
+        let table = reset_table("testtimeouts", &["Alice", "Bob"]);
+        let db = database::get_global_db();
+        let lock_manager = db.get_buffer_pool().get_lock_manager();
+        // message of the panic that ended the thread
+        let panic_message = |handle: thread::JoinHandle<()>| {
+            let error = handle.join().unwrap_err();
+            error.downcast_ref::<String>().unwrap().clone()
+        };
+
+        // operators check the statement timeout between pages, and the query fails
+        let txn = transaction::Transaction::builder()
+            .statement_timeout(Duration::ZERO)
+            .begin();
+        let scan = table.scan(10, &txn);
+        let error = scan
+            .join(&table.scan(10, &txn), "id", "id")
+            .fetch()
+            .unwrap_err();
+        assert!(error.contains("statement timeout"), "{}", error);
+        assert!(txn.commit().is_err());
+        assert_eq!(txn.get_state(), transaction::TransactionState::Aborted);
+
+        // a cancelled transaction aborts and its changes are undone
+        let txn = transaction::Transaction::begin();
+        rename(&table, 0, "Carol", &txn).unwrap();
+        txn.get_cancellation_token().cancel();
+        let error = scan_names(&table, &txn).unwrap_err();
+        assert_eq!(
+            error,
+            format!("Transaction {} aborted: cancelled", txn.get_tid().get_tid())
+        );
+        assert!(rename(&table, 1, "Dan", &txn).is_err());
+        assert!(txn.rollback().is_ok());
+        let reader = transaction::Transaction::begin();
+        assert_eq!(names(&table, &reader), ["Alice", "Bob"]);
+        reader.commit().unwrap();
+
+        // a transaction stuck waiting for a lock is cancelled from another thread
+        let waiter = transaction::Transaction::begin();
+        let token = waiter.get_cancellation_token();
+        let holder = transaction::Transaction::begin();
+        rename(&table, 0, "Carol", &holder).unwrap();
+        let handle = thread::spawn(move || {
+            let table = table::Table::new("testtimeouts".to_string(), "schema.txt".to_string());
+            let _ = rename(&table, 0, "Dan", &waiter);
+        });
+        thread::sleep(Duration::from_millis(100));
+        token.cancel();
+        assert!(panic_message(handle).ends_with("aborted: cancelled"));
+        holder.rollback().unwrap();
+
+        // and one waiting longer than its statement timeout aborts by itself
+        let waiter = transaction::Transaction::builder()
+            .statement_timeout(Duration::from_millis(100))
+            .begin();
+        let waiter_tid = waiter.get_tid();
+        let holder = transaction::Transaction::begin();
+        rename(&table, 1, "Dan", &holder).unwrap();
+        let handle = thread::spawn(move || {
+            let table = table::Table::new("testtimeouts".to_string(), "schema.txt".to_string());
+            let _ = rename(&table, 1, "Eve", &waiter);
+        });
+        let message = panic_message(handle);
+        assert!(
+            message.contains("statement timeout of 100ms exceeded"),
+            "{}",
+            message
+        );
+        let aborts = lock_manager.get_abort_history();
+        let abort = aborts.iter().find(|abort| abort.get_tid() == waiter_tid);
+        assert_eq!(
+            abort.unwrap().get_reason(),
+            lock_manager::AbortReason::StatementTimeout
+        );
+        holder.commit().unwrap();
+
+        // a transaction idle for longer than its idle timeout loses its locks to the
+        // transactions waiting for them
+        let idle = transaction::Transaction::builder()
+            .idle_timeout(Duration::from_millis(50))
+            .begin();
+        rename(&table, 0, "Frank", &idle).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let reader = transaction::Transaction::builder()
+            .isolation(transaction::IsolationLevel::Serializable)
+            .begin();
+        assert_eq!(names(&table, &reader), ["Alice", "Dan"]);
+        reader.commit().unwrap();
+        // the idle transaction was rolled back for it and runs no further statements
+        assert_eq!(idle.get_state(), transaction::TransactionState::Aborted);
+        assert!(idle.savepoint("late").is_err());
+        let error = idle.commit().unwrap_err();
+        assert!(error.contains("idle timeout of 50ms exceeded"), "{}", error);
+    }
+
+    // Reads the names of every row visible to the transaction, failing if it was interrupted
+    fn scan_names(
+        table: &table::Table,
+        txn: &transaction::Transaction,
+    ) -> Result<Vec<String>, String> {
+        let mut scan = table.scan(100, txn);
+        scan.fetch()?;
+        Ok(scan.map(|tuple| tuple.to_string()).collect())
+    }
+
+    #[test]
+    fn test_durable_commits() {
+        let table = reset_table("testdurable", &[]);
+        let db = database::get_global_db();
+        let bp = db.get_buffer_pool();
+        assert_eq!(bp.get_sync_mode(), group_commit::SyncMode::Normal);
+        let group_commit = bp.get_group_commit();
+        let commits = group_commit.get_num_commits();
+
+        // concurrent commits wait for their pages to be synced
+        let handles: Vec<_> = ["Alice", "Bob", "Carol", "Dan"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| {
+                thread::spawn(move || {
+                    let table =
+                        table::Table::new("testdurable".to_string(), "schema.txt".to_string());
+                    let txn = transaction::Transaction::begin();
+                    let tuple = tuple::Tuple::new(
+                        vec![
+                            fields::FieldVal::IntField(fields::IntField::new(i as i32)),
+                            fields::FieldVal::StringField(fields::StringField::new(
+                                name.to_string(),
+                                name.len() as u32,
+                            )),
+                        ],
+                        table.get_tuple_desc(),
+                    );
+                    table.insert_tuple(tuple, &txn);
+                    txn.commit().unwrap();
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert!(group_commit.get_num_commits() >= commits + 4);
+        assert!(group_commit.get_num_syncs() <= group_commit.get_num_commits());
+
+        // read-only commits have nothing to sync
+        let commits = group_commit.get_num_commits();
+        let reader = transaction::Transaction::begin();
+        let mut on_disk = names(&table, &reader);
+        reader.commit().unwrap();
+        on_disk.sort();
+        assert_eq!(on_disk, ["Alice", "Bob", "Carol", "Dan"]);
+        assert_eq!(group_commit.get_num_commits(), commits);
+    }
+
+    // Opens a database in a fresh temporary directory with a schema of the people table
+    fn open_temp_db() -> Arc<database::Database> {
+        let data_dir = std::env::temp_dir().join(format!("rustic_db_{}", uuid::Uuid::new_v4()));
+        let config = database::DatabaseConfig::default()
+            .data_dir(&data_dir)
+            .page_size(1024)
+            .pool_size(8);
+        let db = database::Database::open(config).unwrap();
+        let schema_file_path = data_dir.join("schemas.txt");
+        std::fs::write(&schema_file_path, "people (id: Int, name: String)").unwrap();
+        db.get_catalog()
+            .load_schema(schema_file_path.to_str().unwrap());
+        db
+    }
+
+    #[test]
+    fn test_page_and_pool_size() {
+        let data_dir = std::env::temp_dir().join(format!("rustic_db_{}", uuid::Uuid::new_v4()));
+        let config = database::DatabaseConfig::default().data_dir(&data_dir);
+
+        // a page must hold the header, the bitmap and one tuple with its version stamps
+        let error = database::Database::open(config.clone().page_size(20))
+            .err()
+            .unwrap();
+        assert_eq!(
+            error,
+            "Page size 20 cannot hold a tuple of 4 bytes, which needs pages of at least 37 bytes"
+        );
+        let db = database::Database::open(config.clone().page_size(200)).unwrap();
+        let error = db
+            .get_catalog()
+            .create_table("people (id: Int, name: String)")
+            .err()
+            .unwrap();
+        assert!(
+            error.starts_with("Table people: Page size 200 cannot hold"),
+            "{}",
+            error
+        );
+        assert!(!config.get_file_path("people.dat").exists());
+        drop(db);
+
+        // the data directory keeps the page size it was created with
+        let error = database::Database::open(config.clone().page_size(1024))
+            .err()
+            .unwrap();
+        assert!(
+            error.ends_with("has pages of 200 bytes, not 1024"),
+            "{}",
+            error
+        );
+        let stored = database::DatabaseConfig::for_data_dir(&data_dir).unwrap();
+        assert_eq!(stored.get_page_size(), 200);
+        std::fs::remove_dir_all(&data_dir).unwrap();
+
+        // pages no transaction uses are evicted to keep the pool within its size
+        let db = database::Database::open(config.page_size(1024).pool_size(4)).unwrap();
+        let heap_file = db
+            .get_catalog()
+            .create_table("people (id: Int, name: String)")
+            .unwrap();
+        let table = table::Table::open(&db, "people").unwrap();
+        let txn = transaction::Transaction::builder().database(&db).begin();
+        for id in 0..40 {
+            let tuple = tuple::Tuple::new(
+                vec![
+                    fields::FieldVal::IntField(fields::IntField::new(id)),
+                    fields::FieldVal::StringField(fields::StringField::new("Alice".to_string(), 5)),
+                ],
+                table.get_tuple_desc(),
+            );
+            table.insert_tuple(tuple, &txn);
+        }
+        // pages with uncommitted changes stay, even past the size of the pool
+        let bp = db.get_buffer_pool();
+        assert!(bp.get_num_cached_pages() > 4);
+        txn.commit().unwrap();
+        assert!(heap_file.num_pages() > 4);
+        for _ in 0..2 {
+            let txn = transaction::Transaction::builder().database(&db).begin();
+            assert_eq!(table.scan(100, &txn).count(), 40);
+            txn.commit().unwrap();
+            assert!(bp.get_num_cached_pages() <= 4);
+        }
+        drop((table, heap_file, db));
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_lock_settings() {
+        let data_dir = std::env::temp_dir().join(format!("rustic_db_{}", uuid::Uuid::new_v4()));
+        let config = database::DatabaseConfig::default()
+            .data_dir(&data_dir)
+            .deadlock_policy(lock_manager::DeadlockPolicy::Detection)
+            .lock_timeout(std::time::Duration::from_millis(100));
+        let db = database::Database::open(config).unwrap();
+        let locks = db.get_buffer_pool().get_lock_manager();
+        assert_eq!(
+            locks.get_deadlock_policy(),
+            lock_manager::DeadlockPolicy::Detection
+        );
+        assert_eq!(
+            locks.get_lock_timeout(),
+            Some(std::time::Duration::from_millis(100))
+        );
+        drop(db);
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_separate_databases() {
+        let first = open_temp_db();
+        let second = open_temp_db();
+        assert_eq!(first.get_config().get_page_size(), 1024);
+
+        // each database hands out its own transaction ids
+        let txn = transaction::Transaction::builder().database(&first).begin();
+        assert_eq!(txn.get_tid().get_tid(), 0);
+        let table = table::Table::open(&first, "people").unwrap();
+        for (i, name) in ["Alice", "Bob"].iter().enumerate() {
+            let tuple = tuple::Tuple::new(
+                vec![
+                    fields::FieldVal::IntField(fields::IntField::new(i as i32)),
+                    fields::FieldVal::StringField(fields::StringField::new(
+                        name.to_string(),
+                        name.len() as u32,
+                    )),
+                ],
+                table.get_tuple_desc(),
+            );
+            table.insert_tuple(tuple, &txn);
+        }
+        txn.commit().unwrap();
+
+        // tables of the same name in different databases do not share data
+        let reader = transaction::Transaction::builder()
+            .database(&second)
+            .begin();
+        assert_eq!(reader.get_tid().get_tid(), 0);
+        let other = table::Table::open(&second, "people").unwrap();
+        assert!(names(&other, &reader).is_empty());
+        reader.commit().unwrap();
+        assert!(table::Table::open(&second, "missing").is_err());
+
+        let reader = transaction::Transaction::builder().database(&first).begin();
+        assert_eq!(names(&table, &reader), ["Alice", "Bob"]);
+        reader.commit().unwrap();
+        assert!(first
+            .get_config()
+            .get_data_dir()
+            .join("people.dat")
+            .exists());
+
+        for db in [first, second] {
+            std::fs::remove_dir_all(db.get_config().get_data_dir()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_data_directory() {
+        let db = open_temp_db();
+        let config = db.get_config().clone();
+        let data_dir = config.get_data_dir().to_path_buf();
+        // the directory stays locked while the database is open
+        let error = database::Database::open(config.clone()).err().unwrap();
+        assert!(error.contains("already open"));
+
+        let table = table::Table::open(&db, "people").unwrap();
+        let txn = transaction::Transaction::builder().database(&db).begin();
+        let tuple = tuple::Tuple::new(
+            vec![
+                fields::FieldVal::IntField(fields::IntField::new(0)),
+                fields::FieldVal::StringField(fields::StringField::new("Alice".to_string(), 5)),
+            ],
+            table.get_tuple_desc(),
+        );
+        table.insert_tuple(tuple, &txn);
+        txn.commit().unwrap();
+        for file in ["catalog", "LOCK", "epoch", "people.dat", "people.fsm"] {
+            assert!(data_dir.join(file).exists());
+        }
+        drop((txn, table));
+        drop(db);
+
+        // reopening the directory restores its tables from the catalog
+        let db = database::Database::open(config).unwrap();
+        let table = table::Table::open(&db, "people").unwrap();
+        let reader = transaction::Transaction::builder().database(&db).begin();
+        assert_eq!(names(&table, &reader), ["Alice"]);
+        reader.commit().unwrap();
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_bulk_load_crash() {
+        let db = open_temp_db();
+        let config = db.get_config().clone();
+        let table = table::Table::open(&db, "people").unwrap();
+        let person = |id: i32| {
+            tuple::Tuple::new(
+                vec![
+                    fields::FieldVal::IntField(fields::IntField::new(id)),
+                    fields::FieldVal::StringField(fields::StringField::new("Alice".to_string(), 5)),
+                ],
+                table.get_tuple_desc(),
+            )
+        };
+        let txn = transaction::Transaction::builder().database(&db).begin();
+        table.bulk_load((0..10).map(person), &txn);
+        txn.commit().unwrap();
+        let marker_path = heap_file::bulk_load_marker_path(&config, "people");
+        assert!(!marker_path.exists());
+
+        // copy the files as they are on disk while a second load has not committed, as if
+        // the process crashed
+        let txn = transaction::Transaction::builder().database(&db).begin();
+        table.bulk_load((10..200).map(person), &txn);
+        assert!(marker_path.exists());
+        let crashed = config
+            .clone()
+            .data_dir(std::env::temp_dir().join(format!("rustic_db_{}", uuid::Uuid::new_v4())));
+        std::fs::create_dir_all(crashed.get_data_dir()).unwrap();
+        for entry in std::fs::read_dir(config.get_data_dir()).unwrap() {
+            let path = entry.unwrap().path();
+            if path.file_name().unwrap() != "LOCK" {
+                std::fs::copy(
+                    &path,
+                    crashed.get_file_path(path.file_name().unwrap().to_str().unwrap()),
+                )
+                .unwrap();
+            }
+        }
+        txn.rollback().unwrap();
+        assert!(!marker_path.exists());
+
+        // reopening drops the pages of the load that never committed
+        let reopened = database::Database::open(crashed.clone()).unwrap();
+        assert!(!heap_file::bulk_load_marker_path(&crashed, "people").exists());
+        let people = table::Table::open(&reopened, "people").unwrap();
+        let txn = transaction::Transaction::builder()
+            .database(&reopened)
+            .begin();
+        assert_eq!(people.scan(1000, &txn).count(), 10);
+        txn.commit().unwrap();
+        drop((people, reopened, table));
+        std::fs::remove_dir_all(crashed.get_data_dir()).unwrap();
+        std::fs::remove_dir_all(config.get_data_dir()).unwrap();
+    }
+
+    #[test]
+    fn test_truncate_crash() {
+        let db = open_temp_db();
+        let config = db.get_config().clone();
+        let table = table::Table::open(&db, "people").unwrap();
+        let person = |id: i32| {
+            tuple::Tuple::new(
+                vec![
+                    fields::FieldVal::IntField(fields::IntField::new(id)),
+                    fields::FieldVal::StringField(fields::StringField::new("Alice".to_string(), 5)),
+                ],
+                table.get_tuple_desc(),
+            )
+        };
+        let txn = transaction::Transaction::builder().database(&db).begin();
+        table.bulk_load((0..10).map(person), &txn);
+        txn.commit().unwrap();
+
+        // copy the files as they are on disk while a truncate between two loads has not
+        // committed, as if the process crashed
+        let txn = transaction::Transaction::builder().database(&db).begin();
+        table.bulk_load((10..200).map(person), &txn);
+        table.truncate(&txn);
+        table.bulk_load((200..210).map(person), &txn);
+        let backup_path = heap_file::truncate_backup_path(&config, "people", 0);
+        assert!(backup_path.exists());
+        let crashed = config
+            .clone()
+            .data_dir(std::env::temp_dir().join(format!("rustic_db_{}", uuid::Uuid::new_v4())));
+        std::fs::create_dir_all(crashed.get_data_dir()).unwrap();
+        for entry in std::fs::read_dir(config.get_data_dir()).unwrap() {
+            let path = entry.unwrap().path();
+            if path.file_name().unwrap() != "LOCK" {
+                std::fs::copy(
+                    &path,
+                    crashed.get_file_path(path.file_name().unwrap().to_str().unwrap()),
+                )
+                .unwrap();
+            }
+        }
+        txn.rollback().unwrap();
+        assert!(!backup_path.exists());
+        let txn = transaction::Transaction::builder().database(&db).begin();
+        assert_eq!(table.scan(1000, &txn).count(), 10);
+        txn.commit().unwrap();
+
+        // reopening brings back the committed pages without either load
+        let reopened = database::Database::open(crashed.clone()).unwrap();
+        assert!(!heap_file::truncate_backup_path(&crashed, "people", 0).exists());
+        assert!(!heap_file::bulk_load_marker_path(&crashed, "people").exists());
+        let people = table::Table::open(&reopened, "people").unwrap();
+        let txn = transaction::Transaction::builder()
+            .database(&reopened)
+            .begin();
+        assert_eq!(people.scan(1000, &txn).count(), 10);
+        txn.commit().unwrap();
+        drop((people, reopened, table));
+        std::fs::remove_dir_all(crashed.get_data_dir()).unwrap();
+        std::fs::remove_dir_all(config.get_data_dir()).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_pages() {
+        let db = open_temp_db();
+        let config = db.get_config().clone();
+        let table = table::Table::open(&db, "people").unwrap();
+        let txn = transaction::Transaction::builder().database(&db).begin();
+        let tuple = tuple::Tuple::new(
+            vec![
+                fields::FieldVal::IntField(fields::IntField::new(0)),
+                fields::FieldVal::StringField(fields::StringField::new("Alice".to_string(), 5)),
+            ],
+            table.get_tuple_desc(),
+        );
+        table.insert_tuple(tuple, &txn);
+        txn.commit().unwrap();
+        drop((txn, table, db));
+
+        // flip a byte in the middle of the page, as a torn write would
+        let path = config.get_file_path("people.dat");
+        let mut data = std::fs::read(&path).unwrap();
+        data[100] ^= 0xFF;
+        std::fs::write(&path, &data).unwrap();
+
+        let db = database::Database::open(config.clone()).unwrap();
+        let table = table::Table::open(&db, "people").unwrap();
+        let txn = transaction::Transaction::builder().database(&db).begin();
+        let error = scan_names(&table, &txn).unwrap_err();
+        assert!(error.starts_with("Corruption in table people page 0: checksum mismatch"));
+        let error = table
+            .delete_where("id", table::Predicate::EqualsInt(0), &txn)
+            .unwrap_err();
+        assert!(error.starts_with("Corruption in table people page 0"));
+        txn.rollback().unwrap();
+        drop((txn, table, db));
+
+        // a page cut short at the end of the file, as a torn append would leave it
+        data[100] ^= 0xFF;
+        data.extend([0; 10]);
+        std::fs::write(&path, &data).unwrap();
+        let db = database::Database::open(config).unwrap();
+        let table = table::Table::open(&db, "people").unwrap();
+        let txn = transaction::Transaction::builder().database(&db).begin();
+        let error = scan_names(&table, &txn).unwrap_err();
+        assert_eq!(
+            error,
+            "Corruption in table people page 1: partial page of 10 bytes"
+        );
+        txn.rollback().unwrap();
+        std::fs::remove_dir_all(db.get_config().get_data_dir()).unwrap();
+    }
+
+    #[test]
+    fn test_integrity_check() {
+        let db = open_temp_db();
+        let config = db.get_config().clone();
+        let table = table::Table::open(&db, "people").unwrap();
+        let txn = transaction::Transaction::builder().database(&db).begin();
+        let tuple = tuple::Tuple::new(
+            vec![
+                fields::FieldVal::IntField(fields::IntField::new(0)),
+                fields::FieldVal::StringField(fields::StringField::new("Alice".to_string(), 5)),
+            ],
+            table.get_tuple_desc(),
+        );
+        table.insert_tuple(tuple, &txn);
+        txn.commit().unwrap();
+        // the directory cannot be checked while it is open
+        assert!(check::check(&config, false).is_err());
+        drop((txn, table, db));
+        assert!(check::check(&config, false).unwrap().is_clean());
+
+        // damage the first page, leave a partial page behind it and a stray file
+        let path = config.get_file_path("people.dat");
+        let mut data = std::fs::read(&path).unwrap();
+        data[100] ^= 0xFF;
+        data.extend([1; 100]);
+        std::fs::write(&path, data).unwrap();
+        std::fs::write(config.get_file_path("stray.dat"), [0; 10]).unwrap();
+
+        let report = check::check(&config, false).unwrap();
+        let issues: Vec<String> = report.get_issues().iter().map(|i| i.to_string()).collect();
+        assert_eq!(report.get_num_pages(), 2);
+        assert_eq!(issues.len(), 3);
+        assert_eq!(
+            issues[0],
+            "stray.dat does not belong to a table in the catalog"
+        );
+        assert!(issues[1].starts_with("people page 0: checksum mismatch"));
+        assert_eq!(issues[2], "people page 1: partial page of 100 bytes");
+
+        // repairing moves the bad pages aside, after which only the stray file is left
+        let report = check::check(&config, true).unwrap();
+        assert_eq!(
+            report.get_quarantined(),
+            [("people".to_string(), 0), ("people".to_string(), 1)]
+        );
+        assert!(config
+            .get_file_path(check::QUARANTINE_DIR)
+            .join("people.0.page")
+            .exists());
+        let report = check::check(&config, false).unwrap();
+        assert_eq!(report.get_issues().len(), 1);
+
+        let db = database::Database::open(config).unwrap();
+        let table = table::Table::open(&db, "people").unwrap();
+        let reader = transaction::Transaction::builder().database(&db).begin();
+        assert!(names(&table, &reader).is_empty());
+        reader.commit().unwrap();
+        std::fs::remove_dir_all(db.get_config().get_data_dir()).unwrap();
+    }
+
+    #[test]
+    fn test_inspect_table() {
+        let db = open_temp_db();
+        let config = db.get_config().clone();
+        let table = table::Table::open(&db, "people").unwrap();
+        let txn = transaction::Transaction::builder().database(&db).begin();
+        let tuple = tuple::Tuple::new(
+            vec![
+                fields::FieldVal::IntField(fields::IntField::new(0)),
+                fields::FieldVal::StringField(fields::StringField::new("Alice".to_string(), 5)),
+            ],
+            table.get_tuple_desc(),
+        );
+        table.insert_tuple(tuple, &txn);
+        txn.commit().unwrap();
+
+        // the files are read directly, even while the directory is open
+        let pages = inspect::inspect_table(&config, "people", None).unwrap();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].get_status(), &inspect::PageStatus::Valid);
+        assert_eq!(pages[0].get_slots().len(), 1);
+        drop((txn, table, db));
+
+        // nothing in the directory is written
+        let read_files = || {
+            let mut files: Vec<(String, Vec<u8>)> = std::fs::read_dir(config.get_data_dir())
+                .unwrap()
+                .map(|entry| {
+                    let path = entry.unwrap().path();
+                    let name = path.file_name().unwrap().to_string_lossy().to_string();
+                    (name, std::fs::read(&path).unwrap())
+                })
+                .collect();
+            files.sort();
+            files
+        };
+        let files = read_files();
+        let pages = inspect::inspect_table(&config, "people", Some(0..1)).unwrap();
+        assert_eq!(pages[0].get_slots().len(), 1);
+        assert_eq!(read_files(), files);
+
+        assert_eq!(
+            inspect::inspect_table(&config, "people", Some(1..2)).unwrap_err(),
+            "Table people has no page 1"
+        );
+        assert_eq!(
+            inspect::inspect_table(&config, "nobody", None).unwrap_err(),
+            "No table named nobody"
+        );
+        std::fs::remove_dir_all(config.get_data_dir()).unwrap();
+    }
+
+    #[test]
+    fn test_csv_import_export() {
+        let db = open_temp_db();
+        let table = table::Table::open(&db, "people").unwrap();
+        let input = "name,id\n\
+                     Alice,1\n\
+                     \"Smith, \"\"Bob\"\"\",2\n\
+                     Carol,three\n\
+                     Dan\n\
+                     \"Eve\nAdams\", 4 \n";
+        let mut rejects = vec![];
+        let txn = transaction::Transaction::builder().database(&db).begin();
+        let report = csv::import(&table, input.as_bytes(), &txn, Some(&mut rejects)).unwrap();
+        // the new table is bulk loaded under an exclusive table lock
+        let lock_manager = db.get_buffer_pool().get_lock_manager();
+        let table_lock = |txn: &transaction::Transaction| {
+            lock_manager.get_lock_mode(
+                txn.get_tid(),
+                lock_manager::LockTarget::Table(table.get_id()),
+            )
+        };
+        assert_eq!(table_lock(&txn), Some(lock_manager::LockMode::Exclusive));
+        txn.commit().unwrap();
+        assert_eq!(report.get_num_imported(), 3);
+        let errors: Vec<String> = report.get_errors().iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                "line 4: id: \"three\" is not a valid Int",
+                "line 5: expected 2 fields but found 1",
+            ]
+        );
+        assert_eq!(
+            String::from_utf8(rejects).unwrap(),
+            "name,id\nCarol,three\nDan\n"
+        );
+
+        let txn = transaction::Transaction::builder().database(&db).begin();
+        let mut output = vec![];
+        assert_eq!(csv::export_table(&table, &txn, &mut output).unwrap(), 3);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "id,name\n1,Alice\n2,\"Smith, \"\"Bob\"\"\"\n4,\"Eve\nAdams\"\n"
+        );
+        let mut query = table.scan(10, &txn);
+        query.table_filter("id", table::Predicate::GreaterThan(1));
+        let mut output = vec![];
+        csv::export_query(query.project(vec!["name".to_string()]), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "name\n\"Smith, \"\"Bob\"\"\"\n\"Eve\nAdams\"\n"
+        );
+
+        // the header must name every field of the table exactly once
+        for header in ["id\n", "id,name,age\n", "id,id,name\n", ""] {
+            assert!(csv::import(&table, header.as_bytes(), &txn, None).is_err());
+        }
+        txn.commit().unwrap();
+
+        // rows imported into a table with pages are inserted next to the existing ones
+        let txn = transaction::Transaction::builder().database(&db).begin();
+        let report = csv::import(&table, "id,name\n5,Frank\n".as_bytes(), &txn, None).unwrap();
+        assert_eq!(report.get_num_imported(), 1);
+        assert_eq!(
+            table_lock(&txn),
+            Some(lock_manager::LockMode::IntentionExclusive)
+        );
+        assert_eq!(names(&table, &txn).len(), 4);
+        txn.commit().unwrap();
+        drop(table);
+        std::fs::remove_dir_all(db.get_config().get_data_dir()).unwrap();
+    }
+
+    #[test]
+    fn test_json_lines_import_export() {
+        let db = open_temp_db();
+        let table = table::Table::open(&db, "people").unwrap();
+        let input = "{\"id\": 1, \"name\": \"Alice\"}\n\
+                     \n\
+                     {\"name\": \"B\\u00f6b \\\"Jr\\\"\", \"id\": 2}\n\
+                     {\"id\": 3, \"name\": null}\n\
+                     {\"id\": 4}\n\
+                     [4, \"Dan\"]\n";
+        let mut rejects = vec![];
+        let txn = transaction::Transaction::builder().database(&db).begin();
+        let report = json::import(&table, input.as_bytes(), &txn, Some(&mut rejects)).unwrap();
+        txn.commit().unwrap();
+        assert_eq!(report.get_num_imported(), 2);
+        let errors: Vec<String> = report.get_errors().iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                "line 4: name: cannot be null",
+                "line 5: name is missing",
+                "line 6: expected an object but found an array",
+            ]
+        );
+        assert_eq!(
+            String::from_utf8(rejects).unwrap(),
+            "{\"id\": 3, \"name\": null}\n{\"id\": 4}\n[4, \"Dan\"]\n"
+        );
+
+        let txn = transaction::Transaction::builder().database(&db).begin();
+        let mut output = vec![];
+        assert_eq!(json::export_table(&table, &txn, &mut output).unwrap(), 2);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"id\":1,\"name\":\"Alice\"}\n{\"id\":2,\"name\":\"B\u{f6}b \\\"Jr\\\"\"}\n"
+        );
+        let mut query = table.scan(10, &txn);
+        query.table_filter("id", table::Predicate::EqualsInt(2));
+        let mut output = vec![];
+        json::write_array(query.project(vec!["id".to_string()]), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "[\n{\"id\":2}\n]\n");
+        txn.commit().unwrap();
+        drop(table);
+        std::fs::remove_dir_all(db.get_config().get_data_dir()).unwrap();
+    }
+
+    #[test]
+    fn test_dump_and_restore() {
+        let db = open_temp_db();
+        let people = table::Table::open(&db, "people").unwrap();
+        let schema_file_path = db.get_config().get_file_path("more_schemas.txt");
+        std::fs::write(&schema_file_path, "notes (text: String)\nempty (id: Int)").unwrap();
+        db.get_catalog()
+            .load_schema(schema_file_path.to_str().unwrap());
+        let notes = table::Table::open(&db, "notes").unwrap();
+        let person = |id, name: &str| {
+            tuple::Tuple::new(
+                vec![
+                    fields::FieldVal::IntField(fields::IntField::new(id)),
+                    fields::FieldVal::StringField(fields::StringField::new(
+                        name.to_string(),
+                        name.len() as u32,
+                    )),
+                ],
+                people.get_tuple_desc(),
+            )
+        };
+        let txn = transaction::Transaction::builder().database(&db).begin();
+        for (id, name) in [(1, "Alice"), (-2, "O'Brien")] {
+            people.insert_tuple(person(id, name), &txn);
+        }
+        let text = "two;\nlines, 'quoted'";
+        notes.insert_tuple(
+            tuple::Tuple::new(
+                vec![fields::FieldVal::StringField(fields::StringField::new(
+                    text.to_string(),
+                    text.len() as u32,
+                ))],
+                notes.get_tuple_desc(),
+            ),
+            &txn,
+        );
+        txn.commit().unwrap();
+
+        // rows written after the dump's snapshot was taken are left out
+        let writer = transaction::Transaction::builder().database(&db).begin();
+        people.insert_tuple(person(3, "Uncommitted"), &writer);
+        let mut output = vec![];
+        let report = dump::dump(&db, &mut output).unwrap();
+        writer.commit().unwrap();
+        assert_eq!((report.get_num_tables(), report.get_num_rows()), (3, 3));
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with(&format!(
+            "{}\n\nCREATE TABLE empty (id: Int);\n",
+            dump::DUMP_HEADER
+        )));
+        assert!(output.contains("CREATE TABLE people (id: Int, name: String);\n"));
+        assert!(output.contains("INSERT INTO people VALUES (-2, 'O''Brien');\n"));
+        assert!(!output.contains("Uncommitted"));
+
+        let data_dir = std::env::temp_dir().join(format!("rustic_db_{}", uuid::Uuid::new_v4()));
+        let config = database::DatabaseConfig::default()
+            .data_dir(&data_dir)
+            .page_size(1024)
+            .pool_size(8);
+        let restored = database::Database::open(config.clone()).unwrap();
+        let report = dump::restore(&restored, output.as_bytes()).unwrap();
+        assert_eq!((report.get_num_tables(), report.get_num_rows()), (3, 3));
+        let error = dump::restore(&restored, output.as_bytes()).unwrap_err();
+        assert_eq!(error, "Line 3: Table empty already exists");
+        drop(restored);
+
+        // the restored tables are in the catalog, so they are opened again
+        let restored = database::Database::open(config).unwrap();
+        let people = table::Table::open(&restored, "people").unwrap();
+        let notes = table::Table::open(&restored, "notes").unwrap();
+        let txn = transaction::Transaction::builder()
+            .database(&restored)
+            .begin();
+        assert_eq!(names(&people, &txn), vec!["Alice", "O'Brien"]);
+        let rows: Vec<String> = notes
+            .scan(10, &txn)
+            .map(|t| t.get_fields()[0].to_string())
+            .collect();
+        assert_eq!(rows, vec![text]);
+        txn.commit().unwrap();
+        drop((people, notes, restored, txn));
+        std::fs::remove_dir_all(&data_dir).unwrap();
+        std::fs::remove_dir_all(db.get_config().get_data_dir()).unwrap();
+    }
+
+    #[test]
+    fn test_online_backup() {
+        let db = open_temp_db();
+        let schema_file_path = db.get_config().get_file_path("more_schemas.txt");
+        std::fs::write(&schema_file_path, "events (id: Int)").unwrap();
+        db.get_catalog()
+            .load_schema(schema_file_path.to_str().unwrap());
+        let people = table::Table::open(&db, "people").unwrap();
+        let events = table::Table::open(&db, "events").unwrap();
+        let person = |id, name: &str| {
+            tuple::Tuple::new(
+                vec![
+                    fields::FieldVal::IntField(fields::IntField::new(id)),
+                    fields::FieldVal::StringField(fields::StringField::new(
+                        name.to_string(),
+                        name.len() as u32,
+                    )),
+                ],
+                people.get_tuple_desc(),
+            )
+        };
+        let event = |id| {
+            tuple::Tuple::new(
+                vec![fields::FieldVal::IntField(fields::IntField::new(id))],
+                events.get_tuple_desc(),
+            )
+        };
+        let txn = transaction::Transaction::builder().database(&db).begin();
+        people.insert_many_tuples((0..20).map(|i| person(i, "Alice")).collect(), &txn);
+        events.bulk_load((0..10).map(event), &txn);
+        txn.commit().unwrap();
+
+        // neither the uncommitted insert nor the pages of the running bulk load, which are
+        // already on disk, go into the backup
+        let writer = transaction::Transaction::builder().database(&db).begin();
+        people.insert_tuple(person(100, "Uncommitted"), &writer);
+        let loader = transaction::Transaction::builder().database(&db).begin();
+        events.bulk_load((10..1000).map(event), &loader);
+        let backup_dir = std::env::temp_dir().join(format!("rustic_db_{}", uuid::Uuid::new_v4()));
+        let report = backup::backup(&db, &backup_dir).unwrap();
+        writer.commit().unwrap();
+        loader.commit().unwrap();
+        assert_eq!(report.get_num_tables(), 2);
+        assert!(backup::backup(&db, &backup_dir).is_err());
+
+        // commits wait while a backup copies the files
+        let paused = db.get_buffer_pool().pause_commits();
+        let committer = {
+            let db = Arc::clone(&db);
+            thread::spawn(move || {
+                let table = table::Table::open(&db, "events").unwrap();
+                let txn = transaction::Transaction::builder().database(&db).begin();
+                table.insert_tuple(
+                    tuple::Tuple::new(
+                        vec![fields::FieldVal::IntField(fields::IntField::new(-1))],
+                        table.get_tuple_desc(),
+                    ),
+                    &txn,
+                );
+                txn.commit().unwrap();
+            })
+        };
+        thread::sleep(std::time::Duration::from_millis(100));
+        assert!(!committer.is_finished());
+        drop(paused);
+        committer.join().unwrap();
+
+        let check_report = backup::verify(report.get_config()).unwrap();
+        assert!(check_report.is_clean(), "{}", check_report);
+        let restored = database::Database::open(report.get_config().clone()).unwrap();
+        let people = table::Table::open(&restored, "people").unwrap();
+        let events = table::Table::open(&restored, "events").unwrap();
+        let txn = transaction::Transaction::builder()
+            .database(&restored)
+            .begin();
+        assert_eq!(names(&people, &txn), vec!["Alice"; 20]);
+        assert_eq!(events.scan(2000, &txn).count(), 10);
+        txn.commit().unwrap();
+        drop((people, events, restored));
+        std::fs::remove_dir_all(&backup_dir).unwrap();
+        std::fs::remove_dir_all(db.get_config().get_data_dir()).unwrap();
+    }
+
+    #[test]
+    fn test_commit_after_kill() {
+        let db = open_temp_db();
+        let table = table::Table::open(&db, "people").unwrap();
+        let person = tuple::Tuple::new(
+            vec![
+                fields::FieldVal::IntField(fields::IntField::new(1)),
+                fields::FieldVal::StringField(fields::StringField::new("Alice".to_string(), 5)),
+            ],
+            table.get_tuple_desc(),
+        );
+
+        // a transaction killed between statements fails to commit and is rolled back
+        let txn = transaction::Transaction::builder().database(&db).begin();
+        table.insert_tuple(person, &txn);
+        let bp = db.get_buffer_pool();
+        bp.kill_transaction(txn.get_tid()).unwrap();
+        let error = txn.commit().unwrap_err();
+        assert!(error.contains("killed"), "{}", error);
+        assert_eq!(txn.get_state(), transaction::TransactionState::Aborted);
+        let aborts = bp.get_lock_manager().get_abort_history();
+        assert!(aborts.iter().any(|abort| abort.get_tid() == txn.get_tid()));
+        drop(txn);
+
+        let txn = transaction::Transaction::builder().database(&db).begin();
+        assert!(names(&table, &txn).is_empty());
+        txn.commit().unwrap();
+        drop(table);
+        std::fs::remove_dir_all(db.get_config().get_data_dir()).unwrap();
+    }
+
+    #[test]
+    fn test_server_sessions() {
+        use rustic_db_client::protocol::{Column, Value};
+        use rustic_db_client::Client;
+
+        let db = open_temp_db();
+        let server = server::Server::bind_tcp(&db, "127.0.0.1:0")
+            .unwrap()
+            .max_connections(3);
+        let address = server.get_address();
+        let shutdown = server.get_shutdown_token();
+        let handle = thread::spawn(move || server.run());
+
+        let mut first = Client::connect_tcp(&address).unwrap();
+        let mut second = Client::connect_tcp(&address).unwrap();
+        let count = |client: &mut Client| client.query("SELECT * FROM people").unwrap().count();
+        assert_eq!(
+            first
+                .execute("INSERT INTO people VALUES (1, 'Alice')")
+                .unwrap(),
+            "INSERT 1"
+        );
+
+        // the changes of an open transaction are visible to other sessions once it commits
+        first.begin().unwrap();
+        first
+            .execute("insert into people values (2, 'Bob\tO''Brien');")
+            .unwrap();
+        assert_eq!(count(&mut second), 1);
+        assert_eq!(count(&mut first), 2);
+        first.commit().unwrap();
+        assert_eq!(count(&mut second), 2);
+
+        second.begin().unwrap();
+        assert_eq!(
+            second.execute("DELETE FROM people WHERE id > 0").unwrap(),
+            "DELETE 2"
+        );
+        second.rollback().unwrap();
+        assert_eq!(
+            second
+                .execute("UPDATE people SET name = 'Carol' WHERE id = 1")
+                .unwrap(),
+            "UPDATE 1"
+        );
+
+        // rows are streamed with their columns
+        let rows = first.query("SELECT name FROM people WHERE id = 2").unwrap();
+        assert_eq!(rows.get_columns(), [Column::new("name", "String")]);
+        let rows: Vec<Vec<Value>> = rows.collect::<Result<_, _>>().unwrap();
+        assert_eq!(rows, vec![vec![Value::String("Bob\tO'Brien".to_string())]]);
+        let rows: Vec<Vec<Value>> = first
+            .query("SELECT * FROM people LIMIT 1")
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![vec![Value::Int(1), Value::String("Carol".to_string())]]
+        );
+
+        // columns come back in the order they were selected, each at most once
+        let rows = first
+            .query("SELECT name, id FROM people WHERE id = 1")
+            .unwrap();
+        assert_eq!(
+            rows.get_columns(),
+            [Column::new("name", "String"), Column::new("id", "Int")]
+        );
+        let rows: Vec<Vec<Value>> = rows.collect::<Result<_, _>>().unwrap();
+        assert_eq!(
+            rows,
+            vec![vec![Value::String("Carol".to_string()), Value::Int(1)]]
+        );
+        assert_eq!(
+            first.query("SELECT id, id FROM people").err().unwrap(),
+            "Column id is selected twice"
+        );
+
+        // EXPLAIN returns the plan one row per operator
+        let plan = first
+            .query("EXPLAIN ANALYZE SELECT name FROM people WHERE id = 2")
+            .unwrap();
+        assert_eq!(plan.get_columns(), [Column::new("QUERY PLAN", "String")]);
+        let plan: Vec<Vec<Value>> = plan.collect::<Result<_, _>>().unwrap();
+        assert_eq!(plan.len(), 3);
+        let Value::String(project) = &plan[0][0] else {
+            panic!("expected a String");
+        };
+        assert!(project.starts_with("Project (name)"));
+        assert!(project.contains("(actual rows=1 "));
+
+        // a failed statement rolls back the transaction it ran in
+        assert_eq!(
+            first.execute("SELECT * FROM nobody").unwrap_err(),
+            "No table named nobody"
+        );
+        first.begin().unwrap();
+        first
+            .execute("INSERT INTO people VALUES (3, 'Dan')")
+            .unwrap();
+        assert_eq!(
+            first
+                .execute("INSERT INTO people VALUES ('x', 'y')")
+                .unwrap_err(),
+            "id: expected an Int but found 'x', transaction rolled back"
+        );
+        assert_eq!(first.commit().unwrap_err(), "No transaction is open");
+        assert_eq!(count(&mut first), 2);
+
+        // rolling back to a savepoint keeps the work done before it
+        assert_eq!(
+            first.execute("SAVEPOINT batch").unwrap_err(),
+            "No transaction is open"
+        );
+        first.begin().unwrap();
+        first
+            .execute("INSERT INTO people VALUES (3, 'Dan')")
+            .unwrap();
+        assert_eq!(first.execute("SAVEPOINT batch").unwrap(), "SAVEPOINT");
+        first
+            .execute("INSERT INTO people VALUES (4, 'Eve')")
+            .unwrap();
+        assert_eq!(
+            first.execute("ROLLBACK TO SAVEPOINT batch").unwrap(),
+            "ROLLBACK"
+        );
+        assert_eq!(first.execute("RELEASE batch").unwrap(), "RELEASE");
+        assert!(first.execute("ROLLBACK TO batch").is_err());
+        assert_eq!(count(&mut first), 3);
+        first.rollback().unwrap();
+        assert_eq!(count(&mut first), 2);
+
+        // KILL cancels the transaction of another session, listed in __transactions
+        second.begin().unwrap();
+        second
+            .execute("INSERT INTO people VALUES (3, 'Dan')")
+            .unwrap();
+        let tids: Vec<i32> = first
+            .query("SELECT tid FROM __transactions")
+            .unwrap()
+            .map(|row| match row.unwrap()[..] {
+                [Value::Int(tid)] => tid,
+                _ => panic!("expected a tid"),
+            })
+            .collect();
+        // the query runs in a transaction of its own, which started after the other one
+        let tid = tids.iter().min().unwrap();
+        assert_eq!(first.execute(&format!("KILL {}", tid)).unwrap(), "KILL");
+        let error = second.commit().unwrap_err();
+        assert!(error.contains("killed"), "{}", error);
+        assert_eq!(count(&mut first), 2);
+        assert_eq!(
+            first.execute("KILL 100000").unwrap_err(),
+            "No running transaction 100000"
+        );
+
+        // connections above the limit are refused until another one closes
+        let third = Client::connect_tcp(&address).unwrap();
+        assert_eq!(
+            Client::connect_tcp(&address).err().unwrap(),
+            "too many connections"
+        );
+        drop(third);
+        let mut third = None;
+        for _ in 0..100 {
+            third = Client::connect_tcp(&address).ok();
+            if third.is_some() {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(20));
+        }
+        assert!(third.is_some());
+
+        // shutting down rolls back the open transactions and closes every session
+        second.begin().unwrap();
+        second
+            .execute("INSERT INTO people VALUES (4, 'Eve')")
+            .unwrap();
+        assert_eq!(first.execute("SHUTDOWN").unwrap(), "SHUTDOWN");
+        assert!(shutdown.is_shut_down());
+        handle.join().unwrap().unwrap();
+        assert!(Client::connect_tcp(&address).is_err());
+
+        let people = table::Table::open(&db, "people").unwrap();
+        let txn = transaction::Transaction::builder().database(&db).begin();
+        assert_eq!(names(&people, &txn), vec!["Carol", "Bob\tO'Brien"]);
+        txn.commit().unwrap();
+        drop(people);
+        std::fs::remove_dir_all(db.get_config().get_data_dir()).unwrap();
+    }
+
+    #[test]
+    fn test_row_level_locking() {
+        let table = reset_table("testrowlocks", &["Alice", "Bob"]);
+        let db = database::get_global_db();
+        let lock_manager = db.get_buffer_pool().get_lock_manager();
+
+        let first = transaction::Transaction::begin();
+        let second = transaction::Transaction::begin();
+        rename(&table, 0, "Carol", &first).unwrap();
+        assert_eq!(
+            lock_manager.get_lock_mode(
+                first.get_tid(),
+                lock_manager::LockTarget::Table(table.get_id())
+            ),
+            Some(lock_manager::LockMode::IntentionExclusive)
+        );
+
+        // the younger transaction writes another row of the same page without dying
+        let handle = thread::spawn(move || {
+            let table = table::Table::new("testrowlocks".to_string(), "schema.txt".to_string());
+            rename(&table, 1, "Dan", &second).unwrap();
+            second.commit().unwrap();
+        });
+        assert!(handle.join().is_ok());
+
+        // the page written by the second commit leaves out the first transaction's change
+        let heap_file = db.get_catalog().get_table_from_id(table.get_id()).unwrap();
+        let page = heap_file
+            .read_page(&heap_page::HeapPageId::new(table.get_id(), 0))
+            .unwrap();
+        let on_disk: Vec<String> = page.iter().map(|tuple| tuple.to_string()).collect();
+        assert!(on_disk.iter().any(|name| name.contains("Dan")));
+        assert!(!on_disk.iter().any(|name| name.contains("Carol")));
+
+        first.rollback().unwrap();
+        let txn = transaction::Transaction::begin();
+        assert_eq!(names(&table, &txn), vec!["Alice", "Dan"]);
+        txn.commit().unwrap();
     }
 
     #[test]
@@ -244,9 +2663,9 @@ mod test {
             .map(|i| {
                 let table = table.clone();
                 thread::spawn(move || {
-                    let tid = transaction::TransactionId::new();
-                    let scan = table.scan(2, tid);
-                    for tuple in scan.into_iter() {
+                    let txn = transaction::Transaction::begin();
+                    let scan = table.scan(2, &txn);
+                    for tuple in scan {
                         println!("{} - Thread {}", tuple, i);
                     }
                 })
@@ -272,16 +2691,15 @@ mod test {
         let handles: Vec<_> = (0..2)
             .map(|_| {
                 let table = table.clone();
-                let db = database::get_global_db();
                 thread::spawn(move || {
                     // second transaction waits for 500 ms for first transaction to insert
                     // their first tuple
-                    let tid = transaction::TransactionId::new();
-                    if tid.get_tid() == 1 {
+                    let txn = transaction::Transaction::begin();
+                    if txn.get_tid().get_tid() == 1 {
                         thread::sleep(std::time::Duration::from_millis(500));
                     }
                     // inserted i should be 0 from first transaction and 1 for second transaction
-                    let i = tid.get_tid() as i32;
+                    let i = txn.get_tid().get_tid() as i32;
                     let mut tuple = tuple::Tuple::new(
                         vec![
                             fields::FieldVal::IntField(fields::IntField::new(i)),
@@ -292,9 +2710,10 @@ mod test {
                         ],
                         &table.get_tuple_desc().clone(),
                     );
-                    table.insert_tuple(tuple.clone(), tid);
+                    table.insert_tuple(tuple.clone(), &txn);
                     // first transaction sleeps and allows second thread to attempt insertion
-                    // second transaction should abort since first transaction has write lock
+                    // inserts only lock the rows they claim, so the second transaction does
+                    // not conflict with the first
                     thread::sleep(std::time::Duration::from_millis(2000 * (-i + 1) as u64));
                     tuple.set_field(
                         1,
@@ -303,9 +2722,8 @@ mod test {
                             7,
                         )),
                     );
-                    table.insert_tuple(tuple, tid);
-                    let bp = db.get_buffer_pool();
-                    bp.commit_transaction(tid);
+                    table.insert_tuple(tuple, &txn);
+                    txn.commit().unwrap();
                 })
             })
             .collect();
@@ -316,8 +2734,8 @@ mod test {
             }
         }
 
-        // table should only have the tuples inserted by the first transaction
-        for tuple in table.scan(10, transaction::TransactionId::new()) {
+        // table should have the tuples inserted by both transactions
+        for tuple in table.scan(10, &transaction::Transaction::begin()) {
             println!("{}", tuple);
         }
     }
@@ -337,18 +2755,19 @@ mod test {
         let handles: Vec<_> = (0..2)
             .map(|_| {
                 let table = table.clone();
-                let db = database::get_global_db();
                 thread::spawn(move || {
                     // first transaction waits for 500 ms for second transaction to start insert
-                    let tid = transaction::TransactionId::new();
-                    let i = tid.get_tid() as i32;
+                    let txn = transaction::Transaction::begin();
+                    let i = txn.get_tid().get_tid() as i32;
                     if i == 0 {
                         thread::sleep(std::time::Duration::from_millis(500));
                     }
-                    // second transaction should insert first and have write lock
+                    // second transaction should insert first
                     let mut tuple = tuple::Tuple::new(
                         vec![
-                            fields::FieldVal::IntField(fields::IntField::new(tid.get_tid() as i32)),
+                            fields::FieldVal::IntField(fields::IntField::new(
+                                txn.get_tid().get_tid() as i32,
+                            )),
                             fields::FieldVal::StringField(fields::StringField::new(
                                 format!("Alice_{}", i),
                                 7,
@@ -356,22 +2775,21 @@ mod test {
                         ],
                         &table.get_tuple_desc().clone(),
                     );
-                    table.insert_tuple(tuple.clone(), tid);
+                    table.insert_tuple(tuple.clone(), &txn);
                     // second transaction sleeps and first transaction will try to insert
-                    // first transaction should wait since second transaction has write lock
+                    // into another row of the same page without waiting
                     if i == 1 {
                         thread::sleep(std::time::Duration::from_millis(1000));
                     }
                     tuple.set_field(
                         1,
                         fields::FieldVal::StringField(fields::StringField::new(
-                            format!("Bob_{}", tid.get_tid()),
+                            format!("Bob_{}", txn.get_tid().get_tid()),
                             5,
                         )),
                     );
-                    table.insert_tuple(tuple, tid);
-                    let bp = db.get_buffer_pool();
-                    bp.commit_transaction(tid);
+                    table.insert_tuple(tuple, &txn);
+                    txn.commit().unwrap();
                 })
             })
             .collect();
@@ -382,8 +2800,8 @@ mod test {
             }
         }
 
-        // we should see all 4 tuples inserted with transaction 1's tuples first
-        for tuple in table.scan(10, transaction::TransactionId::new()) {
+        // we should see all 4 tuples inserted
+        for tuple in table.scan(10, &transaction::Transaction::begin()) {
             println!("{}", tuple);
         }
     }
@@ -411,10 +2829,9 @@ mod test {
                 } else {
                     table2.clone()
                 };
-                let db = database::get_global_db();
                 thread::spawn(move || {
-                    let tid = transaction::TransactionId::new();
-                    let i = tid.get_tid() as i32;
+                    let txn = transaction::Transaction::begin();
+                    let i = txn.get_tid().get_tid() as i32;
                     let tuple_collection = (0..10)
                         .map(|j| {
                             let name = format!("Alice_{} from transaction {}", j, i);
@@ -422,7 +2839,7 @@ mod test {
                             tuple::Tuple::new(
                                 vec![
                                     fields::FieldVal::IntField(fields::IntField::new(
-                                        tid.get_tid() as i32,
+                                        txn.get_tid().get_tid() as i32,
                                     )),
                                     fields::FieldVal::StringField(fields::StringField::new(
                                         name,
@@ -434,9 +2851,8 @@ mod test {
                         })
                         .collect();
 
-                    table.insert_many_tuples(tuple_collection, tid);
-                    let bp = db.get_buffer_pool();
-                    bp.commit_transaction(tid);
+                    table.insert_many_tuples(tuple_collection, &txn);
+                    txn.commit().unwrap();
                 })
             })
             .collect();
@@ -448,10 +2864,10 @@ mod test {
         }
 
         // we should see all the tuples inserted
-        for tuple in table1.scan(20, transaction::TransactionId::new()) {
+        for tuple in table1.scan(20, &transaction::Transaction::begin()) {
             println!("{}", tuple);
         }
-        for tuple in table2.scan(20, transaction::TransactionId::new()) {
+        for tuple in table2.scan(20, &transaction::Transaction::begin()) {
             println!("{}", tuple);
         }
     }
@@ -475,14 +2891,15 @@ mod test {
         let tables = vec![table1.clone(), table2.clone()];
         let handles: Vec<_> = (0..2)
             .map(|_| {
-                let db = database::get_global_db();
                 let tables = tables.clone();
                 thread::spawn(move || {
-                    let tid = transaction::TransactionId::new();
-                    let i = tid.get_tid() as usize;
+                    let txn = transaction::Transaction::begin();
+                    let i = txn.get_tid().get_tid() as usize;
                     let tuple = tuple::Tuple::new(
                         vec![
-                            fields::FieldVal::IntField(fields::IntField::new(tid.get_tid() as i32)),
+                            fields::FieldVal::IntField(fields::IntField::new(
+                                txn.get_tid().get_tid() as i32,
+                            )),
                             fields::FieldVal::StringField(fields::StringField::new(
                                 format!("Alice from transaction {}", i),
                                 24,
@@ -490,15 +2907,17 @@ mod test {
                         ],
                         &tables[0].get_tuple_desc().clone(),
                     );
-                    tables[i].insert_tuple(tuple, tid);
-                    // second transaction waits to make sure first transaction has write
-                    // lock on the first table
+                    tables[i].insert_tuple(tuple, &txn);
+                    // second transaction waits to make sure first transaction has inserted
+                    // into the first table
                     if i == 1 {
                         thread::sleep(std::time::Duration::from_millis(1000));
                     }
                     let tuple = tuple::Tuple::new(
                         vec![
-                            fields::FieldVal::IntField(fields::IntField::new(tid.get_tid() as i32)),
+                            fields::FieldVal::IntField(fields::IntField::new(
+                                txn.get_tid().get_tid() as i32,
+                            )),
                             fields::FieldVal::StringField(fields::StringField::new(
                                 format!("Bob from transaction {}", i),
                                 22,
@@ -506,10 +2925,10 @@ mod test {
                         ],
                         &tables[1].get_tuple_desc().clone(),
                     );
-                    // second transaction should abort since first transaction has write lock
-                    tables[(i + 1) % 2].insert_tuple(tuple, tid);
-                    let bp = db.get_buffer_pool();
-                    bp.commit_transaction(tid);
+                    // both transactions hold intention exclusive locks on both tables and
+                    // insert into different rows, so neither has to abort
+                    tables[(i + 1) % 2].insert_tuple(tuple, &txn);
+                    txn.commit().unwrap();
                 })
             })
             .collect();
@@ -520,13 +2939,13 @@ mod test {
             }
         }
 
-        // we should only see the tuples inserted by the first transaction
+        // we should see the tuples inserted by both transactions
         println!("table 1");
-        for tuple in table1.scan(20, transaction::TransactionId::new()) {
+        for tuple in table1.scan(20, &transaction::Transaction::begin()) {
             println!("{}", tuple);
         }
         println!("table 2");
-        for tuple in table2.scan(20, transaction::TransactionId::new()) {
+        for tuple in table2.scan(20, &transaction::Transaction::begin()) {
             println!("{}", tuple);
         }
     }
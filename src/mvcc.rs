@@ -0,0 +1,391 @@
+use crate::transaction::{IsolationLevel, TransactionId};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+// Number of bytes of version information stored in front of every tuple
+pub const VERSION_SIZE: usize = 16;
+// Stored in place of the deleting stamp of versions that have not been deleted
+pub const NO_STAMP: u64 = u64::MAX;
+// A stamp keeps the transaction id in its low bits and the epoch in the high bits
+const EPOCH_SHIFT: u32 = 40;
+const MAX_EPOCH: u64 = (1 << (64 - EPOCH_SHIFT)) - 1;
+// File in the data directory that remembers the epoch of the last run
+pub const EPOCH_FILE: &str = "epoch";
+
+/**
+ * Creating and deleting stamps of a tuple version. A stamp combines the transaction
+ * id with the epoch of the run that created it, since transaction ids start over
+ * every time the database is opened. Versions from earlier epochs are committed.
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct TupleVersion {
+    xmin: u64,
+    xmax: Option<u64>,
+}
+
+impl TupleVersion {
+    pub fn new(xmin: u64) -> Self {
+        TupleVersion { xmin, xmax: None }
+    }
+
+    // Stamp of the transaction that created this version
+    pub fn get_xmin(&self) -> u64 {
+        self.xmin
+    }
+
+    // Stamp of the transaction that deleted this version, if any
+    pub fn get_xmax(&self) -> Option<u64> {
+        self.xmax
+    }
+
+    pub fn set_xmax(&mut self, xmax: Option<u64>) {
+        self.xmax = xmax;
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = self.xmin.to_be_bytes().to_vec();
+        bytes.extend(self.xmax.unwrap_or(NO_STAMP).to_be_bytes());
+        bytes
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Self {
+        let mut xmin = [0; 8];
+        xmin.copy_from_slice(&bytes[..8]);
+        let mut xmax = [0; 8];
+        xmax.copy_from_slice(&bytes[8..VERSION_SIZE]);
+        let xmax = u64::from_be_bytes(xmax);
+        TupleVersion {
+            xmin: u64::from_be_bytes(xmin),
+            xmax: if xmax == NO_STAMP { None } else { Some(xmax) },
+        }
+    }
+}
+
+/**
+ * The set of committed transactions a transaction can see. Versions created by
+ * transactions that committed before the snapshot was taken are visible, as are
+ * the transaction's own changes. A dirty snapshot also sees uncommitted changes.
+ */
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    stamp: u64,
+    commit_seq: u64,
+    epoch: u64,
+    commits: Arc<RwLock<HashMap<u64, u64>>>,
+    running: Arc<RwLock<HashSet<u64>>>,
+    dirty: bool,
+}
+
+impl Snapshot {
+    // Stamp of the transaction that owns this snapshot
+    pub fn get_stamp(&self) -> u64 {
+        self.stamp
+    }
+
+    // Checks whether the changes of the transaction with the stamp are visible
+    pub fn sees(&self, stamp: u64) -> bool {
+        if self.dirty || stamp == self.stamp || stamp >> EPOCH_SHIFT != self.epoch {
+            return true;
+        }
+        if let Some(seq) = self.commits.read().unwrap().get(&stamp) {
+            return *seq <= self.commit_seq;
+        }
+        // committed long enough ago that every snapshot sees it
+        !self.running.read().unwrap().contains(&stamp)
+    }
+
+    // Checks whether the tuple version exists as of this snapshot
+    pub fn is_visible(&self, version: &TupleVersion) -> bool {
+        self.sees(version.xmin) && !version.xmax.is_some_and(|xmax| self.sees(xmax))
+    }
+}
+
+// Tracks which transactions committed and the snapshots of running transactions
+pub struct TransactionTable {
+    epoch: u64,
+    // maps the stamps of committed transactions to their commit sequence numbers, as long
+    // as some snapshot does not see them. Ended transactions missing here are committed
+    commits: Arc<RwLock<HashMap<u64, u64>>>,
+    // stamps of the transactions that began and have not ended
+    running: Arc<RwLock<HashSet<u64>>>,
+    last_commit_seq: AtomicU64,
+    snapshots: RwLock<HashMap<TransactionId, Snapshot>>,
+    isolation_levels: RwLock<HashMap<TransactionId, IsolationLevel>>,
+    // when each running transaction began
+    start_times: RwLock<HashMap<TransactionId, Instant>>,
+}
+
+impl TransactionTable {
+    pub fn new(data_dir: &Path) -> Self {
+        TransactionTable {
+            epoch: Self::next_epoch(data_dir),
+            commits: Arc::new(RwLock::new(HashMap::new())),
+            running: Arc::new(RwLock::new(HashSet::new())),
+            last_commit_seq: AtomicU64::new(0),
+            snapshots: RwLock::new(HashMap::new()),
+            isolation_levels: RwLock::new(HashMap::new()),
+            start_times: RwLock::new(HashMap::new()),
+        }
+    }
+
+    // Reads the epoch of the previous run and records the epoch of this one
+    fn next_epoch(data_dir: &Path) -> u64 {
+        let epoch_file = data_dir.join(EPOCH_FILE);
+        let previous = fs::read_to_string(&epoch_file)
+            .ok()
+            .and_then(|s| s.trim().parse::<u64>().ok())
+            .unwrap_or(0);
+        // epoch 0 is reserved for versions written before stamps carried an epoch
+        let epoch = previous % MAX_EPOCH + 1;
+        fs::write(&epoch_file, epoch.to_string()).unwrap();
+        epoch
+    }
+
+    // Stamp written into the versions created or deleted by the transaction
+    pub fn stamp(&self, tid: TransactionId) -> u64 {
+        (self.epoch << EPOCH_SHIFT) | tid.get_tid()
+    }
+
+    // Registers a transaction that begins now under the isolation level
+    pub fn begin(&self, tid: TransactionId, isolation: IsolationLevel) {
+        self.running.write().unwrap().insert(self.stamp(tid));
+        self.set_isolation(tid, isolation);
+        self.start_times
+            .write()
+            .unwrap()
+            .insert(tid, Instant::now());
+    }
+
+    // Checks whether the transaction began and has not committed or aborted yet
+    pub fn is_running(&self, tid: TransactionId) -> bool {
+        self.running.read().unwrap().contains(&self.stamp(tid))
+    }
+
+    // Lists the transactions that began and have not ended yet with their isolation
+    // levels and start times, oldest first
+    pub fn get_running(&self) -> Vec<(TransactionId, IsolationLevel, Instant)> {
+        let start_times = self.start_times.read().unwrap();
+        let mut running: Vec<_> = start_times
+            .iter()
+            .map(|(tid, started)| (*tid, self.get_isolation(*tid), *started))
+            .collect();
+        running.sort_by_key(|(tid, _, _)| *tid);
+        running
+    }
+
+    // Records the isolation level the transaction runs under
+    pub fn set_isolation(&self, tid: TransactionId, isolation: IsolationLevel) {
+        let mut isolation_levels = self.isolation_levels.write().unwrap();
+        isolation_levels.insert(tid, isolation);
+    }
+
+    // Retrieves the isolation level of the transaction
+    pub fn get_isolation(&self, tid: TransactionId) -> IsolationLevel {
+        let isolation_levels = self.isolation_levels.read().unwrap();
+        isolation_levels.get(&tid).copied().unwrap_or_default()
+    }
+
+    // Retrieves the snapshot of the transaction. Under READ COMMITTED a new snapshot is
+    // taken for every statement; the other levels take it on first use and keep it
+    pub fn get_snapshot(&self, tid: TransactionId) -> Snapshot {
+        if let Some(snapshot) = self.snapshots.read().unwrap().get(&tid) {
+            return snapshot.clone();
+        }
+        let isolation = self.get_isolation(tid);
+        // the snapshot is registered before commits can be forgotten for being seen by all
+        let mut snapshots = self.snapshots.write().unwrap();
+        let commits = self.commits.read().unwrap();
+        let commit_seq = match isolation {
+            // these levels see every committed change, whenever it was committed
+            IsolationLevel::ReadUncommitted | IsolationLevel::Serializable => u64::MAX,
+            _ => self.last_commit_seq.load(Ordering::SeqCst),
+        };
+        drop(commits);
+        let snapshot = Snapshot {
+            stamp: self.stamp(tid),
+            commit_seq,
+            epoch: self.epoch,
+            commits: Arc::clone(&self.commits),
+            running: Arc::clone(&self.running),
+            dirty: isolation == IsolationLevel::ReadUncommitted,
+        };
+        snapshots.entry(tid).or_insert(snapshot).clone()
+    }
+
+    // Forgets the snapshot of a READ COMMITTED transaction between its statements, so that
+    // its next statement sees the changes committed in the meantime
+    pub fn end_statement(&self, tid: TransactionId) {
+        if self.get_isolation(tid) == IsolationLevel::ReadCommitted {
+            self.snapshots.write().unwrap().remove(&tid);
+        }
+    }
+
+    // Makes the changes of the transaction visible to snapshots taken from now on
+    pub fn commit(&self, tid: TransactionId) {
+        let mut commits = self.commits.write().unwrap();
+        let seq = self.last_commit_seq.fetch_add(1, Ordering::SeqCst) + 1;
+        commits.insert(self.stamp(tid), seq);
+        drop(commits);
+        self.running.write().unwrap().remove(&self.stamp(tid));
+        self.snapshots.write().unwrap().remove(&tid);
+        self.isolation_levels.write().unwrap().remove(&tid);
+        self.start_times.write().unwrap().remove(&tid);
+        self.prune_commits();
+    }
+
+    // Forgets the commits that every snapshot sees, so that the table does not grow with
+    // every commit of a long running database. They still count as committed since their
+    // transactions are no longer running
+    fn prune_commits(&self) {
+        let snapshots = self.snapshots.read().unwrap();
+        let oldest = snapshots
+            .values()
+            .map(|snapshot| snapshot.commit_seq)
+            .min()
+            .unwrap_or(u64::MAX);
+        self.commits.write().unwrap().retain(|_, seq| *seq > oldest);
+    }
+
+    // Forgets the snapshot of an aborted transaction. Its versions were already undone
+    pub fn abort(&self, tid: TransactionId) {
+        self.running.write().unwrap().remove(&self.stamp(tid));
+        self.snapshots.write().unwrap().remove(&tid);
+        self.isolation_levels.write().unwrap().remove(&tid);
+        self.start_times.write().unwrap().remove(&tid);
+    }
+
+    // Checks whether a version deleted by the transaction with the stamp can no longer
+    // be seen by any running transaction, so that its slot can be reused
+    pub fn is_dead_to_all(&self, xmax: u64) -> bool {
+        if xmax >> EPOCH_SHIFT != self.epoch {
+            return true;
+        }
+        let seq = match self.commits.read().unwrap().get(&xmax) {
+            Some(seq) => *seq,
+            None => return !self.running.read().unwrap().contains(&xmax),
+        };
+        let snapshots = self.snapshots.read().unwrap();
+        snapshots
+            .values()
+            .all(|snapshot| snapshot.commit_seq >= seq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::TransactionIds;
+
+    fn snapshot(stamp: u64, commit_seq: u64, commits: &[(u64, u64)], running: &[u64]) -> Snapshot {
+        Snapshot {
+            stamp,
+            commit_seq,
+            epoch: 1,
+            commits: Arc::new(RwLock::new(commits.iter().copied().collect())),
+            running: Arc::new(RwLock::new(running.iter().copied().collect())),
+            dirty: false,
+        }
+    }
+
+    #[test]
+    fn test_version_serialize_deserialize() {
+        let mut version = TupleVersion::new(7);
+        assert_eq!(TupleVersion::deserialize(&version.serialize()), version);
+        version.set_xmax(Some(9));
+        assert_eq!(version.serialize().len(), VERSION_SIZE);
+        assert_eq!(TupleVersion::deserialize(&version.serialize()), version);
+    }
+
+    #[test]
+    fn test_snapshot_visibility() {
+        let epoch = 1 << EPOCH_SHIFT;
+        let (me, before, after, running) = (epoch | 1, epoch | 2, epoch | 3, epoch | 4);
+        let snapshot = snapshot(me, 1, &[(before, 1), (after, 2)], &[me, running]);
+
+        // own changes, changes committed before the snapshot and older epochs are visible,
+        // as are commits that were forgotten after every snapshot saw them
+        assert!(snapshot.is_visible(&TupleVersion::new(me)));
+        assert!(snapshot.is_visible(&TupleVersion::new(before)));
+        assert!(snapshot.is_visible(&TupleVersion::new(5)));
+        assert!(snapshot.is_visible(&TupleVersion::new(epoch | 6)));
+        // changes committed after the snapshot or not committed are not
+        assert!(!snapshot.is_visible(&TupleVersion::new(after)));
+        assert!(!snapshot.is_visible(&TupleVersion::new(running)));
+
+        let mut version = TupleVersion::new(before);
+        version.set_xmax(Some(after));
+        assert!(snapshot.is_visible(&version));
+        version.set_xmax(Some(me));
+        assert!(!snapshot.is_visible(&version));
+    }
+
+    #[test]
+    fn test_snapshot_per_isolation_level() {
+        let ids = TransactionIds::new();
+        let (writer, reader) = (ids.next(), ids.next());
+        // the table keeps its epoch file in the data directory
+        let data_dir = std::env::temp_dir().join(format!("rustic_db_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&data_dir).unwrap();
+        for (isolation, before_commit, same_statement, after_commit) in [
+            (IsolationLevel::ReadUncommitted, true, true, true),
+            (IsolationLevel::ReadCommitted, false, false, true),
+            (IsolationLevel::RepeatableRead, false, false, false),
+            (IsolationLevel::Serializable, false, true, true),
+        ] {
+            let transactions = TransactionTable::new(&data_dir);
+            let writes = TupleVersion::new(transactions.stamp(writer));
+            transactions.begin(writer, IsolationLevel::default());
+            transactions.begin(reader, isolation);
+            let snapshot = transactions.get_snapshot(reader);
+            assert_eq!(snapshot.is_visible(&writes), before_commit);
+            transactions.commit(writer);
+            // the rest of the reader's statement keeps its snapshot
+            let snapshot = transactions.get_snapshot(reader);
+            assert_eq!(snapshot.is_visible(&writes), same_statement);
+            // the next statement of the reader
+            transactions.end_statement(reader);
+            let snapshot = transactions.get_snapshot(reader);
+            assert_eq!(snapshot.is_visible(&writes), after_commit);
+            transactions.abort(reader);
+            assert_eq!(
+                transactions.get_isolation(reader),
+                IsolationLevel::default()
+            );
+        }
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_prune_commits() {
+        let ids = TransactionIds::new();
+        let (reader, first, second) = (ids.next(), ids.next(), ids.next());
+        let data_dir = std::env::temp_dir().join(format!("rustic_db_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&data_dir).unwrap();
+        let transactions = TransactionTable::new(&data_dir);
+        for tid in [reader, first, second] {
+            transactions.begin(tid, IsolationLevel::RepeatableRead);
+        }
+        let snapshot = transactions.get_snapshot(reader);
+
+        // the reader's snapshot does not see the commits, so they are kept
+        transactions.commit(first);
+        transactions.commit(second);
+        assert_eq!(transactions.commits.read().unwrap().len(), 2);
+        let writes = TupleVersion::new(transactions.stamp(first));
+        assert!(!snapshot.is_visible(&writes));
+        assert!(!transactions.is_dead_to_all(transactions.stamp(first)));
+
+        // once it ends every snapshot sees them and they are forgotten, still committed
+        transactions.commit(reader);
+        assert_eq!(transactions.commits.read().unwrap().len(), 0);
+        let later = ids.next();
+        transactions.begin(later, IsolationLevel::RepeatableRead);
+        assert!(transactions.get_snapshot(later).is_visible(&writes));
+        assert!(transactions.is_dead_to_all(transactions.stamp(first)));
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+}
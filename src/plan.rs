@@ -0,0 +1,442 @@
+use crate::buffer_pool::{BufferPool, PageStats};
+use crate::database::Database;
+use crate::heap_file::HeapFile;
+use crate::heap_page::HeapPage;
+use crate::system_table;
+use crate::table::{Filterable, Predicate};
+use crate::transaction::TransactionId;
+use crate::tuple::{Tuple, TupleDesc};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// cost of reading one page sequentially from disk
+const SEQ_PAGE_COST: f64 = 1.0;
+// cost of producing one tuple
+const CPU_TUPLE_COST: f64 = 0.01;
+// cost of evaluating a predicate or copying fields for one tuple
+const CPU_OPERATOR_COST: f64 = 0.0025;
+// guessed fraction of tuples that pass an equality predicate
+const EQ_SELECTIVITY: f64 = 0.1;
+// guessed fraction of tuples that pass a range predicate
+const RANGE_SELECTIVITY: f64 = 1.0 / 3.0;
+// guessed number of rows in a system table
+const SYSTEM_TABLE_ROWS: f64 = 10.0;
+
+// Relational operators that make up a query plan
+#[derive(Clone)]
+pub enum Operator {
+    SeqScan {
+        table_name: String,
+        heap_file: Arc<HeapFile>,
+        limit: usize,
+    },
+    // reads the rows of a system table, which are built in memory
+    SystemScan {
+        table_name: String,
+        limit: usize,
+    },
+    Filter {
+        field_name: String,
+        predicate: Predicate,
+    },
+    Project {
+        fields: Vec<String>,
+    },
+    NestedLoopJoin {
+        field_name_left: String,
+        field_name_right: String,
+    },
+}
+
+// Measurements taken while executing a single operator, including its children
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OperatorStats {
+    rows: usize,
+    elapsed: Duration,
+    page_stats: PageStats,
+}
+
+// A node in the operator tree, annotated with the planner's estimates
+#[derive(Clone)]
+pub struct PlanNode {
+    operator: Operator,
+    children: Vec<PlanNode>,
+    est_rows: f64,
+    est_cost: f64,
+    actual: Option<OperatorStats>,
+}
+
+impl PlanNode {
+    pub fn seq_scan(table_name: &str, heap_file: Arc<HeapFile>, limit: usize) -> Self {
+        let num_pages = heap_file.num_pages();
+        let slots = HeapPage::slots_per_page(heap_file.get_tuple_desc(), heap_file.get_page_size());
+        // assume full pages since we keep no statistics on the table
+        let est_rows = ((num_pages * slots).min(limit)) as f64;
+        let pages_read = (est_rows / slots as f64).ceil().min(num_pages as f64);
+        PlanNode {
+            operator: Operator::SeqScan {
+                table_name: table_name.to_string(),
+                heap_file,
+                limit,
+            },
+            children: vec![],
+            est_rows,
+            est_cost: pages_read * SEQ_PAGE_COST + est_rows * CPU_TUPLE_COST,
+            actual: None,
+        }
+    }
+
+    pub fn system_scan(table_name: &str, limit: usize) -> Self {
+        let est_rows = SYSTEM_TABLE_ROWS.min(limit as f64);
+        PlanNode {
+            operator: Operator::SystemScan {
+                table_name: table_name.to_string(),
+                limit,
+            },
+            children: vec![],
+            est_rows,
+            est_cost: est_rows * CPU_TUPLE_COST,
+            actual: None,
+        }
+    }
+
+    pub fn filter(child: PlanNode, field_name: &str, predicate: Predicate) -> Self {
+        let selectivity = match predicate {
+            Predicate::Equals(_) | Predicate::EqualsInt(_) => EQ_SELECTIVITY,
+            Predicate::GreaterThan(_) | Predicate::LessThan(_) => RANGE_SELECTIVITY,
+        };
+        PlanNode {
+            est_rows: child.est_rows * selectivity,
+            est_cost: child.est_cost + child.est_rows * CPU_OPERATOR_COST,
+            operator: Operator::Filter {
+                field_name: field_name.to_string(),
+                predicate,
+            },
+            children: vec![child],
+            actual: None,
+        }
+    }
+
+    pub fn project(child: PlanNode, fields: Vec<String>) -> Self {
+        PlanNode {
+            est_rows: child.est_rows,
+            est_cost: child.est_cost + child.est_rows * CPU_OPERATOR_COST,
+            operator: Operator::Project { fields },
+            children: vec![child],
+            actual: None,
+        }
+    }
+
+    pub fn nested_loop_join(
+        left: PlanNode,
+        right: PlanNode,
+        field_name_left: &str,
+        field_name_right: &str,
+    ) -> Self {
+        // every pair of tuples is compared once
+        let comparisons = left.est_rows * right.est_rows;
+        PlanNode {
+            est_rows: comparisons * EQ_SELECTIVITY,
+            est_cost: left.est_cost + right.est_cost + comparisons * CPU_OPERATOR_COST,
+            operator: Operator::NestedLoopJoin {
+                field_name_left: field_name_left.to_string(),
+                field_name_right: field_name_right.to_string(),
+            },
+            children: vec![left, right],
+            actual: None,
+        }
+    }
+
+    // Describes the tuples produced by the operator tree. Projections keep the fields in
+    // the order of their input
+    pub fn get_tuple_desc(&self) -> TupleDesc {
+        match &self.operator {
+            Operator::SeqScan { heap_file, .. } => heap_file.get_tuple_desc().clone(),
+            Operator::SystemScan { table_name, .. } => system_table::get_tuple_desc(table_name)
+                .unwrap_or_else(|| TupleDesc::new(vec![], vec![])),
+            Operator::Filter { .. } => self.children[0].get_tuple_desc(),
+            Operator::Project { fields } => {
+                let td = self.children[0].get_tuple_desc();
+                project_tuple_desc(&td, &projected_fields(&td, fields))
+            }
+            Operator::NestedLoopJoin { .. } => TupleDesc::combine(
+                &self.children[0].get_tuple_desc(),
+                &self.children[1].get_tuple_desc(),
+            ),
+        }
+    }
+
+    // Runs the operator tree in the database and returns the resulting tuples. Fails if
+    // the transaction is cancelled or runs out of time before the tree finishes
+    pub fn execute(&self, db: &Database, tid: TransactionId) -> Result<Vec<Tuple>, String> {
+        let bp = db.get_buffer_pool();
+        self.lock_predicates(bp, tid, vec![]);
+        self.run(db, tid)
+    }
+
+    fn run(&self, db: &Database, tid: TransactionId) -> Result<Vec<Tuple>, String> {
+        let inputs = self
+            .children
+            .iter()
+            .map(|c| c.run(db, tid))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.run_operator(db, tid, inputs)
+    }
+
+    // Runs the operator tree while recording rows, time and page accesses for every node
+    pub fn execute_analyze(
+        &mut self,
+        db: &Database,
+        tid: TransactionId,
+    ) -> Result<Vec<Tuple>, String> {
+        let bp = db.get_buffer_pool();
+        self.lock_predicates(bp, tid, vec![]);
+        self.analyze(db, tid)
+    }
+
+    // Locks the predicate of every scan for SERIALIZABLE transactions, made of the
+    // filters applied to the scanned rows
+    fn lock_predicates(
+        &self,
+        bp: &BufferPool,
+        tid: TransactionId,
+        mut filters: Vec<(String, Predicate)>,
+    ) {
+        match &self.operator {
+            Operator::SeqScan { heap_file, .. } => {
+                bp.lock_predicate(tid, heap_file.get_id(), filters);
+            }
+            Operator::SystemScan { .. } => {}
+            Operator::Filter {
+                field_name,
+                predicate,
+            } => {
+                filters.push((field_name.clone(), predicate.clone()));
+                self.children[0].lock_predicates(bp, tid, filters);
+            }
+            Operator::Project { .. } => self.children[0].lock_predicates(bp, tid, filters),
+            Operator::NestedLoopJoin { .. } => {
+                for child in self.children.iter() {
+                    child.lock_predicates(bp, tid, vec![]);
+                }
+            }
+        }
+    }
+
+    fn analyze(&mut self, db: &Database, tid: TransactionId) -> Result<Vec<Tuple>, String> {
+        let bp = db.get_buffer_pool();
+        let start_stats = bp.get_page_stats(tid);
+        let start = Instant::now();
+
+        let inputs = self
+            .children
+            .iter_mut()
+            .map(|c| c.analyze(db, tid))
+            .collect::<Result<Vec<_>, _>>()?;
+        let output = self.run_operator(db, tid, inputs)?;
+
+        self.actual = Some(OperatorStats {
+            rows: output.len(),
+            elapsed: start.elapsed(),
+            page_stats: bp.get_page_stats(tid).since(&start_stats),
+        });
+        Ok(output)
+    }
+
+    // Runs this operator on the outputs of its children, checking between pages and
+    // tuples whether the transaction was cancelled or ran out of time
+    fn run_operator(
+        &self,
+        db: &Database,
+        tid: TransactionId,
+        mut inputs: Vec<Vec<Tuple>>,
+    ) -> Result<Vec<Tuple>, String> {
+        let bp = db.get_buffer_pool();
+        bp.check_interrupt(tid)?;
+        let output = match &self.operator {
+            Operator::SeqScan {
+                heap_file, limit, ..
+            } => {
+                let snapshot = bp.get_snapshot(tid);
+                let mut data = Vec::new();
+                let mut pages = heap_file.iter(tid);
+                // stop fetching pages as soon as the limit is reached
+                while data.len() < *limit {
+                    let Some(page) = pages.next() else {
+                        break;
+                    };
+                    bp.check_interrupt(tid)?;
+                    let page = page?;
+                    let page = page.read().unwrap();
+                    let remaining = *limit - data.len();
+                    data.extend(page.iter_visible(&snapshot).take(remaining).cloned());
+                }
+                data
+            }
+            Operator::SystemScan { table_name, limit } => system_table::scan(db, table_name)
+                .unwrap_or_default()
+                .into_iter()
+                .take(*limit)
+                .collect(),
+            Operator::Filter {
+                field_name,
+                predicate,
+            } => inputs
+                .remove(0)
+                .into_iter()
+                .filter(|tuple| tuple.filter(field_name, predicate))
+                .collect(),
+            Operator::Project { fields } => inputs
+                .remove(0)
+                .iter()
+                .map(|tuple| project_tuple(tuple, fields))
+                .collect(),
+            Operator::NestedLoopJoin {
+                field_name_left,
+                field_name_right,
+            } => {
+                let right = inputs.remove(1);
+                let left = inputs.remove(0);
+                join_tuples(bp, tid, &left, &right, field_name_left, field_name_right)?
+            }
+        };
+        Ok(output)
+    }
+
+    // Renders the operator tree with estimates, and measurements if the plan was analyzed
+    pub fn explain(&self) -> String {
+        let mut out = String::new();
+        self.explain_node(&mut out, 0);
+        out
+    }
+
+    fn explain_node(&self, out: &mut String, depth: usize) {
+        if depth > 0 {
+            write!(out, "{}-> ", "  ".repeat(depth)).unwrap();
+        }
+        write!(
+            out,
+            "{}  (cost={:.2} rows={:.0})",
+            self.describe(),
+            self.est_cost,
+            self.est_rows
+        )
+        .unwrap();
+        if let Some(actual) = &self.actual {
+            write!(
+                out,
+                " (actual rows={} time={:.3}ms hits={} reads={} lock_waits={})",
+                actual.rows,
+                actual.elapsed.as_secs_f64() * 1000.0,
+                actual.page_stats.get_hits(),
+                actual.page_stats.get_reads(),
+                actual.page_stats.get_lock_waits()
+            )
+            .unwrap();
+        }
+        out.push('\n');
+        for child in self.children.iter() {
+            child.explain_node(out, depth + 1);
+        }
+    }
+
+    fn describe(&self) -> String {
+        match &self.operator {
+            Operator::SeqScan {
+                table_name, limit, ..
+            } => format!("Seq Scan on {}{}", table_name, describe_limit(*limit)),
+            Operator::SystemScan { table_name, limit } => {
+                format!("System Scan on {}{}", table_name, describe_limit(*limit))
+            }
+            Operator::Filter {
+                field_name,
+                predicate,
+            } => format!("Filter ({})", predicate.describe(field_name)),
+            Operator::Project { fields } => format!("Project ({})", fields.join(", ")),
+            Operator::NestedLoopJoin {
+                field_name_left,
+                field_name_right,
+            } => format!(
+                "Nested Loop Join ({} = {})",
+                field_name_left, field_name_right
+            ),
+        }
+    }
+}
+
+// Shows the limit of a scan, if it has one
+fn describe_limit(limit: usize) -> String {
+    match limit {
+        usize::MAX => String::new(),
+        limit => format!(" (limit {})", limit),
+    }
+}
+
+// Finds the positions of the requested fields in the order they were requested,
+// skipping names the tuple descriptor does not have
+fn projected_fields(td: &TupleDesc, fields: &[String]) -> Vec<usize> {
+    fields
+        .iter()
+        .filter_map(|name| td.name_to_id(name))
+        .collect()
+}
+
+// Describes the fields kept by a projection, with their names and types
+fn project_tuple_desc(td: &TupleDesc, kept: &[usize]) -> TupleDesc {
+    TupleDesc::new(
+        kept.iter()
+            .map(|i| td.get_field_type(*i).unwrap().clone())
+            .collect(),
+        kept.iter()
+            .map(|i| td.get_field_name(*i).unwrap().clone())
+            .collect(),
+    )
+}
+
+// Keeps only the requested fields of the tuple, in the order they were requested
+fn project_tuple(tuple: &Tuple, fields: &[String]) -> Tuple {
+    let td = tuple.get_tuple_desc();
+    let kept = projected_fields(td, fields);
+    let values = kept
+        .iter()
+        .map(|i| tuple.get_field(*i).unwrap().clone())
+        .collect();
+    Tuple::new(values, &project_tuple_desc(td, &kept))
+}
+
+// Combines every pair of tuples whose join fields are equal, checking for interrupts
+// before every tuple of the left input
+fn join_tuples(
+    bp: &BufferPool,
+    tid: TransactionId,
+    left: &[Tuple],
+    right: &[Tuple],
+    field_name_left: &str,
+    field_name_right: &str,
+) -> Result<Vec<Tuple>, String> {
+    let mut data = Vec::new();
+    for tuple in left.iter() {
+        bp.check_interrupt(tid)?;
+        let target_col_left = tuple.get_tuple_desc().name_to_id(field_name_left).unwrap();
+        for other_tuple in right.iter() {
+            let target_col_right = other_tuple
+                .get_tuple_desc()
+                .name_to_id(field_name_right)
+                .unwrap();
+            if tuple.get_field(target_col_left).unwrap()
+                == other_tuple.get_field(target_col_right).unwrap()
+            {
+                let ctd = TupleDesc::combine(tuple.get_tuple_desc(), other_tuple.get_tuple_desc());
+                let combined_fields = tuple
+                    .get_fields()
+                    .iter()
+                    .chain(other_tuple.get_fields().iter())
+                    .cloned()
+                    .collect::<Vec<_>>();
+                data.push(Tuple::new(combined_fields, &ctd));
+            }
+        }
+    }
+    Ok(data)
+}
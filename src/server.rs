@@ -0,0 +1,612 @@
+use crate::database::Database;
+use crate::fields::{FieldVal, StringField};
+use crate::interrupt::CancellationToken;
+use crate::sql::{self, Statement};
+use crate::table::Table;
+use crate::transaction::Transaction;
+use crate::tuple::{Tuple, TupleDesc};
+use crate::types::Type;
+use rustic_db_client::protocol::{self, Column, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// How often the accept loop and idle sessions check whether the server is shutting down
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+pub const DEFAULT_MAX_CONNECTIONS: usize = 16;
+
+enum Listener {
+    Tcp(TcpListener),
+    // the socket file is removed when the server stops
+    Unix(UnixListener, PathBuf),
+}
+
+// A client connection over TCP or a Unix socket
+enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Connection {
+    fn try_clone(&self) -> io::Result<Connection> {
+        match self {
+            Connection::Tcp(stream) => stream.try_clone().map(Connection::Tcp),
+            Connection::Unix(stream) => stream.try_clone().map(Connection::Unix),
+        }
+    }
+
+    // Blocks reads for at most the timeout, so idle sessions notice a shutdown
+    fn set_read_timeout(&self, timeout: Duration) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => {
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(Some(timeout))
+            }
+            Connection::Unix(stream) => {
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(Some(timeout))
+            }
+        }
+    }
+
+    fn close(&self) {
+        let _ = match self {
+            Connection::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            Connection::Unix(stream) => stream.shutdown(Shutdown::Both),
+        };
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            Connection::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            Connection::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            Connection::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct ShutdownState {
+    shut_down: AtomicBool,
+    // cancellation tokens of the transactions the sessions are running, by session id
+    transactions: Mutex<HashMap<usize, CancellationToken>>,
+}
+
+// Stops a server from any thread. Like a CancellationToken it can be cloned and handed to
+// other threads
+#[derive(Clone, Default)]
+pub struct ShutdownToken {
+    state: Arc<ShutdownState>,
+}
+
+impl ShutdownToken {
+    // Stops accepting connections and cancels the transactions the sessions are running.
+    // Sessions roll back their open transactions and close
+    pub fn shutdown(&self) {
+        self.state.shut_down.store(true, Ordering::SeqCst);
+        for token in self.state.transactions.lock().unwrap().values() {
+            token.cancel();
+        }
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.state.shut_down.load(Ordering::SeqCst)
+    }
+
+    // Tracks the transaction a session runs, cancelling it if the server is already
+    // shutting down
+    fn register(&self, session_id: usize, txn: &Transaction) {
+        let token = txn.get_cancellation_token();
+        let mut transactions = self.state.transactions.lock().unwrap();
+        if self.is_shut_down() {
+            token.cancel();
+        }
+        transactions.insert(session_id, token);
+    }
+
+    fn unregister(&self, session_id: usize) {
+        self.state.transactions.lock().unwrap().remove(&session_id);
+    }
+}
+
+/**
+ * Serves a database to clients over TCP or a Unix socket with the line protocol of
+ * rustic_db_client::protocol. Every connection is a session on its own thread that runs
+ * each statement in its own transaction unless the client started one with BEGIN, which
+ * then holds until COMMIT or ROLLBACK. Query results are streamed to the client a row at
+ * a time. Connections above the limit are refused. Shutting down, through the token or a
+ * SHUTDOWN statement, stops accepting connections, rolls back the open transactions of
+ * all sessions and waits for the sessions to close.
+ */
+pub struct Server {
+    db: Arc<Database>,
+    listener: Listener,
+    max_connections: usize,
+    shutdown: ShutdownToken,
+}
+
+impl Server {
+    // Listens on a TCP address such as 127.0.0.1:5454, where port 0 picks a free port
+    pub fn bind_tcp(db: &Arc<Database>, address: impl ToSocketAddrs) -> Result<Self, String> {
+        let listener = TcpListener::bind(address).map_err(|e| format!("Cannot listen: {}", e))?;
+        Ok(Server::new(db, Listener::Tcp(listener)))
+    }
+
+    // Listens on a Unix socket at the path, which must not exist
+    pub fn bind_unix(db: &Arc<Database>, path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let listener = UnixListener::bind(path)
+            .map_err(|e| format!("Cannot listen on {}: {}", path.display(), e))?;
+        Ok(Server::new(
+            db,
+            Listener::Unix(listener, path.to_path_buf()),
+        ))
+    }
+
+    fn new(db: &Arc<Database>, listener: Listener) -> Self {
+        Server {
+            db: Arc::clone(db),
+            listener,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            shutdown: ShutdownToken::default(),
+        }
+    }
+
+    // Sets how many clients may be connected at once
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    // Retrieves the address the server listens on, with the port picked for port 0
+    pub fn get_address(&self) -> String {
+        match &self.listener {
+            Listener::Tcp(listener) => listener
+                .local_addr()
+                .map_or_else(|e| e.to_string(), |address| address.to_string()),
+            Listener::Unix(_, path) => path.display().to_string(),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn get_shutdown_token(&self) -> ShutdownToken {
+        self.shutdown.clone()
+    }
+
+    // Accepts connections until the server shuts down, then waits for the sessions to
+    // close
+    pub fn run(self) -> Result<(), String> {
+        let result = self.accept_connections();
+        if let Listener::Unix(_, path) = &self.listener {
+            let _ = std::fs::remove_file(path);
+        }
+        result
+    }
+
+    fn accept_connections(&self) -> Result<(), String> {
+        let error = |e: io::Error| format!("Cannot accept connections: {}", e);
+        match &self.listener {
+            Listener::Tcp(listener) => listener.set_nonblocking(true),
+            Listener::Unix(listener, _) => listener.set_nonblocking(true),
+        }
+        .map_err(error)?;
+
+        let num_connections = Arc::new(AtomicUsize::new(0));
+        let mut sessions: Vec<JoinHandle<()>> = vec![];
+        let mut next_session_id = 0;
+        while !self.shutdown.is_shut_down() {
+            let accepted = match &self.listener {
+                Listener::Tcp(listener) => listener.accept().map(|(s, _)| Connection::Tcp(s)),
+                Listener::Unix(listener, _) => listener.accept().map(|(s, _)| Connection::Unix(s)),
+            };
+            let mut connection = match accepted {
+                Ok(connection) => connection,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.shutdown.shutdown();
+                    join_sessions(sessions);
+                    return Err(error(e));
+                }
+            };
+            sessions.retain(|session| !session.is_finished());
+            if num_connections.load(Ordering::SeqCst) >= self.max_connections {
+                let _ = writeln!(connection, "{} too many connections", protocol::ERROR);
+                connection.close();
+                continue;
+            }
+            let slot = ConnectionSlot::take(&num_connections);
+            let mut session = Session {
+                id: next_session_id,
+                db: Arc::clone(&self.db),
+                txn: None,
+                shutdown: self.shutdown.clone(),
+            };
+            next_session_id += 1;
+            sessions.push(thread::spawn(move || {
+                let _slot = slot;
+                session.serve(connection);
+            }));
+        }
+        join_sessions(sessions);
+        Ok(())
+    }
+}
+
+// Counts a connection against the maximum until it is dropped, which also happens when its
+// session panics
+struct ConnectionSlot {
+    num_connections: Arc<AtomicUsize>,
+}
+
+impl ConnectionSlot {
+    fn take(num_connections: &Arc<AtomicUsize>) -> Self {
+        num_connections.fetch_add(1, Ordering::SeqCst);
+        ConnectionSlot {
+            num_connections: Arc::clone(num_connections),
+        }
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.num_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn join_sessions(sessions: Vec<JoinHandle<()>>) {
+    for session in sessions {
+        let _ = session.join();
+    }
+}
+
+// One client connection and the transaction it has open
+struct Session {
+    id: usize,
+    db: Arc<Database>,
+    // transaction started with BEGIN, None while statements commit on their own
+    txn: Option<Transaction>,
+    shutdown: ShutdownToken,
+}
+
+impl Session {
+    // Answers the statements of the client until it disconnects or the server shuts down.
+    // An open transaction is rolled back when the session ends
+    fn serve(&mut self, connection: Connection) {
+        let Ok(writer) = connection.try_clone() else {
+            return;
+        };
+        let mut writer = BufWriter::new(writer);
+        if connection.set_read_timeout(POLL_INTERVAL).is_err()
+            || writeln!(writer, "{}", protocol::READY)
+                .and_then(|_| writer.flush())
+                .is_err()
+        {
+            return;
+        }
+        let mut reader = BufReader::new(connection);
+        let mut line = vec![];
+        while !self.shutdown.is_shut_down() {
+            match reader.read_until(b'\n', &mut line) {
+                // the client disconnected
+                Ok(0) => break,
+                Ok(_) if line.ends_with(b"\n") => {}
+                // the client disconnected in the middle of a statement
+                Ok(_) => break,
+                // keep the part of the line read so far and check for a shutdown
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    continue
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+            let reply = String::from_utf8(std::mem::take(&mut line))
+                .map_err(|_| "Statement is not valid UTF-8".to_string())
+                .and_then(|text| protocol::unescape(text.trim_end_matches(['\r', '\n'])))
+                .and_then(|text| self.run(&text, &mut writer));
+            let written = match reply {
+                Ok(summary) => writeln!(writer, "{} {}", protocol::OK, summary),
+                Err(e) => writeln!(writer, "{} {}", protocol::ERROR, protocol::escape(&e)),
+            };
+            if written.and_then(|_| writer.flush()).is_err() {
+                break;
+            }
+        }
+        let _ = self.end_transaction(false);
+        if self.shutdown.is_shut_down() {
+            let _ = writeln!(writer, "{} server shutting down", protocol::ERROR)
+                .and_then(|_| writer.flush());
+        }
+        reader.get_ref().close();
+    }
+
+    // Runs a statement and returns the summary of the OK reply. Rows of queries are
+    // written before it
+    fn run(&mut self, text: &str, writer: &mut dyn Write) -> Result<String, String> {
+        match sql::parse(text)? {
+            Statement::Begin(isolation) => {
+                if self.txn.is_some() {
+                    return Err("A transaction is already open".to_string());
+                }
+                let txn = Transaction::builder()
+                    .database(&self.db)
+                    .isolation(isolation)
+                    .begin();
+                self.shutdown.register(self.id, &txn);
+                self.txn = Some(txn);
+                Ok("BEGIN".to_string())
+            }
+            Statement::Commit => self.end_transaction(true).map(|_| "COMMIT".to_string()),
+            Statement::Rollback => self.end_transaction(false).map(|_| "ROLLBACK".to_string()),
+            Statement::Savepoint(name) => self
+                .get_transaction()?
+                .savepoint(&name)
+                .map(|_| "SAVEPOINT".to_string()),
+            Statement::RollbackTo(name) => self
+                .get_transaction()?
+                .rollback_to(&name)
+                .map(|_| "ROLLBACK".to_string()),
+            Statement::Release(name) => self
+                .get_transaction()?
+                .release(&name)
+                .map(|_| "RELEASE".to_string()),
+            // like SHUTDOWN, KILL acts on other sessions and runs outside of any transaction
+            Statement::Kill(tid) => {
+                let bp = self.db.get_buffer_pool();
+                let info = bp
+                    .get_transaction_snapshot()
+                    .into_iter()
+                    .find(|info| info.get_tid().get_tid() == tid)
+                    .ok_or(format!("No running transaction {}", tid))?;
+                bp.kill_transaction(info.get_tid())
+                    .map(|_| "KILL".to_string())
+            }
+            Statement::Shutdown => {
+                self.shutdown.shutdown();
+                Ok("SHUTDOWN".to_string())
+            }
+            // like other DDL, tables are created at once and outside of any transaction
+            Statement::CreateTable(schema) => self
+                .db
+                .get_catalog()
+                .create_table(&schema)
+                .map(|_| "CREATE TABLE".to_string()),
+            statement => match &self.txn {
+                Some(txn) => match execute(&self.db, statement, txn, writer) {
+                    Ok(summary) => Ok(summary),
+                    // a failed statement rolls back its transaction, whose locks and
+                    // changes may be left half done
+                    Err(e) => {
+                        self.end_transaction(false)?;
+                        Err(format!("{}, transaction rolled back", e))
+                    }
+                },
+                None => {
+                    let txn = Transaction::builder().database(&self.db).begin();
+                    self.shutdown.register(self.id, &txn);
+                    let result = execute(&self.db, statement, &txn, writer);
+                    self.shutdown.unregister(self.id);
+                    match result {
+                        Ok(summary) => txn.commit().map(|_| summary),
+                        Err(e) => {
+                            drop(txn);
+                            Err(e)
+                        }
+                    }
+                }
+            },
+        }
+    }
+
+    // Retrieves the transaction started with BEGIN, which savepoints belong to
+    fn get_transaction(&self) -> Result<&Transaction, String> {
+        self.txn
+            .as_ref()
+            .ok_or("No transaction is open".to_string())
+    }
+
+    // Commits or rolls back the transaction started with BEGIN
+    fn end_transaction(&mut self, commit: bool) -> Result<(), String> {
+        let txn = self.txn.take().ok_or("No transaction is open")?;
+        self.shutdown.unregister(self.id);
+        if commit {
+            txn.commit()
+        } else if txn.is_active() {
+            txn.rollback()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+// Runs a statement that reads or writes rows in the transaction. Table operations panic
+// when the transaction is interrupted, for example by a deadlock or a shutdown, so panics
+// are turned into errors
+fn execute(
+    db: &Arc<Database>,
+    statement: Statement,
+    txn: &Transaction,
+    writer: &mut dyn Write,
+) -> Result<String, String> {
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        execute_statement(db, statement, txn, writer)
+    }))
+    .unwrap_or_else(|payload| {
+        Err(match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => payload
+                .downcast_ref::<&str>()
+                .map_or("Statement failed".to_string(), |message| {
+                    message.to_string()
+                }),
+        })
+    })
+}
+
+fn execute_statement(
+    db: &Arc<Database>,
+    statement: Statement,
+    txn: &Transaction,
+    writer: &mut dyn Write,
+) -> Result<String, String> {
+    match statement {
+        Statement::Insert { table_name, values } => {
+            let table = Table::open(db, &table_name)?;
+            let td = table.get_tuple_desc();
+            if values.len() != td.get_num_fields() {
+                return Err(format!("Expected {} values", td.get_num_fields()));
+            }
+            let fields = values
+                .iter()
+                .enumerate()
+                .map(|(i, value)| {
+                    value
+                        .to_field(td.get_field_type(i).unwrap())
+                        .map_err(|e| format!("{}: {}", td.get_field_name(i).unwrap(), e))
+                })
+                .collect::<Result<Vec<FieldVal>, String>>()?;
+            table.insert_tuple(Tuple::new(fields, td), txn);
+            Ok("INSERT 1".to_string())
+        }
+        Statement::Select {
+            table_name,
+            columns,
+            condition,
+            limit,
+        } => {
+            let mut table = None;
+            let scan = sql::scan_table(db, &table_name, &mut table, txn)?;
+            let mut query = sql::plan_select(scan, columns, condition)?;
+            query.fetch()?;
+            stream_rows(
+                &query.get_tuple_desc(),
+                query.take(limit.unwrap_or(usize::MAX)),
+                writer,
+            )
+            .map(|num_rows| format!("SELECT {}", num_rows))
+            .map_err(|e| format!("Cannot send rows: {}", e))
+        }
+        Statement::Update {
+            table_name,
+            assignments,
+            condition,
+        } => {
+            let table = Table::open(db, &table_name)?;
+            let td = table.get_tuple_desc();
+            sql::field_index(td, condition.get_field_name())?;
+            let mut fields = vec![];
+            for (name, value) in assignments {
+                let field_type = td.get_field_type(sql::field_index(td, &name)?).unwrap();
+                let field = value
+                    .to_field(field_type)
+                    .map_err(|e| format!("{}: {}", name, e))?;
+                fields.push((name, field));
+            }
+            table
+                .update_where(
+                    condition.get_field_name(),
+                    condition.get_predicate().clone(),
+                    fields,
+                    txn,
+                )
+                .map(|num_rows| format!("UPDATE {}", num_rows))
+        }
+        Statement::Delete {
+            table_name,
+            condition,
+        } => {
+            let table = Table::open(db, &table_name)?;
+            sql::field_index(table.get_tuple_desc(), condition.get_field_name())?;
+            table
+                .delete_where(
+                    condition.get_field_name(),
+                    condition.get_predicate().clone(),
+                    txn,
+                )
+                .map(|num_rows| format!("DELETE {}", num_rows))
+        }
+        Statement::Explain { analyze, query } => {
+            let lines = sql::explain(db, *query, analyze, txn)?;
+            let td = TupleDesc::new(vec![Type::StringType], vec!["QUERY PLAN".to_string()]);
+            let tuples = lines.into_iter().map(|line| {
+                let length = line.len() as u32;
+                Tuple::new(
+                    vec![FieldVal::StringField(StringField::new(line, length))],
+                    &td,
+                )
+            });
+            stream_rows(&td, tuples, writer)
+                .map(|_| "EXPLAIN".to_string())
+                .map_err(|e| format!("Cannot send rows: {}", e))
+        }
+        statement => Err(format!("{:?} does not run in a transaction", statement)),
+    }
+}
+
+// Writes the COLUMNS line and a ROW line per tuple, returning the number of rows
+fn stream_rows(
+    td: &TupleDesc,
+    tuples: impl Iterator<Item = Tuple>,
+    writer: &mut dyn Write,
+) -> io::Result<usize> {
+    let columns: Vec<Column> = (0..td.get_num_fields())
+        .map(|i| {
+            Column::new(
+                td.get_field_name(i).unwrap(),
+                td.get_field_type(i).unwrap().get_name(),
+            )
+        })
+        .collect();
+    writeln!(
+        writer,
+        "{} {}",
+        protocol::COLUMNS,
+        protocol::encode_columns(&columns)
+    )?;
+    let mut num_rows = 0;
+    for tuple in tuples {
+        let values: Vec<Value> = tuple
+            .get_fields()
+            .iter()
+            .map(|field| match field {
+                FieldVal::IntField(field) => Value::Int(field.get_value()),
+                FieldVal::StringField(field) => Value::String(field.get_value()),
+            })
+            .collect();
+        writeln!(
+            writer,
+            "{} {}",
+            protocol::ROW,
+            protocol::encode_row(&values)
+        )?;
+        num_rows += 1;
+    }
+    Ok(num_rows)
+}
@@ -0,0 +1,604 @@
+use crate::database::Database;
+use crate::fields::{FieldVal, IntField};
+use crate::system_table;
+use crate::table::{self, Predicate, Table, TableIterator};
+use crate::transaction::{IsolationLevel, Transaction};
+use crate::tuple::TupleDesc;
+use crate::types::Type;
+use std::sync::Arc;
+
+// A literal value in a statement
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Int(i32),
+    String(String),
+}
+
+impl Literal {
+    // Converts the literal to a field of the type, failing if the types differ
+    pub fn to_field(&self, field_type: &Type) -> Result<FieldVal, String> {
+        match (self, field_type) {
+            (Literal::Int(value), Type::IntType) => Ok(FieldVal::IntField(IntField::new(*value))),
+            (Literal::String(value), Type::StringType) => field_type.coerce(value),
+            (Literal::Int(value), Type::StringType) => {
+                Err(format!("expected a quoted String but found {}", value))
+            }
+            (Literal::String(value), Type::IntType) => {
+                Err(format!("expected an Int but found '{}'", value))
+            }
+        }
+    }
+}
+
+// A condition on one field, as in WHERE id > 3
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    field_name: String,
+    predicate: Predicate,
+}
+
+impl Condition {
+    pub fn get_field_name(&self) -> &str {
+        &self.field_name
+    }
+
+    pub fn get_predicate(&self) -> &Predicate {
+        &self.predicate
+    }
+}
+
+// A statement of the small SQL dialect the server accepts
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    Begin(IsolationLevel),
+    Commit,
+    Rollback,
+    // SAVEPOINT, ROLLBACK TO SAVEPOINT and RELEASE SAVEPOINT with the savepoint name
+    Savepoint(String),
+    RollbackTo(String),
+    Release(String),
+    // CREATE TABLE people (id: Int, name: String), with the schema line of the catalog
+    CreateTable(String),
+    Insert {
+        table_name: String,
+        values: Vec<Literal>,
+    },
+    Select {
+        table_name: String,
+        // None for *
+        columns: Option<Vec<String>>,
+        condition: Option<Condition>,
+        limit: Option<usize>,
+    },
+    Update {
+        table_name: String,
+        assignments: Vec<(String, Literal)>,
+        condition: Condition,
+    },
+    Delete {
+        table_name: String,
+        condition: Condition,
+    },
+    // EXPLAIN [ANALYZE] of a SELECT
+    Explain {
+        analyze: bool,
+        query: Box<Statement>,
+    },
+    // cancels the running transaction with the id, as listed in __transactions
+    Kill(u64),
+    // stops the server, rolling back the transactions of all sessions
+    Shutdown,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Int(i32),
+    String(String),
+    Symbol(char),
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Word(word) => word.clone(),
+            Token::Int(value) => value.to_string(),
+            Token::String(value) => format!("'{}'", value),
+            Token::Symbol(c) => c.to_string(),
+        }
+    }
+}
+
+// Splits a statement into words, integers, quoted strings and symbols
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '\'' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\'') if chars.next_if_eq(&'\'').is_some() => value.push('\''),
+                        Some('\'') => break,
+                        Some(c) => value.push(c),
+                        None => return Err("Unclosed string".to_string()),
+                    }
+                }
+                tokens.push(Token::String(value));
+            }
+            c if c.is_ascii_digit()
+                || (c == '-' && chars.peek().is_some_and(char::is_ascii_digit)) =>
+            {
+                let mut digits = c.to_string();
+                digits.extend(std::iter::from_fn(|| chars.next_if(char::is_ascii_digit)));
+                let value = digits
+                    .parse()
+                    .map_err(|_| format!("{} is not a valid Int", digits))?;
+                tokens.push(Token::Int(value));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut word = c.to_string();
+                word.extend(std::iter::from_fn(|| {
+                    chars.next_if(|c| c.is_alphanumeric() || *c == '_')
+                }));
+                tokens.push(Token::Word(word));
+            }
+            '(' | ')' | ',' | '*' | '=' | '<' | '>' | ':' | ';' => tokens.push(Token::Symbol(c)),
+            c => return Err(format!("Unexpected character {}", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+// Reads tokens from the front of a statement
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    // Consumes the keyword if it comes next, ignoring case
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword) => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), String> {
+        if self.keyword(keyword) {
+            return Ok(());
+        }
+        Err(format!(
+            "Expected {} but found {}",
+            keyword,
+            self.describe_next()
+        ))
+    }
+
+    fn symbol(&mut self, symbol: char) -> bool {
+        if self.peek() == Some(&Token::Symbol(symbol)) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect_symbol(&mut self, symbol: char) -> Result<(), String> {
+        if self.symbol(symbol) {
+            return Ok(());
+        }
+        Err(format!(
+            "Expected {} but found {}",
+            symbol,
+            self.describe_next()
+        ))
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Word(word)) => Ok(word),
+            token => Err(format!(
+                "Expected a name but found {}",
+                token.map_or("the end".to_string(), |token| token.describe())
+            )),
+        }
+    }
+
+    fn literal(&mut self) -> Result<Literal, String> {
+        match self.next() {
+            Some(Token::Int(value)) => Ok(Literal::Int(value)),
+            Some(Token::String(value)) => Ok(Literal::String(value)),
+            token => Err(format!(
+                "Expected a value but found {}",
+                token.map_or("the end".to_string(), |token| token.describe())
+            )),
+        }
+    }
+
+    fn describe_next(&self) -> String {
+        self.peek()
+            .map_or("the end".to_string(), |token| token.describe())
+    }
+
+    // Parses WHERE field = value, field > value or field < value
+    fn condition(&mut self) -> Result<Condition, String> {
+        let field_name = self.name()?;
+        let operator = match self.next() {
+            Some(Token::Symbol(c)) if matches!(c, '=' | '<' | '>') => c,
+            token => {
+                return Err(format!(
+                    "Expected =, < or > but found {}",
+                    token.map_or("the end".to_string(), |token| token.describe())
+                ))
+            }
+        };
+        let predicate = match (operator, self.literal()?) {
+            ('=', Literal::Int(value)) => Predicate::EqualsInt(value),
+            ('=', Literal::String(value)) => Predicate::Equals(value),
+            ('>', Literal::Int(value)) => Predicate::GreaterThan(value),
+            (operator, Literal::String(_)) => {
+                return Err(format!(
+                    "Strings can only be compared with =, not {}",
+                    operator
+                ))
+            }
+            (_, Literal::Int(value)) => Predicate::LessThan(value),
+        };
+        Ok(Condition {
+            field_name,
+            predicate,
+        })
+    }
+}
+
+/**
+ * Parses a statement of the dialect the server accepts. Keywords are case insensitive and
+ * a trailing semicolon is allowed:
+ *
+ *   BEGIN [ISOLATION LEVEL READ UNCOMMITTED | READ COMMITTED | REPEATABLE READ | SERIALIZABLE]
+ *   COMMIT | ROLLBACK
+ *   SAVEPOINT batch | ROLLBACK TO [SAVEPOINT] batch | RELEASE [SAVEPOINT] batch
+ *   CREATE TABLE people (id: Int, name: String)
+ *   INSERT INTO people VALUES (1, 'Alice')
+ *   SELECT * | id, name FROM people [WHERE id > 3] [LIMIT 10]
+ *   SELECT * FROM __transactions, or another system table
+ *   UPDATE people SET name = 'Bob' WHERE id = 1
+ *   DELETE FROM people WHERE name = 'Bob'
+ *   EXPLAIN [ANALYZE] SELECT ...
+ *   KILL 42
+ *   SHUTDOWN
+ *
+ * Conditions compare one field with =, < or >, where strings only support =.
+ */
+pub fn parse(text: &str) -> Result<Statement, String> {
+    parse_tokens(tokenize(text)?)
+}
+
+fn parse_tokens(tokens: Vec<Token>) -> Result<Statement, String> {
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+    };
+    let statement = if parser.keyword("EXPLAIN") {
+        let analyze = parser.keyword("ANALYZE");
+        let query = parse_tokens(parser.tokens.split_off(parser.position))?;
+        if !matches!(query, Statement::Select { .. }) {
+            return Err("Only SELECT can be explained".to_string());
+        }
+        Statement::Explain {
+            analyze,
+            query: Box::new(query),
+        }
+    } else if parser.keyword("BEGIN") {
+        let mut isolation = IsolationLevel::default();
+        if parser.keyword("ISOLATION") {
+            parser.expect_keyword("LEVEL")?;
+            isolation = if parser.keyword("SERIALIZABLE") {
+                IsolationLevel::Serializable
+            } else if parser.keyword("REPEATABLE") {
+                parser.expect_keyword("READ")?;
+                IsolationLevel::RepeatableRead
+            } else {
+                parser.expect_keyword("READ")?;
+                if parser.keyword("COMMITTED") {
+                    IsolationLevel::ReadCommitted
+                } else {
+                    parser.expect_keyword("UNCOMMITTED")?;
+                    IsolationLevel::ReadUncommitted
+                }
+            };
+        }
+        Statement::Begin(isolation)
+    } else if parser.keyword("COMMIT") {
+        Statement::Commit
+    } else if parser.keyword("ROLLBACK") {
+        if parser.keyword("TO") {
+            parser.keyword("SAVEPOINT");
+            Statement::RollbackTo(parser.name()?)
+        } else {
+            Statement::Rollback
+        }
+    } else if parser.keyword("SAVEPOINT") {
+        Statement::Savepoint(parser.name()?)
+    } else if parser.keyword("RELEASE") {
+        parser.keyword("SAVEPOINT");
+        Statement::Release(parser.name()?)
+    } else if parser.keyword("KILL") {
+        match parser.next() {
+            Some(Token::Int(tid)) if tid >= 0 => Statement::Kill(tid as u64),
+            _ => return Err("Expected a transaction id after KILL".to_string()),
+        }
+    } else if parser.keyword("SHUTDOWN") {
+        Statement::Shutdown
+    } else if parser.keyword("CREATE") {
+        parser.expect_keyword("TABLE")?;
+        let table_name = parser.name()?;
+        parser.expect_symbol('(')?;
+        let mut fields = vec![];
+        loop {
+            let field_name = parser.name()?;
+            parser.expect_symbol(':')?;
+            fields.push(format!("{}: {}", field_name, parser.name()?));
+            if !parser.symbol(',') {
+                break;
+            }
+        }
+        parser.expect_symbol(')')?;
+        Statement::CreateTable(format!("{} ({})", table_name, fields.join(", ")))
+    } else if parser.keyword("INSERT") {
+        parser.expect_keyword("INTO")?;
+        let table_name = parser.name()?;
+        parser.expect_keyword("VALUES")?;
+        parser.expect_symbol('(')?;
+        let mut values = vec![parser.literal()?];
+        while parser.symbol(',') {
+            values.push(parser.literal()?);
+        }
+        parser.expect_symbol(')')?;
+        Statement::Insert { table_name, values }
+    } else if parser.keyword("SELECT") {
+        let columns = if parser.symbol('*') {
+            None
+        } else {
+            let mut columns = vec![parser.name()?];
+            while parser.symbol(',') {
+                columns.push(parser.name()?);
+            }
+            Some(columns)
+        };
+        parser.expect_keyword("FROM")?;
+        let table_name = parser.name()?;
+        let condition = match parser.keyword("WHERE") {
+            true => Some(parser.condition()?),
+            false => None,
+        };
+        let limit = if parser.keyword("LIMIT") {
+            match parser.next() {
+                Some(Token::Int(limit)) if limit >= 0 => Some(limit as usize),
+                _ => return Err("Expected a number of rows after LIMIT".to_string()),
+            }
+        } else {
+            None
+        };
+        Statement::Select {
+            table_name,
+            columns,
+            condition,
+            limit,
+        }
+    } else if parser.keyword("UPDATE") {
+        let table_name = parser.name()?;
+        parser.expect_keyword("SET")?;
+        let mut assignments = vec![];
+        loop {
+            let field_name = parser.name()?;
+            parser.expect_symbol('=')?;
+            assignments.push((field_name, parser.literal()?));
+            if !parser.symbol(',') {
+                break;
+            }
+        }
+        parser.expect_keyword("WHERE")?;
+        Statement::Update {
+            table_name,
+            assignments,
+            condition: parser.condition()?,
+        }
+    } else if parser.keyword("DELETE") {
+        parser.expect_keyword("FROM")?;
+        let table_name = parser.name()?;
+        parser.expect_keyword("WHERE")?;
+        Statement::Delete {
+            table_name,
+            condition: parser.condition()?,
+        }
+    } else {
+        return Err(format!("Unknown statement {}", parser.describe_next()));
+    };
+    parser.symbol(';');
+    if parser.peek().is_some() {
+        return Err(format!("Unexpected {} at the end", parser.describe_next()));
+    }
+    Ok(statement)
+}
+
+// Scans the table a SELECT names, which may be a system table. A stored table is opened
+// into the slot, which the scan borrows
+pub fn scan_table<'a>(
+    db: &Arc<Database>,
+    table_name: &str,
+    table: &'a mut Option<Table>,
+    txn: &Transaction,
+) -> Result<TableIterator<'a>, String> {
+    if system_table::is_system_table(table_name) {
+        return table::scan_system_table(table_name, usize::MAX, txn);
+    }
+    let table = table.insert(Table::open(db, table_name)?);
+    Ok(table.scan(usize::MAX, txn))
+}
+
+// Builds the query of a SELECT on top of the scan, checking that the fields it names exist.
+// The LIMIT is left to the caller, since it applies to the rows after the filter
+pub fn plan_select<'a>(
+    mut query: TableIterator<'a>,
+    columns: Option<Vec<String>>,
+    condition: Option<Condition>,
+) -> Result<TableIterator<'a>, String> {
+    let td = &query.get_tuple_desc();
+    if let Some(condition) = condition {
+        field_index(td, condition.get_field_name())?;
+        query.table_filter(
+            condition.get_field_name(),
+            condition.get_predicate().clone(),
+        );
+    }
+    if let Some(columns) = columns {
+        for (i, column) in columns.iter().enumerate() {
+            field_index(td, column)?;
+            if columns[..i].contains(column) {
+                return Err(format!("Column {} is selected twice", column));
+            }
+        }
+        query = query.project(columns);
+    }
+    Ok(query)
+}
+
+// Describes the plan of an EXPLAIN [ANALYZE] statement one line per operator. With
+// ANALYZE the query runs in the transaction and the lines hold what each operator did
+pub fn explain(
+    db: &Arc<Database>,
+    query: Statement,
+    analyze: bool,
+    txn: &Transaction,
+) -> Result<Vec<String>, String> {
+    let Statement::Select {
+        table_name,
+        columns,
+        condition,
+        ..
+    } = query
+    else {
+        return Err("Only SELECT can be explained".to_string());
+    };
+    let mut table = None;
+    let scan = scan_table(db, &table_name, &mut table, txn)?;
+    let mut query = plan_select(scan, columns, condition)?;
+    let plan = match analyze {
+        true => query.explain_analyze()?,
+        false => query.explain(),
+    };
+    Ok(plan.lines().map(str::to_string).collect())
+}
+
+// Finds the position of the named field in the tuples
+pub fn field_index(td: &TupleDesc, field_name: &str) -> Result<usize, String> {
+    td.name_to_id(field_name)
+        .ok_or(format!("Unknown field {}", field_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("select id, name from people where name = 'O''Brien' limit 5;").unwrap(),
+            Statement::Select {
+                table_name: "people".to_string(),
+                columns: Some(vec!["id".to_string(), "name".to_string()]),
+                condition: Some(Condition {
+                    field_name: "name".to_string(),
+                    predicate: Predicate::Equals("O'Brien".to_string()),
+                }),
+                limit: Some(5),
+            }
+        );
+        assert_eq!(
+            parse("INSERT INTO people VALUES (-1, 'a, b')").unwrap(),
+            Statement::Insert {
+                table_name: "people".to_string(),
+                values: vec![Literal::Int(-1), Literal::String("a, b".to_string())],
+            }
+        );
+        assert_eq!(
+            parse("UPDATE people SET name = 'x' WHERE id > 3").unwrap(),
+            Statement::Update {
+                table_name: "people".to_string(),
+                assignments: vec![("name".to_string(), Literal::String("x".to_string()))],
+                condition: Condition {
+                    field_name: "id".to_string(),
+                    predicate: Predicate::GreaterThan(3),
+                },
+            }
+        );
+        assert_eq!(
+            parse("CREATE TABLE t (id:Int,name : String)").unwrap(),
+            Statement::CreateTable("t (id: Int, name: String)".to_string())
+        );
+        assert_eq!(
+            parse("begin isolation level read committed").unwrap(),
+            Statement::Begin(IsolationLevel::ReadCommitted)
+        );
+        assert_eq!(
+            parse("rollback to savepoint batch").unwrap(),
+            Statement::RollbackTo("batch".to_string())
+        );
+        assert_eq!(parse("kill 42").unwrap(), Statement::Kill(42));
+        assert_eq!(
+            parse("RELEASE batch;").unwrap(),
+            Statement::Release("batch".to_string())
+        );
+
+        assert_eq!(
+            parse("EXPLAIN ANALYZE SELECT * FROM people").unwrap(),
+            Statement::Explain {
+                analyze: true,
+                query: Box::new(Statement::Select {
+                    table_name: "people".to_string(),
+                    columns: None,
+                    condition: None,
+                    limit: None,
+                }),
+            }
+        );
+
+        let errors = [
+            ("DROP TABLE t", "Unknown statement DROP"),
+            (
+                "EXPLAIN DELETE FROM t WHERE id = 1",
+                "Only SELECT can be explained",
+            ),
+            ("SELECT * people", "Expected FROM but found people"),
+            ("SAVEPOINT", "Expected a name but found the end"),
+            ("KILL -1", "Expected a transaction id after KILL"),
+            ("DELETE FROM t", "Expected WHERE but found the end"),
+            (
+                "SELECT * FROM t WHERE name > 'a'",
+                "Strings can only be compared with =, not >",
+            ),
+            ("SELECT * FROM t x", "Unexpected x at the end"),
+            ("INSERT INTO t VALUES ('a)", "Unclosed string"),
+        ];
+        for (text, error) in errors {
+            assert_eq!(parse(text).unwrap_err(), error);
+        }
+    }
+}
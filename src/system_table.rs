@@ -0,0 +1,199 @@
+use crate::database::Database;
+use crate::fields::{FieldVal, IntField, StringField};
+use crate::lock_manager::{LockMode, LockRequest, LockTarget};
+use crate::transaction::{IsolationLevel, TransactionId};
+use crate::tuple::{Tuple, TupleDesc};
+use crate::types::Type::{IntType, StringType};
+use std::time::SystemTime;
+
+// Locks held and waited for by transactions, one row per lock or waiting request
+pub const LOCKS: &str = "__locks";
+// Running transactions with their age and state
+pub const TRANSACTIONS: &str = "__transactions";
+// Most recent transactions aborted by the lock manager, oldest first
+pub const LOCK_ABORTS: &str = "__lock_aborts";
+
+// Checks whether the name refers to a system table rather than a stored table
+pub fn is_system_table(name: &str) -> bool {
+    get_tuple_desc(name).is_some()
+}
+
+// Retrieves the columns of the system table
+pub fn get_tuple_desc(name: &str) -> Option<TupleDesc> {
+    let (types, names) = match name {
+        LOCKS => (
+            vec![
+                IntType, StringType, StringType, StringType, StringType, StringType,
+            ],
+            vec!["tid", "table", "target", "mode", "status", "blockers"],
+        ),
+        TRANSACTIONS => (
+            vec![
+                IntType, StringType, IntType, StringType, IntType, StringType,
+            ],
+            vec![
+                "tid",
+                "isolation",
+                "age_ms",
+                "state",
+                "locks",
+                "waiting_for",
+            ],
+        ),
+        LOCK_ABORTS => (
+            vec![IntType, StringType, StringType, IntType],
+            vec!["tid", "reason", "blockers", "age_ms"],
+        ),
+        _ => return None,
+    };
+    Some(TupleDesc::new(
+        types,
+        names.into_iter().map(|name| name.to_string()).collect(),
+    ))
+}
+
+// Builds the current rows of the system table from the state of the lock manager and
+// the running transactions
+pub fn scan(db: &Database, name: &str) -> Result<Vec<Tuple>, String> {
+    let td = get_tuple_desc(name).ok_or(format!("No system table named {}", name))?;
+    let bp = db.get_buffer_pool();
+    let lock_manager = bp.get_lock_manager();
+    let rows: Vec<Vec<FieldVal>> = match name {
+        LOCKS => lock_manager
+            .get_lock_snapshot()
+            .iter()
+            .map(|info| {
+                let request = info.get_request();
+                let status = if info.is_granted() {
+                    "granted"
+                } else {
+                    "waiting"
+                };
+                vec![
+                    int(info.get_tid().get_tid() as i64),
+                    string(table_name(db, request.get_table_id())),
+                    string(describe_target(request)),
+                    string(describe_mode(request).to_string()),
+                    string(status.to_string()),
+                    string(describe_tids(info.get_blockers())),
+                ]
+            })
+            .collect(),
+        TRANSACTIONS => bp
+            .get_transaction_snapshot()
+            .iter()
+            .map(|info| {
+                let state = if info.get_wounded().is_some() {
+                    "aborting"
+                } else if info.get_waiting().is_some() {
+                    "waiting"
+                } else {
+                    "active"
+                };
+                let waiting_for = info.get_waiting().map_or(String::new(), |request| {
+                    format!(
+                        "{} {} on {}",
+                        describe_mode(request),
+                        describe_target(request),
+                        table_name(db, request.get_table_id())
+                    )
+                });
+                vec![
+                    int(info.get_tid().get_tid() as i64),
+                    string(describe_isolation(info.get_isolation()).to_string()),
+                    int(info.get_age().as_millis() as i64),
+                    string(state.to_string()),
+                    int(info.get_num_locks() as i64),
+                    string(waiting_for),
+                ]
+            })
+            .collect(),
+        _ => lock_manager
+            .get_abort_history()
+            .iter()
+            .map(|record| {
+                let age = SystemTime::now()
+                    .duration_since(record.get_time())
+                    .unwrap_or_default();
+                vec![
+                    int(record.get_tid().get_tid() as i64),
+                    string(record.get_reason().describe().to_string()),
+                    string(describe_tids(record.get_blockers())),
+                    int(age.as_millis() as i64),
+                ]
+            })
+            .collect(),
+    };
+    Ok(rows
+        .into_iter()
+        .map(|fields| Tuple::new(fields, &td))
+        .collect())
+}
+
+fn int(value: i64) -> FieldVal {
+    FieldVal::IntField(IntField::new(
+        value.clamp(i32::MIN as i64, i32::MAX as i64) as i32
+    ))
+}
+
+fn string(value: String) -> FieldVal {
+    let len = value.len() as u32;
+    FieldVal::StringField(StringField::new(value, len))
+}
+
+// Name of the table with the id, or the id itself if it is not in the catalog
+fn table_name(db: &Database, table_id: usize) -> String {
+    db.get_catalog()
+        .get_table_name(table_id)
+        .unwrap_or_else(|| table_id.to_string())
+}
+
+fn describe_target(request: &LockRequest) -> String {
+    match request {
+        LockRequest::Lock(LockTarget::Table(_), _) => "table".to_string(),
+        LockRequest::Lock(LockTarget::Row(rid), _) => format!(
+            "row {}:{}",
+            rid.get_page_id().get_page_number(),
+            rid.get_tuple_no()
+        ),
+        LockRequest::Predicate(predicate) if predicate.get_filters().is_empty() => {
+            "predicate (all rows)".to_string()
+        }
+        LockRequest::Predicate(predicate) => format!(
+            "predicate ({})",
+            predicate
+                .get_filters()
+                .iter()
+                .map(|(field_name, predicate)| predicate.describe(field_name))
+                .collect::<Vec<_>>()
+                .join(" AND ")
+        ),
+        LockRequest::PredicateWrite(_) => "predicate write".to_string(),
+    }
+}
+
+fn describe_mode(request: &LockRequest) -> &'static str {
+    match request {
+        LockRequest::Lock(_, LockMode::IntentionShared) => "IS",
+        LockRequest::Lock(_, LockMode::IntentionExclusive) => "IX",
+        LockRequest::Lock(_, LockMode::Shared) | LockRequest::Predicate(_) => "S",
+        LockRequest::Lock(_, LockMode::SharedIntentionExclusive) => "SIX",
+        LockRequest::Lock(_, LockMode::Exclusive) | LockRequest::PredicateWrite(_) => "X",
+    }
+}
+
+fn describe_isolation(isolation: IsolationLevel) -> &'static str {
+    match isolation {
+        IsolationLevel::ReadUncommitted => "READ UNCOMMITTED",
+        IsolationLevel::ReadCommitted => "READ COMMITTED",
+        IsolationLevel::RepeatableRead => "REPEATABLE READ",
+        IsolationLevel::Serializable => "SERIALIZABLE",
+    }
+}
+
+fn describe_tids(tids: &[TransactionId]) -> String {
+    tids.iter()
+        .map(|tid| tid.get_tid().to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
@@ -1,13 +1,16 @@
-use crate::database; // Import the `database` module or crate
+use crate::database::{self, Database}; // Import the `database` module or crate
 use crate::fields::FieldVal;
 use crate::heap_file::HeapFile;
-use crate::transaction::TransactionId; // Import the `transaction` module or crate
+use crate::plan::PlanNode;
+use crate::system_table;
+use crate::transaction::{Transaction, TransactionId}; // Import the `transaction` module or crate
 use crate::tuple; // Import the `tuple` module or crate
 use crate::tuple::Tuple;
 use crate::tuple::TupleDesc;
 use std::sync::Arc;
 
 pub struct Table {
+    db: Arc<Database>,
     name: String,
     heap_file: Arc<HeapFile>,
     table_id: usize,
@@ -15,208 +18,333 @@ pub struct Table {
 }
 
 impl Table {
-    pub fn new(name: String, schema: String) -> Self {
-        let db = database::get_global_db();
-        let catalog = db.get_catalog();
-
-        // use the path given in schema to load the schema - should maybe do it differently
-        let mut schema_file_path = std::env::current_dir().unwrap();
-        schema_file_path.push(schema);
-
-        let heap_file = catalog.get_table_from_name(&name).unwrap();
-        let table_id = heap_file.get_id();
+    // Opens a table of the global database. The schema is taken from its catalog, so the
+    // schema file name is unused
+    #[allow(dead_code)]
+    pub fn new(name: String, _schema: String) -> Self {
+        Table::open(&database::get_global_db(), &name).unwrap()
+    }
 
-        Table {
-            name,
+    // Opens a table of the database, whose schema must already be loaded into its catalog
+    pub fn open(db: &Arc<Database>, name: &str) -> Result<Self, String> {
+        let heap_file = db
+            .get_catalog()
+            .get_table_from_name(name)
+            .ok_or(format!("No table named {}", name))?;
+        Ok(Table {
+            db: Arc::clone(db),
+            name: name.to_string(),
             tuple_desc: heap_file.get_tuple_desc().clone(),
+            table_id: heap_file.get_id(),
             heap_file,
-            table_id,
+        })
+    }
+
+    // Inserts the tuple. Panics if the transaction was interrupted or the page it goes to
+    // is corrupted
+    pub fn insert_tuple(&self, tuple: Tuple, txn: &Transaction) {
+        run_or_panic(txn, |tid| self.heap_file.add_tuple(tid, tuple))
+            .unwrap_or_else(|error| panic!("{}", error));
+    }
+
+    pub fn insert_many_tuples(&self, tuples: Vec<Tuple>, txn: &Transaction) {
+        run_or_panic(txn, |tid| {
+            tuples
+                .into_iter()
+                .try_for_each(|tuple| self.heap_file.add_tuple(tid, tuple))
+        })
+        .unwrap_or_else(|error| panic!("{}", error));
+    }
+
+    // Sets the assigned fields on every tuple where field_name satisfies the predicate and
+    // returns the number of tuples updated. Updated tuples keep their record ids
+    pub fn update_where(
+        &self,
+        field_name: &str,
+        predicate: Predicate,
+        assignments: Vec<(String, FieldVal)>,
+        txn: &Transaction,
+    ) -> Result<usize, String> {
+        // resolve the assigned columns before touching any pages
+        let mut resolved = Vec::new();
+        for (name, value) in assignments {
+            let i = self
+                .tuple_desc
+                .name_to_id(&name)
+                .ok_or(format!("Unknown field {}", name))?;
+            if self.tuple_desc.get_field_type(i) != Some(&value.get_type()) {
+                return Err(format!("Type mismatch for field {}", name));
+            }
+            resolved.push((i, value));
         }
+
+        txn.run_statement(|tid| {
+            let matches = self.find_where(field_name, &predicate, tid)?;
+            let count = matches.len();
+            for mut tuple in matches {
+                for (i, value) in resolved.iter() {
+                    tuple.set_field(*i, value.clone());
+                }
+                self.heap_file.update_tuple(tid, tuple)?;
+            }
+            Ok(count)
+        })?
     }
 
-    pub fn insert_tuple(&self, tuple: Tuple, tid: TransactionId) {
-        self.heap_file.add_tuple(tid, tuple);
+    // Deletes every tuple where field_name satisfies the predicate and returns the number
+    // of tuples deleted
+    pub fn delete_where(
+        &self,
+        field_name: &str,
+        predicate: Predicate,
+        txn: &Transaction,
+    ) -> Result<usize, String> {
+        txn.run_statement(|tid| {
+            let matches = self.find_where(field_name, &predicate, tid)?;
+            let count = matches.len();
+            for tuple in matches {
+                self.heap_file.delete_tuple(tid, tuple)?;
+            }
+            Ok(count)
+        })?
     }
 
-    pub fn insert_many_tuples(&self, tuples: Vec<Tuple>, tid: TransactionId) {
-        for tuple in tuples {
-            self.heap_file.add_tuple(tid, tuple);
+    // Collects the visible tuples where field_name satisfies the predicate. All matches are
+    // found before any is modified, so versions written by the statement are not revisited
+    fn find_where(
+        &self,
+        field_name: &str,
+        predicate: &Predicate,
+        tid: TransactionId,
+    ) -> Result<Vec<Tuple>, String> {
+        let bp = self.db.get_buffer_pool();
+        bp.lock_predicate(
+            tid,
+            self.table_id,
+            vec![(field_name.to_string(), predicate.clone())],
+        );
+        let snapshot = bp.get_snapshot(tid);
+        let mut matches = Vec::new();
+        for page in self.heap_file.iter(tid) {
+            bp.check_interrupt(tid)?;
+            let page = page?;
+            let page = page.read().unwrap();
+            matches.extend(
+                page.iter_visible(&snapshot)
+                    .filter(|tuple| tuple.filter(field_name, predicate))
+                    .cloned(),
+            );
         }
+        Ok(matches)
+    }
+
+    // Removes all tuples from the table under an exclusive table lock by swapping its file
+    // for an empty one. Aborting brings the old file back
+    #[allow(dead_code)]
+    pub fn truncate(&self, txn: &Transaction) {
+        let bp = self.db.get_buffer_pool();
+        run_or_panic(txn, |tid| bp.truncate_table(tid, self.table_id));
+    }
+
+    // Loads a large number of tuples onto fresh pages at the end of the table and returns
+    // the number of tuples loaded. The table is locked exclusively until the transaction ends
+    pub fn bulk_load<I>(&self, tuples: I, txn: &Transaction) -> usize
+    where
+        I: IntoIterator<Item = Tuple>,
+    {
+        run_or_panic(txn, |tid| self.heap_file.bulk_load(tid, tuples))
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
     }
 
     pub fn get_tuple_desc(&self) -> &TupleDesc {
         &self.tuple_desc
     }
 
+    // Retrieves the number of pages in the table's file, which is 0 for a new or
+    // truncated table
+    pub fn get_num_pages(&self) -> usize {
+        self.heap_file.num_pages()
+    }
+
+    #[allow(dead_code)]
     pub fn get_id(&self) -> usize {
         self.table_id
     }
 
-    pub fn print(&self) {
-        let db = database::get_global_db();
-        let tid = TransactionId::new();
+    #[allow(dead_code)]
+    pub fn get_db(&self) -> &Arc<Database> {
+        &self.db
+    }
+
+    pub fn print(&self, txn: &Transaction) {
+        let tid = txn.get_active_tid();
+        let snapshot = self.db.get_buffer_pool().get_snapshot(tid);
         for page in self.heap_file.iter(tid) {
+            let page = match page {
+                Ok(page) => page,
+                Err(error) => {
+                    println!("{}", error);
+                    break;
+                }
+            };
             let page = page.read().unwrap();
-            for (i, tuple) in page.iter().enumerate() {
+            for (i, tuple) in page.iter_visible(&snapshot).enumerate() {
                 println!("{}: {}", i, tuple);
             }
         }
-        let bp = db.get_buffer_pool();
-        bp.commit_transaction(tid);
     }
 
-    pub fn scan(&self, count: usize, tid: TransactionId) -> TableIterator {
-        TableIterator::new(self, tid, count)
+    pub fn scan(&self, count: usize, txn: &Transaction) -> TableIterator<'_> {
+        TableIterator::new(self, txn.get_active_tid(), count)
     }
 }
 
-// iterator iterates on a view generated from the heapfile -> quick fix to get the view working
-// and avoid iterating on the heapfile directly, which I cant get working properly
+// Runs a statement that has no way to report errors. Like a lock request that aborts, it
+// panics if the transaction was interrupted
+fn run_or_panic<T>(txn: &Transaction, statement: impl FnOnce(TransactionId) -> T) -> T {
+    txn.run_statement(statement)
+        .unwrap_or_else(|error| panic!("{}", error))
+}
+
+// Scans up to count rows of a system table such as __locks or __transactions, which
+// describe the current state of the database instead of stored data
+pub fn scan_system_table(
+    name: &str,
+    count: usize,
+    txn: &Transaction,
+) -> Result<TableIterator<'static>, String> {
+    if !system_table::is_system_table(name) {
+        return Err(format!("No system table named {}", name));
+    }
+    let plan = PlanNode::system_scan(name, count);
+    Ok(TableIterator::from_plan(
+        Arc::clone(txn.get_db()),
+        None,
+        txn.get_active_tid(),
+        plan,
+    ))
+}
+
+// iterator over the result of a query plan built from scans, projections, filters and joins.
+// the plan is only executed once the first tuple is requested
 pub struct TableIterator<'a> {
-    table: &'a Table,
+    db: Arc<Database>,
+    // None for system tables
+    table: Option<&'a Table>,
     current_page_index: usize,
     tid: TransactionId,
-    data: Vec<tuple::Tuple>, // like a view
-    filters: Vec<(String, Predicate)>,
+    plan: PlanNode,
+    data: Option<Vec<tuple::Tuple>>, // like a view
 }
 
 impl<'a> TableIterator<'a> {
-    // make a new table iterator and fill its vector with count tuples -
+    // make a new table iterator that scans up to count tuples from the table
     fn new(table: &'a Table, tid: TransactionId, count: usize) -> Self {
-        let mut data = Vec::new();
-        let mut count = count;
-        for page in table.heap_file.iter(tid) {
-            let page = page.read().unwrap();
-            for tuple in page.iter() {
-                if count == 0 {
-                    break;
-                }
-                count -= 1;
-                data.push(tuple.clone());
-            }
-        }
+        let plan = PlanNode::seq_scan(&table.name, Arc::clone(&table.heap_file), count);
+        TableIterator::from_plan(Arc::clone(&table.db), Some(table), tid, plan)
+    }
+
+    fn from_plan(
+        db: Arc<Database>,
+        table: Option<&'a Table>,
+        tid: TransactionId,
+        plan: PlanNode,
+    ) -> Self {
         TableIterator {
+            db,
             table,
             current_page_index: 0,
             tid,
-            data,
-            filters: Vec::new(),
+            plan,
+            data: None,
         }
     }
 
-    pub fn project(&self, fields: Vec<String>) -> TableIterator {
-        let mut data = Vec::new();
-
-        // take the Tuple and make a new TupleDesc for it as well as a new Fields for it
-        for tuple in self.data.iter() {
-            let mut new_field_types = Vec::new();
-            let mut new_field_vals = Vec::new();
-
-            // go through each of the fields for this tuple
-            for i in 0..tuple.get_tuple_desc().get_num_fields() {
-                let field_name = tuple.get_tuple_desc().get_field_name(i).unwrap().clone();
-
-                // Check if the field is in the list of fields to keep and has the desired type
-                if fields.contains(&field_name) {
-                    // we want to keep this field - so adding it to the new field types
-
-                    let field_type = tuple.get_tuple_desc().get_field_type(i).unwrap().clone();
-                    new_field_types.push(field_type);
-
-                    let field = tuple.get_field(i).unwrap().clone();
-                    new_field_vals.push(field);
-                }
-            }
-
-            // Create a new tuple descriptor with only the selected fields
-            let new_tuple_desc = TupleDesc::new(new_field_types, fields.clone());
-
-            // Create a new tuple with the selected fields
-            let new_tuple = Tuple::new(new_field_vals, &new_tuple_desc);
-
-            data.push(new_tuple);
-        }
-        // make a new iterator with the new data
-        TableIterator {
-            table: self.table,
-            current_page_index: 0,
-            tid: self.tid,
-            data,
-            filters: Vec::new(),
-        }
+    pub fn project(&self, fields: Vec<String>) -> TableIterator<'a> {
+        let plan = PlanNode::project(self.plan.clone(), fields);
+        TableIterator::from_plan(Arc::clone(&self.db), self.table, self.tid, plan)
     }
 
     pub fn table_filter(&mut self, field_name: &str, predicate: Predicate) {
-        self.filters.push((field_name.to_string(), predicate));
+        self.plan = PlanNode::filter(self.plan.clone(), field_name, predicate);
     }
 
+    #[allow(dead_code)]
     pub fn join(
         &self,
         other: &TableIterator,
         field_name_left: &str,
         field_name_right: &str,
-    ) -> TableIterator {
-        // making a new 'view'/ TableIterator using nxn from both tables
-        // field_name is the field/col that we are joining on
-        // similar to JOIN t1 ON t1.id = t2.id where id is field_name
-        let mut data = Vec::new();
-
-        for tuple in self.data.iter() {
-            println!("{}", tuple);
-            let target_col_left = tuple.get_tuple_desc().name_to_id(field_name_left).unwrap();
-            for other_tuple in other.data.iter() {
-                let target_col_right = other_tuple
-                    .get_tuple_desc()
-                    .name_to_id(field_name_right)
-                    .unwrap();
-                // check if the tuples match
-                // if they do, add them to the new view
-                if tuple.get_field(target_col_left).unwrap()
-                    == other_tuple.get_field(target_col_right).unwrap()
-                {
-                    // add the tuple to the new view
-
-                    // need to combine the two tuples
-
-                    // making a new TupleDesc
-                    let ctd: TupleDesc =
-                        TupleDesc::combine(tuple.get_tuple_desc(), other_tuple.get_tuple_desc());
-                    let combined_fields = tuple
-                        .get_fields()
-                        .iter()
-                        .chain(other_tuple.get_fields().iter())
-                        .cloned()
-                        .collect::<Vec<_>>();
-                    let new_tuple = Tuple::new(combined_fields, &ctd);
-                    data.push(new_tuple);
-                }
-            }
-        }
-        TableIterator {
-            table: self.table,
-            current_page_index: 0,
-            tid: self.tid,
-            data,
-            filters: Vec::new(),
+    ) -> TableIterator<'a> {
+        // similar to JOIN t1 ON t1.left = t2.right, comparing every pair of tuples
+        let plan = PlanNode::nested_loop_join(
+            self.plan.clone(),
+            other.plan.clone(),
+            field_name_left,
+            field_name_right,
+        );
+        TableIterator::from_plan(Arc::clone(&self.db), self.table, self.tid, plan)
+    }
+
+    // Describes the tuples the iterator produces
+    pub fn get_tuple_desc(&self) -> TupleDesc {
+        self.plan.get_tuple_desc()
+    }
+
+    // Describes the operator tree with estimated rows and costs without running it
+    pub fn explain(&self) -> String {
+        self.plan.explain()
+    }
+
+    // Runs the query and describes the operator tree with the measured rows, time,
+    // page accesses and lock waits of every operator. The results remain available
+    // through the iterator afterwards
+    pub fn explain_analyze(&mut self) -> Result<String, String> {
+        let (db, plan, tid) = (&self.db, &mut self.plan, self.tid);
+        self.data = Some(run_statement(db, tid, || plan.execute_analyze(db, tid))?);
+        self.current_page_index = 0;
+        Ok(self.plan.explain())
+    }
+
+    // Runs the query unless it already ran. Iterating runs it as well, but panics if the
+    // transaction is cancelled or times out, while this returns the error
+    pub fn fetch(&mut self) -> Result<(), String> {
+        if self.data.is_none() {
+            let (db, plan, tid) = (&self.db, &self.plan, self.tid);
+            self.data = Some(run_statement(db, tid, || plan.execute(db, tid))?);
         }
+        Ok(())
     }
 }
 
+// Runs the query as a statement of the transaction, which fails if the transaction was
+// cancelled or ran out of time
+fn run_statement(
+    db: &Database,
+    tid: TransactionId,
+    query: impl FnOnce() -> Result<Vec<Tuple>, String>,
+) -> Result<Vec<Tuple>, String> {
+    let bp = db.get_buffer_pool();
+    bp.begin_statement(tid)?;
+    let result = query();
+    bp.end_statement(tid);
+    result
+}
+
 impl<'a> Iterator for TableIterator<'a> {
     type Item = tuple::Tuple;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.current_page_index < self.data.len() {
-            let tuple = self.data[self.current_page_index].clone();
+        if let Err(error) = self.fetch() {
+            panic!("{}", error);
+        }
+        let data = self.data.as_ref().unwrap();
+        if self.current_page_index < data.len() {
+            let tuple = data[self.current_page_index].clone();
             self.current_page_index += 1;
-
-            // also apply any filters here - dumb but i think it would work
-            for filter in self.filters.iter() {
-                if !tuple.filter(&filter.0, &filter.1) {
-                    return self.next();
-                }
-            }
-
             Some(tuple)
         } else {
             None
@@ -224,6 +352,7 @@ impl<'a> Iterator for TableIterator<'a> {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum Predicate {
     Equals(String),
     EqualsInt(i32),
@@ -231,6 +360,18 @@ pub enum Predicate {
     LessThan(i32),
 }
 
+impl Predicate {
+    // Renders the predicate applied to the field like a SQL condition
+    pub fn describe(&self, field_name: &str) -> String {
+        match self {
+            Predicate::Equals(value) => format!("{} = '{}'", field_name, value),
+            Predicate::EqualsInt(value) => format!("{} = {}", field_name, value),
+            Predicate::GreaterThan(value) => format!("{} > {}", field_name, value),
+            Predicate::LessThan(value) => format!("{} < {}", field_name, value),
+        }
+    }
+}
+
 // trait to do filtering for filter()
 pub trait Filterable {
     fn filter(&self, field_name: &str, predicate: &Predicate) -> bool;
@@ -254,11 +395,6 @@ impl Filterable for Tuple {
                         }
                     }
                     Predicate::GreaterThan(value) => {
-                        print!(
-                            "field: {:?}\n",
-                            field.clone().into_int().unwrap().get_value()
-                        );
-                        print!("value: {:?}\n", value);
                         if let FieldVal::IntField(int_field) = &field {
                             return int_field.get_value() > *value;
                         } else {
@@ -1,4 +1,8 @@
+use crate::database::{self, Database};
+use crate::interrupt::CancellationToken;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct TransactionId {
@@ -6,15 +10,314 @@ pub struct TransactionId {
 }
 
 impl TransactionId {
+    pub fn get_tid(&self) -> u64 {
+        self.tid
+    }
+}
+
+// Hands out increasing transaction ids starting from 0, so that older transactions have
+// smaller ids. Every database has its own
+#[derive(Debug, Default)]
+pub struct TransactionIds {
+    next: AtomicU64,
+}
+
+impl TransactionIds {
     pub fn new() -> Self {
-        static COUNTER: AtomicU64 = AtomicU64::new(0);
-        let tid = COUNTER.fetch_add(1, Ordering::SeqCst);
-        TransactionId { tid }
+        TransactionIds::default()
     }
 
-    pub fn get_tid(&self) -> u64 {
+    pub fn next(&self) -> TransactionId {
+        TransactionId {
+            tid: self.next.fetch_add(1, Ordering::SeqCst),
+        }
+    }
+}
+
+// How much of the work of concurrent transactions a transaction may observe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum IsolationLevel {
+    // Sees the latest version of every tuple, including uncommitted changes
+    ReadUncommitted,
+    // Sees the changes committed before each statement started
+    ReadCommitted,
+    // Sees the changes committed before its first read, for the whole transaction
+    #[default]
+    RepeatableRead,
+    // Sees the latest committed changes and holds locks until it ends: intention shared
+    // locks on the tables it reads, shared locks on the rows of the pages it reads, and
+    // predicate locks on the filters of its scans, so concurrent writers can neither
+    // change what it read nor add rows its scans would return
+    Serializable,
+}
+
+// Where a transaction is in its lifecycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransactionState {
+    Active,
+    Committed,
+    Aborted,
+}
+
+/**
+ * Handle to a running transaction. The transaction ends when commit or rollback is
+ * called, and is rolled back automatically if the handle is dropped while still active,
+ * so its locks never outlive it.
+ */
+pub struct Transaction {
+    db: Arc<Database>,
+    tid: TransactionId,
+    isolation: IsolationLevel,
+    state: RwLock<TransactionState>,
+    token: CancellationToken,
+}
+
+impl Transaction {
+    pub fn builder() -> TransactionBuilder {
+        TransactionBuilder::default()
+    }
+
+    // Starts a transaction with the default isolation level
+    #[allow(dead_code)]
+    pub fn begin() -> Self {
+        Self::builder().begin()
+    }
+
+    pub fn get_tid(&self) -> TransactionId {
+        self.tid
+    }
+
+    // Retrieves the database the transaction runs in
+    #[allow(dead_code)]
+    pub fn get_db(&self) -> &Arc<Database> {
+        &self.db
+    }
+
+    #[allow(dead_code)]
+    pub fn get_isolation(&self) -> IsolationLevel {
+        self.isolation
+    }
+
+    // Retrieves the state of the transaction. A transaction the lock manager aborted, such
+    // as a deadlock victim, counts as aborted although its handle has not ended yet
+    pub fn get_state(&self) -> TransactionState {
+        match self.get_handle_state() {
+            TransactionState::Active if self.was_aborted() => TransactionState::Aborted,
+            state => state,
+        }
+    }
+
+    fn get_handle_state(&self) -> TransactionState {
+        // a panic while ending the transaction must not make Drop panic again
+        *self.state.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Checks whether the transaction was rolled back without its handle, which happens
+    // when the lock manager aborts it and unwinds its waiting statement
+    fn was_aborted(&self) -> bool {
+        let bp = self.db.get_buffer_pool();
+        !bp.get_transaction_table().is_running(self.tid)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.get_state() == TransactionState::Active
+    }
+
+    // Retrieves the token that cancels the transaction, which can be handed to other
+    // threads
+    pub fn get_cancellation_token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    // Makes the running statement fail and abort the transaction at its next check
+    #[allow(dead_code)]
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    // Retrieves the id of the transaction, panicking if it already ended since its work
+    // would otherwise run outside of any transaction
+    pub fn get_active_tid(&self) -> TransactionId {
+        if !self.is_active() {
+            panic!("Transaction {:?} is no longer active", self.tid);
+        }
         self.tid
     }
+
+    // Makes the changes of the transaction durable and visible, and releases its locks
+    pub fn commit(&self) -> Result<(), String> {
+        self.end(TransactionState::Committed)
+    }
+
+    // Undoes the changes of the transaction and releases its locks
+    pub fn rollback(&self) -> Result<(), String> {
+        self.end(TransactionState::Aborted)
+    }
+
+    // Runs a statement of the transaction. The statement fails without running if the
+    // transaction was cancelled or sat idle for longer than its idle timeout, and counts
+    // towards its statement timeout while it runs
+    pub fn run_statement<T>(
+        &self,
+        statement: impl FnOnce(TransactionId) -> T,
+    ) -> Result<T, String> {
+        let tid = self.check_active()?;
+        let bp = self.db.get_buffer_pool();
+        bp.begin_statement(tid)?;
+        let result = statement(tid);
+        bp.end_statement(tid);
+        Ok(result)
+    }
+
+    // Marks the current state of the transaction so that later work can be undone alone
+    pub fn savepoint(&self, name: &str) -> Result<(), String> {
+        let bp = self.db.get_buffer_pool();
+        self.run_statement(|tid| bp.set_savepoint(tid, name))
+    }
+
+    // Undoes the work done since the savepoint, keeping the work done before it
+    pub fn rollback_to(&self, name: &str) -> Result<(), String> {
+        let bp = self.db.get_buffer_pool();
+        self.run_statement(|tid| bp.rollback_to_savepoint(tid, name))?
+    }
+
+    // Forgets the savepoint, keeping the work done since it
+    pub fn release(&self, name: &str) -> Result<(), String> {
+        let bp = self.db.get_buffer_pool();
+        self.run_statement(|tid| bp.release_savepoint(tid, name))?
+    }
+
+    fn check_active(&self) -> Result<TransactionId, String> {
+        if !self.is_active() {
+            return Err(format!("Transaction {:?} is no longer active", self.tid));
+        }
+        Ok(self.tid)
+    }
+
+    // Describes why the transaction was rolled back without its handle
+    fn describe_abort(&self) -> String {
+        let bp = self.db.get_buffer_pool();
+        let interrupts = bp.get_interrupts();
+        let reason = interrupts.check(self.tid).or_else(|| {
+            let aborts = bp.get_lock_manager().get_abort_history();
+            let abort = aborts
+                .iter()
+                .rev()
+                .find(|abort| abort.get_tid() == self.tid);
+            abort.map(|abort| abort.get_reason())
+        });
+        match reason {
+            Some(reason) => interrupts.get_error(self.tid, reason),
+            None => format!("Transaction {:?} was aborted", self.tid),
+        }
+    }
+
+    fn end(&self, new_state: TransactionState) -> Result<(), String> {
+        let mut state = self.state.write().unwrap_or_else(PoisonError::into_inner);
+        if *state != TransactionState::Active {
+            return Err(format!(
+                "Transaction {:?} already ended as {:?}",
+                self.tid, *state
+            ));
+        }
+        let bp = self.db.get_buffer_pool();
+        if self.was_aborted() {
+            // only the handle is left to end
+            let result = match new_state {
+                TransactionState::Committed => Err(self.describe_abort()),
+                _ => Ok(()),
+            };
+            bp.get_interrupts().remove(self.tid);
+            *state = TransactionState::Aborted;
+            return result;
+        }
+        // committing counts as a statement, so the transaction cannot be aborted as idle
+        // while it commits. An interrupted transaction was already aborted and cannot commit
+        let mut result = match new_state {
+            TransactionState::Committed => bp.begin_statement(self.tid),
+            _ => Ok(()),
+        };
+        match (new_state, &result) {
+            (TransactionState::Committed, Ok(())) => result = bp.commit_transaction(self.tid),
+            _ => bp.abort_transaction(self.tid),
+        }
+        bp.get_interrupts().remove(self.tid);
+        *state = if result.is_ok() {
+            new_state
+        } else {
+            TransactionState::Aborted
+        };
+        result
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if self.get_handle_state() == TransactionState::Active {
+            let _ = self.rollback();
+        }
+    }
+}
+
+// Collects the options of a transaction before starting it
+#[derive(Default)]
+pub struct TransactionBuilder {
+    // the global database if None
+    db: Option<Arc<Database>>,
+    isolation: IsolationLevel,
+    statement_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+}
+
+impl TransactionBuilder {
+    // Runs the transaction in the database instead of the global one
+    pub fn database(mut self, db: &Arc<Database>) -> Self {
+        self.db = Some(Arc::clone(db));
+        self
+    }
+
+    pub fn isolation(mut self, isolation: IsolationLevel) -> Self {
+        self.isolation = isolation;
+        self
+    }
+
+    // Aborts the transaction when a single statement runs longer than the timeout
+    #[allow(dead_code)]
+    pub fn statement_timeout(mut self, timeout: Duration) -> Self {
+        self.statement_timeout = Some(timeout);
+        self
+    }
+
+    // Aborts the transaction when it sits between statements longer than the timeout
+    // while another transaction waits for its locks, or before its next statement
+    #[allow(dead_code)]
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    // Starts the transaction and registers its isolation level and timeouts with the
+    // buffer pool
+    pub fn begin(self) -> Transaction {
+        let db = self.db.unwrap_or_else(database::get_global_db);
+        let tid = db.next_transaction_id();
+        let token = CancellationToken::new();
+        let bp = db.get_buffer_pool();
+        bp.begin_transaction(tid, self.isolation);
+        bp.get_interrupts().register(
+            tid,
+            token.clone(),
+            self.statement_timeout,
+            self.idle_timeout,
+        );
+        Transaction {
+            db,
+            tid,
+            isolation: self.isolation,
+            state: RwLock::new(TransactionState::Active),
+            token,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -22,15 +325,17 @@ mod tests {
     use super::*;
     #[test]
     fn test_transaction_id_increments() {
-        let tid1 = TransactionId::new();
-        let tid2 = TransactionId::new();
+        let ids = TransactionIds::new();
+        let tid1 = ids.next();
+        let tid2 = ids.next();
         assert_ne!(tid1, tid2);
     }
 
     #[test]
     fn test_transaction_id_get_tid() {
-        let tid1 = TransactionId::new();
-        let tid2 = TransactionId::new();
+        let ids = TransactionIds::new();
+        let tid1 = ids.next();
+        let tid2 = ids.next();
         assert_eq!(tid1.get_tid(), 0);
         assert_eq!(tid2.get_tid(), 1);
     }
@@ -142,15 +142,16 @@ impl Tuple {
         bytes
     }
 
-    pub fn deserialize(bytes: &[u8], td: &TupleDesc) -> Self {
+    // Reads a tuple written by serialize, failing if a field cannot be parsed
+    pub fn deserialize(bytes: &[u8], td: &TupleDesc) -> Result<Self, String> {
         let mut offset = 0;
         let mut fields = vec![];
         for t in td.types.iter() {
-            let field = t.parse(&bytes[offset..]).unwrap();
+            let field = t.parse(bytes.get(offset..).unwrap_or_default())?;
             offset += t.get_len();
             fields.push(field);
         }
-        Tuple::new(fields, td)
+        Ok(Tuple::new(fields, td))
     }
 
     pub fn get_fields(&self) -> Vec<FieldVal> {
@@ -205,7 +206,7 @@ mod tests {
             &td,
         );
         let bytes = tuple.serialize();
-        let tuple2 = Tuple::deserialize(&bytes, &td);
+        let tuple2 = Tuple::deserialize(&bytes, &td).unwrap();
         assert_eq!(tuple, tuple2);
     }
 }
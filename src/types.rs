@@ -20,8 +20,40 @@ impl Type {
         }
     }
 
-    // Parse bytes into a FieldVal
+    // Names the type as schema files do
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Type::IntType => "Int",
+            Type::StringType => "String",
+        }
+    }
+
+    // Converts text, such as a field of an imported file, into a value of this type
+    pub fn coerce(&self, text: &str) -> Result<FieldVal, String> {
+        match self {
+            Type::IntType => text
+                .trim()
+                .parse()
+                .map(|value| FieldVal::IntField(IntField::new(value)))
+                .map_err(|_| format!("\"{}\" is not a valid Int", text)),
+            Type::StringType if text.len() > STRING_SIZE => Err(format!(
+                "String of {} bytes exceeds {}",
+                text.len(),
+                STRING_SIZE
+            )),
+            Type::StringType => Ok(FieldVal::StringField(StringField::new(
+                text.to_string(),
+                text.len() as u32,
+            ))),
+        }
+    }
+
+    // Parse bytes into a FieldVal. Fails if the bytes are too short or hold a string
+    // whose length or contents are invalid
     pub fn parse(&self, bytes: &[u8]) -> Result<FieldVal, String> {
+        if bytes.len() < self.get_len() {
+            return Err(format!("{:?} needs {} bytes", self, self.get_len()));
+        }
         match self {
             Type::IntType => {
                 let mut int_bytes = [0; 4];
@@ -34,11 +66,13 @@ impl Type {
                 let mut len_bytes = [0; 4];
                 len_bytes.copy_from_slice(&bytes[..4]);
                 let len = u32::from_be_bytes(len_bytes);
+                if len as usize > STRING_SIZE {
+                    return Err(format!("String length {} exceeds {}", len, STRING_SIZE));
+                }
                 let string_bytes = bytes[4..len as usize + 4].to_vec();
-                Ok(FieldVal::StringField(StringField::new(
-                    String::from_utf8(string_bytes.to_vec()).unwrap(),
-                    len,
-                )))
+                let string = String::from_utf8(string_bytes)
+                    .map_err(|e| format!("String is not valid UTF-8: {}", e))?;
+                Ok(FieldVal::StringField(StringField::new(string, len)))
             }
         }
     }
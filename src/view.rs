@@ -1,10 +1,7 @@
 use std::sync::Arc;
 
-use crate::database;
-use crate::database::Database;
 use crate::heap_file::HeapFile;
 use crate::transaction;
-use crate::tuple::Tuple;
 use crate::tuple::TupleDesc; // Import the `database` module
 
 // have an iterator over the tuples in the table
@@ -43,6 +40,7 @@ use crate::tuple::TupleDesc; // Import the `database` module
 // for joins - take both tables into memory and then do the join and combine it nxn
 
 // make a Table struct, inside it it stores HeapFile and then call the functions on it
+#[allow(dead_code)]
 pub struct View {
     name: String,
     td: TupleDesc,
@@ -51,6 +49,7 @@ pub struct View {
     // db: Arc<Database>,
 }
 
+#[allow(dead_code)]
 impl View {
     // pub fn new(name: String, td: TupleDesc, table: Arc<HeapFile>) -> Self {
     //     let mut data = vec![];
@@ -61,7 +60,7 @@ impl View {
     //         let page = page.read().unwrap();
     //         page_count += 1;
     //         for tuple in page.iter() {
-    //             // print!("tuple: {:?}\n", tuple);
+    //             // println!("tuple: {:?}", tuple);
     //             data.push(tuple.clone());
     //             tuple_count += 1;
     //         }
@@ -88,24 +87,30 @@ impl View {
         &self.table
     }
 
-    pub fn print(&self) {
-        let db = database::get_global_db();
+    pub fn print(&self, txn: &transaction::Transaction) {
+        let db = self.table.get_db();
         let mut tuple_count = 0;
         let mut page_count = 0;
-        let tid = transaction::TransactionId::new();
+        let tid = txn.get_active_tid();
+        let snapshot = db.get_buffer_pool().get_snapshot(tid);
         for page in self.table.iter(tid) {
+            let page = match page {
+                Ok(page) => page,
+                Err(error) => {
+                    println!("{}", error);
+                    break;
+                }
+            };
             let page = page.read().unwrap();
             page_count += 1;
-            for tuple in page.iter() {
-                print!("tuple: {:?}\n", tuple);
+            for tuple in page.iter_visible(&snapshot) {
+                println!("tuple: {:?}", tuple);
                 tuple_count += 1;
             }
         }
-        let bp = db.get_buffer_pool();
-        bp.commit_transaction(tid);
 
-        print!("page count: {}\n", page_count);
-        print!("tuple count: {}\n", tuple_count);
+        println!("page count: {}", page_count);
+        println!("tuple count: {}", tuple_count);
     }
 
     // pub fn filter(&self, predicate: &dyn Fn(&Tuple) -> bool) -> Self {